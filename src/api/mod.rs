@@ -1,21 +1,59 @@
+pub mod ws;
+
 use serde::Serialize;
 use crate::miner::Handle as Handle;
+use crate::txgenerator::{Handle as GeneratorHandle, ValueDistribution};
 use crate::network::server::Handle as NetworkServerHandle;
+use crate::receipt::ReceiptStatus;
 use crate::network::message::Message;
+use crate::archive;
+use crate::blockchain::{Blockchain, TransactionLocation};
+use crate::block::{AccountState, Model, State};
+use crate::crypto::address::H160;
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::{SignatureScheme, SignedTransaction, Transaction, TxValidationError, MAX_INPUTS};
+use crate::mempool::{DropReason, Mempool};
+use crate::events::Metrics;
+use crate::faucet::Faucet;
+use crate::fee::FeeEstimator;
+use crate::latency::LatencySummary;
+use crate::network::compression::CompressionSummary;
+use crate::light::HeaderChain;
 
 use log::info;
 use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+use std::path::{Component, Path, PathBuf};
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+/// How long a light client waits for the full nodes to serve a merkle proof.
+static PROOF_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
 pub struct Server {
     handle: HTTPServer,
-    miner: Handle,
-    generator: Handle,
-    network: NetworkServerHandle,
+    ctx: Context,
+}
+
+/// The parts of the node the API serves and controls.
+#[derive(Clone)]
+pub struct Context {
+    pub miner: Handle,
+    pub generator: GeneratorHandle,
+    pub network: NetworkServerHandle,
+    pub blockchain: Arc<Blockchain>,
+    pub tx_mempool: Arc<Mutex<Mempool>>,
+    pub fee_estimator: Arc<FeeEstimator>,
+    /// Serves `/faucet/request` when set.
+    pub faucet: Option<Arc<Faucet>>,
+    pub metrics: Arc<Metrics>,
+    /// Serves `/blockchain/export` when set, writing the block files into this directory only.
+    pub export_dir: Option<PathBuf>,
 }
 
 #[derive(Serialize)]
@@ -24,6 +62,248 @@ struct ApiResponse {
     message: String,
 }
 
+/// A transaction refused by the mempool, with the reason as counted in the peer statistics.
+#[derive(Serialize)]
+struct RejectedResponse {
+    success: bool,
+    message: String,
+    reason: &'static str,
+}
+
+#[derive(Serialize)]
+struct BalanceResponse {
+    address: String,
+    balance: u64,
+    nonce: u64,
+    /// token id -> balance
+    tokens: HashMap<String, u64>,
+}
+
+#[derive(Serialize)]
+struct TransactionResponse {
+    txid: String,
+    block: String,
+    position: usize,
+    confirmations: u32,
+    outputs: Vec<OutputResponse>,
+    fee: u64,
+    nonce: u64,
+    data: String,
+}
+
+/// A transaction that touched an address, see `Blockchain::get_history`.
+#[derive(Serialize)]
+struct HistoryEntryResponse {
+    block: String,
+    txid: String,
+    /// None once the block was pruned.
+    height: Option<u32>,
+    /// 0 if the block is off the longest chain.
+    confirmations: u32,
+}
+
+#[derive(Serialize)]
+struct FeeEstimateResponse {
+    target_blocks: u32,
+    /// At least the minimum fee of the mempool.
+    fee: u64,
+    /// Whether transactions were confirmed in the recent blocks, otherwise the fee is the minimum.
+    from_history: bool,
+}
+
+#[derive(Serialize)]
+struct TransactionStatusResponse {
+    txid: String,
+    /// confirmed, pending, queued, dropped or unknown
+    status: &'static str,
+    block: Option<String>,
+    height: Option<u32>,
+    confirmations: Option<u32>,
+    /// Why a dropped transaction was dropped.
+    reason: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UnsignedTransactionResponse {
+    /// Hex encoded canonical bytes, see `Transaction::to_bytes`.
+    transaction: String,
+    /// The digest to sign.
+    digest: String,
+}
+
+#[derive(Serialize)]
+struct ReceiptResponse {
+    txid: String,
+    block: String,
+    position: usize,
+    success: bool,
+    gas_used: u64,
+    cumulative_gas_used: u64,
+    balances: Vec<BalanceAfterResponse>,
+}
+
+#[derive(Serialize)]
+struct BalanceAfterResponse {
+    address: String,
+    balance: u64,
+}
+
+#[derive(Serialize, Debug)]
+struct OutputResponse {
+    recipient: String,
+    value: u64,
+}
+
+/// Where a transaction stands, see `get_transaction_status`.
+enum TransactionStatus {
+    /// In a block of the longest chain.
+    Confirmed(Box<TransactionLocation>),
+    /// In the mempool, ready for the next block.
+    Pending,
+    /// In the mempool, after a nonce gap.
+    Queued,
+    Dropped(DropReason),
+    Unknown,
+}
+
+/// The status of `txid`: confirmed by the longest chain, otherwise waiting in the mempool,
+/// otherwise dropped by the mempool recently.
+fn get_transaction_status(txid: &H256, blockchain: &Blockchain, tx_mempool: &Mutex<Mempool>) -> TransactionStatus {
+    if let Some(location) = blockchain.get_transaction(txid).filter(|location| location.confirmations > 0) {
+        return TransactionStatus::Confirmed(Box::new(location));
+    }
+    let tx_mempool = tx_mempool.lock().unwrap();
+    if tx_mempool.is_pending(txid) {
+        TransactionStatus::Pending
+    } else if tx_mempool.contains_key(txid) {
+        TransactionStatus::Queued
+    } else {
+        match tx_mempool.drop_reason(txid) {
+            Some(reason) => TransactionStatus::Dropped(reason),
+            None => TransactionStatus::Unknown,
+        }
+    }
+}
+
+/// Check the signature of `tx`, add it to the mempool on top of the tip and broadcast it.
+fn submit(tx: SignedTransaction, blockchain: &Blockchain, tx_mempool: &Mutex<Mempool>, network: &NetworkServerHandle) -> Result<H256, TxValidationError> {
+    if !tx.has_valid_signature() {
+        return Err(TxValidationError::BadSignature);
+    }
+    let tx_hash = tx.hash();
+    let (_, state) = blockchain.tip_with_state();
+    tx_mempool.lock().unwrap().insert(tx.clone(), &state)?;
+    network.broadcast(Message::Transactions(vec![tx]));
+    Ok(tx_hash)
+}
+
+/// A transfer of `value` from `from` to `to` on top of `state`, unsigned. In the account model,
+/// its nonce follows the transactions of `from` waiting in `tx_mempool`. In the UTXO model, it
+/// spends the outputs of `from` until they cover the cost and returns the rest as change. `None`
+/// if the balance of `from` does not cover the cost.
+fn unsigned_transfer(state: &State, tx_mempool: &Mempool, from: &H160, to: &H160, value: u64, fee: u64) -> Option<Transaction> {
+    let cost = value.checked_add(fee)?;
+    let mut transaction = Transaction {
+        outputs: vec![(*to, value)],
+        fee,
+        ..Default::default()
+    };
+    match state.model {
+        Model::Account => {
+            let account = state.account_state.get(from).cloned().unwrap_or_default();
+            if account.balance < cost {
+                return None;
+            }
+            // a transfer exported before the previous ones confirm must not replace them
+            transaction.account_nonce = (account.nonce + 1..).find(|nonce| !tx_mempool.has_nonce(from, *nonce)).unwrap();
+        }
+        Model::Utxo => {
+            let mut total = 0u64;
+            for utxo in state.unspent_of(from).take(MAX_INPUTS) {
+                if total >= cost {
+                    break;
+                }
+                transaction.inputs.push(utxo.outpoint);
+                total = total.saturating_add(utxo.value);
+            }
+            if total < cost {
+                return None;
+            }
+            if total > cost {
+                transaction.outputs.push((*from, total - cost));
+            }
+        }
+    }
+    Some(transaction)
+}
+
+fn output_responses(transaction: &Transaction) -> Vec<OutputResponse> {
+    transaction.outputs.iter().map(|(recipient, value)| OutputResponse {
+        recipient: recipient.to_checksummed(),
+        value: *value,
+    }).collect()
+}
+
+#[derive(Serialize)]
+struct BlockResponse {
+    hash: String,
+    parent: String,
+    height: u32,
+    nonce: u32,
+    extra_nonce: u64,
+    timestamp: u128,
+    merkle_root: String,
+    state_root: String,
+    transactions: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct TipResponse {
+    hash: String,
+    parent: String,
+    height: u32,
+    timestamp: u128,
+    num_transactions: usize,
+    mempool_size: usize,
+}
+
+#[derive(Serialize)]
+struct HeaderTipResponse {
+    hash: String,
+    parent: String,
+    height: u32,
+    timestamp: u128,
+}
+
+#[derive(Serialize)]
+struct ProvenTransactionResponse {
+    txid: String,
+    block: String,
+    confirmations: u32,
+}
+
+#[derive(Serialize)]
+struct ProvenBalanceResponse {
+    address: String,
+    block: String,
+    balance: u64,
+    nonce: u64,
+}
+
+#[derive(Serialize)]
+struct MetricsResponse {
+    blocks_mined: u64,
+    blocks_accepted: u64,
+    reorgs: u64,
+    transactions_accepted: u64,
+    transactions_generated: u64,
+    peers_connected: u64,
+    peers_disconnected: u64,
+    malformed_messages: u64,
+    confirmation_latency: LatencySummary,
+    compression: CompressionSummary,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -37,25 +317,75 @@ macro_rules! respond_result {
     }};
 }
 
-impl Server {
-    pub fn start(
-        addr: std::net::SocketAddr,
-        miner: &Handle,
-        generator: &Handle,
-        network: &NetworkServerHandle,
-    ) {
-        let handle = HTTPServer::http(&addr).unwrap();
-        let server = Self {
-            handle,
-            miner: miner.clone(),
-            generator: generator.clone(),
-            network: network.clone(),
+macro_rules! respond_json {
+    ( $req:expr, $payload:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$payload).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+/// The query parameter `$name` of `$params` parsed by `$parse`. Responds with an error and
+/// returns if it is missing or does not parse.
+macro_rules! query_param {
+    ( $req:expr, $params:expr, $name:expr, $parse:expr ) => {{
+        match $params.get($name).map($parse) {
+            Some(Ok(v)) => v,
+            Some(Err(e)) => {
+                respond_result!($req, false, format!("error parsing {}: {}", $name, e));
+                return;
+            }
+            None => {
+                respond_result!($req, false, format!("missing {}", $name));
+                return;
+            }
+        }
+    }};
+}
+
+macro_rules! lambda_param {
+    ( $req:expr, $url:expr ) => {{
+        let params = $url.query_pairs();
+        let params: HashMap<_, _> = params.into_owned().collect();
+        let lambda = match params.get("lambda") {
+            Some(v) => v,
+            None => {
+                respond_result!($req, false, "missing lambda");
+                return;
+            }
         };
+        match lambda.parse::<u64>() {
+            Ok(v) => v,
+            Err(e) => {
+                respond_result!(
+                    $req,
+                    false,
+                    format!("error parsing lambda: {}", e)
+                );
+                return;
+            }
+        }
+    }};
+}
+
+impl Server {
+    pub fn start(addr: std::net::SocketAddr, ctx: Context) {
+        let handle = HTTPServer::http(addr).unwrap();
+        let server = Self { handle, ctx };
         thread::spawn(move || {
             for req in server.handle.incoming_requests() {
-                let miner = server.miner.clone();
-                let generator = server.generator.clone();
-                let network = server.network.clone();
+                let Context {
+                    miner,
+                    generator,
+                    network,
+                    blockchain,
+                    tx_mempool,
+                    fee_estimator,
+                    faucet,
+                    metrics,
+                    export_dir,
+                } = server.ctx.clone();
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -67,35 +397,455 @@ impl Server {
                         }
                     };
                     match url.path() {
+                        // starts the txgenerator too, unless called with generator=false
                         "/miner/start" => {
-                            let params = url.query_pairs();
-                            let params: HashMap<_, _> = params.into_owned().collect();
-                            let lambda = match params.get("lambda") {
-                                Some(v) => v,
+                            let lambda = lambda_param!(req, url);
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            miner.start(lambda);
+                            if params.get("generator").map(|v| v.as_str()) != Some("false") {
+                                generator.start(lambda);
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/stop" => {
+                            miner.exit();
+                            generator.exit();
+                            respond_result!(req, true, "exit");
+                        }
+                        "/miner/rate" => {
+                            let lambda = lambda_param!(req, url);
+                            miner.set_rate(lambda);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/miner/pause" => {
+                            miner.pause();
+                            respond_result!(req, true, "paused");
+                        }
+                        "/miner/resume" => {
+                            miner.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        "/generator/start" => {
+                            let lambda = lambda_param!(req, url);
+                            generator.start(lambda);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/generator/stop" => {
+                            generator.exit();
+                            respond_result!(req, true, "exit");
+                        }
+                        "/generator/rate" => {
+                            let lambda = lambda_param!(req, url);
+                            generator.set_rate(lambda);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/generator/pause" => {
+                            generator.pause();
+                            respond_result!(req, true, "paused");
+                        }
+                        "/generator/resume" => {
+                            generator.resume();
+                            respond_result!(req, true, "ok");
+                        }
+                        // a comma separated list of addresses, none for every address of the state
+                        "/generator/targets" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let targets = params.get("addresses").map(|v| v.as_str()).unwrap_or("")
+                                .split(',')
+                                .filter(|v| !v.is_empty())
+                                .map(|v| v.parse::<H160>())
+                                .collect::<Result<Vec<_>, _>>();
+                            match targets {
+                                Ok(targets) => {
+                                    generator.set_targets(targets);
+                                    respond_result!(req, true, "ok");
+                                }
+                                Err(e) => respond_result!(req, false, format!("error parsing addresses: {}", e)),
+                            }
+                        }
+                        // half the balance by default, or a fixed value, or uniform from min to max
+                        "/generator/values" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let values = match params.get("distribution").map(|v| v.as_str()) {
+                                None | Some("half") => ValueDistribution::HalfBalance,
+                                Some("fixed") => ValueDistribution::Fixed(query_param!(req, params, "value", |v| v.parse::<u64>())),
+                                Some("uniform") => ValueDistribution::Uniform(
+                                    query_param!(req, params, "min", |v| v.parse::<u64>()),
+                                    query_param!(req, params, "max", |v| v.parse::<u64>()),
+                                ),
+                                Some(v) => {
+                                    respond_result!(req, false, format!("unknown value distribution {}", v));
+                                    return;
+                                }
+                            };
+                            generator.set_values(values);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/generator/burst" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let count = query_param!(req, params, "count", |v| v.parse::<usize>());
+                            generator.burst(count);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/faucet/request" => {
+                            let faucet = match &faucet {
+                                Some(faucet) => faucet,
                                 None => {
-                                    respond_result!(req, false, "missing lambda");
+                                    respond_result!(req, false, "no faucet on this node");
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address = query_param!(req, params, "address", |v| v.parse::<H160>());
+                            let amount = query_param!(req, params, "amount", |v| v.parse::<u64>());
+                            let (_, state) = blockchain.tip_with_state();
+                            let transfer = faucet.transfer(&address, amount, &state, &tx_mempool.lock().unwrap());
+                            let tx = match transfer {
+                                Ok(tx) => tx,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
                                     return;
                                 }
                             };
-                            let lambda = match lambda.parse::<u64>() {
+                            match submit(tx, &blockchain, &tx_mempool, &network) {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/transaction/submit" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let raw = query_param!(req, params, "tx", hex::decode);
+                            let tx: SignedTransaction = match bincode::deserialize(&raw) {
                                 Ok(v) => v,
                                 Err(e) => {
-                                    respond_result!(
-                                        req,
-                                        false,
-                                        format!("error parsing lambda: {}", e)
-                                    );
+                                    respond_result!(req, false, format!("error parsing tx: {}", e));
                                     return;
                                 }
                             };
-                            miner.start(lambda);
-                            generator.start(lambda);
-                            respond_result!(req, true, "ok");
+                            match submit(tx, &blockchain, &tx_mempool, &network) {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_json!(req, RejectedResponse {
+                                    success: false,
+                                    message: e.to_string(),
+                                    reason: e.name(),
+                                }),
+                            }
                         }
-                        "/miner/stop" => {
-                            miner.exit();
-                            generator.exit();
-                            respond_result!(req, true, "exit");
+                        // a transfer for an external signer to sign, see `/transaction/assemble`
+                        "/transaction/unsigned" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let from = query_param!(req, params, "from", |v| v.parse::<H160>());
+                            let to = query_param!(req, params, "to", |v| v.parse::<H160>());
+                            let value = query_param!(req, params, "value", |v| v.parse::<u64>());
+                            let fee = match params.get("fee").map(|v| v.parse::<u64>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing fee: {}", e));
+                                    return;
+                                }
+                                None => 0,
+                            };
+                            let (_, state) = blockchain.tip_with_state();
+                            let transaction = unsigned_transfer(&state, &tx_mempool.lock().unwrap(), &from, &to, value, fee);
+                            match transaction {
+                                Some(transaction) => respond_json!(req, UnsignedTransactionResponse {
+                                    transaction: hex::encode(transaction.to_bytes()),
+                                    digest: transaction.hash().to_string(),
+                                }),
+                                None => respond_result!(req, false, "the balance does not cover the transfer"),
+                            }
+                        }
+                        "/transaction/assemble" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let raw = query_param!(req, params, "tx", hex::decode);
+                            let signature = query_param!(req, params, "signature", hex::decode);
+                            let public_key = query_param!(req, params, "public_key", hex::decode);
+                            let scheme = match params.get("scheme").map(|v| v.as_str()) {
+                                None | Some("ed25519") => SignatureScheme::Ed25519,
+                                Some("secp256k1") => SignatureScheme::Secp256k1,
+                                Some(v) => {
+                                    respond_result!(req, false, format!("unknown signature scheme {}", v));
+                                    return;
+                                }
+                            };
+                            let transaction = match Transaction::from_bytes(&raw) {
+                                Some(transaction) => transaction,
+                                None => {
+                                    respond_result!(req, false, "error parsing tx");
+                                    return;
+                                }
+                            };
+                            let tx = match SignedTransaction::assemble(transaction, scheme, signature, public_key) {
+                                Some(tx) => tx,
+                                None => {
+                                    respond_result!(req, false, "invalid signature");
+                                    return;
+                                }
+                            };
+                            match submit(tx, &blockchain, &tx_mempool, &network) {
+                                Ok(tx_hash) => respond_result!(req, true, tx_hash),
+                                Err(e) => respond_json!(req, RejectedResponse {
+                                    success: false,
+                                    message: e.to_string(),
+                                    reason: e.name(),
+                                }),
+                            }
+                        }
+                        "/transaction/status" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let txid = query_param!(req, params, "txid", |v| v.parse::<H256>());
+                            let mut response = TransactionStatusResponse {
+                                txid: txid.to_string(),
+                                status: "unknown",
+                                block: None,
+                                height: None,
+                                confirmations: None,
+                                reason: None,
+                            };
+                            match get_transaction_status(&txid, &blockchain, &tx_mempool) {
+                                TransactionStatus::Confirmed(location) => {
+                                    response.status = "confirmed";
+                                    response.block = Some(location.block_hash.to_string());
+                                    response.height = Some(location.height);
+                                    response.confirmations = Some(location.confirmations);
+                                }
+                                TransactionStatus::Pending => response.status = "pending",
+                                TransactionStatus::Queued => response.status = "queued",
+                                TransactionStatus::Dropped(reason) => {
+                                    response.status = "dropped";
+                                    response.reason = Some(reason.to_string());
+                                }
+                                TransactionStatus::Unknown => {}
+                            }
+                            respond_json!(req, response);
+                        }
+                        "/blockchain/balance" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address = match params.get("address").map(|v| v.parse::<H160>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing address: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing address");
+                                    return;
+                                }
+                            };
+                            let (_, state) = blockchain.tip_with_state();
+                            // the unspent outputs of the address make its balance in the UTXO model
+                            let account = match state.model {
+                                Model::Account => state.account_state.get(&address).cloned(),
+                                Model::Utxo if state.address_list.contains(&address) => Some(AccountState {
+                                    balance: state.balance(&address),
+                                    ..Default::default()
+                                }),
+                                Model::Utxo => None,
+                            };
+                            match account {
+                                Some(account) => respond_json!(req, BalanceResponse {
+                                    address: address.to_checksummed(),
+                                    balance: account.balance,
+                                    nonce: account.nonce,
+                                    tokens: account.tokens.iter().map(|(token, balance)| (token.to_checksummed(), *balance)).collect(),
+                                }),
+                                None => respond_result!(req, false, "unknown address"),
+                            }
+                        }
+                        "/blockchain/transaction" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let txid = match params.get("txid").map(|v| v.parse::<H256>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing txid: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing txid");
+                                    return;
+                                }
+                            };
+                            match blockchain.get_transaction(&txid) {
+                                Some(location) => respond_json!(req, TransactionResponse {
+                                    txid: txid.to_string(),
+                                    block: location.block_hash.to_string(),
+                                    position: location.position,
+                                    confirmations: location.confirmations,
+                                    outputs: output_responses(&location.transaction.transaction),
+                                    fee: location.transaction.transaction.fee,
+                                    nonce: location.transaction.transaction.account_nonce,
+                                    data: hex::encode(&location.transaction.transaction.data),
+                                }),
+                                None => respond_result!(req, false, "transaction not found in any block"),
+                            }
+                        }
+                        "/blockchain/history" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let address = query_param!(req, params, "address", |v| v.parse::<H160>());
+                            let tip_height = blockchain.height();
+                            let history: Vec<HistoryEntryResponse> = blockchain.get_history(&address).into_iter()
+                                .map(|(block_hash, txid)| {
+                                    let height = blockchain.get_block_height(&block_hash);
+                                    let confirmations = match height {
+                                        Some(height) if blockchain.get_hash_by_height(height) == Some(block_hash) => tip_height.saturating_sub(height) + 1,
+                                        _ => 0,
+                                    };
+                                    HistoryEntryResponse {
+                                        block: block_hash.to_string(),
+                                        txid: txid.to_string(),
+                                        height,
+                                        confirmations,
+                                    }
+                                })
+                                .collect();
+                            respond_json!(req, history);
+                        }
+                        "/blockchain/receipt" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let txid = match params.get("txid").map(|v| v.parse::<H256>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing txid: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing txid");
+                                    return;
+                                }
+                            };
+                            match blockchain.get_receipt(&txid) {
+                                Some(receipt) => respond_json!(req, ReceiptResponse {
+                                    txid: receipt.txid.to_string(),
+                                    block: receipt.block_hash.to_string(),
+                                    position: receipt.position,
+                                    success: receipt.status == ReceiptStatus::Success,
+                                    gas_used: receipt.gas_used,
+                                    cumulative_gas_used: receipt.cumulative_gas_used,
+                                    balances: receipt.balances.iter().map(|(address, balance)| BalanceAfterResponse {
+                                        address: address.to_checksummed(),
+                                        balance: *balance,
+                                    }).collect(),
+                                }),
+                                None => respond_result!(req, false, "no receipt for the transaction"),
+                            }
+                        }
+                        "/blockchain/block" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let height = match params.get("height").map(|v| v.parse::<u32>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing height: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing height");
+                                    return;
+                                }
+                            };
+                            let full = params.get("full").is_some_and(|v| v == "true");
+                            match blockchain.get_block_by_height(height) {
+                                Some(block) if full => respond_json!(req, block),
+                                Some(block) => respond_json!(req, BlockResponse {
+                                    hash: block.hash().to_string(),
+                                    parent: block.header.parent.to_string(),
+                                    height,
+                                    nonce: block.header.nonce,
+                                    extra_nonce: block.header.extra_nonce,
+                                    timestamp: block.header.timestamp,
+                                    merkle_root: block.header.merkle_root.to_string(),
+                                    state_root: block.header.state_root.to_string(),
+                                    transactions: block.content.transactions.iter().map(|tx| tx.hash().to_string()).collect(),
+                                }),
+                                None => respond_result!(req, false, "no block at this height"),
+                            }
+                        }
+                        "/blockchain/state" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let height = query_param!(req, params, "height", |v| v.parse::<u32>());
+                            match blockchain.get_hash_by_height(height).and_then(|hash| blockchain.get_state(&hash)) {
+                                Some(state) => respond_json!(req, state),
+                                None => respond_result!(req, false, "no state at this height"),
+                            }
+                        }
+                        "/blockchain/tip" => {
+                            let tip = blockchain.tip();
+                            let block = blockchain.get_block(&tip).unwrap();
+                            let mempool_size = tx_mempool.lock().unwrap().len();
+                            respond_json!(req, TipResponse {
+                                hash: tip.to_string(),
+                                parent: block.header.parent.to_string(),
+                                height: blockchain.get_block_height(&tip).unwrap(),
+                                timestamp: block.header.timestamp,
+                                num_transactions: block.content.len(),
+                                mempool_size,
+                            });
+                        }
+                        "/fee/estimate" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let target_blocks = query_param!(req, params, "target", |v| v.parse::<u32>());
+                            let min_fee = tx_mempool.lock().unwrap().min_fee();
+                            let estimate = fee_estimator.estimate_fee(target_blocks);
+                            respond_json!(req, FeeEstimateResponse {
+                                target_blocks,
+                                fee: estimate.unwrap_or(0).max(min_fee),
+                                from_history: estimate.is_some(),
+                            });
+                        }
+                        "/blockchain/dot" => {
+                            let dot = blockchain.to_dot(&metrics.mined.lock().unwrap());
+                            let content_type = "Content-Type: text/vnd.graphviz".parse::<Header>().unwrap();
+                            req.respond(Response::from_string(dot).with_header(content_type)).unwrap();
+                        }
+                        // writes a file on the node, so only on purpose and only in its directory
+                        "/blockchain/export" => {
+                            if *req.method() != Method::Post {
+                                respond_result!(req, false, "export is a POST");
+                                return;
+                            }
+                            let export_dir = match &export_dir {
+                                Some(export_dir) => export_dir,
+                                None => {
+                                    respond_result!(req, false, "no export directory on this node");
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let file = query_param!(req, params, "file", |v| v.parse::<PathBuf>());
+                            if !is_file_name(&file) {
+                                respond_result!(req, false, format!("{} is not a file name", file.display()));
+                                return;
+                            }
+                            let path = export_dir.join(&file);
+                            let from = query_param!(req, params, "from", |v| v.parse::<u32>());
+                            let to = query_param!(req, params, "to", |v| v.parse::<u32>());
+                            let exported = match params.get("json").map(|v| v.as_str()) {
+                                Some("true") => archive::export_chain_json(&blockchain, &path, from, to),
+                                _ => archive::export_chain(&blockchain, &path, from, to),
+                            };
+                            match exported {
+                                Ok(exported) => respond_result!(req, true, format!("exported {} blocks", exported)),
+                                Err(e) => respond_result!(req, false, format!("error exporting to {}: {}", file.display(), e)),
+                            }
+                        }
+                        "/blockchain/forks" => {
+                            respond_json!(req, blockchain.fork_stats());
+                        }
+                        "/node/metrics" => {
+                            respond_json!(req, MetricsResponse {
+                                blocks_mined: metrics.blocks_mined.load(Ordering::Relaxed),
+                                blocks_accepted: metrics.blocks_accepted.load(Ordering::Relaxed),
+                                reorgs: metrics.reorgs.load(Ordering::Relaxed),
+                                transactions_accepted: metrics.transactions_accepted.load(Ordering::Relaxed),
+                                transactions_generated: metrics.transactions_generated.load(Ordering::Relaxed),
+                                peers_connected: metrics.peers_connected.load(Ordering::Relaxed),
+                                peers_disconnected: metrics.peers_disconnected.load(Ordering::Relaxed),
+                                malformed_messages: metrics.malformed_messages.load(Ordering::Relaxed),
+                                confirmation_latency: metrics.confirmations.summary(),
+                                compression: metrics.compression.summary(),
+                            });
+                        }
+                        "/network/peers" => {
+                            respond_json!(req, network.peers());
                         }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
@@ -122,3 +872,177 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+/// Whether `path` is a bare file name, which cannot lead out of the directory it is joined to.
+fn is_file_name(path: &Path) -> bool {
+    let mut components = path.components();
+    matches!((components.next(), components.next()), (Some(Component::Normal(_)), None))
+}
+
+/// Poll `proven` until the light worker has checked the proof requested from the full nodes, or
+/// `PROOF_TIMEOUT` elapses.
+fn wait_for_proof<T>(proven: impl Fn() -> Option<T>) -> Option<T> {
+    let deadline = std::time::Instant::now() + PROOF_TIMEOUT;
+    loop {
+        if let Some(value) = proven() {
+            return Some(value);
+        }
+        if std::time::Instant::now() > deadline {
+            return None;
+        }
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
+}
+
+/// Start the API server of a light client, which only knows the headers of the longest chain.
+pub fn start_light(addr: std::net::SocketAddr, network: &NetworkServerHandle, headers: &Arc<Mutex<HeaderChain>>) {
+    let handle = HTTPServer::http(addr).unwrap();
+    let network = network.clone();
+    let headers = Arc::clone(headers);
+    thread::spawn(move || {
+        for req in handle.incoming_requests() {
+            let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
+            let url = match base_url.join(req.url()) {
+                Ok(u) => u,
+                Err(e) => {
+                    respond_result!(req, false, format!("error parsing url: {}", e));
+                    continue;
+                }
+            };
+            match url.path() {
+                "/light/tip" => {
+                    let headers = headers.lock().unwrap();
+                    let tip = headers.tip();
+                    let header = headers.get(&tip).unwrap();
+                    respond_json!(req, HeaderTipResponse {
+                        hash: tip.to_string(),
+                        parent: header.parent.to_string(),
+                        height: headers.height(),
+                        timestamp: header.timestamp,
+                    });
+                }
+                "/light/verify" => {
+                    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                    let (block, txid) = match (
+                        params.get("block").map(|v| v.parse::<H256>()),
+                        params.get("txid").map(|v| v.parse::<H256>()),
+                    ) {
+                        (Some(Ok(block)), Some(Ok(txid))) => (block, txid),
+                        _ => {
+                            respond_result!(req, false, "missing or malformed block or txid");
+                            continue;
+                        }
+                    };
+                    if !headers.lock().unwrap().contains_key(&block) {
+                        respond_result!(req, false, "unknown block header");
+                        continue;
+                    }
+                    // ask the full nodes for the proof, then wait for the light worker to check it
+                    network.broadcast(Message::GetMerkleProof(block, txid));
+                    let headers = Arc::clone(&headers);
+                    thread::spawn(move || {
+                        let proven = wait_for_proof(|| {
+                            let headers = headers.lock().unwrap();
+                            match headers.proven_block(&txid) {
+                                Some(hash) if hash == block => Some(headers.confirmations(&block)),
+                                _ => None,
+                            }
+                        });
+                        match proven {
+                            Some(confirmations) => respond_json!(req, ProvenTransactionResponse {
+                                txid: txid.to_string(),
+                                block: block.to_string(),
+                                confirmations,
+                            }),
+                            None => respond_result!(req, false, "no valid proof received"),
+                        }
+                    });
+                }
+                "/light/verify_batch" => {
+                    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                    let block = match params.get("block").map(|v| v.parse::<H256>()) {
+                        Some(Ok(block)) => block,
+                        _ => {
+                            respond_result!(req, false, "missing or malformed block");
+                            continue;
+                        }
+                    };
+                    // comma separated
+                    let txids = match params.get("txids").map(|v| v.split(',').map(|txid| txid.parse::<H256>()).collect::<Result<Vec<_>, _>>()) {
+                        Some(Ok(txids)) => txids,
+                        _ => {
+                            respond_result!(req, false, "missing or malformed txids");
+                            continue;
+                        }
+                    };
+                    if !headers.lock().unwrap().contains_key(&block) {
+                        respond_result!(req, false, "unknown block header");
+                        continue;
+                    }
+                    // a single proof of all the transactions, from the full nodes serving them
+                    network.broadcast(Message::GetMerkleMultiProof(block, txids.clone()));
+                    let headers = Arc::clone(&headers);
+                    thread::spawn(move || {
+                        let proven = wait_for_proof(|| {
+                            let headers = headers.lock().unwrap();
+                            if txids.iter().all(|txid| headers.proven_block(txid) == Some(block)) {
+                                Some(headers.confirmations(&block))
+                            } else {
+                                None
+                            }
+                        });
+                        match proven {
+                            Some(confirmations) => respond_json!(req, txids.iter().map(|txid| ProvenTransactionResponse {
+                                txid: txid.to_string(),
+                                block: block.to_string(),
+                                confirmations,
+                            }).collect::<Vec<_>>()),
+                            None => respond_result!(req, false, "no valid proof received"),
+                        }
+                    });
+                }
+                "/light/balance" => {
+                    let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                    let address = match params.get("address").map(|v| v.parse::<H160>()) {
+                        Some(Ok(address)) => address,
+                        _ => {
+                            respond_result!(req, false, "missing or malformed address");
+                            continue;
+                        }
+                    };
+                    // at the tip unless a block is given
+                    let block = match params.get("block").map(|v| v.parse::<H256>()) {
+                        Some(Ok(block)) => block,
+                        Some(Err(_)) => {
+                            respond_result!(req, false, "malformed block");
+                            continue;
+                        }
+                        None => headers.lock().unwrap().tip(),
+                    };
+                    if !headers.lock().unwrap().contains_key(&block) {
+                        respond_result!(req, false, "unknown block header");
+                        continue;
+                    }
+                    network.broadcast(Message::GetAccountProof(block, address));
+                    let headers = Arc::clone(&headers);
+                    thread::spawn(move || {
+                        match wait_for_proof(|| headers.lock().unwrap().proven_account(&block, &address)) {
+                            Some(account) => {
+                                let account = account.unwrap_or_default();
+                                respond_json!(req, ProvenBalanceResponse {
+                                    address: address.to_checksummed(),
+                                    block: block.to_string(),
+                                    balance: account.balance,
+                                    nonce: account.nonce,
+                                });
+                            }
+                            None => respond_result!(req, false, "no valid proof received"),
+                        }
+                    });
+                }
+                _ => respond_result!(req, false, "endpoint not found"),
+            }
+        }
+    });
+    info!("Light client API server listening at {}", &addr);
+}
@@ -0,0 +1,225 @@
+use serde::{Serialize, Deserialize};
+use super::{output_responses, OutputResponse};
+use crate::crypto::hash::Hashable;
+use crate::events::{EventBus, NodeEvent};
+use crate::transaction::SignedTransaction;
+use crossbeam::channel::Receiver;
+use log::{debug, info, warn};
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time;
+use tungstenite::{Message as WsMessage, WebSocket};
+
+/// How long a connection waits for a client request before forwarding the pending notifications.
+static POLL_INTERVAL: time::Duration = time::Duration::from_millis(50);
+
+/// The topics a client can subscribe to.
+static TOPICS: [&str; 3] = ["newHeads", "pendingTransactions", "reorg"];
+
+/// A request of a client, `{"id": 1, "method": "subscribe", "params": ["newHeads"]}`. The
+/// methods are `subscribe` and `unsubscribe`, the params a list of topics.
+#[derive(Deserialize, Debug)]
+struct Request {
+    id: u64,
+    method: String,
+    #[serde(default)]
+    params: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    id: u64,
+    result: Vec<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Notification<T> {
+    subscription: &'static str,
+    result: T,
+}
+
+#[derive(Serialize, Debug)]
+struct HeadNotification {
+    hash: String,
+    parent: String,
+    height: u32,
+    timestamp: u128,
+    num_transactions: usize,
+}
+
+#[derive(Serialize, Debug)]
+struct TransactionNotification {
+    txid: String,
+    sender: String,
+    outputs: Vec<OutputResponse>,
+    fee: u64,
+    nonce: u64,
+    data: String,
+}
+
+#[derive(Serialize, Debug)]
+struct ReorgNotification {
+    disconnected: Vec<String>,
+    connected: Vec<String>,
+    evicted_transactions: Vec<String>,
+}
+
+/// Start the WebSocket server where clients subscribe to new heads, pending transactions and
+/// reorgs instead of polling the API server.
+pub fn start(
+    addr: SocketAddr,
+    events: &Arc<EventBus>,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let events = Arc::clone(events);
+    thread::Builder::new()
+        .name("websocket".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("Error accepting WebSocket client: {}", e);
+                        continue;
+                    }
+                };
+                // subscribe before the handshake so no event is missed once it succeeds
+                let receiver = events.subscribe();
+                thread::spawn(move || {
+                    serve(stream, receiver);
+                });
+            }
+        })
+        .unwrap();
+    info!("WebSocket server listening at {}", addr);
+    Ok(())
+}
+
+fn send<T: Serialize>(socket: &mut WebSocket<TcpStream>, message: &T) -> bool {
+    let text = serde_json::to_string(message).unwrap();
+    socket.write_message(WsMessage::Text(text)).is_ok()
+}
+
+/// Answer a subscription request, updating the topics of the client.
+fn handle_request(text: &str, topics: &mut HashSet<&'static str>) -> Response {
+    let request: Request = match serde_json::from_str(text) {
+        Ok(r) => r,
+        Err(e) => return Response { id: 0, result: vec![], error: Some(format!("bad request: {}", e)) },
+    };
+    let mut requested = Vec::new();
+    for param in request.params.iter() {
+        match TOPICS.iter().find(|topic| *topic == param) {
+            Some(topic) => requested.push(*topic),
+            None => return Response { id: request.id, result: vec![], error: Some(format!("unknown topic {}", param)) },
+        }
+    }
+    match request.method.as_str() {
+        "subscribe" => topics.extend(requested.iter()),
+        "unsubscribe" => topics.retain(|topic| !requested.contains(topic)),
+        _ => return Response { id: request.id, result: vec![], error: Some("unknown method".to_string()) },
+    }
+    let mut result: Vec<String> = topics.iter().map(|topic| topic.to_string()).collect();
+    result.sort();
+    Response { id: request.id, result, error: None }
+}
+
+fn transaction_notification(tx: &SignedTransaction) -> TransactionNotification {
+    TransactionNotification {
+        txid: tx.hash().to_string(),
+        sender: tx.sender().to_checksummed(),
+        outputs: output_responses(&tx.transaction),
+        fee: tx.transaction.fee,
+        nonce: tx.transaction.account_nonce,
+        data: hex::encode(&tx.transaction.data),
+    }
+}
+
+fn serve(stream: TcpStream, events: Receiver<NodeEvent>) {
+    let peer = stream.peer_addr().ok();
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("WebSocket handshake with {:?} failed: {}", peer, e);
+            return;
+        }
+    };
+    if socket.get_ref().set_read_timeout(Some(POLL_INTERVAL)).is_err() {
+        return;
+    }
+    let mut topics: HashSet<&'static str> = HashSet::new();
+    loop {
+        match socket.read_message() {
+            Ok(WsMessage::Text(text)) => {
+                let response = handle_request(&text, &mut topics);
+                if !send(&mut socket, &response) {
+                    break;
+                }
+            }
+            Ok(WsMessage::Close(_)) => break,
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(ref e))
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(_) => break,
+        }
+
+        let mut connected = true;
+        for event in events.try_iter() {
+            connected &= match event {
+                NodeEvent::NewHead { hash, header, height, num_transactions } if topics.contains("newHeads") => {
+                    send(&mut socket, &Notification {
+                        subscription: "newHeads",
+                        result: HeadNotification {
+                            hash: hash.to_string(),
+                            parent: header.parent.to_string(),
+                            height,
+                            timestamp: header.timestamp,
+                            num_transactions,
+                        },
+                    })
+                }
+                NodeEvent::Reorg(reorg) if topics.contains("reorg") => {
+                    send(&mut socket, &Notification {
+                        subscription: "reorg",
+                        result: ReorgNotification {
+                            disconnected: reorg.disconnected.iter().map(|h| h.to_string()).collect(),
+                            connected: reorg.connected.iter().map(|h| h.to_string()).collect(),
+                            evicted_transactions: reorg.evicted_transactions.iter().map(|tx| tx.hash().to_string()).collect(),
+                        },
+                    })
+                }
+                NodeEvent::TxAccepted(tx) if topics.contains("pendingTransactions") => {
+                    send(&mut socket, &Notification {
+                        subscription: "pendingTransactions",
+                        result: transaction_notification(&tx),
+                    })
+                }
+                _ => true,
+            };
+        }
+        if !connected {
+            break;
+        }
+    }
+    debug!("WebSocket client {:?} disconnected", peer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let mut topics = HashSet::new();
+        let response = handle_request(r#"{"id": 1, "method": "subscribe", "params": ["newHeads", "reorg"]}"#, &mut topics);
+        assert_eq!(response.result, vec!["newHeads", "reorg"]);
+        let response = handle_request(r#"{"id": 2, "method": "unsubscribe", "params": ["reorg"]}"#, &mut topics);
+        assert_eq!((response.id, response.result), (2, vec!["newHeads".to_string()]));
+        let response = handle_request(r#"{"id": 3, "method": "subscribe", "params": ["blocks"]}"#, &mut topics);
+        assert!(response.error.is_some());
+        assert_eq!(topics.len(), 1);
+    }
+}
@@ -0,0 +1,133 @@
+//! Portable block files: the blocks of a range of heights of the longest chain, streamed one
+//! after the other, each prefixed with its length, so that the chain of an experiment can be
+//! archived and replayed into a fresh node for analysis.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::schema::{self, Store, SCHEMA_MAGIC};
+use log::info;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::Path;
+
+/// Largest block read from a block file, in bytes.
+pub static MAX_ARCHIVED_BLOCK_SIZE: usize = 16 * 1024 * 1024;
+
+/// Write the blocks of the longest chain from height `from_height` to height `to_height`, both
+/// included, up to the tip, to `path`. The pruned blocks are skipped. Returns the number of
+/// blocks written.
+pub fn export_chain(blockchain: &Blockchain, path: &Path, from_height: u32, to_height: u32) -> Result<usize> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&schema::encode(Store::Chain, &[]))?;
+    let mut exported = 0;
+    for hash in blockchain.canonical_range(from_height, to_height) {
+        let block = match blockchain.get_block(&hash) {
+            Some(block) => block,
+            None => continue,
+        };
+        let bytes = bincode::serialize(&block).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        writer.write_all(&bytes)?;
+        exported += 1;
+    }
+    writer.flush()?;
+    info!("Exported {} blocks from height {} to {}", exported, from_height, path.display());
+    Ok(exported)
+}
+
+/// Write the blocks of the longest chain from height `from_height` to height `to_height` to
+/// `path` as JSON, one block per line, with the hashes, addresses and signatures in hex, for the
+/// tools outside of the node. Such a file cannot be imported back. Returns the number of blocks
+/// written.
+pub fn export_chain_json(blockchain: &Blockchain, path: &Path, from_height: u32, to_height: u32) -> Result<usize> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut exported = 0;
+    for hash in blockchain.canonical_range(from_height, to_height) {
+        let block = match blockchain.get_block(&hash) {
+            Some(block) => block,
+            None => continue,
+        };
+        serde_json::to_writer(&mut writer, &block)?;
+        writer.write_all(b"\n")?;
+        exported += 1;
+    }
+    writer.flush()?;
+    info!("Exported {} blocks from height {} to {} as JSON", exported, from_height, path.display());
+    Ok(exported)
+}
+
+/// Replay the blocks of the block file at `path` into `blockchain`, in the order of the file,
+/// validating each one on top of its parent, see `Blockchain::replay`. The blocks whose parent
+/// is unknown, or that fail validation, are skipped. Returns the number of blocks inserted.
+pub fn import_chain(blockchain: &Blockchain, path: &Path) -> Result<usize> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 8];
+    reader.read_exact(&mut header)?;
+    if !header.starts_with(SCHEMA_MAGIC) {
+        return Err(Error::new(ErrorKind::InvalidData, "not a block file"));
+    }
+    schema::decode(Store::Chain, header.to_vec())?;
+    let (mut read, mut imported) = (0, 0);
+    loop {
+        let mut len = [0u8; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_ARCHIVED_BLOCK_SIZE {
+            return Err(Error::new(ErrorKind::InvalidData, format!("block {} of {} bytes is too large", read, len)));
+        }
+        let mut bytes = vec![0u8; len];
+        reader.read_exact(&mut bytes)?;
+        let block: Block = bincode::deserialize(&bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        read += 1;
+        if blockchain.replay(&block) {
+            imported += 1;
+        }
+    }
+    info!("Imported {} of {} blocks from {}", imported, read, path.display());
+    Ok(imported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::{generate_random_block, solve};
+    use crate::crypto::hash::{H256, Hashable};
+
+    #[test]
+    fn replays_into_a_fresh_node() {
+        let blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        let state = blockchain.get_state(&parent).unwrap();
+        for height in 1..=3 {
+            let mut block = generate_random_block(&parent);
+            block.header.timestamp = height;
+            block.header.merkle_root = crate::crypto::merkle::MerkleTree::new(&block.content.transactions).root();
+            block.header.state_root = state.root();
+            solve(&mut block.header);
+            blockchain.insert(&block, &state);
+            parent = block.hash();
+        }
+        let path = std::env::temp_dir().join(format!("prism-chain-{}.blk", rand::random::<u64>()));
+        assert_eq!(export_chain(&blockchain, &path, 1, 100).unwrap(), 3);
+
+        let fresh = Blockchain::new();
+        assert_eq!(import_chain(&fresh, &path).unwrap(), 3);
+        assert_eq!(fresh.tip(), blockchain.tip());
+        assert_eq!(fresh.canonical_range(0, 3).collect::<Vec<H256>>(), blockchain.canonical_range(0, 3).collect::<Vec<H256>>());
+        // already known
+        assert_eq!(import_chain(&fresh, &path).unwrap(), 0);
+
+        assert_eq!(export_chain_json(&blockchain, &path, 2, 100).unwrap(), 2);
+        let lines = std::fs::read_to_string(&path).unwrap();
+        let blocks: Vec<Block> = lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(blocks.iter().map(|block| block.hash()).collect::<Vec<H256>>(), blockchain.canonical_range(2, 3).collect::<Vec<H256>>());
+
+        std::fs::write(&path, b"garbage!").unwrap();
+        assert_eq!(import_chain(&fresh, &path).unwrap_err().kind(), ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
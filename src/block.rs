@@ -1,11 +1,21 @@
 use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
-use crate::crypto::hash::{H256, Hashable};
-use crate::transaction::{SignedTransaction};
+use std::collections::{BTreeMap, HashMap};
+use crate::crypto::hash::{self, H256, Hashable};
+use crate::transaction::{OutPoint, SignedTransaction};
 use crate::crypto::address::H160;
+use crate::crypto::merkle::MerkleTree;
+use crate::crypto::trie::{SparseMerkleTrie, TrieProof};
+use std::collections::HashSet;
+use log::debug;
 
 pub static INIT_COINS: u64 = 25;
-pub static BLOCK_CAPACITY: usize = 3;
+/// Gas limit of the default genesis, room for three plain transfers. Every block inherits the
+/// gas limit of the genesis.
+pub static DEFAULT_GAS_LIMIT: u64 = 9000;
+/// Maximum serialized size of a block, in bytes.
+pub static MAX_BLOCK_SIZE: usize = 64 * 1024;
+/// A full state snapshot is stored every SNAPSHOT_INTERVAL blocks; the blocks in between store diffs.
+pub static SNAPSHOT_INTERVAL: u32 = 16;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Block {
@@ -24,22 +34,220 @@ impl Block {
     pub fn add_tx(mut self, tx: SignedTransaction) {
         self.content.transactions.push(tx);
     }
+
+    /// Total gas of the transactions.
+    pub fn gas_used(&self) -> u64 {
+        self.content.transactions.iter().map(|tx| tx.gas()).fold(0, u64::saturating_add)
+    }
+
+    /// Check the block structure independently of any state: the gas used and the serialized
+    /// size are within limits, every transaction is well formed and appears once, and
+    /// the merkle root in the header commits to the transactions.
+    pub fn is_well_formed(&self) -> bool {
+        let hash = self.hash();
+        let gas_used = self.gas_used();
+        if gas_used > self.header.gas_limit {
+            debug!("Block {:?} uses {} gas, over the limit of {}", hash, gas_used, self.header.gas_limit);
+            return false;
+        }
+        let size = bincode::serialized_size(self).unwrap() as usize;
+        if size > MAX_BLOCK_SIZE {
+            debug!("Block {:?} is {} bytes, over the size limit", hash, size);
+            return false;
+        }
+        let mut tx_hashes = HashSet::new();
+        for tx in self.content.transactions.iter() {
+            if !tx.transaction.is_well_formed() {
+                debug!("Block {:?} contains malformed transaction {:?}", hash, tx.hash());
+                return false;
+            }
+            if !tx_hashes.insert(tx.hash()) {
+                debug!("Block {:?} contains duplicate transaction {:?}", hash, tx.hash());
+                return false;
+            }
+        }
+        if MerkleTree::new(&self.content.transactions).root() != self.header.merkle_root {
+            debug!("Block {:?} merkle root mismatch", hash);
+            return false;
+        }
+        true
+    }
+
+    /// Find a sender whose transactions conflict whatever their order on top of `state`: two of
+    /// them have the same nonce, or together they cost more than the sender balance before the
+    /// block and every transfer to the sender in the block. In the UTXO model, two of them spend
+    /// the same output.
+    pub fn find_double_spend(&self, state: &State) -> Option<H160> {
+        if state.model == Model::Utxo {
+            let mut inputs = HashSet::new();
+            return self.content.transactions.iter()
+                .find(|tx| !tx.transaction.inputs.iter().all(|input| inputs.insert(*input)))
+                .map(|tx| tx.sender());
+        }
+        let mut nonces = HashSet::new();
+        // in u128, so that the sums cannot overflow
+        let mut spent: HashMap<H160, u128> = HashMap::new();
+        let mut received: HashMap<H160, u128> = HashMap::new();
+        for tx in self.content.transactions.iter() {
+            let sender = tx.sender();
+            if !nonces.insert((sender, tx.transaction.account_nonce)) {
+                return Some(sender);
+            }
+            *spent.entry(sender).or_default() += tx.transaction.fee as u128;
+            if tx.transaction.token.is_some() {
+                continue;
+            }
+            for (recipient, value) in tx.transaction.outputs.iter() {
+                *spent.entry(sender).or_default() += *value as u128;
+                *received.entry(*recipient).or_default() += *value as u128;
+            }
+        }
+        spent.into_iter().find_map(|(sender, spent)| {
+            let balance = state.account_state.get(&sender).map_or(0, |account| account.balance) as u128;
+            let available = balance + received.get(&sender).copied().unwrap_or(0);
+            if spent > available {
+                Some(sender)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The state after the transactions of the block on top of `state`, their signatures aside.
+    /// The order of the block, which the merkle root commits to, is the canonical order: every
+    /// transaction must be valid on top of the state left by the ones before it, so the block
+    /// is valid if and only if its committed order is.
+    pub fn apply(&self, state: &State) -> Option<State> {
+        let mut state = state.clone();
+        for tx in self.content.transactions.iter() {
+            if !tx.update_state(&mut state) {
+                debug!("Block {:?} has transaction {:?} invalid in its position", self.hash(), tx.hash());
+                return None;
+            }
+        }
+        Some(state)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
 pub struct Header{
     pub parent: H256,
     pub nonce: u32,
+    /// Advanced every time the miner exhausts the 32-bit nonce space.
+    pub extra_nonce: u64,
     pub difficulty: H256,
     pub timestamp: u128,
     pub merkle_root: H256,
+    /// Commits to the state after the block, see `State::root`.
+    pub state_root: H256,
+    /// Most gas the transactions of the block may use, the same as the parent.
+    pub gas_limit: u64,
 }
 
 impl Hashable for Header{
     fn hash(&self) -> H256 {
         let bytes = bincode::serialize(&self).unwrap();
-        let digest = ring::digest::digest(&ring::digest::SHA256, &bytes);
-        digest.into()
+        hash::digest(&bytes)
+    }
+}
+
+/// Furthest a block timestamp may be ahead of the local clock, in microseconds: two hours.
+pub static MAX_FUTURE_DRIFT: u128 = 2 * 60 * 60 * 1_000_000;
+
+impl Header {
+    /// Check the proof of work alone, without the transactions or the state: the hash meets the
+    /// difficulty of the header, which is the difficulty of the parent when it is known. The gas
+    /// limit must be the parent's as well.
+    pub fn meets_difficulty(&self, parent: Option<&Header>) -> bool {
+        if parent.is_some_and(|parent| !self.inherits(parent)) {
+            return false;
+        }
+        self.hash().meets_target(&self.difficulty)
+    }
+
+    /// Whether the header has the difficulty and the gas limit of its parent, as every block
+    /// connected to the chain must.
+    pub fn inherits(&self, parent: &Header) -> bool {
+        self.difficulty == parent.difficulty && self.gas_limit == parent.gas_limit
+    }
+
+    /// Whether the timestamp is further than `MAX_FUTURE_DRIFT` ahead of `now`, in microseconds
+    /// since the UNIX epoch.
+    pub fn is_from_future(&self, now: u128) -> bool {
+        self.timestamp > now.saturating_add(MAX_FUTURE_DRIFT)
+    }
+
+    /// Expected number of hashes to meet the difficulty, see `H256::work`.
+    pub fn work(&self) -> hash::Work {
+        self.difficulty.work()
+    }
+}
+
+/// Proof that the transaction `txid` is committed to by the merkle root of the header of block
+/// `block_hash`, served by full nodes to light clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MerkleProof {
+    pub block_hash: H256,
+    pub txid: H256,
+    /// Position of the transaction in the block.
+    pub index: usize,
+    /// Number of transactions in the block.
+    pub leaf_size: usize,
+    pub proof: Vec<H256>,
+}
+
+/// Single proof that the transactions `txids` are committed to by the merkle root of the header
+/// of block `block_hash`, smaller than a `MerkleProof` per transaction.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MerkleMultiProof {
+    pub block_hash: H256,
+    pub txids: Vec<H256>,
+    /// Positions of the transactions in the block, in the order of `txids`.
+    pub indices: Vec<usize>,
+    /// Number of transactions in the block.
+    pub leaf_size: usize,
+    pub proof: Vec<H256>,
+}
+
+/// Proof of the account of `address` in the state after block `block_hash`, checked against
+/// the state root of its header. `account` is `None` for an address without an account.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AccountProof {
+    pub block_hash: H256,
+    pub address: H160,
+    pub account: Option<AccountState>,
+    pub proof: TrieProof,
+}
+
+impl Block {
+    /// The inclusion proof of the transaction `txid`, if the block contains it.
+    pub fn merkle_proof(&self, txid: &H256) -> Option<MerkleProof> {
+        let index = self.content.transactions.iter().position(|tx| tx.hash() == *txid)?;
+        Some(MerkleProof {
+            block_hash: self.hash(),
+            txid: *txid,
+            index,
+            leaf_size: self.content.len(),
+            proof: MerkleTree::new(&self.content.transactions).proof(index),
+        })
+    }
+
+    /// The inclusion proof of all the transactions `txids`, if the block contains them all.
+    pub fn merkle_multiproof(&self, txids: &[H256]) -> Option<MerkleMultiProof> {
+        let hashes: Vec<H256> = self.content.transactions.iter().map(|tx| tx.hash()).collect();
+        let indices = txids.iter()
+            .map(|txid| hashes.iter().position(|hash| hash == txid))
+            .collect::<Option<Vec<usize>>>()?;
+        if indices.is_empty() {
+            return None;
+        }
+        Some(MerkleMultiProof {
+            block_hash: self.hash(),
+            txids: txids.to_vec(),
+            proof: MerkleTree::new(&self.content.transactions).proof_multi(&indices),
+            indices,
+            leaf_size: self.content.len(),
+        })
     }
 }
 
@@ -60,44 +268,338 @@ impl Content{
     }
 }
 
+/// How the coins are held, fixed by the genesis configuration.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Model {
+    /// Accounts with a balance and a nonce, debited by the transactions of their address.
+    #[default]
+    Account,
+    /// Unspent transaction outputs, which the transactions of their owner spend whole.
+    Utxo,
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct State {
     pub address_list: Vec<H160>,
-    pub account_state: HashMap<H160, AccountState>
+    pub account_state: SparseMerkleTrie<AccountState>,
+    /// The unspent outputs by `OutPoint::key`, empty in the account model.
+    pub utxos: SparseMerkleTrie<Utxo>,
+    pub model: Model,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+impl State {
+    /// Root of the account trie, or of the unspent outputs trie in the UTXO model. The address
+    /// list is not committed to: every node builds it the same way from the blocks.
+    pub fn root(&self) -> H256 {
+        match self.model {
+            Model::Account => self.account_state.root(),
+            Model::Utxo => self.utxos.root(),
+        }
+    }
+
+    /// The unspent outputs of `owner`, in the UTXO model.
+    pub fn unspent_of<'a>(&'a self, owner: &'a H160) -> impl Iterator<Item = &'a Utxo> {
+        self.utxos.values().filter(move |utxo| utxo.owner == *owner)
+    }
+
+    /// The coins of `address`, the sum of its unspent outputs in the UTXO model.
+    pub fn balance(&self, address: &H160) -> u64 {
+        match self.model {
+            Model::Account => self.account_state.get(address).map_or(0, |account| account.balance),
+            Model::Utxo => self.unspent_of(address).map(|utxo| utxo.value).sum(),
+        }
+    }
+
+    /// Proof of the account of `address`, or of its absence, against `root`.
+    pub fn account_proof(&self, address: &H160) -> TrieProof {
+        self.account_state.proof(address)
+    }
+
+    /// Compute the diff that turns `parent` into `self`.
+    pub fn diff(&self, parent: &State) -> StateDiff {
+        let mut account_state = HashMap::new();
+        for (address, account) in self.account_state.iter() {
+            if parent.account_state.get(address) != Some(account) {
+                account_state.insert(*address, account.clone());
+            }
+        }
+        let mut utxos: HashMap<H160, Option<Utxo>> = parent.utxos.iter()
+            .filter(|(key, _)| !self.utxos.contains_key(key))
+            .map(|(key, _)| (*key, None))
+            .collect();
+        for (key, utxo) in self.utxos.iter() {
+            if !parent.utxos.contains_key(key) {
+                utxos.insert(*key, Some(*utxo));
+            }
+        }
+        StateDiff {
+            new_addresses: self.address_list[parent.address_list.len().min(self.address_list.len())..].to_vec(),
+            account_state,
+            utxos,
+        }
+    }
+
+    /// Apply a diff produced by `State::diff` on top of its parent state.
+    pub fn apply(&mut self, diff: &StateDiff) {
+        self.address_list.extend_from_slice(&diff.new_addresses);
+        for (address, account) in diff.account_state.iter() {
+            self.account_state.insert(*address, account.clone());
+        }
+        for (key, utxo) in diff.utxos.iter() {
+            match utxo {
+                Some(utxo) => self.utxos.insert(*key, *utxo),
+                None => self.utxos.remove(key),
+            };
+        }
+    }
+}
+
+/// The accounts touched by a block, with their values after the block.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct StateDiff {
+    pub new_addresses: Vec<H160>,
+    pub account_state: HashMap<H160, AccountState>,
+    /// The outputs created by the block, and those it spent as `None`.
+    pub utxos: HashMap<H160, Option<Utxo>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
 pub struct AccountState {
-    pub nonce: i32,
+    pub nonce: u64,
     pub balance: u64,
+    /// Balances of the native tokens held, by token id, without the zero ones.
+    pub tokens: BTreeMap<H160, u64>,
 }
 
 impl AccountState {
     pub fn new() -> Self {
         AccountState {
             nonce: 0,
-            balance: 25
+            balance: 25,
+            tokens: BTreeMap::new(),
+        }
+    }
+
+    /// Add `amount` of `token`. `None`, leaving the balance unchanged, if it overflows.
+    pub fn credit_token(&mut self, token: &H160, amount: u64) -> Option<()> {
+        let balance = self.tokens.get(token).copied().unwrap_or(0).checked_add(amount)?;
+        if balance > 0 {
+            self.tokens.insert(*token, balance);
+        }
+        Some(())
+    }
+
+    /// Take `amount` of `token`. `None`, leaving the balance unchanged, if it does not cover it.
+    pub fn debit_token(&mut self, token: &H160, amount: u64) -> Option<()> {
+        let balance = self.tokens.get(token).copied().unwrap_or(0).checked_sub(amount)?;
+        if balance > 0 {
+            self.tokens.insert(*token, balance);
+        } else {
+            self.tokens.remove(token);
         }
+        Some(())
     }
 }
 
+/// An unspent output in the UTXO model.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub owner: H160,
+    pub value: u64,
+}
+
 #[cfg(any(test, test_utilities))]
 pub mod test {
     use super::*;
     use crate::crypto::hash::H256;
+    use crate::transaction::MAX_DATA_SIZE;
 
     pub fn generate_random_block(parent: &H256) -> Block { 
         Block {
             header: Header{
                 parent: parent.clone(),
                 nonce: rand::random::<u32>(),
-                difficulty: Default::default(),
+                extra_nonce: 0,
+                // the difficulty of the default genesis, inherited by the blocks of its chain
+                difficulty: crate::genesis::GenesisConfig::default().difficulty.parse().unwrap(),
                 timestamp: Default::default(),
                 merkle_root: Default::default(),
+                state_root: Default::default(),
+                gas_limit: DEFAULT_GAS_LIMIT,
             },
             content: Content{
                 transactions: Default::default(),
             }
         }
     }
+
+    /// Grind the nonce of `header` until its hash meets its difficulty.
+    pub fn solve(header: &mut Header) {
+        while !header.hash().meets_target(&header.difficulty) {
+            header.nonce = header.nonce.wrapping_add(1);
+        }
+    }
+
+    fn generate_block_with_txs(transactions: Vec<SignedTransaction>) -> Block {
+        let mut block = generate_random_block(&Default::default());
+        block.header.merkle_root = MerkleTree::new(&transactions).root();
+        block.content.transactions = transactions;
+        block
+    }
+
+    fn generate_tx(value: u64) -> SignedTransaction {
+        let mut tx: SignedTransaction = Default::default();
+        tx.transaction.outputs = vec![(H160::default(), value)];
+        tx
+    }
+
+    #[test]
+    fn well_formed() {
+        assert!(generate_random_block(&Default::default()).is_well_formed());
+        let txs = (0..4).map(generate_tx).collect();
+        assert!(generate_block_with_txs(txs).is_well_formed());
+    }
+
+    #[test]
+    fn bad_merkle_root() {
+        let mut block = generate_block_with_txs(vec![generate_tx(1)]);
+        block.header.merkle_root = Default::default();
+        assert!(!block.is_well_formed());
+    }
+
+    #[test]
+    fn over_gas_limit() {
+        let mut block = generate_block_with_txs((0..4).map(generate_tx).collect());
+        assert_eq!(block.gas_used(), (0..4).map(|value| generate_tx(value).gas()).sum::<u64>());
+        block.header.gas_limit = block.gas_used();
+        assert!(block.is_well_formed());
+        block.header.gas_limit -= 1;
+        assert!(!block.is_well_formed());
+    }
+
+    #[test]
+    fn duplicate_transactions() {
+        assert!(!generate_block_with_txs(vec![generate_tx(1), generate_tx(1)]).is_well_formed());
+    }
+
+    #[test]
+    fn malformed_transactions() {
+        let mut tx = generate_tx(1);
+        tx.transaction.data = vec![0; MAX_DATA_SIZE + 1];
+        tx.transaction.fee = tx.transaction.data_fee();
+        assert!(!generate_block_with_txs(vec![tx]).is_well_formed());
+        assert!(!generate_block_with_txs(vec![Default::default()]).is_well_formed());
+    }
+
+    #[test]
+    fn utxo_double_spends() {
+        let mut tx = generate_tx(2);
+        tx.transaction.inputs = vec![OutPoint::default()];
+        let mut state = State { model: Model::Utxo, ..Default::default() };
+        state.utxos.insert(OutPoint::default().key(), Utxo { outpoint: OutPoint::default(), owner: tx.sender(), value: 2 });
+        let mut other = tx.clone();
+        other.transaction.fee = 1;
+        other.transaction.outputs[0].1 = 1;
+        assert_eq!(generate_block_with_txs(vec![tx.clone(), other]).find_double_spend(&state), Some(tx.sender()));
+        let block = generate_block_with_txs(vec![tx]);
+        assert_eq!(block.find_double_spend(&state), None);
+
+        // the diff spends the output and creates the new one
+        let after = block.apply(&state).unwrap();
+        assert_eq!(after.utxos.len(), 1);
+        assert_ne!(after.root(), state.root());
+        let mut rebuilt = state.clone();
+        rebuilt.apply(&after.diff(&state));
+        assert_eq!(rebuilt.utxos, after.utxos);
+    }
+
+    #[test]
+    fn double_spends() {
+        let mut state = State::default();
+        let sender = generate_tx(0).sender();
+        state.account_state.insert(sender, AccountState { nonce: 0, balance: 10, ..Default::default() });
+        let spend = |value, account_nonce| {
+            let mut tx = generate_tx(value);
+            tx.transaction.account_nonce = account_nonce;
+            tx
+        };
+        // the same nonce twice
+        assert_eq!(generate_block_with_txs(vec![spend(1, 1), spend(2, 1)]).find_double_spend(&state), Some(sender));
+        // more than the balance in total, whatever the order
+        assert_eq!(generate_block_with_txs(vec![spend(6, 1), spend(5, 2)]).find_double_spend(&state), Some(sender));
+        assert_eq!(generate_block_with_txs(vec![spend(5, 1), spend(5, 2)]).find_double_spend(&state), None);
+
+        // money received in the block may be spent in it
+        let mut refund = generate_tx(1);
+        refund.public_key = vec![1];
+        refund.transaction.outputs[0].0 = sender;
+        state.account_state.insert(refund.sender(), AccountState { nonce: 0, balance: 1, ..Default::default() });
+        let block = generate_block_with_txs(vec![spend(6, 1), spend(5, 2), refund]);
+        assert_eq!(block.find_double_spend(&state), None);
+    }
+
+    #[test]
+    fn applied_in_committed_order() {
+        let mut state = State::default();
+        let sender = generate_tx(0).sender();
+        state.account_state.insert(sender, AccountState { nonce: 0, balance: 10, ..Default::default() });
+        let spend = |value, account_nonce| {
+            let mut tx = generate_tx(value);
+            tx.transaction.account_nonce = account_nonce;
+            tx
+        };
+        let after = generate_block_with_txs(vec![spend(1, 1), spend(2, 2)]).apply(&state).unwrap();
+        assert_eq!(after.account_state[&sender], AccountState { nonce: 2, balance: 7, ..Default::default() });
+        assert!(generate_block_with_txs(vec![spend(2, 2), spend(1, 1)]).apply(&state).is_none());
+    }
+
+    #[test]
+    fn future_drift() {
+        let mut header = generate_random_block(&Default::default()).header;
+        header.timestamp = 1000 + MAX_FUTURE_DRIFT;
+        assert!(!header.is_from_future(1000));
+        assert!(header.is_from_future(999));
+    }
+
+    #[test]
+    fn inherits_gas_limit() {
+        let parent = generate_random_block(&Default::default()).header;
+        let mut header = generate_random_block(&parent.hash()).header;
+        header.difficulty = [0xff; 32].into();
+        let easy_parent = Header { difficulty: header.difficulty, ..parent };
+        assert!(header.meets_difficulty(Some(&easy_parent)));
+        header.gas_limit += 1;
+        assert!(!header.meets_difficulty(Some(&easy_parent)));
+        assert!(header.meets_difficulty(None));
+    }
+
+    #[test]
+    fn work_grows_as_difficulty_falls() {
+        let mut header = generate_random_block(&Default::default()).header;
+        header.difficulty = Default::default();
+        assert_eq!(header.work(), hash::Work::MAX);
+        header.difficulty = [0xff; 32].into();
+        assert_eq!(header.work(), 1.into());
+        let mut difficulty = [0xff; 32];
+        difficulty[0] = 0;
+        difficulty[1] = 0x3f;
+        header.difficulty = difficulty.into();
+        assert_eq!(header.work(), 1024.into());
+    }
+
+    #[test]
+    fn state_root() {
+        let (_, state) = crate::genesis::GenesisConfig::default().build().unwrap();
+        // the root does not depend on the layout of the accounts map
+        let mut rebuilt = State {
+            address_list: state.address_list.clone(),
+            account_state: state.address_list.iter().rev().map(|a| (*a, state.account_state[a].clone())).collect(),
+            ..Default::default()
+        };
+        assert_eq!(rebuilt.root(), state.root());
+        rebuilt.account_state.get_mut(&state.address_list[0]).unwrap().balance += 1;
+        assert_ne!(rebuilt.root(), state.root());
+    }
 }
@@ -1,56 +1,324 @@
-use crate::block::{Block, Header, Content, State, INIT_COINS, AccountState};
-use crate::crypto::hash::{H256, Hashable};
+use crate::block::{Block, Header, State, StateDiff, SNAPSHOT_INTERVAL};
 use crate::crypto::address::H160;
-use crate::crypto::key_pair;
-use ring::signature::KeyPair;
-use std::collections::HashMap;
-use log::info;
+use crate::crypto::hash::{H256, Hashable, Work};
+use crate::genesis::GenesisConfig;
+use crate::receipt::{self, Receipt};
+use crate::events::{EventBus, NodeEvent};
+use crate::snapshot::{Snapshot, CHECKPOINT_INTERVAL};
+use crate::transaction::SignedTransaction;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::sync::{Arc, RwLock, RwLockWriteGuard};
+use std::thread;
+use log::{debug, error, info};
+use rayon::prelude::*;
 
+/// How the state after a block is kept: in the longest chain, a full snapshot every
+/// `SNAPSHOT_INTERVAL` blocks (and at the genesis) and a diff against the parent's state
+/// otherwise. Off the longest chain, nothing: the state is recomputed from the block contents
+/// on demand, and stored again if the block joins the longest chain.
+enum StoredState {
+    Snapshot(State),
+    Diff(StateDiff),
+    Lazy,
+}
+
+/// Rebuild the state after block `hash` by walking back to the closest snapshot and replaying
+/// the diffs and the blocks kept lazily on the way forward. `None` if a block of a fork does
+/// not apply on top of its parent's state.
+fn reconstruct_state(blocks: &HashMap<H256,Block>, block_states: &HashMap<H256, StoredState>, hash: &H256) -> Option<State> {
+    let mut steps: Vec<(&H256, &StoredState)> = Vec::new();
+    let mut curr = hash;
+    loop {
+        let (key, stored) = block_states.get_key_value(curr)?;
+        if let StoredState::Snapshot(snapshot) = stored {
+            let mut state = snapshot.clone();
+            for (hash, stored) in steps.iter().rev() {
+                match stored {
+                    StoredState::Diff(diff) => state.apply(diff),
+                    _ => state = blocks.get(*hash)?.apply(&state)?,
+                }
+            }
+            return Some(state);
+        }
+        steps.push((key, stored));
+        curr = &blocks.get(curr)?.header.parent;
+    }
+}
+
+/// Store the states of the blocks that joined the longest chain in `reorg`, up to the new tip
+/// excluded, and drop the ones of the blocks that left it.
+fn materialize_states(blocks: &HashMap<H256,Block>, block_len: &HashMap<H256,u32>, block_states: &mut HashMap<H256, StoredState>, reorg: &Reorg) {
+    let connected = &reorg.connected[..reorg.connected.len().saturating_sub(1)];
+    let mut parent_state = match connected.first() {
+        Some(first) => reconstruct_state(blocks, block_states, &blocks[first].header.parent),
+        None => None,
+    };
+    for hash in connected.iter() {
+        let state = match reconstruct_state(blocks, block_states, hash) {
+            Some(state) => state,
+            None => return,
+        };
+        let stored_state = match &parent_state {
+            Some(parent_state) if !block_len[hash].is_multiple_of(SNAPSHOT_INTERVAL) => StoredState::Diff(state.diff(parent_state)),
+            _ => StoredState::Snapshot(state.clone()),
+        };
+        block_states.insert(*hash, stored_state);
+        parent_state = Some(state);
+    }
+    for hash in reorg.disconnected.iter() {
+        block_states.insert(*hash, StoredState::Lazy);
+    }
+}
+
+/// Verify a block on top of the state of its parent, at `height`. Returns the state after the
+/// block if it is valid.
+pub fn verify_block(block: &Block, parent_state: &State, height: u32) -> Option<State> {
+    if !block.is_well_formed() {
+        return None;
+    }
+    if let Some(tx) = block.content.transactions.iter().find(|tx| tx.transaction.is_expired(height)) {
+        debug!("Block {:?} at height {} has expired transaction {:?}", block.hash(), height, tx.hash());
+        return None;
+    }
+    // the signatures do not depend on the state, check them all at once on every core
+    if !block.content.transactions.par_iter().all(|tx| tx.has_valid_signature()) {
+        debug!("Block {:?} has a transaction with an invalid signature", block.hash());
+        return None;
+    }
+    if let Some(sender) = block.find_double_spend(parent_state) {
+        debug!("Block {:?} has conflicting transactions of {:?}", block.hash(), sender);
+        return None;
+    }
+    // in the committed order, see `Block::apply`
+    let state = block.apply(parent_state)?;
+    if state.root() != block.header.state_root {
+        debug!("Block {:?} state root mismatch", block.hash());
+        return None;
+    }
+    Some(state)
+}
+
+/// The median timestamp of the `MEDIAN_TIME_SPAN` blocks up to `hash`, see
+/// `Blockchain::median_time_past`.
+fn median_time_past(blocks: &HashMap<H256,Block>, pruned_headers: &HashMap<H256, Header>, hash: &H256) -> Option<u128> {
+    let header = |hash: &H256| blocks.get(hash).map(|block| block.header).or_else(|| pruned_headers.get(hash).copied());
+    let mut timestamps = vec![];
+    let mut curr = header(hash)?;
+    loop {
+        timestamps.push(curr.timestamp);
+        match header(&curr.parent) {
+            Some(parent) if timestamps.len() < MEDIAN_TIME_SPAN => curr = parent,
+            _ => break,
+        }
+    }
+    timestamps.sort_unstable();
+    Some(timestamps[timestamps.len() / 2])
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// The addresses `tx` touches: its sender and the recipients of its outputs, each once.
+fn touched_addresses(tx: &SignedTransaction) -> Vec<H160> {
+    let mut addresses = vec![tx.sender()];
+    for (recipient, _) in tx.transaction.outputs.iter() {
+        if !addresses.contains(recipient) {
+            addresses.push(*recipient);
+        }
+    }
+    addresses
+}
+
+/// Among the `occurrences` of a transaction, the one in the longest chain, or else the last
+/// inserted one, so that a fork block carrying a confirmed transaction does not shadow it.
+fn canonical_occurrence(occurrences: &[(H256, usize)], block_len: &HashMap<H256,u32>, canonical: &[H256]) -> Option<(H256, usize)> {
+    let is_canonical = |hash: &H256| block_len.get(hash).is_some_and(|len| canonical.get(*len as usize - 1) == Some(hash));
+    occurrences.iter().find(|(hash, _)| is_canonical(hash)).or_else(|| occurrences.last()).copied()
+}
+
+/// Where a transaction was included, as returned by `Blockchain::get_transaction`.
+#[derive(Debug, Clone)]
+pub struct TransactionLocation {
+    pub transaction: SignedTransaction,
+    pub block_hash: H256,
+    /// Height of the block, 0 for the genesis.
+    pub height: u32,
+    pub position: usize,
+    /// Number of blocks on top of (and including) the block in the longest chain, or 0 if the
+    /// block is on a fork.
+    pub confirmations: u32,
+}
+
+/// The blocks that left and joined the longest chain when the head switched to another fork.
+#[derive(Debug, Clone, Default)]
+pub struct Reorg {
+    /// Blocks of the abandoned branch, from the old tip back to the fork point.
+    pub disconnected: Vec<H256>,
+    /// Blocks of the new branch, from the fork point up to the new tip.
+    pub connected: Vec<H256>,
+    /// Transactions of the disconnected blocks that are not in any connected block.
+    pub evicted_transactions: Vec<SignedTransaction>,
+}
+
+/// The outcome of `Blockchain::insert`.
+#[derive(Debug, Clone, Default)]
+pub struct InsertResult {
+    pub inserted: bool,
+    /// Set when the inserted block made a fork overtake the previous head.
+    pub reorg: Option<Reorg>,
+}
+
+/// Fork bookkeeping of the block tree, as returned by `Blockchain::fork_stats`.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ForkStats {
+    /// Blocks whose parent already had a child when they were inserted.
+    pub forks: usize,
+    /// Blocks off the longest chain.
+    pub stale_blocks: usize,
+    /// Length of the longest branch off the longest chain.
+    pub max_fork_depth: u32,
+    pub reorgs: u64,
+    /// Most blocks disconnected by a single reorg.
+    pub max_reorg_depth: u32,
+}
+
+/// Walk back from `a` and `b`, the higher one first, until they meet.
+fn common_ancestor(blocks: &HashMap<H256,Block>, block_len: &HashMap<H256,u32>, a: H256, b: H256) -> Option<H256> {
+    let mut a = a;
+    let mut b = b;
+    while a != b {
+        if block_len.get(&a)? >= block_len.get(&b)? {
+            a = blocks.get(&a)?.header.parent;
+        } else {
+            b = blocks.get(&b)?.header.parent;
+        }
+    }
+    Some(a)
+}
+
+/// Walk back from `old_tip` and `new_tip` to their common ancestor and collect the reorg.
+fn compute_reorg(blocks: &HashMap<H256,Block>, block_len: &HashMap<H256,u32>, old_tip: H256, new_tip: H256) -> Reorg {
+    let mut disconnected = Vec::new();
+    let mut connected = Vec::new();
+    let mut old = old_tip;
+    let mut new = new_tip;
+    while block_len[&old] > block_len[&new] {
+        disconnected.push(old);
+        old = blocks[&old].header.parent;
+    }
+    while block_len[&new] > block_len[&old] {
+        connected.push(new);
+        new = blocks[&new].header.parent;
+    }
+    while old != new {
+        disconnected.push(old);
+        connected.push(new);
+        old = blocks[&old].header.parent;
+        new = blocks[&new].header.parent;
+    }
+    connected.reverse();
+
+    let mut connected_txs = std::collections::HashSet::new();
+    for hash in connected.iter() {
+        for tx in blocks[hash].content.transactions.iter() {
+            connected_txs.insert(tx.hash());
+        }
+    }
+    let mut evicted_transactions = Vec::new();
+    for hash in disconnected.iter() {
+        for tx in blocks[hash].content.transactions.iter() {
+            if !connected_txs.contains(&tx.hash()) {
+                evicted_transactions.push(tx.clone());
+            }
+        }
+    }
+    Reorg {
+        disconnected,
+        connected,
+        evicted_transactions,
+    }
+}
+
+/// The write guards of the tables of a `Blockchain`, taken in lock order by `lock_tables`, to
+/// insert, import, prune or rebuild atomically.
+struct Tables<'a> {
+    head: RwLockWriteGuard<'a, H256>,
+    blocks: RwLockWriteGuard<'a, HashMap<H256,Block>>,
+    block_len: RwLockWriteGuard<'a, HashMap<H256,u32>>,
+    block_work: RwLockWriteGuard<'a, HashMap<H256,Work>>,
+    block_states: RwLockWriteGuard<'a, HashMap<H256, StoredState>>,
+    receipts: RwLockWriteGuard<'a, HashMap<H256, Vec<Receipt>>>,
+    tx_index: RwLockWriteGuard<'a, HashMap<H256, Vec<(H256, usize)>>>,
+    address_index: RwLockWriteGuard<'a, HashMap<H160, Vec<(H256, H256)>>>,
+    canonical: RwLockWriteGuard<'a, Vec<H256>>,
+    reorg_stats: RwLockWriteGuard<'a, (u64, u32)>,
+    pruned_headers: RwLockWriteGuard<'a, HashMap<H256, Header>>,
+}
+
+/// The blockchain is shared between the worker threads, the miner and the txgenerator as an
+/// `Arc<Blockchain>`. Every field sits behind its own `RwLock`, so readers (tip reads, state
+/// lookups) never block each other.
+///
+/// Lock ordering: whenever more than one lock is held at the same time, they are acquired in
+/// the order `head` -> `blocks` -> `block_len` -> `block_work` -> `block_states` -> `receipts`
+/// -> `tx_index` -> `address_index` -> `canonical` -> `reorg_stats` -> `pruned_headers`, and
+/// released in reverse. No method hands out a guard, so callers can never violate the ordering
+/// from the outside.
 pub struct Blockchain {
-    blocks: HashMap<H256,Block>,
-    block_len: HashMap<H256,u32>,
-    block_states: HashMap<H256, State>,
-    head: H256,
+    head: RwLock<H256>,
+    blocks: RwLock<HashMap<H256,Block>>,
+    block_len: RwLock<HashMap<H256,u32>>,
+    /// Work of the chain ending at each block, see `Header::work`. The head is the block with
+    /// the most work, the first one inserted among equals.
+    block_work: RwLock<HashMap<H256,Work>>,
+    block_states: RwLock<HashMap<H256, StoredState>>,
+    /// Receipts of the transactions of each block, in block order. Missing for the blocks whose
+    /// parent state was not known, such as an imported checkpoint.
+    receipts: RwLock<HashMap<H256, Vec<Receipt>>>,
+    /// txid -> (block hash, position) of every block containing it, in insertion order, forks
+    /// included, see `canonical_occurrence`.
+    tx_index: RwLock<HashMap<H256, Vec<(H256, usize)>>>,
+    /// address -> (block hash, txid) of the transactions that touched it, in insertion order,
+    /// forks included.
+    address_index: RwLock<HashMap<H160, Vec<(H256, H256)>>>,
+    /// Hashes of the longest chain indexed by height, the genesis being at height 0.
+    canonical: RwLock<Vec<H256>>,
+    /// Number of reorgs and most blocks disconnected by one of them.
+    reorg_stats: RwLock<(u64, u32)>,
+    /// Headers of the blocks of the longest chain before the oldest block kept, when the chain
+    /// was pruned or started from an imported snapshot. Every block kept descends from the
+    /// oldest one.
+    pruned_headers: RwLock<HashMap<H256, Header>>,
+    /// Gets the `NewHead` and `Reorg` events.
+    events: Arc<EventBus>,
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Blockchain::new()
+    }
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain, only containing the default genesis block
     pub fn new() -> Self {
-        let genesis_block = Block {
-            header: Header{
-                parent: Default::default(),
-                nonce: Default::default(),
-                difficulty: H256::from([0,64,0,0,0,0,0,0,
-                                        0,0,0,0,0,0,0,0,
-                                        0,0,0,0,0,0,0,0,
-                                        0,0,0,0,0,0,0,0]),
-                timestamp: Default::default(),
-                merkle_root: Default::default(),
-            },
-            content: Content{
-                transactions: Default::default(),
-            },
-        };
+        let (genesis_block, genesis_state) = GenesisConfig::default().build().unwrap();
+        Blockchain::from_genesis(genesis_block, genesis_state, &Default::default())
+    }
 
-        let mut address_list = Vec::new();
-        let mut account_state: HashMap<H160, AccountState> = HashMap::new();
-        for i in 0..8 {
-            let key_pair = key_pair::frombyte(i as u8);
-            let address: H160 = ring::digest::digest(&ring::digest::SHA256, key_pair.public_key().as_ref()).into();
-            address_list.push(address);
-            account_state.insert(address, AccountState{
-                balance: INIT_COINS,
-                nonce: 0,
-            });
-        }
-        info!("ICO: address0: {:?}, balance: {}; address1: {:?}, balance: {}; address2: {:?}, balance: {}", 
-            address_list[0], INIT_COINS, address_list[1], INIT_COINS, address_list[2], INIT_COINS);
-        let genesis_state = State {
-            address_list: address_list,
-            account_state: account_state,
-        };
+    /// Create a new blockchain, only containing the given genesis block and state, publishing
+    /// the changes of the longest chain on `events`
+    pub fn from_genesis(genesis_block: Block, genesis_state: State, events: &Arc<EventBus>) -> Self {
+        info!("ICO: {} accounts, total balance: {}, chain id: {}",
+            genesis_state.address_list.len(),
+            genesis_state.address_list.iter().map(|address| genesis_state.balance(address)).sum::<u64>(),
+            genesis_block.header.nonce);
 
         let head = genesis_block.hash();
+        let genesis_work = genesis_block.header.work();
 
         let mut _blocks: HashMap<H256,Block> = HashMap::new();
         _blocks.insert(head,genesis_block);
@@ -58,106 +326,656 @@ impl Blockchain {
         let mut _block_len: HashMap<H256,u32> = HashMap::new();
         _block_len.insert(head,1);
 
-        let mut _block_state: HashMap<H256, State> = HashMap::new();
-        _block_state.insert(head, genesis_state);
+        let mut _block_state: HashMap<H256, StoredState> = HashMap::new();
+        _block_state.insert(head, StoredState::Snapshot(genesis_state));
 
         Blockchain{
-            blocks: _blocks,
-            block_len: _block_len,
-            head: head,
-            block_states: _block_state,
+            head: RwLock::new(head),
+            blocks: RwLock::new(_blocks),
+            block_len: RwLock::new(_block_len),
+            block_work: RwLock::new(vec![(head, genesis_work)].into_iter().collect()),
+            block_states: RwLock::new(_block_state),
+            receipts: RwLock::new(HashMap::new()),
+            tx_index: RwLock::new(HashMap::new()),
+            address_index: RwLock::new(HashMap::new()),
+            canonical: RwLock::new(vec![head]),
+            reorg_stats: RwLock::new((0, 0)),
+            pruned_headers: RwLock::new(HashMap::new()),
+            events: Arc::clone(events),
+        }
+    }
+
+    fn lock_tables(&self) -> Tables<'_> {
+        Tables {
+            head: self.head.write().unwrap(),
+            blocks: self.blocks.write().unwrap(),
+            block_len: self.block_len.write().unwrap(),
+            block_work: self.block_work.write().unwrap(),
+            block_states: self.block_states.write().unwrap(),
+            receipts: self.receipts.write().unwrap(),
+            tx_index: self.tx_index.write().unwrap(),
+            address_index: self.address_index.write().unwrap(),
+            canonical: self.canonical.write().unwrap(),
+            reorg_stats: self.reorg_stats.write().unwrap(),
+            pruned_headers: self.pruned_headers.write().unwrap(),
         }
     }
 
-    /// Insert a block & the state into blockchain
-    pub fn insert(&mut self, block: &Block, state: &State) -> bool{
+    /// Insert a block & the state into blockchain. The block is not inserted if the parent is
+    /// unknown, the block is already in the chain, or it does not inherit the difficulty and the
+    /// gas limit of its parent, see `Header::inherits`: its work would not be the one its proof
+    /// of work was checked against.
+    pub fn insert(&self, block: &Block, state: &State) -> InsertResult {
+        // Take every write lock up front, in lock order, so the insertion is atomic.
+        let mut tables = self.lock_tables();
+        self.connect(&mut tables, block, state)
+    }
+
+    /// `insert`, with the locks already held.
+    fn connect(&self, tables: &mut Tables, block: &Block, state: &State) -> InsertResult {
         let curr_block_hash = block.hash();
         let prev_block_hash = block.header.parent;
 
-        if let Some(_) = self.blocks.get(&prev_block_hash){
-            self.blocks.insert(curr_block_hash, block.clone());
+        if !tables.blocks.contains_key(&prev_block_hash) || tables.blocks.contains_key(&curr_block_hash)
+            || !block.header.inherits(&tables.blocks[&prev_block_hash].header) {
+            return Default::default();
+        }
 
-            let new_len: u32 = self.block_len.get(&prev_block_hash).unwrap() + 1; 
-            self.block_len.insert(curr_block_hash, new_len);
-            self.block_states.insert(curr_block_hash, state.clone());
+        let new_len: u32 = tables.block_len[&prev_block_hash] + 1;
+        let new_work = tables.block_work[&prev_block_hash].saturating_add(block.header.work());
+        let parent_state = reconstruct_state(&tables.blocks, &tables.block_states, &prev_block_hash);
+        let stored_state = match &parent_state {
+            // a fork, recomputed from the block if it becomes the longest chain
+            Some(_) if new_work <= tables.block_work[&*tables.head] => StoredState::Lazy,
+            Some(parent_state) if !new_len.is_multiple_of(SNAPSHOT_INTERVAL) => StoredState::Diff(state.diff(parent_state)),
+            _ => StoredState::Snapshot(state.clone()),
+        };
+        if let Some(parent_state) = &parent_state {
+            tables.receipts.insert(curr_block_hash, receipt::execute(block, parent_state));
+        }
 
-            info!("New block_hash: {:?} total blocks: {:?}, longest_chain_len: {:?}",
-                block.hash(), self.blocks.len(), self.block_len.get(self.tip()).unwrap());
+        tables.blocks.insert(curr_block_hash, block.clone());
+        tables.block_len.insert(curr_block_hash, new_len);
+        tables.block_work.insert(curr_block_hash, new_work);
+        tables.block_states.insert(curr_block_hash, stored_state);
+        for (position, tx) in block.content.transactions.iter().enumerate() {
+            tables.tx_index.entry(tx.hash()).or_default().push((curr_block_hash, position));
+            for address in touched_addresses(tx) {
+                tables.address_index.entry(address).or_default().push((curr_block_hash, tx.hash()));
+            }
+        }
 
-            if new_len > *self.block_len.get(&self.head).unwrap(){
-                self.head = curr_block_hash;
-                info!("Blockchain: tip_hash: {:?}, tip state: {:#?}; ", self.tip(), state.account_state);
+        info!("New block_hash: {:?} total blocks: {:?}, longest_chain_len: {:?}",
+            curr_block_hash, tables.blocks.len(), tables.block_len[&*tables.head]);
+
+        let mut reorg = None;
+        if new_work > tables.block_work[&*tables.head] {
+            if *tables.head != prev_block_hash {
+                let r = compute_reorg(&tables.blocks, &tables.block_len, *tables.head, curr_block_hash);
+                info!("Reorg: {} blocks disconnected, {} blocks connected, {} transactions evicted",
+                    r.disconnected.len(), r.connected.len(), r.evicted_transactions.len());
+                let fork_height = tables.canonical.len() - r.disconnected.len();
+                tables.canonical.truncate(fork_height);
+                tables.canonical.extend_from_slice(&r.connected);
+                materialize_states(&tables.blocks, &tables.block_len, &mut tables.block_states, &r);
+                tables.reorg_stats.0 += 1;
+                tables.reorg_stats.1 = tables.reorg_stats.1.max(r.disconnected.len() as u32);
+                self.events.publish(NodeEvent::Reorg(r.clone()));
+                reorg = Some(r);
+            } else {
+                tables.canonical.push(curr_block_hash);
             }
+            *tables.head = curr_block_hash;
+            self.events.publish(NodeEvent::NewHead {
+                hash: curr_block_hash,
+                header: block.header,
+                height: new_len - 1,
+                num_transactions: block.content.len(),
+            });
+            info!("Blockchain: tip_hash: {:?}, tip state: {:#?}; ", *tables.head, state.account_state);
+        }
 
-            return true;
+        InsertResult {
+            inserted: true,
+            reorg,
         }
-        false
     }
 
     /// Get the last block's hash of the longest chain
-    pub fn tip(&self) -> &H256 {
-        &self.head
+    pub fn tip(&self) -> H256 {
+        *self.head.read().unwrap()
+    }
+
+    /// Get the tip hash together with its state, read consistently with respect to `insert`.
+    pub fn tip_with_state(&self) -> (H256, State) {
+        let head = self.head.read().unwrap();
+        let blocks = self.blocks.read().unwrap();
+        let block_states = self.block_states.read().unwrap();
+        (*head, reconstruct_state(&blocks, &block_states, &head).unwrap())
+    }
+
+    pub fn get_block(&self, hash: &H256) -> Option<Block> {
+        self.blocks.read().unwrap().get(hash).cloned()
     }
 
-    pub fn get_block(&self, hash: &H256) -> Option<&Block> {
-        self.blocks.get(&hash)
+    /// Get the header of a block, kept even if the block was pruned, if it is in the longest
+    /// chain.
+    pub fn get_header(&self, hash: &H256) -> Option<Header> {
+        let blocks = self.blocks.read().unwrap();
+        let pruned_headers = self.pruned_headers.read().unwrap();
+        blocks.get(hash).map(|block| block.header).or_else(|| pruned_headers.get(hash).copied())
     }
 
-    pub fn get_state(&self, hash: &H256) -> Option<& State> {
-        self.block_states.get(hash)
+    /// Get the state after block `hash`, reconstructed from the closest snapshot.
+    pub fn get_state(&self, hash: &H256) -> Option<State> {
+        let blocks = self.blocks.read().unwrap();
+        let block_states = self.block_states.read().unwrap();
+        reconstruct_state(&blocks, &block_states, hash)
     }
 
-    pub fn update_state(&mut self, hash: &H256, state: &State) {
-        self.block_states.insert(hash.clone(), state.clone());
+    /// Replace the state after block `hash` with a full snapshot.
+    pub fn update_state(&self, hash: &H256, state: &State) {
+        self.block_states.write().unwrap().insert(*hash, StoredState::Snapshot(state.clone()));
+    }
+
+    /// Look up a transaction by its hash, with the block that contains it and its confirmation
+    /// depth in the longest chain.
+    pub fn get_transaction(&self, txid: &H256) -> Option<TransactionLocation> {
+        let blocks = self.blocks.read().unwrap();
+        let block_len = self.block_len.read().unwrap();
+        let tx_index = self.tx_index.read().unwrap();
+        let canonical = self.canonical.read().unwrap();
+
+        let (block_hash, position) = canonical_occurrence(tx_index.get(txid)?, &block_len, &canonical)?;
+        let block = blocks.get(&block_hash)?;
+        let height = (block_len[&block_hash] - 1) as usize;
+        let confirmations = if canonical.get(height) == Some(&block_hash) {
+            (canonical.len() - height) as u32
+        } else {
+            0
+        };
+
+        Some(TransactionLocation {
+            transaction: block.content.transactions[position].clone(),
+            block_hash,
+            height: height as u32,
+            position,
+            confirmations,
+        })
+    }
+
+    /// The receipt of a transaction, from the block containing it in the longest chain, or else
+    /// the last inserted one.
+    pub fn get_receipt(&self, txid: &H256) -> Option<Receipt> {
+        let block_len = self.block_len.read().unwrap();
+        let receipts = self.receipts.read().unwrap();
+        let tx_index = self.tx_index.read().unwrap();
+        let canonical = self.canonical.read().unwrap();
+        let (block_hash, position) = canonical_occurrence(tx_index.get(txid)?, &block_len, &canonical)?;
+        receipts.get(&block_hash)?.get(position).cloned()
+    }
+
+    /// The (block hash, txid) of the transactions that touched `address`, as sender or recipient,
+    /// oldest first. Blocks off the longest chain are listed too.
+    pub fn get_history(&self, address: &H160) -> Vec<(H256, H256)> {
+        self.address_index.read().unwrap().get(address).cloned().unwrap_or_default()
+    }
+
+    /// Height of the tip of the longest chain, the genesis being at height 0.
+    pub fn height(&self) -> u32 {
+        (self.canonical.read().unwrap().len() - 1) as u32
+    }
+
+    /// Height of any known block, whether it is in the longest chain or not.
+    pub fn get_block_height(&self, hash: &H256) -> Option<u32> {
+        self.block_len.read().unwrap().get(hash).map(|len| len - 1)
+    }
+
+    /// Hash of the block at `height` in the longest chain.
+    pub fn get_hash_by_height(&self, height: u32) -> Option<H256> {
+        self.canonical.read().unwrap().get(height as usize).copied()
+    }
+
+    /// Block at `height` in the longest chain.
+    pub fn get_block_by_height(&self, height: u32) -> Option<Block> {
+        let blocks = self.blocks.read().unwrap();
+        let canonical = self.canonical.read().unwrap();
+        canonical.get(height as usize).and_then(|hash| blocks.get(hash)).cloned()
+    }
+
+    /// Count the forks and the stale blocks of the block tree.
+    pub fn fork_stats(&self) -> ForkStats {
+        let blocks = self.blocks.read().unwrap();
+        let block_len = self.block_len.read().unwrap();
+        let canonical = self.canonical.read().unwrap();
+        let reorg_stats = self.reorg_stats.read().unwrap();
+
+        let is_canonical = |hash: &H256| canonical.get(block_len[hash] as usize - 1) == Some(hash);
+        // every block but the oldest one has a parent, and every parent beyond its first child
+        // forks
+        let parents: HashSet<H256> = blocks
+            .values()
+            .map(|block| block.header.parent)
+            .filter(|parent| blocks.contains_key(parent))
+            .collect();
+        // number of stale blocks from each stale block down to the longest chain
+        let mut branch_depth: HashMap<H256, u32> = HashMap::new();
+        for hash in blocks.keys() {
+            let mut branch = vec![];
+            let mut curr = *hash;
+            while !is_canonical(&curr) && !branch_depth.contains_key(&curr) {
+                branch.push(curr);
+                curr = blocks[&curr].header.parent;
+            }
+            let mut depth = branch_depth.get(&curr).copied().unwrap_or(0);
+            for stale in branch.into_iter().rev() {
+                depth += 1;
+                branch_depth.insert(stale, depth);
+            }
+        }
+
+        ForkStats {
+            forks: blocks.len() - 1 - parents.len(),
+            stale_blocks: blocks.keys().filter(|hash| !is_canonical(hash)).count(),
+            max_fork_depth: branch_depth.values().copied().max().unwrap_or(0),
+            reorgs: reorg_stats.0,
+            max_reorg_depth: reorg_stats.1,
+        }
+    }
+
+    /// The tree of the blocks kept, forks included, as a Graphviz DOT digraph with an edge from
+    /// each block to its children. The blocks of the longest chain are filled, and the ones in
+    /// `mined` tagged as mined by this node.
+    pub fn to_dot(&self, mined: &HashSet<H256>) -> String {
+        let blocks = self.blocks.read().unwrap();
+        let block_len = self.block_len.read().unwrap();
+        let canonical = self.canonical.read().unwrap();
+
+        let mut by_height: Vec<(u32, H256)> = block_len.iter().map(|(hash, len)| (len - 1, *hash)).collect();
+        by_height.sort_unstable();
+        let mut dot = String::from("digraph blocks {\n  rankdir=LR;\n  node [shape=box];\n");
+        for (height, hash) in by_height.iter() {
+            let style = if canonical.get(*height as usize) == Some(hash) { ", style=filled" } else { "" };
+            let miner = if mined.contains(hash) { "self" } else { "peer" };
+            dot.push_str(&format!("  \"{}\" [label=\"{:.8}\\nheight {}\\nminer {}\"{}];\n", hash, hash, height, miner, style));
+            let parent = blocks[hash].header.parent;
+            if blocks.contains_key(&parent) {
+                dot.push_str(&format!("  \"{}\" -> \"{}\";\n", parent, hash));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The checkpoint at the highest multiple of `interval` in the longest chain, if any.
+    pub fn snapshot(&self, interval: u32) -> Option<Snapshot> {
+        let blocks = self.blocks.read().unwrap();
+        let block_states = self.block_states.read().unwrap();
+        let canonical = self.canonical.read().unwrap();
+        let pruned_headers = self.pruned_headers.read().unwrap();
+
+        let height = (canonical.len() as u32 - 1) / interval * interval;
+        if height == 0 {
+            return None;
+        }
+        let block = blocks.get(&canonical[height as usize])?.clone();
+        let headers = canonical[..=height as usize]
+            .iter()
+            .map(|hash| blocks.get(hash).map(|block| block.header).or_else(|| pruned_headers.get(hash).copied()))
+            .collect::<Option<Vec<Header>>>()?;
+        let state = reconstruct_state(&blocks, &block_states, &block.hash())?;
+        Some(Snapshot { headers, block, state })
+    }
+
+    /// Start the chain over from `snapshot`, forgetting the blocks known so far, if the headers
+    /// of the snapshot carry more work than the longest chain. The snapshot should be verified against the genesis
+    /// first. Returns whether it was imported.
+    pub fn import_snapshot(&self, snapshot: &Snapshot) -> bool {
+        let mut tables = self.lock_tables();
+
+        let height = snapshot.height();
+        // as in `insert`, only the work of difficulties inherited from the parent counts
+        if snapshot.headers.windows(2).any(|pair| !pair[1].inherits(&pair[0])) {
+            return false;
+        }
+        let work = snapshot.headers.iter().fold(Work::ZERO, |work, header| work.saturating_add(header.work()));
+        if work <= tables.block_work[&*tables.head] {
+            return false;
+        }
+        let hash = snapshot.block.hash();
+        *tables.head = hash;
+        *tables.blocks = vec![(hash, snapshot.block.clone())].into_iter().collect();
+        *tables.block_len = vec![(hash, height + 1)].into_iter().collect();
+        *tables.block_work = vec![(hash, work)].into_iter().collect();
+        *tables.block_states = vec![(hash, StoredState::Snapshot(snapshot.state.clone()))].into_iter().collect();
+        tables.receipts.clear();
+        *tables.tx_index = snapshot.block.content.transactions.iter().enumerate()
+            .map(|(position, tx)| (tx.hash(), vec![(hash, position)]))
+            .collect();
+        tables.address_index.clear();
+        for tx in snapshot.block.content.transactions.iter() {
+            for address in touched_addresses(tx) {
+                tables.address_index.entry(address).or_default().push((hash, tx.hash()));
+            }
+        }
+        *tables.canonical = snapshot.headers.iter().map(|header| header.hash()).collect();
+        *tables.pruned_headers = snapshot.headers[..height as usize].iter().map(|header| (header.hash(), *header)).collect();
+        *tables.reorg_stats = (0, 0);
+        info!("Imported the checkpoint {:?} at height {}", hash, height);
+        self.events.publish(NodeEvent::NewHead {
+            hash,
+            header: snapshot.block.header,
+            height,
+            num_transactions: snapshot.block.content.len(),
+        });
+        true
+    }
+
+    /// Discard the blocks and the states before height `below` in the longest chain, keeping
+    /// their headers, along with the forks off the longest chain before that height. Returns the
+    /// number of blocks discarded, 0 when the state at the new oldest block cannot be rebuilt.
+    pub fn prune(&self, below: u32) -> usize {
+        let mut tables = self.lock_tables();
+
+        let root = tables.pruned_headers.len();
+        let below = (below as usize).min(tables.canonical.len() - 1);
+        if below <= root {
+            return 0;
+        }
+        // the new oldest block needs a full state, its ancestors are gone
+        let new_root = tables.canonical[below];
+        let root_state = match reconstruct_state(&tables.blocks, &tables.block_states, &new_root) {
+            Some(state) => state,
+            None => {
+                error!("Cannot prune below height {}: the state of block {:?} is unknown", below, new_root);
+                return 0;
+            }
+        };
+        tables.block_states.insert(new_root, StoredState::Snapshot(root_state));
+        for hash in tables.canonical[root..below].iter() {
+            tables.pruned_headers.insert(*hash, tables.blocks[hash].header);
+        }
+
+        // keep the descendants of the new oldest block, parents before children
+        let mut by_height: Vec<(u32, H256)> = tables.block_len.iter().map(|(hash, len)| (*len, *hash)).collect();
+        by_height.sort_unstable();
+        let mut kept: HashSet<H256> = HashSet::new();
+        let mut discarded = 0;
+        for (_, hash) in by_height {
+            if hash == new_root || kept.contains(&tables.blocks[&hash].header.parent) {
+                kept.insert(hash);
+                continue;
+            }
+            let block = tables.blocks.remove(&hash).unwrap();
+            tables.block_len.remove(&hash);
+            tables.block_work.remove(&hash);
+            tables.block_states.remove(&hash);
+            tables.receipts.remove(&hash);
+            for tx in block.content.transactions.iter() {
+                if let Some(occurrences) = tables.tx_index.get_mut(&tx.hash()) {
+                    occurrences.retain(|(block_hash, _)| *block_hash != hash);
+                    if occurrences.is_empty() {
+                        tables.tx_index.remove(&tx.hash());
+                    }
+                }
+                for address in touched_addresses(tx) {
+                    if let Some(history) = tables.address_index.get_mut(&address) {
+                        history.retain(|(block_hash, _)| *block_hash != hash);
+                        if history.is_empty() {
+                            tables.address_index.remove(&address);
+                        }
+                    }
+                }
+            }
+            discarded += 1;
+        }
+        info!("Pruned {} blocks before height {}", discarded, below);
+        discarded
+    }
+
+    /// Insert `block` with the state it leads to from its parent's, if it passes the checks of a
+    /// block received from a peer: the proof of work and the gas limit against its parent, a
+    /// timestamp after the median time past of its parent, and `verify_block`. Returns whether
+    /// it was inserted.
+    pub fn replay(&self, block: &Block) -> bool {
+        let mut tables = self.lock_tables();
+        self.replay_locked(&mut tables, block)
+    }
+
+    /// `replay`, with the locks already held.
+    fn replay_locked(&self, tables: &mut Tables, block: &Block) -> bool {
+        let parent_hash = block.header.parent;
+        let parent_header = match tables.blocks.get(&parent_hash) {
+            Some(parent) => parent.header,
+            None => return false,
+        };
+        let after_median_time = median_time_past(&tables.blocks, &tables.pruned_headers, &parent_hash)
+            .is_some_and(|median| block.header.timestamp > median);
+        if !block.header.meets_difficulty(Some(&parent_header)) || !after_median_time {
+            debug!("Block {:?} fails the header checks", block.hash());
+            return false;
+        }
+        let height = tables.block_len[&parent_hash];
+        let state = match reconstruct_state(&tables.blocks, &tables.block_states, &parent_hash) {
+            Some(parent_state) => verify_block(block, &parent_state, height),
+            None => None,
+        };
+        match state {
+            Some(state) => self.connect(tables, block, &state).inserted,
+            None => false,
+        }
+    }
+
+    /// Rebuild the states, heights, work, receipts, indices and the longest chain by replaying
+    /// the blocks on top of the oldest one kept, parents before children, with the checks of
+    /// `replay`. The locks are held throughout, so no reader sees the chain half rebuilt.
+    /// Recovers from a corrupted state, the blocks being intact. The blocks that fail, and their
+    /// descendants, are dropped. Returns the number of blocks dropped, or an `InvalidData` error,
+    /// leaving the chain as it was, when the oldest block or its full state is missing.
+    pub fn reindex(&self) -> io::Result<usize> {
+        let mut tables = self.lock_tables();
+
+        // the oldest block kept, the genesis or a checkpoint, is the only one with its full
+        // state whatever happened to the others
+        let roots: Vec<H256> = tables.blocks.iter()
+            .filter(|(_, block)| !tables.blocks.contains_key(&block.header.parent))
+            .map(|(hash, _)| *hash)
+            .collect();
+        let root = match roots[..] {
+            [root] => root,
+            _ => return Err(invalid_data(format!("expected one oldest block, found {}", roots.len()))),
+        };
+        let (root_len, root_work) = match (tables.block_len.get(&root), tables.block_work.get(&root)) {
+            (Some(len), Some(work)) => (*len, *work),
+            _ => return Err(invalid_data(format!("the oldest block {:?} has no height or work", root))),
+        };
+        let root_state = match tables.block_states.get(&root) {
+            Some(StoredState::Snapshot(state)) => state.clone(),
+            _ => return Err(invalid_data(format!("the oldest block {:?} has no full state", root))),
+        };
+        let root_block = tables.blocks.remove(&root).unwrap();
+        let mut children: HashMap<H256, Vec<Block>> = HashMap::new();
+        let total = tables.blocks.len();
+        for (_, block) in tables.blocks.drain() {
+            children.entry(block.header.parent).or_default().push(block);
+        }
+
+        *tables.head = root;
+        tables.blocks.insert(root, root_block.clone());
+        *tables.block_len = vec![(root, root_len)].into_iter().collect();
+        *tables.block_work = vec![(root, root_work)].into_iter().collect();
+        *tables.block_states = vec![(root, StoredState::Snapshot(root_state))].into_iter().collect();
+        tables.receipts.retain(|hash, _| *hash == root);
+        tables.tx_index.clear();
+        tables.address_index.clear();
+        for (position, tx) in root_block.content.transactions.iter().enumerate() {
+            tables.tx_index.insert(tx.hash(), vec![(root, position)]);
+            for address in touched_addresses(tx) {
+                tables.address_index.entry(address).or_default().push((root, tx.hash()));
+            }
+        }
+        tables.canonical.truncate(root_len as usize);
+        *tables.reorg_stats = (0, 0);
+
+        let mut replayed = 0;
+        let mut queue = std::collections::VecDeque::from(vec![root]);
+        while let Some(parent) = queue.pop_front() {
+            for block in children.remove(&parent).unwrap_or_default() {
+                if self.replay_locked(&mut tables, &block) {
+                    queue.push_back(block.hash());
+                    replayed += 1;
+                }
+            }
+        }
+        info!("Reindexed {} blocks, dropped {}", replayed, total - replayed);
+        Ok(total - replayed)
+    }
+
+    /// The closest block that both `a` and `b` are or descend from, `None` if either is unknown
+    /// or was pruned.
+    pub fn find_common_ancestor(&self, a: &H256, b: &H256) -> Option<H256> {
+        let blocks = self.blocks.read().unwrap();
+        let block_len = self.block_len.read().unwrap();
+        common_ancestor(&blocks, &block_len, *a, *b)
+    }
+
+    /// Whether `descendant` is `ancestor` or descends from it.
+    pub fn is_ancestor(&self, ancestor: &H256, descendant: &H256) -> bool {
+        self.find_common_ancestor(ancestor, descendant) == Some(*ancestor)
+    }
+
+    /// Hashes of the longest chain from height `from` to height `to`, both included, up to the
+    /// tip. Taken at once, so the hashes are consistent even if the head moves meanwhile.
+    pub fn canonical_range(&self, from: u32, to: u32) -> std::vec::IntoIter<H256> {
+        let canonical = self.canonical.read().unwrap();
+        let to = (to as usize).min(canonical.len().saturating_sub(1));
+        canonical.get(from as usize..=to).unwrap_or_default().to_vec().into_iter()
+    }
+
+    /// Median timestamp of block `hash` and its `MEDIAN_TIME_SPAN - 1` closest ancestors, fewer
+    /// near the genesis. A child of the block must have a later timestamp.
+    pub fn median_time_past(&self, hash: &H256) -> Option<u128> {
+        let blocks = self.blocks.read().unwrap();
+        let pruned_headers = self.pruned_headers.read().unwrap();
+        median_time_past(&blocks, &pruned_headers, hash)
+    }
+
+    /// Hashes of the longest chain to locate the fork point with a peer: the last
+    /// `LOCATOR_DENSE_LEN` blocks, then blocks twice as far apart at every step, down to the
+    /// genesis.
+    pub fn locator(&self) -> Vec<H256> {
+        let canonical = self.canonical.read().unwrap();
+        let mut locator = vec![];
+        let mut height = canonical.len() - 1;
+        let mut step = 1;
+        loop {
+            locator.push(canonical[height]);
+            if height == 0 {
+                return locator;
+            }
+            if locator.len() >= LOCATOR_DENSE_LEN {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+    }
+
+    /// The first hash of `locator` in the longest chain and its height: the fork point with the
+    /// chain of the peer that sent it. Only the first `MAX_LOCATOR_LEN` hashes are looked at.
+    pub fn locate(&self, locator: &[H256]) -> Option<(H256, u32)> {
+        let block_len = self.block_len.read().unwrap();
+        let canonical = self.canonical.read().unwrap();
+        let pruned_headers = self.pruned_headers.read().unwrap();
+        let height = |hash: &H256| match block_len.get(hash) {
+            Some(len) if canonical.get(*len as usize - 1) == Some(hash) => Some(len - 1),
+            Some(_) => None,
+            None if pruned_headers.contains_key(hash) => canonical.iter().position(|h| h == hash).map(|height| height as u32),
+            None => None,
+        };
+        locator.iter().take(MAX_LOCATOR_LEN).find_map(|hash| height(hash).map(|height| (*hash, height)))
     }
 
     pub fn contains_key(&self, hash: &H256) -> bool{
-        self.blocks.contains_key(&hash)
+        self.blocks.read().unwrap().contains_key(hash)
     }
 
-    /// Get the last block's hash of the longest chain
-    //#[cfg(any(test, test_utilities))]
+    /// Get all the blocks' hashes of the longest chain, from the tip back to the genesis
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
+        let head = self.head.read().unwrap();
+        let blocks = self.blocks.read().unwrap();
         let mut longest_chain = Vec::<H256>::new();
 
-        let mut curr = self.head;
+        let mut curr = *head;
 
-        while self.blocks.contains_key(&curr){
+        while let Some(block) = blocks.get(&curr) {
             longest_chain.push(curr);
-            curr = self.blocks.get(&curr).unwrap().header.parent;
+            curr = block.header.parent;
         }
 
         longest_chain
     }
 }
 
+/// Number of blocks whose timestamps `Blockchain::median_time_past` takes the median of.
+pub static MEDIAN_TIME_SPAN: usize = 11;
+
+/// Number of consecutive blocks from the tip in a locator.
+pub static LOCATOR_DENSE_LEN: usize = 10;
+/// Most hashes of a locator looked at, enough for a chain of 2^50 blocks.
+pub static MAX_LOCATOR_LEN: usize = 64;
+
+/// Smallest depth accepted by `start_pruning`: no reorg is expected to go deeper.
+pub static MIN_PRUNE_DEPTH: u32 = 16;
+
+/// Keep the blocks of the last `depth` heights of the longest chain, and the latest checkpoint,
+/// as the chain grows: on every new head, prune the blocks before both of them.
+pub fn start_pruning(events: &Arc<EventBus>, blockchain: &Arc<Blockchain>, depth: u32) {
+    let receiver = events.subscribe();
+    let blockchain = Arc::clone(blockchain);
+    thread::Builder::new()
+        .name("pruning".to_string())
+        .spawn(move || {
+            for event in receiver.iter() {
+                if let NodeEvent::NewHead { height, .. } = event {
+                    let checkpoint = height / CHECKPOINT_INTERVAL * CHECKPOINT_INTERVAL;
+                    // prune in steps of `SNAPSHOT_INTERVAL` heights
+                    let below = height.saturating_sub(depth).min(checkpoint) / SNAPSHOT_INTERVAL * SNAPSHOT_INTERVAL;
+                    blockchain.prune(below);
+                }
+            }
+        })
+        .unwrap();
+}
+
 #[cfg(any(test, test_utilities))]
 mod tests {
     use super::*;
-    use crate::block::test::generate_random_block;
+    use crate::block::test::{generate_random_block, solve};
     use crate::crypto::hash::Hashable;
+    use crate::faucet::Faucet;
+    use crate::mempool::Mempool;
 
     #[test]
     fn insert_one() {
-        let mut blockchain = Blockchain::new(Default::default());
+        let blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
         let block = generate_random_block(&genesis_hash);
-        blockchain.insert(&block);
+        assert!(blockchain.insert(&block, &Default::default()).inserted);
         assert_eq!(blockchain.tip(), block.hash());
-
+        assert!(!blockchain.insert(&block, &Default::default()).inserted);
     }
 
     #[test]
     fn test_longest_chain() {
-        let mut blockchain = Blockchain::new(Default::default());
+        let blockchain = Blockchain::new();
         let hash_0 = blockchain.tip();
         let mut block1 = generate_random_block(&hash_0);
         let mut block2 = generate_random_block(&hash_0);
         let mut chain_correct = Vec::<H256>::new();
         chain_correct.push(hash_0);
         for _ in 0..20 {
-            blockchain.insert(&block1, Default::default());
-            blockchain.insert(&block2, Default::default());
+            blockchain.insert(&block1, &Default::default());
+            blockchain.insert(&block2, &Default::default());
             chain_correct.push(block1.hash());
             block1 = generate_random_block(&block1.hash());
             block2 = generate_random_block(&block2.hash());
@@ -165,5 +983,511 @@ mod tests {
         chain_correct.reverse();
         let chain_to_verify = blockchain.all_blocks_in_longest_chain();
         assert_eq!(chain_to_verify, chain_correct);
-    } 
+    }
+
+    #[test]
+    fn fork_stats() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let insert_chain = |parent: H256, len: usize| -> Vec<H256> {
+            let mut hashes = vec![parent];
+            for _ in 0..len {
+                let block = generate_random_block(hashes.last().unwrap());
+                blockchain.insert(&block, &Default::default());
+                hashes.push(block.hash());
+            }
+            hashes
+        };
+        let a = insert_chain(genesis, 2);
+        insert_chain(genesis, 3);
+        insert_chain(a[1], 1);
+        assert_eq!(blockchain.fork_stats(), ForkStats {
+            forks: 2,
+            stale_blocks: 3,
+            max_fork_depth: 2,
+            reorgs: 1,
+            max_reorg_depth: 2,
+        });
+    }
+
+    #[test]
+    fn state_reconstructed_from_diffs() {
+        let blockchain = Blockchain::new();
+        let genesis_state = blockchain.get_state(&blockchain.tip()).unwrap();
+        let addresses = genesis_state.address_list.clone();
+        let mut parent = blockchain.tip();
+        let mut expected = Vec::new();
+        let mut state = genesis_state;
+        for i in 0..(2 * SNAPSHOT_INTERVAL as usize + 3) {
+            let account = state.account_state.get_mut(&addresses[i % addresses.len()]).unwrap();
+            account.balance += i as u64;
+            account.nonce += 1;
+            let block = generate_random_block(&parent);
+            assert!(blockchain.insert(&block, &state).inserted);
+            parent = block.hash();
+            expected.push((parent, state.clone()));
+        }
+        for (hash, state) in expected.iter() {
+            assert_eq!(blockchain.get_state(hash).unwrap().account_state, state.account_state);
+        }
+        assert_eq!(blockchain.tip_with_state().1.account_state, expected.last().unwrap().1.account_state);
+    }
+
+    #[test]
+    fn fork_states_recomputed_from_blocks() {
+        let config = GenesisConfig { faucet_balance: 1000, ..Default::default() };
+        let (genesis_block, genesis_state) = config.build().unwrap();
+        let blockchain = Blockchain::from_genesis(genesis_block, genesis_state.clone(), &Default::default());
+        let genesis = blockchain.tip();
+        let recipient: H160 = [7u8; 20].into();
+        let transfer = Faucet::default().transfer(&recipient, 100, &genesis_state, &Mempool::default()).unwrap();
+        let main = generate_random_block(&genesis);
+        blockchain.insert(&main, &genesis_state);
+        let mut fork = generate_random_block(&genesis);
+        fork.content.transactions.push(transfer);
+        let fork_state = fork.apply(&genesis_state).unwrap();
+
+        // the fork is not stored, and recomputed when asked for
+        assert!(blockchain.insert(&fork, &fork_state).reorg.is_none());
+        assert!(matches!(blockchain.block_states.read().unwrap()[&fork.hash()], StoredState::Lazy));
+        assert_eq!(blockchain.get_state(&fork.hash()).unwrap().balance(&recipient), 100);
+
+        // until it overtakes the longest chain, which is the one recomputed from then on
+        let fork_tip = generate_random_block(&fork.hash());
+        assert!(blockchain.insert(&fork_tip, &fork_state).reorg.is_some());
+        assert!(matches!(blockchain.block_states.read().unwrap()[&fork.hash()], StoredState::Diff(_)));
+        assert!(matches!(blockchain.block_states.read().unwrap()[&main.hash()], StoredState::Lazy));
+        assert_eq!(blockchain.tip_with_state().1.balance(&recipient), 100);
+        assert_eq!(blockchain.get_state(&main.hash()).unwrap().root(), genesis_state.root());
+    }
+
+    #[test]
+    fn reindex_replays_the_blocks() {
+        let config = GenesisConfig { faucet_balance: 1000, ..Default::default() };
+        let (genesis_block, genesis_state) = config.build().unwrap();
+        let blockchain = Blockchain::from_genesis(genesis_block, genesis_state.clone(), &Default::default());
+        let genesis = blockchain.tip();
+        // a valid block on top of `parent` at `height`, with `state` after it
+        let seal = |parent: &H256, height: u128, parent_state: &State, transactions: Vec<SignedTransaction>| {
+            let mut block = generate_random_block(parent);
+            block.header.timestamp = height;
+            block.header.merkle_root = crate::crypto::merkle::MerkleTree::new(&transactions).root();
+            block.content.transactions = transactions;
+            let state = block.apply(parent_state).unwrap();
+            block.header.state_root = state.root();
+            solve(&mut block.header);
+            (block, state)
+        };
+        let recipient: H160 = [7u8; 20].into();
+        let transfer = Faucet::default().transfer(&recipient, 100, &genesis_state, &Mempool::default()).unwrap();
+        let (a1, a1_state) = seal(&genesis, 1, &genesis_state, vec![transfer.clone()]);
+        let (a2, a2_state) = seal(&a1.hash(), 2, &a1_state, vec![]);
+        let (fork, fork_state) = seal(&genesis, 1, &genesis_state, vec![]);
+        let (mut invalid, _) = seal(&a2.hash(), 3, &a2_state, vec![]);
+        invalid.header.state_root = Default::default();
+        solve(&mut invalid.header);
+        let (orphaned, _) = seal(&invalid.hash(), 4, &a2_state, vec![]);
+        // not after the median time past of its parent
+        let (late, late_state) = seal(&a1.hash(), 1, &a1_state, vec![]);
+        blockchain.insert(&a1, &a1_state);
+        blockchain.insert(&a2, &a2_state);
+        blockchain.insert(&fork, &fork_state);
+        blockchain.insert(&invalid, &a2_state);
+        blockchain.insert(&orphaned, &a2_state);
+        blockchain.insert(&late, &late_state);
+        // corrupt the stored states
+        blockchain.update_state(&a1.hash(), &Default::default());
+        blockchain.update_state(&a2.hash(), &Default::default());
+
+        // the invalid block is dropped along with its child, and the late one
+        assert_eq!(blockchain.reindex().unwrap(), 3);
+        assert_eq!(blockchain.tip(), a2.hash());
+        assert_eq!(blockchain.height(), 2);
+        assert_eq!(blockchain.canonical_range(0, 2).collect::<Vec<H256>>(), vec![genesis, a1.hash(), a2.hash()]);
+        assert_eq!(blockchain.tip_with_state().1.balance(&recipient), 100);
+        assert!(blockchain.get_block(&invalid.hash()).is_none());
+        assert!(blockchain.get_block(&late.hash()).is_none());
+        assert_eq!(blockchain.get_state(&fork.hash()).unwrap().root(), genesis_state.root());
+        assert_eq!(blockchain.get_transaction(&transfer.hash()).unwrap().block_hash, a1.hash());
+        assert_eq!(blockchain.get_history(&recipient), vec![(a1.hash(), transfer.hash())]);
+
+        // without the full state of the genesis there is nothing to replay on
+        blockchain.block_states.write().unwrap().insert(genesis, StoredState::Lazy);
+        assert_eq!(blockchain.reindex().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(blockchain.tip(), a2.hash());
+    }
+
+    #[test]
+    fn transaction_index() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let mut tx: SignedTransaction = Default::default();
+        tx.transaction.outputs = vec![(Default::default(), 7)];
+        let mut block = generate_random_block(&genesis);
+        block.content.transactions.push(Default::default());
+        block.content.transactions.push(tx.clone());
+        blockchain.insert(&block, &Default::default());
+
+        let location = blockchain.get_transaction(&tx.hash()).unwrap();
+        assert_eq!(location.block_hash, block.hash());
+        assert_eq!(location.position, 1);
+        assert_eq!(location.confirmations, 1);
+        assert_eq!(location.transaction.transaction.value(), Some(7));
+        // unsigned, from an unfunded sender: the receipt records the failure
+        let receipt = blockchain.get_receipt(&tx.hash()).unwrap();
+        assert_eq!((receipt.block_hash, receipt.position), (block.hash(), 1));
+        assert_eq!(receipt.status, crate::receipt::ReceiptStatus::Failure);
+        assert_eq!(receipt.cumulative_gas_used, block.gas_used());
+
+        let child = generate_random_block(&block.hash());
+        blockchain.insert(&child, &Default::default());
+        assert_eq!(blockchain.get_transaction(&tx.hash()).unwrap().confirmations, 2);
+
+        // a stale block carrying the transaction again does not shadow the confirmed one
+        let mut stale = generate_random_block(&genesis);
+        stale.content.transactions.push(tx.clone());
+        blockchain.insert(&stale, &Default::default());
+        let location = blockchain.get_transaction(&tx.hash()).unwrap();
+        assert_eq!((location.block_hash, location.confirmations), (block.hash(), 2));
+        assert_eq!(blockchain.get_receipt(&tx.hash()).unwrap().block_hash, block.hash());
+
+        // a longer fork without the transaction leaves it unconfirmed
+        let mut parent = genesis;
+        for _ in 0..3 {
+            let fork = generate_random_block(&parent);
+            blockchain.insert(&fork, &Default::default());
+            parent = fork.hash();
+        }
+        assert_eq!(blockchain.get_transaction(&tx.hash()).unwrap().confirmations, 0);
+        assert!(blockchain.get_transaction(&Default::default()).is_none());
+
+        // until it is included in the fork
+        let mut included = generate_random_block(&parent);
+        included.content.transactions.push(tx.clone());
+        blockchain.insert(&included, &Default::default());
+        let location = blockchain.get_transaction(&tx.hash()).unwrap();
+        assert_eq!((location.block_hash, location.position, location.confirmations), (included.hash(), 0, 1));
+        assert_eq!(blockchain.get_receipt(&tx.hash()).unwrap().block_hash, included.hash());
+    }
+
+    #[test]
+    fn address_history() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let recipient: H160 = [7u8; 20].into();
+        let mut tx: SignedTransaction = Default::default();
+        tx.transaction.outputs = vec![(recipient, 7), (recipient, 3)];
+        let mut block = generate_random_block(&genesis);
+        block.content.transactions.push(tx.clone());
+        blockchain.insert(&block, &Default::default());
+        let mut fork = generate_random_block(&genesis);
+        fork.content.transactions.push(tx.clone());
+        blockchain.insert(&fork, &Default::default());
+
+        // listed once per block, whether the block is on the longest chain or not
+        let expected = vec![(block.hash(), tx.hash()), (fork.hash(), tx.hash())];
+        assert_eq!(blockchain.get_history(&recipient), expected);
+        assert_eq!(blockchain.get_history(&tx.sender()), expected);
+        assert!(blockchain.get_history(&[8u8; 20].into()).is_empty());
+
+        // pruning drops the entries of the discarded fork
+        blockchain.prune(1);
+        assert_eq!(blockchain.get_history(&recipient), vec![(block.hash(), tx.hash())]);
+    }
+
+    #[test]
+    fn dot_export() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let block = generate_random_block(&genesis);
+        blockchain.insert(&block, &Default::default());
+        let fork = generate_random_block(&genesis);
+        blockchain.insert(&fork, &Default::default());
+
+        let dot = blockchain.to_dot(&vec![fork.hash()].into_iter().collect());
+        assert!(dot.starts_with("digraph blocks {"));
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\";", genesis, block.hash())));
+        assert!(dot.contains(&format!("\"{}\" -> \"{}\";", genesis, fork.hash())));
+        assert!(dot.contains(&format!("\"{}\" [label=\"{:.8}\\nheight 1\\nminer peer\", style=filled];", block.hash(), block.hash())));
+        assert!(dot.contains(&format!("\"{}\" [label=\"{:.8}\\nheight 1\\nminer self\"];", fork.hash(), fork.hash())));
+    }
+
+    #[test]
+    fn reorg_evicts_transactions() {
+        let bus = Arc::new(EventBus::default());
+        let (genesis_block, genesis_state) = GenesisConfig::default().build().unwrap();
+        let blockchain = Blockchain::from_genesis(genesis_block, genesis_state, &bus);
+        let genesis = blockchain.tip();
+        let events = bus.subscribe();
+        let mut shared_tx: SignedTransaction = Default::default();
+        shared_tx.transaction.outputs = vec![(Default::default(), 1)];
+        let mut lost_tx: SignedTransaction = Default::default();
+        lost_tx.transaction.outputs = vec![(Default::default(), 2)];
+
+        let mut a1 = generate_random_block(&genesis);
+        a1.content.transactions = vec![shared_tx.clone(), lost_tx.clone()];
+        let a2 = generate_random_block(&a1.hash());
+        assert!(blockchain.insert(&a1, &Default::default()).reorg.is_none());
+        assert!(blockchain.insert(&a2, &Default::default()).reorg.is_none());
+
+        let mut b1 = generate_random_block(&genesis);
+        b1.content.transactions = vec![shared_tx.clone()];
+        let b2 = generate_random_block(&b1.hash());
+        let b3 = generate_random_block(&b2.hash());
+        assert!(blockchain.insert(&b1, &Default::default()).reorg.is_none());
+        assert!(blockchain.insert(&b2, &Default::default()).reorg.is_none());
+        let reorg = blockchain.insert(&b3, &Default::default()).reorg.unwrap();
+
+        assert_eq!(reorg.disconnected, vec![a2.hash(), a1.hash()]);
+        assert_eq!(reorg.connected, vec![b1.hash(), b2.hash(), b3.hash()]);
+        let evicted: Vec<H256> = reorg.evicted_transactions.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(evicted, vec![lost_tx.hash()]);
+        assert_eq!(blockchain.tip(), b3.hash());
+        assert_eq!(blockchain.get_hash_by_height(1), Some(b1.hash()));
+        assert_eq!(blockchain.get_hash_by_height(3), Some(b3.hash()));
+
+        // a1 and a2 were new heads, the b branch only notifies once it takes over
+        let events: Vec<NodeEvent> = events.try_iter().collect();
+        assert_eq!(events.len(), 4);
+        match (&events[2], &events[3]) {
+            (NodeEvent::Reorg(r), NodeEvent::NewHead { hash, height, .. }) => {
+                assert_eq!(r.connected, reorg.connected);
+                assert_eq!((*hash, *height), (b3.hash(), 3));
+            }
+            _ => panic!("expected a reorg then a new head"),
+        }
+    }
+
+    #[test]
+    fn heights() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        assert_eq!(blockchain.height(), 0);
+        assert_eq!(blockchain.get_hash_by_height(0), Some(genesis));
+
+        let mut chain = vec![genesis];
+        for _ in 0..5 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block, &Default::default());
+            chain.push(block.hash());
+        }
+        let fork = generate_random_block(&chain[2]);
+        blockchain.insert(&fork, &Default::default());
+
+        assert_eq!(blockchain.height(), 5);
+        assert_eq!(blockchain.get_block_height(&fork.hash()), Some(3));
+        for (height, hash) in chain.iter().enumerate() {
+            assert_eq!(blockchain.get_hash_by_height(height as u32), Some(*hash));
+            assert_eq!(blockchain.get_block_by_height(height as u32).unwrap().hash(), *hash);
+        }
+        assert_eq!(blockchain.get_hash_by_height(6), None);
+    }
+
+    #[test]
+    fn most_work_wins() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        // a block must inherit the difficulty and the gas limit of its parent
+        let mut hard = generate_random_block(&genesis);
+        hard.header.difficulty = Default::default();
+        assert!(!blockchain.insert(&hard, &Default::default()).inserted);
+        let mut easy = generate_random_block(&genesis);
+        easy.header.difficulty = [0xff; 32].into();
+        assert!(!blockchain.insert(&easy, &Default::default()).inserted);
+        let mut bigger = generate_random_block(&genesis);
+        bigger.header.gas_limit += 1;
+        assert!(!blockchain.insert(&bigger, &Default::default()).inserted);
+        assert_eq!(blockchain.tip(), genesis);
+
+        let first = generate_random_block(&genesis);
+        blockchain.insert(&first, &Default::default());
+        let second = generate_random_block(&genesis);
+        assert!(blockchain.insert(&second, &Default::default()).reorg.is_none());
+        assert_eq!(blockchain.tip(), first.hash());
+        let result = blockchain.insert(&generate_random_block(&second.hash()), &Default::default());
+        assert_eq!(result.reorg.unwrap().disconnected, vec![first.hash()]);
+        assert_eq!(blockchain.height(), 2);
+    }
+
+    #[test]
+    fn ancestors_and_ranges() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let mut chain = vec![genesis];
+        for _ in 0..4 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block, &Default::default());
+            chain.push(block.hash());
+        }
+        let fork = generate_random_block(&chain[1]);
+        blockchain.insert(&fork, &Default::default());
+        let fork_child = generate_random_block(&fork.hash());
+        blockchain.insert(&fork_child, &Default::default());
+
+        assert_eq!(blockchain.find_common_ancestor(&chain[4], &fork_child.hash()), Some(chain[1]));
+        assert_eq!(blockchain.find_common_ancestor(&fork.hash(), &chain[1]), Some(chain[1]));
+        assert_eq!(blockchain.find_common_ancestor(&chain[3], &chain[3]), Some(chain[3]));
+        assert_eq!(blockchain.find_common_ancestor(&chain[3], &H256::default()), None);
+        assert!(blockchain.is_ancestor(&genesis, &fork_child.hash()));
+        assert!(blockchain.is_ancestor(&chain[2], &chain[2]));
+        assert!(!blockchain.is_ancestor(&chain[2], &fork_child.hash()));
+        assert!(!blockchain.is_ancestor(&chain[4], &chain[2]));
+
+        assert_eq!(blockchain.canonical_range(1, 3).collect::<Vec<H256>>(), chain[1..=3].to_vec());
+        assert_eq!(blockchain.canonical_range(3, 10).collect::<Vec<H256>>(), chain[3..].to_vec());
+        assert_eq!(blockchain.canonical_range(5, 10).count(), 0);
+        assert_eq!(blockchain.canonical_range(3, 2).count(), 0);
+    }
+
+    #[test]
+    fn locator_finds_the_fork_point() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let mut chain = vec![genesis];
+        for _ in 0..30 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block, &Default::default());
+            chain.push(block.hash());
+        }
+        let locator = blockchain.locator();
+        let heights: Vec<usize> = locator.iter().map(|hash| chain.iter().position(|h| h == hash).unwrap()).collect();
+        assert_eq!(heights, vec![30, 29, 28, 27, 26, 25, 24, 23, 22, 21, 19, 15, 7, 0]);
+
+        // a peer on a fork off height 20, whose first block we know and whose second we do not
+        let fork = generate_random_block(&chain[20]);
+        blockchain.insert(&fork, &Default::default());
+        let fork_child = generate_random_block(&fork.hash());
+        assert_eq!(blockchain.locate(&[fork_child.hash(), fork.hash(), chain[20], chain[0]]), Some((chain[20], 20)));
+        assert_eq!(blockchain.locate(&[H256::default()]), None);
+
+        // the fork point is found among the pruned blocks too
+        blockchain.prune(16);
+        assert_eq!(blockchain.locate(&[chain[3]]), Some((chain[3], 3)));
+    }
+
+    #[test]
+    fn median_time_past() {
+        let blockchain = Blockchain::new();
+        let mut parent = blockchain.tip();
+        assert_eq!(blockchain.median_time_past(&parent), Some(0));
+        // a clock jumping back and forth
+        let timestamps = [10, 20, 5, 30, 40, 1000, 50, 60, 70, 80, 90, 100, 110];
+        let mut medians = vec![];
+        for timestamp in timestamps.iter() {
+            let mut block = generate_random_block(&parent);
+            block.header.timestamp = *timestamp;
+            blockchain.insert(&block, &Default::default());
+            parent = block.hash();
+            medians.push(blockchain.median_time_past(&parent).unwrap());
+        }
+        assert_eq!(medians, vec![10, 10, 10, 10, 20, 20, 30, 30, 40, 40, 50, 60, 70]);
+        assert_eq!(blockchain.median_time_past(&H256::default()), None);
+    }
+
+    #[test]
+    fn concurrent_readers_and_writer() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let blockchain = Arc::new(Blockchain::new());
+        let writer = {
+            let blockchain = Arc::clone(&blockchain);
+            thread::spawn(move || {
+                let mut parent = blockchain.tip();
+                for _ in 0..50 {
+                    let block = generate_random_block(&parent);
+                    blockchain.insert(&block, &Default::default());
+                    parent = block.hash();
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_| {
+            let blockchain = Arc::clone(&blockchain);
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    let (tip, _) = blockchain.tip_with_state();
+                    assert!(blockchain.contains_key(&tip));
+                }
+            })
+        }).collect();
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(blockchain.all_blocks_in_longest_chain().len(), 51);
+    }
+
+    #[test]
+    fn starts_from_snapshot() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let mut chain = vec![genesis];
+        for _ in 0..5 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block, &Default::default());
+            chain.push(block.hash());
+        }
+        let snapshot = blockchain.snapshot(2).unwrap();
+        assert_eq!(snapshot.height(), 4);
+        assert_eq!(snapshot.block.hash(), chain[4]);
+
+        let synced = Blockchain::new();
+        // a reorg on the chain the snapshot replaces is forgotten with it
+        let first = generate_random_block(&genesis);
+        let second = generate_random_block(&genesis);
+        synced.insert(&first, &Default::default());
+        synced.insert(&second, &Default::default());
+        assert!(synced.insert(&generate_random_block(&second.hash()), &Default::default()).reorg.is_some());
+        assert!(synced.import_snapshot(&snapshot));
+        assert!(!synced.import_snapshot(&snapshot));
+        assert_eq!(synced.tip(), chain[4]);
+        assert_eq!(synced.height(), 4);
+        assert_eq!(synced.get_hash_by_height(1), Some(chain[1]));
+        assert!(!synced.contains_key(&genesis));
+        assert!(synced.get_block_by_height(1).is_none());
+        assert_eq!(synced.fork_stats(), ForkStats::default());
+
+        // the chain grows on the checkpoint, and the next checkpoints span the imported headers
+        let mut parent = chain[4];
+        for _ in 0..2 {
+            let block = generate_random_block(&parent);
+            assert!(synced.insert(&block, &Default::default()).inserted);
+            parent = block.hash();
+        }
+        let next = synced.snapshot(2).unwrap();
+        assert_eq!(next.height(), 6);
+        assert_eq!(next.headers[1].hash(), chain[1]);
+    }
+
+    #[test]
+    fn prunes_old_blocks() {
+        let blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let mut chain = vec![genesis];
+        for _ in 0..6 {
+            let block = generate_random_block(chain.last().unwrap());
+            blockchain.insert(&block, &Default::default());
+            chain.push(block.hash());
+        }
+        let fork = generate_random_block(&chain[1]);
+        blockchain.insert(&fork, &Default::default());
+
+        assert_eq!(blockchain.prune(3), 4);
+        assert_eq!(blockchain.prune(3), 0);
+        assert!(!blockchain.contains_key(&chain[2]));
+        assert!(!blockchain.contains_key(&fork.hash()));
+        assert!(blockchain.get_block_by_height(2).is_none());
+        assert_eq!(blockchain.get_header(&chain[2]).unwrap().hash(), chain[2]);
+        assert!(blockchain.get_header(&fork.hash()).is_none());
+        assert_eq!(blockchain.get_hash_by_height(1), Some(chain[1]));
+        assert!(blockchain.get_state(&chain[3]).is_some());
+        assert_eq!(blockchain.fork_stats(), ForkStats::default());
+
+        // the chain still grows, and its checkpoints span the pruned headers
+        let block = generate_random_block(&chain[6]);
+        assert!(blockchain.insert(&block, &Default::default()).inserted);
+        let snapshot = blockchain.snapshot(2).unwrap();
+        assert_eq!(snapshot.height(), 6);
+        assert_eq!(snapshot.headers[2].hash(), chain[2]);
+    }
 }
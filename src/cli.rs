@@ -0,0 +1,155 @@
+use crate::crypto::address::H160;
+use crate::transaction::Transaction;
+use crate::wallet::Wallet;
+use clap::ArgMatches;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+/// Send a `method` request to the API server of a running node and return the response body.
+fn request(node: &str, method: &str, path: &str) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(node)?;
+    write!(stream, "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Length: 0\r\n\r\n", method, path, node)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.find("\r\n\r\n") {
+        Some(i) => Ok(response[i + 4..].to_string()),
+        None => Ok(response),
+    }
+}
+
+/// Map a `cli` subcommand to the API endpoint it calls.
+fn endpoint(matches: &ArgMatches) -> Option<String> {
+    match matches.subcommand() {
+        ("miner", Some(m)) => match m.subcommand() {
+            ("start", Some(s)) => Some(format!(
+                "/miner/start?lambda={}&generator=false",
+                s.value_of("lambda").unwrap()
+            )),
+            ("stop", Some(_)) => Some("/miner/stop".to_string()),
+            ("rate", Some(s)) => Some(format!("/miner/rate?lambda={}", s.value_of("lambda").unwrap())),
+            ("pause", Some(_)) => Some("/miner/pause".to_string()),
+            ("resume", Some(_)) => Some("/miner/resume".to_string()),
+            _ => None,
+        },
+        ("generator", Some(m)) => match m.subcommand() {
+            ("start", Some(s)) => Some(format!(
+                "/generator/start?lambda={}",
+                s.value_of("lambda").unwrap()
+            )),
+            ("stop", Some(_)) => Some("/generator/stop".to_string()),
+            ("rate", Some(s)) => Some(format!("/generator/rate?lambda={}", s.value_of("lambda").unwrap())),
+            ("pause", Some(_)) => Some("/generator/pause".to_string()),
+            ("resume", Some(_)) => Some("/generator/resume".to_string()),
+            ("targets", Some(s)) => Some(format!(
+                "/generator/targets?addresses={}",
+                s.values_of("addresses").map(|v| v.collect::<Vec<_>>().join(",")).unwrap_or_default()
+            )),
+            ("burst", Some(s)) => Some(format!("/generator/burst?count={}", s.value_of("count").unwrap())),
+            _ => None,
+        },
+        ("submit", Some(s)) => Some(format!("/transaction/submit?tx={}", s.value_of("tx").unwrap())),
+        ("unsigned", Some(s)) => Some(format!(
+            "/transaction/unsigned?from={}&to={}&value={}&fee={}",
+            s.value_of("from").unwrap(),
+            s.value_of("to").unwrap(),
+            s.value_of("value").unwrap(),
+            s.value_of("fee").unwrap_or("0")
+        )),
+        ("assemble", Some(s)) => Some(format!(
+            "/transaction/assemble?tx={}&signature={}&public_key={}&scheme={}",
+            s.value_of("tx").unwrap(),
+            s.value_of("signature").unwrap(),
+            s.value_of("public_key").unwrap(),
+            s.value_of("scheme").unwrap()
+        )),
+        ("balance", Some(s)) => Some(format!(
+            "/blockchain/balance?address={}",
+            s.value_of("address").unwrap()
+        )),
+        ("transaction", Some(s)) => Some(format!(
+            "/blockchain/transaction?txid={}",
+            s.value_of("txid").unwrap()
+        )),
+        ("block", Some(s)) => Some(format!(
+            "/blockchain/block?height={}&full={}",
+            s.value_of("height").unwrap(),
+            s.is_present("full")
+        )),
+        ("state", Some(s)) => Some(format!("/blockchain/state?height={}", s.value_of("height").unwrap())),
+        ("tip", Some(_)) => Some("/blockchain/tip".to_string()),
+        ("forks", Some(_)) => Some("/blockchain/forks".to_string()),
+        ("faucet", Some(s)) => Some(format!(
+            "/faucet/request?address={}&amount={}",
+            s.value_of("address").unwrap(),
+            s.value_of("amount").unwrap()
+        )),
+        ("dot", Some(_)) => Some("/blockchain/dot".to_string()),
+        ("export", Some(s)) => Some(format!(
+            "/blockchain/export?file={}&from={}&to={}&json={}",
+            s.value_of("file").unwrap(),
+            s.value_of("from").unwrap(),
+            s.value_of("to").unwrap(),
+            s.is_present("json")
+        )),
+        ("fee", Some(s)) => Some(format!("/fee/estimate?target={}", s.value_of("target").unwrap())),
+        ("peers", Some(_)) => Some("/network/peers".to_string()),
+        _ => None,
+    }
+}
+
+/// Sign the unsigned transaction of the `sign` subcommand with a key of the keystore, and print
+/// the detached signature and public key, to be passed to `assemble`.
+fn sign(matches: &ArgMatches) -> Result<(String, String), String> {
+    let passphrase = match matches.value_of("passphrase") {
+        Some(p) => p.to_string(),
+        None => std::env::var("PRISM_PASSPHRASE").unwrap_or_default(),
+    };
+    let from: H160 = matches.value_of("from").unwrap().parse().map_err(|e| format!("error parsing address: {}", e))?;
+    let raw = hex::decode(matches.value_of("tx").unwrap()).map_err(|e| format!("error decoding tx: {}", e))?;
+    let transaction = Transaction::from_bytes(&raw).ok_or("error parsing tx")?;
+    let keystore = matches.value_of("keystore").unwrap();
+    let wallet = Wallet::open(std::path::Path::new(keystore), &passphrase)
+        .map_err(|e| format!("error loading keystore {}: {}", keystore, e))?;
+    let tx = wallet.sign(&from, transaction).ok_or_else(|| format!("no key for {} in keystore {}", from, keystore))?;
+    Ok((hex::encode(tx.signature), hex::encode(tx.public_key)))
+}
+
+/// Run the `cli` subcommand against the node given by `--node`. Returns the process exit code.
+pub fn run(matches: &ArgMatches) -> i32 {
+    // signing is offline, so that the keys can stay on a machine without network
+    if let ("sign", Some(s)) = matches.subcommand() {
+        return match sign(s) {
+            Ok((signature, public_key)) => {
+                println!("signature: {}\npublic_key: {}", signature, public_key);
+                0
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        };
+    }
+    let node = matches.value_of("node").unwrap();
+    let path = match endpoint(matches) {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", matches.usage());
+            return 1;
+        }
+    };
+    // the export writes a file on the node
+    let method = match matches.subcommand() {
+        ("export", _) => "POST",
+        _ => "GET",
+    };
+    match request(node, method, &path) {
+        Ok(body) => {
+            println!("{}", body);
+            0
+        }
+        Err(e) => {
+            eprintln!("Error contacting node at {}: {}", node, e);
+            1
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! The time and the randomness seen by the node.
+//!
+//! By default these are the system clock and the thread-local RNG. With the `simulation`
+//! feature, time comes from a virtual clock that only moves when a thread sleeps on it, and
+//! randomness from an RNG seeded by the `PRISM_SEED` environment variable, so that the
+//! timestamps, delays and random choices of a multi-node experiment can be replayed.
+
+use std::time::Duration;
+
+#[cfg(not(feature = "simulation"))]
+mod imp {
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    pub type NodeRng = rand::rngs::ThreadRng;
+
+    pub fn now() -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+
+    pub fn sleep(duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    pub fn rng() -> NodeRng {
+        rand::thread_rng()
+    }
+}
+
+#[cfg(feature = "simulation")]
+mod imp {
+    use rand::SeedableRng;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    pub type NodeRng = rand::rngs::StdRng;
+
+    /// Where the virtual clock starts, in microseconds since the UNIX epoch.
+    static START_MICROS: u64 = 1_600_000_000_000_000;
+
+    static ELAPSED_MICROS: AtomicU64 = AtomicU64::new(0);
+    // number of RNGs handed out, each one gets its own seed
+    static DRAWS: AtomicU64 = AtomicU64::new(0);
+
+    pub fn now() -> Duration {
+        Duration::from_micros(START_MICROS + ELAPSED_MICROS.load(Ordering::SeqCst))
+    }
+
+    /// Advance the virtual clock instead of waiting.
+    pub fn sleep(duration: Duration) {
+        ELAPSED_MICROS.fetch_add(duration.as_micros() as u64, Ordering::SeqCst);
+        thread::yield_now();
+    }
+
+    pub fn rng() -> NodeRng {
+        let seed = std::env::var("PRISM_SEED").ok().and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+        let draw = DRAWS.fetch_add(1, Ordering::SeqCst);
+        NodeRng::seed_from_u64(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15).wrapping_add(draw))
+    }
+}
+
+pub use imp::NodeRng;
+
+/// Time since the UNIX epoch.
+pub fn now() -> Duration {
+    imp::now()
+}
+
+/// Microseconds since the UNIX epoch, the unit of block timestamps.
+pub fn now_micros() -> u128 {
+    imp::now().as_micros()
+}
+
+pub fn sleep(duration: Duration) {
+    imp::sleep(duration)
+}
+
+pub fn rng() -> NodeRng {
+    imp::rng()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn clock_moves_forward() {
+        let before = now_micros();
+        sleep(Duration::from_millis(1));
+        assert!(now_micros() >= before + 1000);
+        let draw: u32 = rng().gen_range(0, 10);
+        assert!(draw < 10);
+    }
+}
@@ -1,12 +1,68 @@
-use serde::{Serialize, Deserialize};
-use std::convert::TryInto;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha3::{Digest, Keccak256};
 
-/// An H160 Address.
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+/// An H160 Address, serialized as hex in the human readable formats.
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct H160([u8; 20]); // big endian u256
 
+impl Serialize for H160 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_newtype_struct("H160", &self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for H160 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+        } else {
+            <[u8; 20]>::deserialize(deserializer).map(H160)
+        }
+    }
+}
+
+impl H160 {
+    /// The EIP-55 encoding of the address: `0x` and the hex digits, each letter in uppercase
+    /// when the matching nibble of the Keccak-256 hash of the lowercase hex is 8 or more, so that
+    /// a mistyped address most likely fails to parse. Also printed by `{:#}`.
+    pub fn to_checksummed(&self) -> String {
+        let lowercase = hex::encode(self.0);
+        let hash = Keccak256::digest(lowercase.as_bytes());
+        let digits: String = lowercase.chars().enumerate().map(|(i, c)| {
+            let nibble = hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 }) & 0xf;
+            if nibble >= 8 { c.to_ascii_uppercase() } else { c }
+        }).collect();
+        format!("0x{}", digits)
+    }
+}
+
+/// Why a string is not an address.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseAddressError {
+    Hex(hex::FromHexError),
+    /// The letters are in mixed case, but not the case of the EIP-55 checksum.
+    Checksum,
+}
+
+impl std::fmt::Display for ParseAddressError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ParseAddressError::Hex(e) => write!(f, "{}", e),
+            ParseAddressError::Checksum => write!(f, "wrong address checksum"),
+        }
+    }
+}
+
 impl std::fmt::Display for H160 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if f.alternate() {
+            return write!(f, "{}", self.to_checksummed());
+        }
         let start = if let Some(precision) = f.precision() {
             if precision >= 40 {
                 0
@@ -78,15 +134,8 @@ impl std::convert::From<ring::digest::Digest> for H160 {
 
 impl Ord for H160 {
     fn cmp(&self, other: &H160) -> std::cmp::Ordering {
-        let self_higher = u128::from_be_bytes(self.0[0..10].try_into().unwrap());
-        let self_lower = u128::from_be_bytes(self.0[10..20].try_into().unwrap());
-        let other_higher = u128::from_be_bytes(other.0[0..10].try_into().unwrap());
-        let other_lower = u128::from_be_bytes(other.0[10..20].try_into().unwrap());
-        let higher = self_higher.cmp(&other_higher);
-        match higher {
-            std::cmp::Ordering::Equal => self_lower.cmp(&other_lower),
-            _ => higher,
-        }
+        // big endian, so the byte order is the numeric order
+        self.0.cmp(&other.0)
     }
 }
 
@@ -94,4 +143,45 @@ impl PartialOrd for H160 {
     fn partial_cmp(&self, other: &H160) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
     }
-}
\ No newline at end of file
+}
+impl std::str::FromStr for H160 {
+    type Err = ParseAddressError;
+
+    /// Parse a 40 character hex string, as printed by `Display`, optionally prefixed by `0x`.
+    /// In mixed case, the string must carry the EIP-55 checksum, see `to_checksummed`.
+    fn from_str(s: &str) -> Result<H160, Self::Err> {
+        let digits = s.strip_prefix("0x").unwrap_or(s);
+        let mut buffer: [u8; 20] = [0; 20];
+        hex::decode_to_slice(digits, &mut buffer).map_err(ParseAddressError::Hex)?;
+        let address = H160(buffer);
+        let mixed_case = digits.chars().any(|c| c.is_ascii_uppercase()) && digits.chars().any(|c| c.is_ascii_lowercase());
+        if mixed_case && address.to_checksummed()[2..] != *digits {
+            return Err(ParseAddressError::Checksum);
+        }
+        Ok(address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eip55_checksum() {
+        for checksummed in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ].iter() {
+            let address: H160 = checksummed.parse().unwrap();
+            assert_eq!(address.to_checksummed(), *checksummed);
+            assert_eq!(format!("{:#}", address), *checksummed);
+            // all lowercase or all uppercase carries no checksum
+            assert_eq!(checksummed[2..].to_lowercase().parse::<H160>(), Ok(address));
+            assert_eq!(format!("0x{}", checksummed[2..].to_uppercase()).parse::<H160>(), Ok(address));
+        }
+        assert_eq!("0x5aaeb6053F3E94C9b9A09f33669435E7Ef1BeAed".parse::<H160>(), Err(ParseAddressError::Checksum));
+        assert!(matches!("0x5aae".parse::<H160>(), Err(ParseAddressError::Hex(_))));
+    }
+}
@@ -0,0 +1,258 @@
+//! BLS signatures over BLS12-381, in the minimal public key size variant of the IETF draft: 48-byte
+//! public keys in G1 and 96-byte signatures in G2. Signatures aggregate into a single signature of
+//! the same size, so that the votes of many voters on a block, or many transactions of one sender,
+//! cost one signature in the block and one multi-pairing to verify.
+//!
+//! Signing the same message by several keys is only safe to aggregate once every key has proven
+//! possession of its secret, see `prove_possession`, otherwise a rogue key could cancel the others.
+
+use bls12_381::hash_to_curve::{ExpandMsgXmd, HashToCurve};
+use bls12_381::{multi_miller_loop, G1Affine, G1Projective, G2Affine, G2Prepared, G2Projective, Gt, Scalar};
+use ring::hkdf;
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// Length of a public key, in bytes.
+pub static PUBLIC_KEY_LEN: usize = 48;
+/// Length of a signature or an aggregate signature, in bytes.
+pub static SIGNATURE_LEN: usize = 96;
+
+const SIGNATURE_DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+const POSSESSION_DST: &[u8] = b"BLS_POP_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+
+/// A secret key, a non-zero scalar.
+#[derive(Clone)]
+pub struct SecretKey(Scalar);
+
+// the length of the HKDF output
+struct OkmLen(usize);
+
+impl hkdf::KeyType for OkmLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+// a scalar from big endian bytes, reduced modulo the group order
+fn scalar_from_be(bytes: &[u8]) -> Scalar {
+    let mut wide = [0u8; 64];
+    for (i, byte) in bytes.iter().rev().enumerate() {
+        wide[i] = *byte;
+    }
+    Scalar::from_bytes_wide(&wide)
+}
+
+impl SecretKey {
+    /// The key derived from `ikm`, at least 32 bytes of secret randomness, by the `KeyGen` of the
+    /// draft.
+    pub fn from_seed(ikm: &[u8]) -> Self {
+        let mut salt = b"BLS-SIG-KEYGEN-SALT-".to_vec();
+        let mut input = ikm.to_vec();
+        input.push(0);
+        loop {
+            let salt_hash = ring::digest::digest(&ring::digest::SHA256, &salt);
+            salt = salt_hash.as_ref().to_vec();
+            let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, &salt).extract(&input);
+            let info = [0u8, 48];
+            let mut okm = [0u8; 48];
+            prk.expand(&[&info], OkmLen(48)).unwrap().fill(&mut okm).unwrap();
+            let scalar = scalar_from_be(&okm);
+            if scalar != Scalar::zero() {
+                return SecretKey(scalar);
+            }
+        }
+    }
+
+    pub fn random() -> Self {
+        let mut ikm = [0u8; 32];
+        SystemRandom::new().fill(&mut ikm).unwrap();
+        SecretKey::from_seed(&ikm)
+    }
+
+    pub fn public_key(&self) -> [u8; 48] {
+        G1Affine::from(G1Affine::generator() * self.0).to_compressed()
+    }
+
+    pub fn sign(&self, msg: &[u8]) -> [u8; 96] {
+        G2Affine::from(hash_to_g2(msg, SIGNATURE_DST) * self.0).to_compressed()
+    }
+
+    /// The proof that the holder of the public key knows its secret key, to publish along with
+    /// the public key before its signatures of a common message are aggregated.
+    pub fn prove_possession(&self) -> [u8; 96] {
+        G2Affine::from(hash_to_g2(&self.public_key(), POSSESSION_DST) * self.0).to_compressed()
+    }
+}
+
+fn hash_to_g2(msg: &[u8], dst: &[u8]) -> G2Projective {
+    <G2Projective as HashToCurve<ExpandMsgXmd<sha2::Sha256>>>::hash_to_curve(msg, dst)
+}
+
+fn decode_public_key(bytes: &[u8]) -> Option<G1Affine> {
+    let mut compressed = [0u8; 48];
+    if bytes.len() != PUBLIC_KEY_LEN {
+        return None;
+    }
+    compressed.copy_from_slice(bytes);
+    let key: G1Affine = Option::from(G1Affine::from_compressed(&compressed))?;
+    // the identity would verify any aggregate it is part of
+    if bool::from(key.is_identity()) {
+        return None;
+    }
+    Some(key)
+}
+
+fn decode_signature(bytes: &[u8]) -> Option<G2Affine> {
+    let mut compressed = [0u8; 96];
+    if bytes.len() != SIGNATURE_LEN {
+        return None;
+    }
+    compressed.copy_from_slice(bytes);
+    Option::from(G2Affine::from_compressed(&compressed))
+}
+
+// whether e(g1, signature) is the product of the e(key, point) of the terms
+fn pairings_match(terms: &[(G1Affine, G2Affine)], signature: &G2Affine) -> bool {
+    let mut prepared: Vec<(G1Affine, G2Prepared)> = terms.iter()
+        .map(|(key, point)| (*key, G2Prepared::from(*point)))
+        .collect();
+    prepared.push((-G1Affine::generator(), G2Prepared::from(*signature)));
+    let refs: Vec<(&G1Affine, &G2Prepared)> = prepared.iter().map(|(key, point)| (key, point)).collect();
+    multi_miller_loop(&refs).final_exponentiation() == Gt::identity()
+}
+
+/// Whether `signature` is the one of `msg` by `public_key`.
+pub fn verify(public_key: &[u8], msg: &[u8], signature: &[u8]) -> bool {
+    aggregate_verify(&[(public_key, msg)], signature)
+}
+
+/// Whether `proof` is a proof of possession of the secret key of `public_key`.
+pub fn verify_possession(public_key: &[u8], proof: &[u8]) -> bool {
+    match (decode_public_key(public_key), decode_signature(proof)) {
+        (Some(key), Some(proof)) => pairings_match(&[(key, hash_to_g2(public_key, POSSESSION_DST).into())], &proof),
+        _ => false,
+    }
+}
+
+/// The aggregate of `signatures`, `None` if there are none or one is malformed.
+pub fn aggregate(signatures: &[&[u8]]) -> Option<[u8; 96]> {
+    if signatures.is_empty() {
+        return None;
+    }
+    let mut sum = G2Projective::identity();
+    for signature in signatures {
+        sum += decode_signature(signature)?;
+    }
+    Some(G2Affine::from(sum).to_compressed())
+}
+
+/// Whether `signature` aggregates the signatures of every message by its public key. A public key
+/// may sign several messages, but several keys signing the same message need
+/// `fast_aggregate_verify`.
+pub fn aggregate_verify(signed: &[(&[u8], &[u8])], signature: &[u8]) -> bool {
+    if signed.is_empty() {
+        return false;
+    }
+    let terms = signed.iter()
+        .map(|(public_key, msg)| Some((decode_public_key(public_key)?, hash_to_g2(msg, SIGNATURE_DST).into())))
+        .collect::<Option<Vec<(G1Affine, G2Affine)>>>();
+    match (terms, decode_signature(signature)) {
+        (Some(terms), Some(signature)) => pairings_match(&terms, &signature),
+        _ => false,
+    }
+}
+
+/// Whether `signature` aggregates the signatures of `msg` by all of `public_keys`, such as the
+/// votes on a block, in two pairings. The possession of every key must have been verified.
+pub fn fast_aggregate_verify(public_keys: &[&[u8]], msg: &[u8], signature: &[u8]) -> bool {
+    if public_keys.is_empty() {
+        return false;
+    }
+    let mut sum = G1Projective::identity();
+    for public_key in public_keys {
+        match decode_public_key(public_key) {
+            Some(key) => sum += key,
+            None => return false,
+        }
+    }
+    match decode_signature(signature) {
+        Some(signature) => pairings_match(&[(sum.into(), hash_to_g2(msg, SIGNATURE_DST).into())], &signature),
+        None => false,
+    }
+}
+
+/// Whether every `(public key, message, signature)` is valid, checked at once with random
+/// coefficients so that invalid signatures cannot cancel out. Cheaper than a `verify` per
+/// signature, but says nothing of which one is invalid.
+pub fn batch_verify(items: &[(&[u8], &[u8], &[u8])]) -> bool {
+    if items.is_empty() {
+        return false;
+    }
+    let rng = SystemRandom::new();
+    let mut terms = Vec::with_capacity(items.len());
+    let mut sum = G2Projective::identity();
+    for (public_key, msg, signature) in items {
+        let (key, signature) = match (decode_public_key(public_key), decode_signature(signature)) {
+            (Some(key), Some(signature)) => (key, signature),
+            _ => return false,
+        };
+        let mut bytes = [0u8; 64];
+        rng.fill(&mut bytes[..16]).unwrap();
+        let coefficient = Scalar::from_bytes_wide(&bytes);
+        terms.push((G1Affine::from(key * coefficient), hash_to_g2(msg, SIGNATURE_DST).into()));
+        sum += signature * coefficient;
+    }
+    pairings_match(&terms, &sum.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_verify() {
+        let key = SecretKey::from_seed(&[1; 32]);
+        assert_eq!(key.public_key(), SecretKey::from_seed(&[1; 32]).public_key());
+        let signature = key.sign(b"block");
+        assert!(verify(&key.public_key(), b"block", &signature));
+        assert!(!verify(&key.public_key(), b"other block", &signature));
+        assert!(!verify(&SecretKey::random().public_key(), b"block", &signature));
+        assert!(!verify(&key.public_key(), b"block", &signature[1..]));
+        assert!(verify_possession(&key.public_key(), &key.prove_possession()));
+        assert!(!verify_possession(&key.public_key(), &signature));
+    }
+
+    #[test]
+    fn votes_on_one_block() {
+        let voters: Vec<SecretKey> = (0..4u8).map(|i| SecretKey::from_seed(&[i; 32])).collect();
+        let public_keys: Vec<[u8; 48]> = voters.iter().map(SecretKey::public_key).collect();
+        let keys: Vec<&[u8]> = public_keys.iter().map(|key| &key[..]).collect();
+        let votes: Vec<[u8; 96]> = voters.iter().map(|voter| voter.sign(b"block")).collect();
+        let signature = aggregate(&votes.iter().map(|vote| &vote[..]).collect::<Vec<_>>()).unwrap();
+        assert!(fast_aggregate_verify(&keys, b"block", &signature));
+        assert!(!fast_aggregate_verify(&keys[1..], b"block", &signature));
+        assert!(!fast_aggregate_verify(&keys, b"other block", &signature));
+        assert!(!fast_aggregate_verify(&[], b"block", &signature));
+        assert!(aggregate(&[]).is_none());
+    }
+
+    #[test]
+    fn transactions_of_one_sender() {
+        let sender = SecretKey::random();
+        let public_key = sender.public_key();
+        let txs: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 10]).collect();
+        let signatures: Vec<[u8; 96]> = txs.iter().map(|tx| sender.sign(tx)).collect();
+        let signature = aggregate(&signatures.iter().map(|s| &s[..]).collect::<Vec<_>>()).unwrap();
+        let signed: Vec<(&[u8], &[u8])> = txs.iter().map(|tx| (&public_key[..], &tx[..])).collect();
+        assert!(aggregate_verify(&signed, &signature));
+        assert!(!aggregate_verify(&signed[..2], &signature));
+
+        let items: Vec<(&[u8], &[u8], &[u8])> = txs.iter().zip(signatures.iter())
+            .map(|(tx, signature)| (&public_key[..], &tx[..], &signature[..]))
+            .collect();
+        assert!(batch_verify(&items));
+        let mut swapped = items.clone();
+        swapped[0].2 = items[1].2;
+        swapped[1].2 = items[0].2;
+        assert!(!batch_verify(&swapped));
+    }
+}
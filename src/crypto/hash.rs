@@ -1,19 +1,274 @@
-use serde::{Serialize, Deserialize};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::convert::TryInto;
+use std::sync::atomic::{AtomicU8, Ordering};
 
 /// An object that can be meaningfully hashed.
 pub trait Hashable {
-    /// Hash the object using SHA256.
+    /// Hash the object using the hash function of the chain, see `digest`.
     fn hash(&self) -> H256;
 }
 
-/// A SHA256 hash.
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+/// The hash function of the blocks, transactions, merkle trees and state tries of a chain,
+/// chosen by its genesis. Blake3 is only available with the `blake3` feature.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HashFunction {
+    #[default]
+    Sha256,
+    /// For compatibility with Ethereum.
+    Keccak256,
+    /// For speed.
+    Blake3,
+}
+
+impl HashFunction {
+    /// Whether the node is built with support for the hash function.
+    pub fn is_available(self) -> bool {
+        self != HashFunction::Blake3 || cfg!(feature = "blake3")
+    }
+
+    pub fn digest(self, bytes: &[u8]) -> H256 {
+        match self {
+            HashFunction::Sha256 => ring::digest::digest(&ring::digest::SHA256, bytes).into(),
+            HashFunction::Keccak256 => {
+                use sha3::Digest;
+                let hash: [u8; 32] = sha3::Keccak256::digest(bytes).into();
+                hash.into()
+            }
+            #[cfg(feature = "blake3")]
+            HashFunction::Blake3 => (*blake3::hash(bytes).as_bytes()).into(),
+            #[cfg(not(feature = "blake3"))]
+            HashFunction::Blake3 => panic!("built without the blake3 feature"),
+        }
+    }
+}
+
+// the `HashFunction` of the chain, as a `u8`
+static HASH_FUNCTION: AtomicU8 = AtomicU8::new(HashFunction::Sha256 as u8);
+
+/// Select the hash function of the chain, once at start before anything is hashed: a node only
+/// runs one chain.
+pub fn set_hash_function(function: HashFunction) {
+    assert!(function.is_available(), "hash function {:?} is not available", function);
+    HASH_FUNCTION.store(function as u8, Ordering::Relaxed);
+}
+
+pub fn hash_function() -> HashFunction {
+    match HASH_FUNCTION.load(Ordering::Relaxed) {
+        0 => HashFunction::Sha256,
+        1 => HashFunction::Keccak256,
+        _ => HashFunction::Blake3,
+    }
+}
+
+/// Hash `bytes` with the hash function of the chain.
+pub fn digest(bytes: &[u8]) -> H256 {
+    hash_function().digest(bytes)
+}
+
+/// A 256-bit hash, serialized as hex in the human readable formats.
+#[derive(Eq, PartialEq, Clone, Hash, Default, Copy)]
 pub struct H256([u8; 32]); // big endian u256
 
+impl Serialize for H256 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            serializer.serialize_newtype_struct("H256", &self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for H256 {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+        } else {
+            <[u8; 32]>::deserialize(deserializer).map(H256)
+        }
+    }
+}
+
+/// Arithmetic of proof of work targets: a header meets its target when its hash, read as a big
+/// endian integer, is at most the target.
+impl H256 {
+    pub const ZERO: H256 = H256([0; 32]);
+    /// The easiest target, met by any hash.
+    pub const MAX: H256 = H256([0xff; 32]);
+
+    /// Whether this hash meets `target`.
+    pub fn meets_target(&self, target: &H256) -> bool {
+        self <= target
+    }
+
+    /// The target of the compact encoding `bits` of Bitcoin headers: a 3-byte mantissa and a
+    /// 1-byte exponent counting the bytes of the target. `None` if the sign bit is set or the
+    /// target exceeds 256 bits.
+    pub fn from_compact(bits: u32) -> Option<H256> {
+        let size = (bits >> 24) as i32;
+        let mantissa = bits & 0x007f_ffff;
+        if mantissa != 0 && bits & 0x0080_0000 != 0 {
+            return None;
+        }
+        let mut target = [0u8; 32];
+        for (i, byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+            // the significance of the byte, 0 for the least significant one
+            let significance = size - 1 - i as i32;
+            if significance < 0 || *byte == 0 {
+                continue;
+            }
+            if significance >= 32 {
+                return None;
+            }
+            target[31 - significance as usize] = *byte;
+        }
+        Some(H256(target))
+    }
+
+    /// The compact encoding of the target, rounded down to its 3 most significant bytes.
+    pub fn to_compact(&self) -> u32 {
+        let mut size = self.0.iter().position(|byte| *byte != 0).map_or(0, |i| 32 - i);
+        let mut mantissa = (0..3).fold(0u32, |mantissa, i| {
+            let byte = if size > i { self.0[32 - size + i] } else { 0 };
+            mantissa << 8 | byte as u32
+        });
+        // the mantissa is signed, move a set top bit into another byte
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        (size as u32) << 24 | mantissa
+    }
+
+    /// The target multiplied by `numerator / denominator`, such as the actual over the expected
+    /// time of a retarget period, rounded down and capped at `MAX`.
+    pub fn scale(&self, numerator: u64, denominator: u64) -> H256 {
+        assert!(denominator > 0, "scaling a target by a zero denominator");
+        let limbs: Vec<u64> = self.0.chunks(8).map(|chunk| u64::from_be_bytes(chunk.try_into().unwrap())).collect();
+        // the product on 5 limbs, most significant first
+        let mut product = [0u64; 5];
+        let mut carry = 0u128;
+        for i in (0..4).rev() {
+            let value = limbs[i] as u128 * numerator as u128 + carry;
+            product[i + 1] = value as u64;
+            carry = value >> 64;
+        }
+        product[0] = carry as u64;
+        let mut quotient = [0u64; 5];
+        let mut remainder = 0u128;
+        for i in 0..5 {
+            let value = remainder << 64 | product[i] as u128;
+            quotient[i] = (value / denominator as u128) as u64;
+            remainder = value % denominator as u128;
+        }
+        if quotient[0] != 0 {
+            return H256::MAX;
+        }
+        let mut target = [0u8; 32];
+        for i in 0..4 {
+            target[i * 8..i * 8 + 8].copy_from_slice(&quotient[i + 1].to_be_bytes());
+        }
+        H256(target)
+    }
+
+    /// Expected number of hashes to meet the target, `2^256 / (target + 1)` over the whole
+    /// target, saturated at `Work::MAX` for the zero target.
+    pub fn work(&self) -> Work {
+        let target = Work(self.limbs());
+        if target == Work::MAX {
+            return Work::from(1);
+        }
+        // 2^256 / (target + 1) = !target / (target + 1) + 1, without overflowing 256 bits
+        let divisor = target.saturating_add(Work::from(1));
+        Work(div(&(!target).0, &divisor.0)).saturating_add(Work::from(1))
+    }
+
+    // the 4 limbs of the target, most significant first
+    fn limbs(&self) -> [u64; 4] {
+        let mut limbs = [0u64; 4];
+        for (limb, chunk) in limbs.iter_mut().zip(self.0.chunks(8)) {
+            *limb = u64::from_be_bytes(chunk.try_into().unwrap());
+        }
+        limbs
+    }
+}
+
+// the quotient of 256-bit integers on 4 limbs, most significant first, by long division
+fn div(numerator: &[u64; 4], divisor: &[u64; 4]) -> [u64; 4] {
+    let mut quotient = [0u64; 4];
+    let mut remainder = Work::ZERO;
+    for bit in 0..256 {
+        // shift the next bit of the numerator into the remainder
+        let overflow = remainder.0[0] >> 63 == 1;
+        for i in 0..4 {
+            let next = if i < 3 { remainder.0[i + 1] >> 63 } else { numerator[bit / 64] >> (63 - bit % 64) & 1 };
+            remainder.0[i] = remainder.0[i] << 1 | next;
+        }
+        if overflow || remainder.0 >= *divisor {
+            remainder = remainder.wrapping_sub(divisor);
+            quotient[bit / 64] |= 1 << (63 - bit % 64);
+        }
+    }
+    quotient
+}
+
+/// An amount of work, in expected hashes: a 256-bit unsigned integer, saturating at `Work::MAX`,
+/// so that the work of a chain never overflows.
+#[derive(Eq, PartialEq, Ord, PartialOrd, Clone, Copy, Default, Debug)]
+pub struct Work([u64; 4]); // most significant limb first
+
+impl Work {
+    pub const ZERO: Work = Work([0; 4]);
+    pub const MAX: Work = Work([u64::MAX; 4]);
+
+    pub fn saturating_add(self, other: Work) -> Work {
+        let mut sum = [0u64; 4];
+        let mut carry = false;
+        for i in (0..4).rev() {
+            let (value, overflow) = self.0[i].overflowing_add(other.0[i]);
+            let (value, carried) = value.overflowing_add(carry as u64);
+            sum[i] = value;
+            carry = overflow || carried;
+        }
+        if carry {
+            Work::MAX
+        } else {
+            Work(sum)
+        }
+    }
+
+    fn wrapping_sub(self, other: &[u64; 4]) -> Work {
+        let mut difference = [0u64; 4];
+        let mut borrow = false;
+        for i in (0..4).rev() {
+            let (value, overflow) = self.0[i].overflowing_sub(other[i]);
+            let (value, borrowed) = value.overflowing_sub(borrow as u64);
+            difference[i] = value;
+            borrow = overflow || borrowed;
+        }
+        Work(difference)
+    }
+}
+
+impl From<u64> for Work {
+    fn from(work: u64) -> Work {
+        Work([0, 0, 0, work])
+    }
+}
+
+impl std::ops::Not for Work {
+    type Output = Work;
+
+    fn not(self) -> Work {
+        Work([!self.0[0], !self.0[1], !self.0[2], !self.0[3]])
+    }
+}
+
 impl Hashable for H256 {
     fn hash(&self) -> H256 {
-        ring::digest::digest(&ring::digest::SHA256, &self.0).into()
+        digest(&self.0)
     }
 }
 
@@ -107,6 +362,17 @@ impl PartialOrd for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = hex::FromHexError;
+
+    /// Parse a 64 character hex string, as printed by `Display`.
+    fn from_str(s: &str) -> Result<H256, Self::Err> {
+        let mut buffer: [u8; 32] = [0; 32];
+        hex::decode_to_slice(s, &mut buffer)?;
+        Ok(H256(buffer))
+    }
+}
+
 #[cfg(any(test, test_utilities))]
 pub mod tests {
     use super::H256;
@@ -120,4 +386,56 @@ pub mod tests {
         (&raw_bytes).into()
     }
 
+    #[test]
+    fn compact_targets() {
+        let genesis: H256 = "00000000ffff0000000000000000000000000000000000000000000000000000".parse().unwrap();
+        assert_eq!(H256::from_compact(0x1d00ffff), Some(genesis));
+        assert_eq!(genesis.to_compact(), 0x1d00ffff);
+        let small: H256 = "0000000000000000000000000000000000000000000000000000000092340000".parse().unwrap();
+        assert_eq!(H256::from_compact(0x05009234), Some(small));
+        assert_eq!(small.to_compact(), 0x05009234);
+        assert_eq!(H256::from_compact(0x01003456), Some(H256::ZERO));
+        assert_eq!(H256::ZERO.to_compact(), 0);
+        let top_bit: H256 = "0000000000000000000000000000000000000000000000000000000000000080".parse().unwrap();
+        assert_eq!(top_bit.to_compact(), 0x02008000);
+        assert_eq!(H256::from_compact(0x02008000), Some(top_bit));
+        // negative, then too large
+        assert_eq!(H256::from_compact(0x04923456), None);
+        assert_eq!(H256::from_compact(0x21010000), None);
+        assert_eq!(H256::from_compact(0x2000ffff).unwrap().to_compact(), 0x2000ffff);
+    }
+
+    #[test]
+    fn scaled_targets() {
+        let target: H256 = "0040000000000000000000000000000000000000000000000000000000000000".parse().unwrap();
+        let doubled: H256 = "0080000000000000000000000000000000000000000000000000000000000000".parse().unwrap();
+        assert_eq!(target.scale(2, 1), doubled);
+        assert_eq!(doubled.scale(1, 2), target);
+        assert_eq!(target.scale(3, 3), target);
+        assert_eq!(H256::MAX.scale(2, 1), H256::MAX);
+        assert_eq!(H256::MAX.scale(1, 1 << 63).to_compact(), 0x1901ffff);
+        assert!(doubled.work() < target.work());
+        assert_eq!(target.work(), super::Work::from(1023));
+        assert_eq!(H256::MAX.work(), super::Work::from(1));
+        assert_eq!(H256::ZERO.work(), super::Work::MAX);
+        let third: H256 = "5555555555555555555555555555555555555555555555555555555555555554".parse().unwrap();
+        assert_eq!(third.work(), super::Work::from(3));
+        assert!(H256::ZERO.meets_target(&target) && target.meets_target(&target) && !doubled.meets_target(&target));
+    }
+
+    #[test]
+    fn hash_functions() {
+        use super::HashFunction;
+        let sha256: H256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".parse().unwrap();
+        let keccak256: H256 = "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470".parse().unwrap();
+        assert_eq!(HashFunction::Sha256.digest(b""), sha256);
+        assert_eq!(HashFunction::Keccak256.digest(b""), keccak256);
+        assert!(HashFunction::Keccak256.is_available());
+        assert_eq!(HashFunction::Blake3.is_available(), cfg!(feature = "blake3"));
+        #[cfg(feature = "blake3")]
+        {
+            let blake3: H256 = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262".parse().unwrap();
+            assert_eq!(HashFunction::Blake3.digest(b""), blake3);
+        }
+    }
 }
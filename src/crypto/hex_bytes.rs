@@ -0,0 +1,70 @@
+//! Serialization of byte strings, such as keys and signatures, as hex strings in the human
+//! readable formats, so that external scripts can read the JSON of the blocks without bincode.
+//! The binary formats keep the raw bytes. Use with `#[serde(with = "crate::crypto::hex_bytes")]`.
+
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Borrowed bytes, serialized as hex in the human readable formats.
+struct Hex<'a>(&'a [u8]);
+
+impl Serialize for Hex<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(self.0))
+        } else {
+            self.0.serialize(serializer)
+        }
+    }
+}
+
+/// Owned bytes, deserialized from hex in the human readable formats.
+struct HexBuf(Vec<u8>);
+
+impl<'de> Deserialize<'de> for HexBuf {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            let s = String::deserialize(deserializer)?;
+            hex::decode(s.strip_prefix("0x").unwrap_or(&s)).map(HexBuf).map_err(D::Error::custom)
+        } else {
+            Vec::<u8>::deserialize(deserializer).map(HexBuf)
+        }
+    }
+}
+
+pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    Hex(bytes).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    HexBuf::deserialize(deserializer).map(|bytes| bytes.0)
+}
+
+/// A list of byte strings, such as the keys of a multisig policy.
+pub mod list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(list: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(|bytes| Hex(bytes)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        let list = Vec::<HexBuf>::deserialize(deserializer)?;
+        Ok(list.into_iter().map(|bytes| bytes.0).collect())
+    }
+}
+
+/// A list of byte strings each tagged with a position, such as the signatures of the cosigners
+/// of a multisig account.
+pub mod indexed {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(list: &[(u8, Vec<u8>)], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(list.iter().map(|(index, bytes)| (*index, Hex(bytes))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<(u8, Vec<u8>)>, D::Error> {
+        let list = Vec::<(u8, HexBuf)>::deserialize(deserializer)?;
+        Ok(list.into_iter().map(|(index, bytes)| (index, bytes.0)).collect())
+    }
+}
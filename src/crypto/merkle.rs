@@ -1,12 +1,17 @@
-use super::hash::{Hashable, H256};
+use super::hash::{self, Hashable, H256};
 use std::vec::Vec;
 
-/// A Merkle tree.
+/// Root of a tree without leaves, the merkle root of a block without transactions.
+pub static EMPTY_ROOT: H256 = H256::ZERO;
+
+/// A Merkle tree. Without leaves its root is `EMPTY_ROOT`, with a single leaf the hash of that
+/// leaf, and otherwise the last node of a level with an odd number of nodes is paired with itself.
 #[derive(Debug, Default)]
 pub struct MerkleTree {
     tree: Vec<H256>,    // Vector of tree nodes.
     valid: Vec<bool>,   // Vector of flags indicating whether index in tree[] corresponds to valid node.
     sz: usize,          // Next greatest power of 2 of the leaf size.
+    leaves: usize,      // Number of leaves.
 }
 
 impl MerkleTree {
@@ -48,13 +53,13 @@ impl MerkleTree {
                     _tree[r_idx] = _tree[l_idx];
                     buf.extend_from_slice(_tree[l_idx].as_ref()); 
                     buf.extend_from_slice(_tree[r_idx].as_ref());
-                    _tree[p_idx] = ring::digest::digest(&ring::digest::SHA256, &buf).into();
+                    _tree[p_idx] = hash::digest(&buf);
                     _valid[p_idx] = true;
                 }
                 else{                                       // Otherwise, fill parent hash with hash of current node and its right sibling.
                     buf.extend_from_slice(_tree[l_idx].as_ref()); 
                     buf.extend_from_slice(_tree[r_idx].as_ref());
-                    _tree[p_idx] = ring::digest::digest(&ring::digest::SHA256, &buf).into();
+                    _tree[p_idx] = hash::digest(&buf);
                     _valid[p_idx] = true;
                 } 
 
@@ -68,17 +73,93 @@ impl MerkleTree {
             tree: _tree,
             valid: _valid,
             sz: save_sz,
+            leaves: data.len(),
         }
 
     }
 
     pub fn root(&self) -> H256 {
-        self.tree[0]                                        // Root of tree is at index 0.
+        if self.leaves == 0 {                               // No leaf, a default tree has no node at all.
+            return EMPTY_ROOT;
+        }
+        self.tree[0]                                        // Root of tree is at index 0, the leaf itself if it is alone.
+    }
+
+    /// Number of leaves.
+    pub fn len(&self) -> usize {
+        self.leaves
     }
 
-    /// Returns the Merkle Proof of data at index i
+    pub fn is_empty(&self) -> bool {
+        self.leaves == 0
+    }
+
+    /// Append `datum` as the last leaf, recomputing only its path to the root, and the levels of
+    /// the tree when it doubles.
+    pub fn append<T>(&mut self, datum: &T) where T: Hashable, {
+        if self.leaves == self.sz {
+            self.grow();
+        }
+        let idx = self.sz - 1 + self.leaves;
+        self.tree[idx] = datum.hash();
+        self.valid[idx] = true;
+        self.leaves += 1;
+        self.rehash_path(idx);
+    }
+
+    /// Replace the leaf at `index` by `datum`, recomputing only its path to the root.
+    pub fn update<T>(&mut self, index: usize, datum: &T) where T: Hashable, {
+        assert!(index < self.leaves, "leaf {} out of {} leaves", index, self.leaves);
+        let idx = self.sz - 1 + index;
+        self.tree[idx] = datum.hash();
+        self.rehash_path(idx);
+    }
+
+    // Double the leaf size: every level moves one level down, the old root becoming the left child
+    // of the new root.
+    fn grow(&mut self) {
+        let new_sz = (self.sz << 1).max(1);                  // A default tree has no node at all.
+        let mut tree = vec![H256::default(); 2*new_sz-1];
+        let mut valid = vec![false; 2*new_sz-1];
+        let mut width = 1;
+        while width <= self.sz {
+            for i in 0..width {
+                tree[2*width-1+i] = self.tree[width-1+i];
+                valid[2*width-1+i] = self.valid[width-1+i];
+            }
+            width <<= 1;
+        }
+        self.tree = tree;
+        self.valid = valid;
+        self.sz = new_sz;
+        if self.leaves > 0 {
+            self.rehash_path(1);
+        }
+    }
+
+    // Recompute the parents of the node at `idx` in tree[], up to the root, the same way as `new`.
+    fn rehash_path(&mut self, mut idx: usize) {
+        while idx > 0 {
+            let (l_idx, r_idx) = if idx % 2 == 1 { (idx, idx + 1) } else { (idx - 1, idx) };
+            if !self.valid[r_idx] {                         // The last node of an odd level is copied to its right sibling.
+                self.tree[r_idx] = self.tree[l_idx];
+            }
+            let mut buf: Vec<u8> = Vec::with_capacity(64);
+            buf.extend_from_slice(self.tree[l_idx].as_ref());
+            buf.extend_from_slice(self.tree[r_idx].as_ref());
+            let p_idx = (l_idx - 1) >> 1;
+            self.tree[p_idx] = hash::digest(&buf);
+            self.valid[p_idx] = true;
+            idx = p_idx;
+        }
+    }
+
+    /// Returns the Merkle Proof of data at index i, empty if it is not a valid leaf or the only one.
     pub fn proof(&self, index: usize) -> Vec<H256> {
         let mut proof : Vec<H256> = Vec::<H256>::new();
+        if index >= self.leaves {
+            return proof;
+        }
 
         let mut idx = self.sz - 1 + index;                 // Get index of leaf in the tree[].
 
@@ -98,10 +179,48 @@ impl MerkleTree {
         } 
         proof 
     }
+
+    /// Returns a single Merkle proof of the data at all of `indices`: the siblings of their paths,
+    /// level by level from the leaves, except those computed from the data themselves or copied
+    /// from them at the end of a level. Empty if an index is not a valid leaf.
+    pub fn proof_multi(&self, indices: &[usize]) -> Vec<H256> {
+        let mut proof: Vec<H256> = Vec::new();
+        let mut positions = indices.to_vec();
+        positions.sort_unstable();
+        positions.dedup();
+        if positions.iter().any(|index| *index >= self.leaves) {
+            return proof;
+        }
+
+        let mut width = self.sz;                            // Width of the current level in tree[].
+        let mut count = self.leaves;                        // Number of valid nodes in the level.
+        while width > 1 {
+            let mut parents = Vec::with_capacity(positions.len());
+            let mut i = 0;
+            while i < positions.len() {
+                let pos = positions[i];
+                let sibling = pos ^ 1;
+                if i + 1 < positions.len() && positions[i + 1] == sibling {
+                    i += 2;                                 // Both children are known.
+                } else {
+                    if sibling != count {                   // Otherwise the sibling is a copy of pos.
+                        proof.push(self.tree[width - 1 + sibling]);
+                    }
+                    i += 1;
+                }
+                parents.push(pos >> 1);
+            }
+            positions = parents;
+            width >>= 1;
+            count = (count + 1) >> 1;
+        }
+        proof
+    }
 }
 
 /// Verify that the datum hash with a vector of proofs will produce the Merkle root. Also need the
-/// index of datum and `leaf_size`, the total number of leaves.
+/// index of datum and `leaf_size`, the total number of leaves. Nothing is proven without leaves,
+/// and a single leaf is proven by an empty proof against its own hash.
 pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
     let mut _sz = 1;
     let mut cnt = 0;
@@ -119,12 +238,12 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
             if idx % 2 == 0{                                      // If the current index is even, we know it is the left child of its parent.
                 buf.extend_from_slice(curr.as_ref());
                 buf.extend_from_slice(hash.as_ref());
-                curr = ring::digest::digest(&ring::digest::SHA256,&buf).into(); 
+                curr = hash::digest(&buf); 
             }
             else{
                 buf.extend_from_slice(hash.as_ref());             // If current index is odd, it is right child of parent.
                 buf.extend_from_slice(curr.as_ref());
-                curr = ring::digest::digest(&ring::digest::SHA256,&buf).into();
+                curr = hash::digest(&buf);
             }
             idx = idx >> 1;
         }
@@ -134,6 +253,54 @@ pub fn verify(root: &H256, datum: &H256, proof: &[H256], index: usize, leaf_size
     
 }
 
+/// Verify that the data hashes at their indices, with the proof of `MerkleTree::proof_multi` of
+/// these indices, produce the Merkle root of a tree of `leaf_size` leaves.
+pub fn verify_multi(root: &H256, data: &[(usize, H256)], proof: &[H256], leaf_size: usize) -> bool {
+    let mut level = data.to_vec();
+    level.sort_unstable_by_key(|(index, _)| *index);
+    level.dedup();
+    if level.is_empty() || level.windows(2).any(|pair| pair[0].0 == pair[1].0) || level.last().unwrap().0 >= leaf_size {
+        return false;                                             // No datum, an index with two data, or an invalid index.
+    }
+
+    let mut proof = proof.iter();
+    let mut count = leaf_size;
+    while count > 1 {
+        let mut parents = Vec::with_capacity(level.len());
+        let mut i = 0;
+        while i < level.len() {
+            let (pos, curr) = level[i];
+            let (left, right) = if pos % 2 == 1 {                 // A right child, its left sibling is in the proof.
+                i += 1;
+                match proof.next() {
+                    Some(hash) => (*hash, curr),
+                    None => return false,
+                }
+            } else if i + 1 < level.len() && level[i + 1].0 == pos + 1 {
+                i += 2;                                           // Both children are known.
+                (curr, level[i - 1].1)
+            } else if pos + 1 == count {                          // The last node of an odd level is copied.
+                i += 1;
+                (curr, curr)
+            } else {
+                i += 1;
+                match proof.next() {
+                    Some(hash) => (curr, *hash),
+                    None => return false,
+                }
+            };
+            let mut buf: Vec<u8> = Vec::with_capacity(64);
+            buf.extend_from_slice(left.as_ref());
+            buf.extend_from_slice(right.as_ref());
+            parents.push((pos >> 1, hash::digest(&buf)));
+        }
+        level = parents;
+        count = (count + 1) >> 1;
+    }
+
+    proof.next().is_none() && *root == level[0].1                 // The whole proof is used.
+}
+
 #[cfg(test)]
 mod tests {
     use crate::crypto::hash::H256;
@@ -206,4 +373,82 @@ mod tests {
         }
         
     }
+
+    #[test]
+    fn empty_and_single() {
+        let empty = MerkleTree::new(&Vec::<H256>::new());
+        assert_eq!(empty.root(), EMPTY_ROOT);
+        assert_eq!(MerkleTree::default().root(), EMPTY_ROOT);
+        assert!(empty.proof(0).is_empty());
+        assert!(MerkleTree::default().proof(0).is_empty());
+        assert!(MerkleTree::default().proof_multi(&[0]).is_empty());
+        assert!(!verify(&EMPTY_ROOT, &EMPTY_ROOT, &[], 0, 0));
+        assert!(!verify_multi(&EMPTY_ROOT, &[(0, EMPTY_ROOT)], &[], 0));
+
+        let datum: H256 = hex!("0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d0a0b0c0d0e0f0e0d").into();
+        let single = MerkleTree::new(&[datum]);
+        assert_eq!(single.root(), datum.hash());
+        assert!(single.proof(0).is_empty());
+        assert!(single.proof(1).is_empty());
+        assert!(verify(&single.root(), &datum.hash(), &[], 0, 1));
+        assert!(!verify(&single.root(), &datum.hash(), &[], 1, 1));
+        assert!(verify_multi(&single.root(), &[(0, datum.hash())], &single.proof_multi(&[0]), 1));
+
+        let mut appended = MerkleTree::default();
+        appended.append(&datum);
+        assert_eq!(appended.root(), single.root());
+    }
+
+    #[test]
+    fn multiproof() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let merkle_tree = MerkleTree::new(&input_data);
+        let root = merkle_tree.root();
+        let data = |indices: &[usize]| -> Vec<(usize, H256)> {
+            indices.iter().map(|i| (*i, input_data[*i].hash())).collect()
+        };
+        for indices in [vec![0], vec![4], vec![0, 1], vec![1, 2, 4], vec![3, 0], vec![0, 1, 2, 3, 4]] {
+            let proof = merkle_tree.proof_multi(&indices);
+            assert!(verify_multi(&root, &data(&indices), &proof, input_data.len()), "{:?}", indices);
+        }
+        // a single leaf is the same proof as `proof`
+        assert_eq!(merkle_tree.proof_multi(&[3]), merkle_tree.proof(3));
+        // sibling leaves share their path
+        assert_eq!(merkle_tree.proof_multi(&[2, 3]).len(), 2);
+        assert!(merkle_tree.proof_multi(&[0, 5]).is_empty());
+
+        let proof = merkle_tree.proof_multi(&[1, 2]);
+        assert!(!verify_multi(&root, &data(&[1, 3]), &proof, input_data.len()));
+        assert!(!verify_multi(&root, &data(&[1]), &proof, input_data.len()));
+        assert!(!verify_multi(&root, &data(&[1, 2]), &proof[1..], input_data.len()));
+        assert!(!verify_multi(&root, &data(&[1, 2]), &proof, 4));
+        assert!(!verify_multi(&root, &[], &proof, input_data.len()));
+        let mut conflicting = data(&[1, 2]);
+        conflicting.push((1, input_data[0].hash()));
+        assert!(!verify_multi(&root, &conflicting, &proof, input_data.len()));
+    }
+
+    #[test]
+    fn append_and_update() {
+        let input_data: Vec<H256> = gen_merkle_tree_data!();
+        let mut merkle_tree = MerkleTree::new(&Vec::<H256>::new());
+        for i in 0..input_data.len() {
+            merkle_tree.append(&input_data[i]);
+            let rebuilt = MerkleTree::new(&input_data[..=i]);
+            assert_eq!(merkle_tree.root(), rebuilt.root());
+            assert_eq!(merkle_tree.len(), i + 1);
+            for j in 0..=i {
+                assert_eq!(merkle_tree.proof(j), rebuilt.proof(j));
+            }
+        }
+
+        let mut updated = input_data.clone();
+        updated[4] = hex!("0101010101010101010101010101010101010101010101010101010101010206").into();
+        merkle_tree.update(4, &updated[4]);
+        assert_eq!(merkle_tree.root(), MerkleTree::new(&updated).root());
+        updated[1] = updated[0];
+        merkle_tree.update(1, &updated[1]);
+        assert_eq!(merkle_tree.root(), MerkleTree::new(&updated).root());
+        assert_eq!(merkle_tree.proof(1), MerkleTree::new(&updated).proof(1));
+    }
 }
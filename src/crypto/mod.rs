@@ -1,4 +1,9 @@
 pub mod hash;
 pub mod address;
 pub mod merkle;
+pub mod trie;
 pub mod key_pair;
+pub mod secp256k1;
+pub mod vrf;
+pub mod bls;
+pub mod hex_bytes;
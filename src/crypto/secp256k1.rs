@@ -0,0 +1,84 @@
+//! ECDSA over secp256k1, the curve of Ethereum, as an alternative to Ed25519. The public keys
+//! are SEC1 encoded, the signatures are the 64 bytes of `r` and `s`, with `s` in the lower half
+//! of the order so that a signature cannot be altered, and the addresses are derived the
+//! Ethereum way: the last 20 bytes of the Keccak-256 hash of the uncompressed public key.
+
+use crate::crypto::address::H160;
+use k256::ecdsa::signature::hazmat::{PrehashSigner, PrehashVerifier};
+use k256::ecdsa::{Signature, SigningKey, VerifyingKey};
+use ring::rand::{SecureRandom, SystemRandom};
+use sha3::{Digest, Keccak256};
+
+/// Generate a random key.
+pub fn random() -> SigningKey {
+    let rng = SystemRandom::new();
+    let mut secret = [0u8; 32];
+    loop {
+        rng.fill(&mut secret).unwrap();
+        // the secret must be below the order of the curve, and not zero
+        if let Ok(key) = SigningKey::from_slice(&secret) {
+            return key;
+        }
+    }
+}
+
+/// The compressed SEC1 encoding of the public key of `key`.
+pub fn public_key(key: &SigningKey) -> Vec<u8> {
+    key.verifying_key().to_encoded_point(true).as_bytes().to_vec()
+}
+
+/// The address of a SEC1 encoded public key, `None` if it is not a point of the curve.
+pub fn address(public_key: &[u8]) -> Option<H160> {
+    let key = VerifyingKey::from_sec1_bytes(public_key).ok()?;
+    let hash = Keccak256::digest(&key.to_encoded_point(false).as_bytes()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    Some(address.into())
+}
+
+/// Sign the 32-byte `digest`.
+pub fn sign(digest: &[u8], key: &SigningKey) -> Vec<u8> {
+    let signature: Signature = key.sign_prehash(digest).unwrap();
+    signature.to_bytes().to_vec()
+}
+
+/// Whether `signature` is the one of `digest` by the key of `public_key`.
+pub fn verify(digest: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+    let key = match VerifyingKey::from_sec1_bytes(public_key) {
+        Ok(key) => key,
+        Err(_) => return false,
+    };
+    match Signature::from_slice(signature) {
+        Ok(signature) => key.verify_prehash(digest, &signature).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ethereum_address() {
+        let mut secret = [0u8; 32];
+        secret[31] = 1;
+        let key = SigningKey::from_slice(&secret).unwrap();
+        let expected: H160 = "7e5f4552091a69125d5dfcb7b8c2659029395bdf".parse().unwrap();
+        assert_eq!(address(&public_key(&key)), Some(expected));
+        let uncompressed = key.verifying_key().to_encoded_point(false);
+        assert_eq!(address(uncompressed.as_bytes()), Some(expected));
+        assert_eq!(address(&[5; 33]), None);
+    }
+
+    #[test]
+    fn sign_verify() {
+        let key = random();
+        let digest = [7u8; 32];
+        let signature = sign(&digest, &key);
+        assert_eq!(signature.len(), 64);
+        assert!(verify(&digest, &public_key(&key), &signature));
+        assert!(!verify(&[8u8; 32], &public_key(&key), &signature));
+        assert!(!verify(&digest, &public_key(&random()), &signature));
+        assert!(!verify(&digest, &public_key(&key), &signature[1..]));
+    }
+}
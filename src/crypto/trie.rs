@@ -0,0 +1,233 @@
+use serde::{Serialize, Deserialize};
+use super::address::H160;
+use super::hash::{self, H256};
+use std::collections::BTreeMap;
+use std::iter::FromIterator;
+
+/// Depth of the trie: one level per bit of an address.
+const DEPTH: usize = 160;
+
+/// A sparse Merkle trie mapping every possible address to a value, most of them empty. The
+/// root commits to all the values, and a proof shows the value of an address, or its absence,
+/// against the root.
+///
+/// The leaves are kept in a sorted map and the interior nodes are recomputed on demand: the
+/// empty subtrees hash to a constant per level, so computing the root costs `DEPTH` hashes per
+/// stored address.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SparseMerkleTrie<V> {
+    leaves: BTreeMap<H160, V>,
+}
+
+/// The siblings of the path from the root to an address. `bitmap` flags, from the root down,
+/// the levels whose sibling is not an empty subtree; only those are listed in `siblings`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TrieProof {
+    pub bitmap: Vec<u8>,
+    pub siblings: Vec<H256>,
+}
+
+fn bit(address: &H160, depth: usize) -> bool {
+    address.as_ref()[depth / 8] >> (7 - depth % 8) & 1 == 1
+}
+
+fn hash_node(left: &H256, right: &H256) -> H256 {
+    let mut buf = vec![1u8];
+    buf.extend_from_slice(left.as_ref());
+    buf.extend_from_slice(right.as_ref());
+    hash::digest(&buf)
+}
+
+fn hash_leaf<V: Serialize>(address: &H160, value: &V) -> H256 {
+    let mut buf = vec![0u8];
+    buf.extend_from_slice(address.as_ref());
+    buf.extend_from_slice(&bincode::serialize(value).unwrap());
+    hash::digest(&buf)
+}
+
+/// The hash of an empty subtree rooted at each depth, `DEPTH` being the leaves.
+fn empty_hashes() -> Vec<H256> {
+    let mut hashes = vec![H256::default(); DEPTH + 1];
+    for depth in (0..DEPTH).rev() {
+        hashes[depth] = hash_node(&hashes[depth + 1], &hashes[depth + 1]);
+    }
+    hashes
+}
+
+/// Root of the subtree at `depth` holding `leaves`, sorted by address.
+fn subtree_root<V: Serialize>(leaves: &[(&H160, &V)], depth: usize, empty: &[H256]) -> H256 {
+    if leaves.is_empty() {
+        return empty[depth];
+    }
+    if depth == DEPTH {
+        return hash_leaf(leaves[0].0, leaves[0].1);
+    }
+    let split = leaves.partition_point(|(address, _)| !bit(address, depth));
+    hash_node(
+        &subtree_root(&leaves[..split], depth + 1, empty),
+        &subtree_root(&leaves[split..], depth + 1, empty),
+    )
+}
+
+impl<V: Serialize> SparseMerkleTrie<V> {
+    pub fn new() -> Self {
+        SparseMerkleTrie {
+            leaves: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn get(&self, address: &H160) -> Option<&V> {
+        self.leaves.get(address)
+    }
+
+    pub fn get_mut(&mut self, address: &H160) -> Option<&mut V> {
+        self.leaves.get_mut(address)
+    }
+
+    pub fn contains_key(&self, address: &H160) -> bool {
+        self.leaves.contains_key(address)
+    }
+
+    pub fn insert(&mut self, address: H160, value: V) -> Option<V> {
+        self.leaves.insert(address, value)
+    }
+
+    /// Empty the leaf of `address` again.
+    pub fn remove(&mut self, address: &H160) -> Option<V> {
+        self.leaves.remove(address)
+    }
+
+    /// The values in the order of their addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (&H160, &V)> {
+        self.leaves.iter()
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.leaves.values()
+    }
+
+    pub fn root(&self) -> H256 {
+        let leaves: Vec<(&H160, &V)> = self.leaves.iter().collect();
+        subtree_root(&leaves, 0, &empty_hashes())
+    }
+
+    /// The proof of the value of `address`, or of its absence.
+    pub fn proof(&self, address: &H160) -> TrieProof {
+        let empty = empty_hashes();
+        let leaves: Vec<(&H160, &V)> = self.leaves.iter().collect();
+        let mut path = &leaves[..];
+        let mut proof = TrieProof {
+            bitmap: vec![0; DEPTH / 8],
+            siblings: Vec::new(),
+        };
+        for depth in 0..DEPTH {
+            let split = path.partition_point(|(a, _)| !bit(a, depth));
+            let (left, right) = path.split_at(split);
+            let (next, sibling) = if bit(address, depth) { (right, left) } else { (left, right) };
+            if !sibling.is_empty() {
+                proof.bitmap[depth / 8] |= 1 << (7 - depth % 8);
+                proof.siblings.push(subtree_root(sibling, depth + 1, &empty));
+            }
+            path = next;
+        }
+        proof
+    }
+}
+
+impl<V> std::ops::Index<&H160> for SparseMerkleTrie<V> {
+    type Output = V;
+
+    fn index(&self, address: &H160) -> &V {
+        &self.leaves[address]
+    }
+}
+
+impl<V> FromIterator<(H160, V)> for SparseMerkleTrie<V> {
+    fn from_iter<I: IntoIterator<Item = (H160, V)>>(iter: I) -> Self {
+        SparseMerkleTrie {
+            leaves: iter.into_iter().collect(),
+        }
+    }
+}
+
+/// Verify that `address` holds `value` (`None` for an absent address) in the trie of `root`.
+pub fn verify<V: Serialize>(root: &H256, address: &H160, value: Option<&V>, proof: &TrieProof) -> bool {
+    if proof.bitmap.len() != DEPTH / 8 {
+        return false;
+    }
+    let empty = empty_hashes();
+    let mut siblings = proof.siblings.iter().rev();
+    let mut current = match value {
+        Some(value) => hash_leaf(address, value),
+        None => empty[DEPTH],
+    };
+    for depth in (0..DEPTH).rev() {
+        let sibling = if proof.bitmap[depth / 8] >> (7 - depth % 8) & 1 == 1 {
+            match siblings.next() {
+                Some(sibling) => *sibling,
+                None => return false,
+            }
+        } else {
+            empty[depth + 1]
+        };
+        current = if bit(address, depth) {
+            hash_node(&sibling, &current)
+        } else {
+            hash_node(&current, &sibling)
+        };
+    }
+    siblings.next().is_none() && current == *root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(byte: u8) -> H160 {
+        let mut bytes = [0u8; 20];
+        bytes[0] = byte;
+        bytes[19] = byte;
+        bytes.into()
+    }
+
+    #[test]
+    fn inclusion_and_absence() {
+        let mut trie: SparseMerkleTrie<u64> = SparseMerkleTrie::new();
+        let empty_root = trie.root();
+        for byte in [0x00, 0x80, 0x81, 0x40].iter() {
+            trie.insert(address(*byte), *byte as u64);
+        }
+        let root = trie.root();
+        assert_ne!(root, empty_root);
+        // the root does not depend on the insertion order
+        let rebuilt: SparseMerkleTrie<u64> = [0x40, 0x81, 0x00, 0x80].iter().map(|b| (address(*b), *b as u64)).collect();
+        assert_eq!(rebuilt.root(), root);
+
+        let proof = trie.proof(&address(0x81));
+        assert!(verify(&root, &address(0x81), Some(&0x81u64), &proof));
+        assert!(!verify(&root, &address(0x81), Some(&0x80u64), &proof));
+        assert!(!verify(&root, &address(0x81), None::<&u64>, &proof));
+        assert!(!verify(&root, &address(0x80), Some(&0x81u64), &proof));
+
+        let proof = trie.proof(&address(0x41));
+        assert!(verify(&root, &address(0x41), None::<&u64>, &proof));
+        assert!(!verify(&empty_root, &address(0x41), None::<&u64>, &proof));
+
+        *trie.get_mut(&address(0x40)).unwrap() += 1;
+        assert_ne!(trie.root(), root);
+
+        // removing every leaf gives the empty trie back
+        for byte in [0x00, 0x80, 0x81, 0x40].iter() {
+            trie.remove(&address(*byte));
+        }
+        assert_eq!(trie.root(), empty_root);
+    }
+}
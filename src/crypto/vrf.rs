@@ -0,0 +1,178 @@
+//! Verifiable random function for sortition: ECVRF-EDWARDS25519-SHA512-TAI of RFC 9381. The
+//! holder of a secret key computes from any input a pseudorandom output along with a proof, and
+//! anyone with the public key checks that the output is the only one of the key for that input,
+//! so that a node can show it was elected for a block without being able to pick the result.
+//!
+//! The keys are the 32-byte seeds and public keys of Ed25519.
+
+use curve25519_dalek::constants::ED25519_BASEPOINT_POINT;
+use curve25519_dalek::edwards::{CompressedEdwardsY, EdwardsPoint};
+use curve25519_dalek::scalar::Scalar;
+use ring::digest::{digest, SHA512};
+
+/// Length of a proof, in bytes: a point, a 16-byte challenge and a scalar.
+pub static PROOF_LEN: usize = 80;
+/// Length of an output, in bytes.
+pub static OUTPUT_LEN: usize = 64;
+
+const SUITE: u8 = 0x03;
+const CHALLENGE_LEN: usize = 16;
+
+fn sha512(parts: &[&[u8]]) -> [u8; 64] {
+    let bytes: Vec<u8> = parts.concat();
+    let mut hash = [0u8; 64];
+    hash.copy_from_slice(digest(&SHA512, &bytes).as_ref());
+    hash
+}
+
+// the secret scalar and the nonce prefix of an Ed25519 seed, as in RFC 8032
+fn expand(seed: &[u8; 32]) -> (Scalar, [u8; 32]) {
+    let hash = sha512(&[seed]);
+    let mut scalar = [0u8; 32];
+    scalar.copy_from_slice(&hash[..32]);
+    scalar[0] &= 248;
+    scalar[31] &= 127;
+    scalar[31] |= 64;
+    let mut prefix = [0u8; 32];
+    prefix.copy_from_slice(&hash[32..]);
+    (Scalar::from_bytes_mod_order(scalar), prefix)
+}
+
+fn decode_point(bytes: &[u8]) -> Option<EdwardsPoint> {
+    let mut compressed = [0u8; 32];
+    if bytes.len() != 32 {
+        return None;
+    }
+    compressed.copy_from_slice(bytes);
+    CompressedEdwardsY(compressed).decompress()
+}
+
+// hash `alpha` to a point of the prime order subgroup, by try and increment
+fn encode_to_curve(public_key: &[u8], alpha: &[u8]) -> EdwardsPoint {
+    for counter in 0..=255u8 {
+        let hash = sha512(&[&[SUITE, 0x01], public_key, alpha, &[counter, 0x00]]);
+        if let Some(point) = decode_point(&hash[..32]) {
+            return point.mul_by_cofactor();
+        }
+    }
+    // about one string in two is a point, so this is never reached in practice
+    panic!("no point found for the VRF input");
+}
+
+fn challenge(points: &[&EdwardsPoint; 5]) -> Scalar {
+    let mut bytes = vec![SUITE, 0x02];
+    for point in points.iter() {
+        bytes.extend_from_slice(point.compress().as_bytes());
+    }
+    bytes.push(0x00);
+    let hash = sha512(&[&bytes]);
+    let mut challenge = [0u8; 32];
+    challenge[..CHALLENGE_LEN].copy_from_slice(&hash[..CHALLENGE_LEN]);
+    Scalar::from_bytes_mod_order(challenge)
+}
+
+fn proof_to_output(gamma: &EdwardsPoint) -> [u8; 64] {
+    sha512(&[&[SUITE, 0x03], gamma.mul_by_cofactor().compress().as_bytes(), &[0x00]])
+}
+
+/// The public key of the secret `seed`.
+pub fn public_key(seed: &[u8; 32]) -> [u8; 32] {
+    let (x, _) = expand(seed);
+    (x * ED25519_BASEPOINT_POINT).compress().to_bytes()
+}
+
+/// The output of the key `seed` for `alpha`, and the proof of it.
+pub fn prove(seed: &[u8; 32], alpha: &[u8]) -> ([u8; 64], Vec<u8>) {
+    let (x, prefix) = expand(seed);
+    let y = x * ED25519_BASEPOINT_POINT;
+    let h = encode_to_curve(y.compress().as_bytes(), alpha);
+    let gamma = x * h;
+    let k = Scalar::from_bytes_mod_order_wide(&sha512(&[&prefix, h.compress().as_bytes()]));
+    let c = challenge(&[&y, &h, &gamma, &(k * ED25519_BASEPOINT_POINT), &(k * h)]);
+    let s = k + c * x;
+
+    let mut proof = Vec::with_capacity(PROOF_LEN);
+    proof.extend_from_slice(gamma.compress().as_bytes());
+    proof.extend_from_slice(&c.as_bytes()[..CHALLENGE_LEN]);
+    proof.extend_from_slice(s.as_bytes());
+    (proof_to_output(&gamma), proof)
+}
+
+/// The output proven by `proof` for `alpha` and the key `public_key`, `None` if the proof is
+/// invalid.
+pub fn verify(public_key: &[u8], alpha: &[u8], proof: &[u8]) -> Option<[u8; 64]> {
+    if proof.len() != PROOF_LEN {
+        return None;
+    }
+    let y = decode_point(public_key)?;
+    // a key of small order would prove any output
+    if y.is_small_order() {
+        return None;
+    }
+    let gamma = decode_point(&proof[..32])?;
+    let mut c = [0u8; 32];
+    c[..CHALLENGE_LEN].copy_from_slice(&proof[32..32 + CHALLENGE_LEN]);
+    let c = Scalar::from_bytes_mod_order(c);
+    let mut s = [0u8; 32];
+    s.copy_from_slice(&proof[32 + CHALLENGE_LEN..]);
+    let s: Scalar = Option::from(Scalar::from_canonical_bytes(s))?;
+
+    let h = encode_to_curve(public_key, alpha);
+    let u = s * ED25519_BASEPOINT_POINT - c * y;
+    let v = s * h - c * gamma;
+    if challenge(&[&y, &h, &gamma, &u, &v]) != c {
+        return None;
+    }
+    Some(proof_to_output(&gamma))
+}
+
+/// Whether `output` is elected by a sortition with the given `probability`, reading its first 8
+/// bytes as a uniform fraction.
+pub fn is_selected(output: &[u8; 64], probability: f64) -> bool {
+    let mut high = [0u8; 8];
+    high.copy_from_slice(&output[..8]);
+    (u64::from_be_bytes(high) as f64) < probability * (u64::MAX as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc9381_test_vector() {
+        let seed: [u8; 32] = hex!("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60");
+        let public = public_key(&seed);
+        assert_eq!(hex::encode(public), "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a");
+        let (output, proof) = prove(&seed, b"");
+        assert_eq!(hex::encode(&proof), "8657106690b5526245a92b003bb079ccd1a92130477671f6fc01ad16f26f723f26f8a57ccaed74ee1b190bed1f479d9727d2d0f9b005a6e456a35d4fb0daab1268a1b0db10836d9826a528ca76567805");
+        assert_eq!(hex::encode(&output[..]), "90cf1df3b703cce59e2a35b925d411164068269d7b2d29f3301c03dd757876ff66b71dda49d2de59d03450451af026798e8f81cd2e333de5cdf4f3e140fdd8ae");
+        assert_eq!(verify(&public, b"", &proof), Some(output));
+    }
+
+    #[test]
+    fn invalid_proofs() {
+        let seed = [7u8; 32];
+        let public = public_key(&seed);
+        let (output, proof) = prove(&seed, b"round 1");
+        assert_eq!(verify(&public, b"round 1", &proof), Some(output));
+        assert_eq!(verify(&public, b"round 2", &proof), None);
+        assert_eq!(verify(&public_key(&[8u8; 32]), b"round 1", &proof), None);
+        assert_eq!(verify(&public, b"round 1", &proof[1..]), None);
+        let mut tampered = proof.clone();
+        tampered[40] ^= 1;
+        assert_eq!(verify(&public, b"round 1", &tampered), None);
+        // the identity point as the key
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        assert_eq!(verify(&identity, b"round 1", &proof), None);
+    }
+
+    #[test]
+    fn sortition() {
+        let (output, _) = prove(&[7u8; 32], b"round 1");
+        assert!(is_selected(&output, 1.0));
+        assert!(!is_selected(&output, 0.0));
+        let selected = (0..64u32).filter(|i| is_selected(&prove(&[7u8; 32], &i.to_be_bytes()).0, 0.25)).count();
+        assert!(selected > 4 && selected < 32, "{}", selected);
+    }
+}
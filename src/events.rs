@@ -0,0 +1,171 @@
+use crate::block::Header;
+use crate::blockchain::Reorg;
+use crate::crypto::hash::{H256, Hashable};
+use crate::latency::ConfirmationLatency;
+use crate::network::compression::CompressionStats;
+use crate::notify::Subscribers;
+use crate::transaction::SignedTransaction;
+use crossbeam::channel::Receiver;
+use log::debug;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+/// Something that happened in the node, published on the `EventBus`.
+#[derive(Debug, Clone)]
+pub enum NodeEvent {
+    /// The miner of this node found a block.
+    BlockMined {
+        hash: H256,
+        num_transactions: usize,
+    },
+    /// A block received from a peer passed validation and was inserted in the blockchain.
+    BlockAccepted {
+        hash: H256,
+        num_transactions: usize,
+    },
+    /// The longest chain has a new tip.
+    NewHead {
+        hash: H256,
+        header: Header,
+        height: u32,
+        num_transactions: usize,
+    },
+    /// A fork overtook the previous head. Published before the `NewHead` of the new tip.
+    Reorg(Reorg),
+    /// The mempool took a new transaction, or a replacement.
+    TxAccepted(SignedTransaction),
+    /// The transaction generator of this node created a transaction.
+    TxGenerated(H256),
+    PeerConnected(SocketAddr),
+    PeerDisconnected(SocketAddr),
+    /// A peer sent a message that could not be decoded.
+    MalformedMessage(SocketAddr),
+}
+
+/// Fan-out of the `NodeEvent`s to any number of subscribers (logger, metrics, RPC subscriptions).
+/// Shared as an `Arc<EventBus>` by the modules that publish events.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Subscribers<NodeEvent>,
+}
+
+impl EventBus {
+    pub fn publish(&self, event: NodeEvent) {
+        self.subscribers.notify(event);
+    }
+
+    pub fn subscribe(&self) -> Receiver<NodeEvent> {
+        self.subscribers.subscribe()
+    }
+}
+
+/// Log every event at debug level, as one line each.
+pub fn start_logger(events: &Arc<EventBus>) {
+    let receiver = events.subscribe();
+    thread::Builder::new()
+        .name("event-logger".to_string())
+        .spawn(move || {
+            for event in receiver.iter() {
+                match event {
+                    NodeEvent::BlockMined { hash, num_transactions } => debug!("Event: block mined {} with {} transactions", hash, num_transactions),
+                    NodeEvent::BlockAccepted { hash, num_transactions } => debug!("Event: block accepted {} with {} transactions", hash, num_transactions),
+                    NodeEvent::NewHead { hash, height, .. } => debug!("Event: new head {} at height {}", hash, height),
+                    NodeEvent::Reorg(reorg) => debug!("Event: reorg of {} blocks disconnected, {} connected", reorg.disconnected.len(), reorg.connected.len()),
+                    NodeEvent::TxAccepted(tx) => debug!("Event: transaction accepted {}", tx.hash()),
+                    NodeEvent::TxGenerated(txid) => debug!("Event: transaction generated {}", txid),
+                    NodeEvent::PeerConnected(addr) => debug!("Event: peer connected {}", addr),
+                    NodeEvent::PeerDisconnected(addr) => debug!("Event: peer disconnected {}", addr),
+                    NodeEvent::MalformedMessage(addr) => debug!("Event: malformed message from {}", addr),
+                }
+            }
+        })
+        .unwrap();
+}
+
+/// Counters of the events seen since the node started.
+#[derive(Default)]
+pub struct Metrics {
+    pub blocks_mined: AtomicU64,
+    pub blocks_accepted: AtomicU64,
+    pub reorgs: AtomicU64,
+    pub transactions_accepted: AtomicU64,
+    pub transactions_generated: AtomicU64,
+    pub peers_connected: AtomicU64,
+    pub peers_disconnected: AtomicU64,
+    pub malformed_messages: AtomicU64,
+    /// Kept up to date by `latency::start`.
+    pub confirmations: ConfirmationLatency,
+    /// Kept up to date by the P2P server.
+    pub compression: Arc<CompressionStats>,
+    /// Hashes of the blocks mined by this node, to tell them apart in the block tree.
+    pub mined: Mutex<HashSet<H256>>,
+}
+
+impl Metrics {
+    pub fn new(confirmation_depth: u32) -> Self {
+        Metrics {
+            confirmations: ConfirmationLatency::new(confirmation_depth),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&self, event: &NodeEvent) {
+        let counter = match event {
+            NodeEvent::BlockMined { hash, .. } => {
+                self.mined.lock().unwrap().insert(*hash);
+                &self.blocks_mined
+            }
+            NodeEvent::BlockAccepted { .. } => &self.blocks_accepted,
+            NodeEvent::Reorg(_) => &self.reorgs,
+            NodeEvent::TxAccepted(_) => &self.transactions_accepted,
+            NodeEvent::TxGenerated(_) => &self.transactions_generated,
+            NodeEvent::PeerConnected(_) => &self.peers_connected,
+            NodeEvent::PeerDisconnected(_) => &self.peers_disconnected,
+            NodeEvent::MalformedMessage(_) => &self.malformed_messages,
+            NodeEvent::NewHead { .. } => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Keep `Metrics` up to date from the events of the bus.
+pub fn start_metrics(events: &Arc<EventBus>, confirmation_depth: u32) -> Arc<Metrics> {
+    let receiver = events.subscribe();
+    let metrics = Arc::new(Metrics::new(confirmation_depth));
+    let recorder = Arc::clone(&metrics);
+    thread::Builder::new()
+        .name("event-metrics".to_string())
+        .spawn(move || {
+            for event in receiver.iter() {
+                recorder.record(&event);
+            }
+        })
+        .unwrap();
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_subscriber_sees_every_event() {
+        let events = EventBus::default();
+        let first = events.subscribe();
+        let second = events.subscribe();
+        events.publish(NodeEvent::PeerConnected("127.0.0.1:6000".parse().unwrap()));
+        events.publish(NodeEvent::BlockMined { hash: H256::default(), num_transactions: 3 });
+        assert_eq!(first.try_iter().count(), 2);
+
+        let metrics = Metrics::default();
+        for event in second.try_iter() {
+            metrics.record(&event);
+        }
+        assert_eq!(metrics.peers_connected.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.blocks_mined.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.blocks_accepted.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,138 @@
+//! Hands out the coins of the faucet account funded at the genesis, see
+//! `GenesisConfig::faucet_balance`, so that new identities of a running network get a balance
+//! without a new genesis.
+
+use crate::block::{Model, State};
+use crate::crypto::address::H160;
+use crate::genesis::FAUCET_KEY_BYTE;
+use crate::mempool::Mempool;
+use crate::miner::Identity;
+use crate::transaction::{sign, SignatureScheme, SignedTransaction, Transaction, MAX_INPUTS};
+use ring::signature::KeyPair;
+
+/// Most coins handed out by a single request.
+pub static FAUCET_MAX_AMOUNT: u64 = 1000;
+
+/// Why the faucet refused a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaucetError {
+    /// Over the most coins of a single request, or none.
+    InvalidAmount,
+    /// The faucet cannot cover the amount and the fee, its transfers waiting in the mempool
+    /// aside.
+    Empty,
+}
+
+impl std::fmt::Display for FaucetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            FaucetError::InvalidAmount => write!(f, "amount must be from 1 to {}", FAUCET_MAX_AMOUNT),
+            FaucetError::Empty => write!(f, "faucet empty"),
+        }
+    }
+}
+
+pub struct Faucet {
+    identity: Identity,
+}
+
+impl Default for Faucet {
+    fn default() -> Self {
+        Faucet {
+            identity: Identity::new(FAUCET_KEY_BYTE),
+        }
+    }
+}
+
+impl Faucet {
+    pub fn address(&self) -> H160 {
+        self.identity.address
+    }
+
+    /// A signed transfer of `amount` to `address` on top of `state`, paying the minimum fee of
+    /// `tx_mempool` and following the faucet transfers waiting in it: the next free nonce in the
+    /// account model, the outputs they do not spend in the UTXO model.
+    pub fn transfer(&self, address: &H160, amount: u64, state: &State, tx_mempool: &Mempool) -> Result<SignedTransaction, FaucetError> {
+        if amount == 0 || amount > FAUCET_MAX_AMOUNT {
+            return Err(FaucetError::InvalidAmount);
+        }
+        let from = self.address();
+        let fee = tx_mempool.min_fee();
+        let cost = amount.checked_add(fee).ok_or(FaucetError::InvalidAmount)?;
+        let mut transaction = Transaction {
+            outputs: vec![(*address, amount)],
+            fee,
+            ..Default::default()
+        };
+        match state.model {
+            Model::Account => {
+                let account = state.account_state.get(&from).cloned().unwrap_or_default();
+                // the transfers waiting in the mempool are paid from the same balance
+                let nonce = (account.nonce + 1..).find(|nonce| !tx_mempool.has_nonce(&from, *nonce)).unwrap();
+                let waiting = tx_mempool.iter()
+                    .filter(|tx| tx.sender() == from)
+                    .fold(0u64, |sum, tx| sum.saturating_add(tx.transaction.cost().unwrap_or(u64::MAX)));
+                if account.balance.saturating_sub(waiting) < cost {
+                    return Err(FaucetError::Empty);
+                }
+                transaction.account_nonce = nonce;
+            }
+            Model::Utxo => {
+                let mut total = 0u64;
+                for utxo in state.unspent_of(&from).filter(|utxo| !tx_mempool.is_spent(&utxo.outpoint)).take(MAX_INPUTS) {
+                    if total >= cost {
+                        break;
+                    }
+                    transaction.inputs.push(utxo.outpoint);
+                    total = total.saturating_add(utxo.value);
+                }
+                if total < cost {
+                    return Err(FaucetError::Empty);
+                }
+                if total > cost {
+                    transaction.outputs.push((from, total - cost));
+                }
+            }
+        }
+        Ok(SignedTransaction {
+            signature: sign(&transaction, &self.identity.key_pair).as_ref().to_vec(),
+            public_key: self.identity.key_pair.public_key().as_ref().to_vec(),
+            transaction,
+            scheme: SignatureScheme::Ed25519,
+            multisig: None,
+            sender_cache: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::genesis::GenesisConfig;
+
+    #[test]
+    fn hands_out_the_genesis_funds() {
+        let faucet = Faucet::default();
+        let recipient: H160 = [7u8; 20].into();
+        let config = GenesisConfig { faucet_balance: 1500, ..Default::default() };
+        let (_, state) = config.build().unwrap();
+        assert_eq!(state.balance(&faucet.address()), 1500);
+        let mut tx_mempool = Mempool::default();
+
+        assert_eq!(faucet.transfer(&recipient, FAUCET_MAX_AMOUNT + 1, &state, &tx_mempool).err(), Some(FaucetError::InvalidAmount));
+        let first = faucet.transfer(&recipient, 1000, &state, &tx_mempool).unwrap();
+        assert!(first.has_valid_signature());
+        assert!(tx_mempool.insert(first, &state).is_ok());
+        // the second transfer follows the first, which leaves too little for another 1000
+        let second = faucet.transfer(&recipient, 500, &state, &tx_mempool).unwrap();
+        assert_eq!(second.transaction.account_nonce, 2);
+        assert!(tx_mempool.insert(second, &state).is_ok());
+        assert_eq!(faucet.transfer(&recipient, 1, &state, &tx_mempool).err(), Some(FaucetError::Empty));
+    }
+
+    #[test]
+    fn no_faucet_by_default() {
+        let (_, state) = GenesisConfig::default().build().unwrap();
+        assert_eq!(state.balance(&Faucet::default().address()), 0);
+    }
+}
@@ -0,0 +1,132 @@
+//! Fee estimation from the fees of the transactions confirmed in the last blocks of the longest
+//! chain, for the wallets and the transaction generator.
+
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::H256;
+use crate::events::{EventBus, NodeEvent};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Number of blocks at the tip of the longest chain whose fees are tracked.
+pub static FEE_WINDOW: u32 = 20;
+
+pub struct FeeEstimator {
+    window: u32,
+    /// (block hash, fees of its transactions) of the last `window` blocks of the longest chain,
+    /// oldest first.
+    blocks: Mutex<Vec<(H256, Vec<u64>)>>,
+}
+
+impl Default for FeeEstimator {
+    fn default() -> Self {
+        FeeEstimator::new(FEE_WINDOW)
+    }
+}
+
+impl FeeEstimator {
+    pub fn new(window: u32) -> Self {
+        FeeEstimator {
+            window: window.max(1),
+            blocks: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Track the last blocks of the longest chain of `blockchain`, forgetting the ones a reorg
+    /// disconnected. Pruned blocks are skipped.
+    pub fn update(&self, blockchain: &Blockchain) {
+        let mut blocks = self.blocks.lock().unwrap();
+        let height = blockchain.height();
+        let from = (height + 1).saturating_sub(self.window);
+        let mut tracked = Vec::with_capacity(self.window as usize);
+        for hash in blockchain.canonical_range(from, height) {
+            if let Some(known) = blocks.iter().position(|(known, _)| *known == hash) {
+                tracked.push(std::mem::take(&mut blocks[known]));
+            } else if let Some(block) = blockchain.get_block(&hash) {
+                tracked.push((hash, block.content.transactions.iter().map(|tx| tx.transaction.fee).collect()));
+            }
+        }
+        *blocks = tracked;
+    }
+
+    /// The fee for a transaction to be confirmed within `target_blocks` blocks: the median fee
+    /// confirmed in the window for the next block, and lower percentiles the further the target,
+    /// the `50 / target_blocks`th. `None` if no transaction was confirmed in the window.
+    pub fn estimate_fee(&self, target_blocks: u32) -> Option<u64> {
+        let blocks = self.blocks.lock().unwrap();
+        let mut fees: Vec<u64> = blocks.iter().flat_map(|(_, fees)| fees.iter().copied()).collect();
+        if fees.is_empty() {
+            return None;
+        }
+        fees.sort_unstable();
+        // nearest rank
+        let p = 50 / target_blocks.max(1) as usize;
+        let n = fees.len();
+        Some(fees[((p * n).saturating_sub(1) / 100).min(n - 1)])
+    }
+}
+
+/// Keep `estimator` up to date with the longest chain of `blockchain` at every new head.
+pub fn start(events: &Arc<EventBus>, blockchain: &Arc<Blockchain>, estimator: &Arc<FeeEstimator>) {
+    let receiver = events.subscribe();
+    let blockchain = Arc::clone(blockchain);
+    let estimator = Arc::clone(estimator);
+    thread::Builder::new()
+        .name("fee-estimator".to_string())
+        .spawn(move || {
+            for event in receiver.iter() {
+                if let NodeEvent::NewHead { .. } = event {
+                    estimator.update(&blockchain);
+                }
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+    use crate::transaction::SignedTransaction;
+
+    fn block_with_fees(parent: &H256, fees: &[u64]) -> crate::block::Block {
+        let mut block = generate_random_block(parent);
+        for fee in fees {
+            let mut tx = SignedTransaction::default();
+            tx.transaction.fee = *fee;
+            block.content.transactions.push(tx);
+        }
+        block
+    }
+
+    #[test]
+    fn estimates_from_the_window() {
+        let blockchain = Blockchain::new();
+        let estimator = FeeEstimator::new(2);
+        estimator.update(&blockchain);
+        assert_eq!(estimator.estimate_fee(1), None);
+
+        let old = block_with_fees(&blockchain.tip(), &[100, 100]);
+        blockchain.insert(&old, &Default::default());
+        let recent = block_with_fees(&old.hash(), &[1, 2, 3, 4]);
+        blockchain.insert(&recent, &Default::default());
+        let tip = block_with_fees(&recent.hash(), &[5, 6, 7, 8]);
+        blockchain.insert(&tip, &Default::default());
+        estimator.update(&blockchain);
+
+        // the fees of the oldest block fell out of the window
+        assert_eq!(estimator.estimate_fee(1), Some(4));
+        assert_eq!(estimator.estimate_fee(2), Some(2));
+        assert_eq!(estimator.estimate_fee(100), Some(1));
+        assert_eq!(estimator.estimate_fee(0), estimator.estimate_fee(1));
+
+        // a longer fork replaces the fees of the disconnected blocks
+        let fork = block_with_fees(&old.hash(), &[]);
+        blockchain.insert(&fork, &Default::default());
+        let fork_tip = block_with_fees(&fork.hash(), &[]);
+        blockchain.insert(&fork_tip, &Default::default());
+        blockchain.insert(&block_with_fees(&fork_tip.hash(), &[9]), &Default::default());
+        estimator.update(&blockchain);
+        assert_eq!(estimator.estimate_fee(1), Some(9));
+    }
+}
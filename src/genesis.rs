@@ -0,0 +1,220 @@
+use serde::{Serialize, Deserialize};
+use crate::block::{Block, Header, Content, State, AccountState, Model, Utxo, DEFAULT_GAS_LIMIT, INIT_COINS};
+use crate::crypto::address::H160;
+use crate::crypto::hash::{self, H256, HashFunction};
+use crate::crypto::key_pair;
+use crate::crypto::merkle::EMPTY_ROOT;
+use crate::crypto::trie::SparseMerkleTrie;
+use crate::transaction::OutPoint;
+use ring::signature::KeyPair;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// The well-known key of the faucet account, see `GenesisConfig::faucet_balance`.
+pub static FAUCET_KEY_BYTE: u8 = 255;
+
+/// An account funded at the genesis, given either by its hex address or by the byte of a
+/// well-known `key_pair::frombyte` key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenesisAccount {
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub key_byte: Option<u8>,
+    pub balance: u64,
+}
+
+/// Genesis parameters, loaded from a JSON file such as
+///
+/// ```json
+/// {
+///   "chain_id": 1,
+///   "timestamp": 0,
+///   "difficulty": "0040000000000000000000000000000000000000000000000000000000000000",
+///   "accounts": [ { "key_byte": 0, "balance": 25 }, { "address": "a1b2...", "balance": 100 } ],
+///   "model": "utxo",
+///   "gas_limit": 9000,
+///   "hash": "keccak256",
+///   "faucet_balance": 1000000
+/// }
+/// ```
+///
+/// In the UTXO model, the genesis gives every account one output of its balance, the `i`th
+/// account the output `i` of the default transaction hash.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GenesisConfig {
+    /// Committed to as the nonce of the genesis block, so different chains never share blocks.
+    pub chain_id: u32,
+    pub timestamp: u128,
+    /// Hex encoded target of the genesis block.
+    pub difficulty: String,
+    pub accounts: Vec<GenesisAccount>,
+    /// The account model when absent.
+    #[serde(default)]
+    pub model: Model,
+    /// Gas limit of the genesis block, and so of every block. `DEFAULT_GAS_LIMIT` when absent.
+    #[serde(default = "default_gas_limit")]
+    pub gas_limit: u64,
+    /// Hash function of the chain, `sha256`, `keccak256` or `blake3`. SHA-256 when absent.
+    /// Building the genesis selects it for the node, see `hash::set_hash_function`.
+    #[serde(default)]
+    pub hash: HashFunction,
+    /// Balance of the faucet, the account of the well-known key `FAUCET_KEY_BYTE`, funded after
+    /// the other accounts. No faucet when 0 or absent.
+    #[serde(default)]
+    pub faucet_balance: u64,
+}
+
+fn default_gas_limit() -> u64 {
+    DEFAULT_GAS_LIMIT
+}
+
+impl Default for GenesisConfig {
+    /// The 8 well-known keys of the experiment scripts, with `INIT_COINS` each.
+    fn default() -> Self {
+        GenesisConfig {
+            chain_id: 0,
+            timestamp: 0,
+            difficulty: "0040000000000000000000000000000000000000000000000000000000000000".to_string(),
+            accounts: (0..8).map(|i| GenesisAccount {
+                address: None,
+                key_byte: Some(i),
+                balance: INIT_COINS,
+            }).collect(),
+            model: Model::Account,
+            gas_limit: DEFAULT_GAS_LIMIT,
+            hash: HashFunction::Sha256,
+            faucet_balance: 0,
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+impl GenesisConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path)?;
+        serde_json::from_slice(&bytes).map_err(|e| invalid_data(format!("error parsing genesis file: {}", e)))
+    }
+
+    /// Build the genesis block and state described by the configuration, after selecting the
+    /// hash function of the chain: a node runs the chain of the last genesis it built.
+    pub fn build(&self) -> Result<(Block, State)> {
+        if !self.hash.is_available() {
+            return Err(invalid_data(format!("hash function {:?} requires the node to be built with its feature", self.hash)));
+        }
+        hash::set_hash_function(self.hash);
+        let difficulty: H256 = self.difficulty.parse()
+            .map_err(|e| invalid_data(format!("error parsing genesis difficulty: {}", e)))?;
+        let mut address_list = Vec::new();
+        let mut account_state = SparseMerkleTrie::new();
+        let mut utxos = SparseMerkleTrie::new();
+        let faucet = Some(GenesisAccount {
+            address: None,
+            key_byte: Some(FAUCET_KEY_BYTE),
+            balance: self.faucet_balance,
+        }).filter(|faucet| faucet.balance > 0);
+        for (index, account) in self.accounts.iter().chain(faucet.iter()).enumerate() {
+            let address: H160 = match (&account.address, account.key_byte) {
+                (Some(address), None) => address.parse()
+                    .map_err(|e| invalid_data(format!("error parsing genesis address {}: {}", address, e)))?,
+                (None, Some(byte)) => {
+                    let key_pair = key_pair::frombyte(byte);
+                    ring::digest::digest(&ring::digest::SHA256, key_pair.public_key().as_ref()).into()
+                }
+                _ => return Err(invalid_data("a genesis account needs exactly one of address and key_byte".to_string())),
+            };
+            if address_list.contains(&address) {
+                return Err(invalid_data(format!("duplicate genesis account {}", address)));
+            }
+            address_list.push(address);
+            match self.model {
+                Model::Account => {
+                    account_state.insert(address, AccountState{
+                        balance: account.balance,
+                        ..Default::default()
+                    });
+                }
+                Model::Utxo => {
+                    let outpoint = OutPoint { txid: Default::default(), index: index as u32 };
+                    utxos.insert(outpoint.key(), Utxo { outpoint, owner: address, value: account.balance });
+                }
+            }
+        }
+        let state = State {
+            address_list,
+            account_state,
+            utxos,
+            model: self.model,
+        };
+        let genesis_block = Block {
+            header: Header{
+                parent: Default::default(),
+                nonce: self.chain_id,
+                extra_nonce: 0,
+                difficulty,
+                timestamp: self.timestamp,
+                merkle_root: EMPTY_ROOT,
+                state_root: state.root(),
+                gas_limit: self.gas_limit,
+            },
+            content: Content{
+                transactions: Default::default(),
+            },
+        };
+        Ok((genesis_block, state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::Hashable;
+
+    #[test]
+    fn parse_and_build() {
+        let config: GenesisConfig = serde_json::from_str(r#"{
+            "chain_id": 7,
+            "timestamp": 1000,
+            "difficulty": "00ff000000000000000000000000000000000000000000000000000000000000",
+            "accounts": [
+                { "key_byte": 1, "balance": 50 },
+                { "address": "0102030405060708090a0b0c0d0e0f1011121314", "balance": 3 }
+            ]
+        }"#).unwrap();
+        let (block, state) = config.build().unwrap();
+        assert_eq!(block.header.nonce, 7);
+        assert_eq!(block.header.timestamp, 1000);
+        assert_eq!(state.address_list.len(), 2);
+        let address: H160 = "0102030405060708090a0b0c0d0e0f1011121314".parse().unwrap();
+        assert_eq!(state.address_list[1], address);
+        assert_eq!(state.account_state[&address].balance, 3);
+        assert_eq!(state.account_state[&state.address_list[0]].balance, 50);
+        assert_eq!(config.hash, HashFunction::Sha256);
+        let config: GenesisConfig = serde_json::from_str(r#"{
+            "chain_id": 7, "timestamp": 0, "accounts": [], "hash": "keccak256",
+            "difficulty": "00ff000000000000000000000000000000000000000000000000000000000000"
+        }"#).unwrap();
+        assert_eq!(config.hash, HashFunction::Keccak256);
+    }
+
+    #[test]
+    fn chain_id_changes_genesis() {
+        let mut config = GenesisConfig::default();
+        let (first, _) = config.build().unwrap();
+        config.chain_id = 1;
+        let (second, _) = config.build().unwrap();
+        assert_ne!(first.hash(), second.hash());
+    }
+
+    #[test]
+    fn invalid_accounts() {
+        let mut config = GenesisConfig::default();
+        config.accounts.push(GenesisAccount { address: None, key_byte: Some(0), balance: 1 });
+        assert!(config.build().is_err());
+        config.accounts = vec![GenesisAccount { address: None, key_byte: None, balance: 1 }];
+        assert!(config.build().is_err());
+    }
+}
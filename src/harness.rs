@@ -0,0 +1,194 @@
+//! Runs several full nodes in one process, linked by a `MemoryNetwork`, for integration tests
+//! that check the nodes converge on one chain and agree on the ledger.
+
+use crate::events::EventBus;
+use crate::miner::{Identity, Strategy};
+use crate::network::limits::WORKER_QUEUE_CAPACITY;
+use crate::network::memory::MemoryNetwork;
+use crate::node::{self, Node};
+use crossbeam::channel;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Number of worker threads of each node.
+static HARNESS_WORKERS: usize = 2;
+
+/// The address node `i` registers at on the network of a harness.
+fn node_addr(i: usize) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 16000 + i as u16))
+}
+
+/// Start a node with the well-known key `key_byte`, registered at `addr` on `network`.
+fn start_node(network: &MemoryNetwork, addr: SocketAddr, key_byte: u8, strategy: Strategy) -> Node {
+    let events = Arc::new(EventBus::default());
+    let (msg_tx, msg_rx) = channel::bounded(WORKER_QUEUE_CAPACITY);
+    let server = network.start_server(addr, msg_tx, &events);
+    node::Builder::new(&events, &server, msg_rx, &Arc::new(Identity::new(key_byte)))
+        .workers(HARNESS_WORKERS)
+        .strategy(strategy)
+        .start()
+}
+
+/// A fully connected network of in-process nodes.
+pub struct Harness {
+    pub nodes: Vec<Node>,
+}
+
+impl Harness {
+    /// Start `num_nodes` honest nodes, at most one per funded genesis key, and connect every pair.
+    pub fn new(num_nodes: usize) -> Harness {
+        Harness::with_strategies(&vec![Strategy::Honest; num_nodes])
+    }
+
+    /// Start one node per mining strategy, and connect every pair.
+    pub fn with_strategies(strategies: &[Strategy]) -> Harness {
+        let network = MemoryNetwork::default();
+        let nodes: Vec<Node> = strategies
+            .iter()
+            .enumerate()
+            .map(|(i, strategy)| start_node(&network, node_addr(i), i as u8, *strategy))
+            .collect();
+        for (i, node) in nodes.iter().enumerate() {
+            for j in i + 1..nodes.len() {
+                node.server.connect(node_addr(j)).unwrap();
+            }
+        }
+        Harness { nodes }
+    }
+
+    pub fn start_mining(&self, lambda: u64) {
+        for node in self.nodes.iter() {
+            node.miner.start(lambda);
+        }
+    }
+
+    pub fn start_generating(&self, lambda: u64) {
+        for node in self.nodes.iter() {
+            node.generator.start(lambda);
+        }
+    }
+
+    pub fn stop(&self) {
+        for node in self.nodes.iter() {
+            node.miner.exit();
+            node.generator.exit();
+        }
+    }
+
+    /// Poll `condition` until it holds or `timeout` elapses.
+    pub fn wait_until<F: Fn(&Harness) -> bool>(&self, timeout: Duration, condition: F) -> bool {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if condition(self) {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        condition(self)
+    }
+
+    /// Whether every node has the same tip.
+    pub fn converged(&self) -> bool {
+        let tip = self.nodes[0].blockchain.tip();
+        self.nodes.iter().all(|node| node.blockchain.tip() == tip)
+    }
+
+    /// Whether every node computes the same state at its tip.
+    pub fn ledgers_agree(&self) -> bool {
+        let root = self.nodes[0].blockchain.tip_with_state().1.root();
+        self.nodes.iter().all(|node| node.blockchain.tip_with_state().1.root() == root)
+    }
+
+    pub fn min_height(&self) -> u32 {
+        self.nodes.iter().map(|node| node.blockchain.height()).min().unwrap()
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        // the in-memory links outlive the servers, so the workers are not joined
+        for node in self.nodes.iter() {
+            node.stop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::address::H160;
+    use crate::txgenerator::ValueDistribution;
+
+    #[test]
+    fn nodes_converge() {
+        let harness = Harness::new(3);
+        harness.start_generating(0);
+        // a single miner, so that no tie between forks of the same length is left at the end
+        harness.nodes[0].miner.start(0);
+        assert!(harness.wait_until(Duration::from_secs(60), |h| h.min_height() >= 3));
+        harness.stop();
+        assert!(harness.wait_until(Duration::from_secs(20), |h| h.converged()));
+        assert!(harness.ledgers_agree());
+    }
+
+    #[test]
+    fn miner_pauses_and_resumes() {
+        let harness = Harness::new(3);
+        harness.start_generating(0);
+        let miner = &harness.nodes[0].miner;
+        miner.start(0);
+        assert!(harness.wait_until(Duration::from_secs(60), |h| h.nodes[0].blockchain.height() >= 1));
+        miner.pause();
+        thread::sleep(Duration::from_millis(200));
+        let paused_height = harness.nodes[0].blockchain.height();
+        thread::sleep(Duration::from_millis(500));
+        assert_eq!(harness.nodes[0].blockchain.height(), paused_height);
+
+        miner.set_rate(0);
+        miner.resume();
+        assert!(harness.wait_until(Duration::from_secs(60), |h| h.nodes[0].blockchain.height() > paused_height));
+    }
+
+    #[test]
+    fn generator_pays_targets() {
+        let harness = Harness::new(3);
+        let target: H160 = [9u8; 20].into();
+        let generator = &harness.nodes[0].generator;
+        generator.set_targets(vec![target]);
+        generator.set_values(ValueDistribution::Fixed(1));
+        // the other generators fill the blocks
+        harness.start_generating(0);
+        harness.nodes[0].miner.start(0);
+        assert!(harness.wait_until(Duration::from_secs(60), |h| h.nodes[0].blockchain.tip_with_state().1.balance(&target) >= 1));
+    }
+
+    #[test]
+    fn generator_pipelines_nonces() {
+        let harness = Harness::new(2);
+        harness.nodes[0].generator.start(0);
+        // without blocks, each transaction takes the nonce after the previous one
+        assert!(harness.wait_until(Duration::from_secs(20), |h| h.nodes[0].tx_mempool.lock().unwrap().len() >= 3));
+        harness.stop();
+        let tx_mempool = harness.nodes[0].tx_mempool.lock().unwrap();
+        let mut nonces: Vec<u64> = tx_mempool.iter().map(|tx| tx.transaction.account_nonce).collect();
+        nonces.sort_unstable();
+        assert_eq!(nonces, (1..=nonces.len() as u64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn selfish_miner_withholds() {
+        let harness = Harness::with_strategies(&[Strategy::Selfish, Strategy::Honest, Strategy::Honest]);
+        harness.start_generating(0);
+        harness.nodes[0].miner.start(0);
+        assert!(harness.wait_until(Duration::from_secs(60), |h| h.nodes[0].blockchain.height() >= 1));
+        let withheld = harness.nodes[0].blockchain.get_hash_by_height(1).unwrap();
+        thread::sleep(Duration::from_millis(200));
+        assert!(!harness.nodes[1].blockchain.contains_key(&withheld));
+
+        // a public block of the same height makes the selfish miner release its block to race
+        harness.nodes[1].miner.start(0);
+        assert!(harness.wait_until(Duration::from_secs(60), |h| h.nodes[1].blockchain.contains_key(&withheld)));
+    }
+}
@@ -0,0 +1,146 @@
+//! Confirmation latency of the transactions generated by this node: the time from the creation
+//! of a transaction to its inclusion in the longest chain at the confirmation depth.
+
+use crate::blockchain::Blockchain;
+use crate::clock;
+use crate::crypto::hash::H256;
+use crate::events::{EventBus, Metrics, NodeEvent};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Number of blocks on top of a transaction, its own included, for it to count as confirmed.
+pub static CONFIRMATION_DEPTH: u32 = 6;
+
+pub struct ConfirmationLatency {
+    depth: u32,
+    /// Creation time of the transactions not confirmed yet, in microseconds.
+    pending: Mutex<HashMap<H256, u128>>,
+    /// Confirmation latency of every confirmed transaction, in microseconds.
+    samples: Mutex<Vec<u128>>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LatencySummary {
+    pub depth: u32,
+    pub confirmed: usize,
+    pub pending: usize,
+    pub mean_ms: f64,
+    pub p50_ms: f64,
+    pub p90_ms: f64,
+    pub p99_ms: f64,
+}
+
+impl Default for ConfirmationLatency {
+    fn default() -> Self {
+        ConfirmationLatency::new(CONFIRMATION_DEPTH)
+    }
+}
+
+impl ConfirmationLatency {
+    pub fn new(depth: u32) -> Self {
+        ConfirmationLatency {
+            depth,
+            pending: Mutex::new(HashMap::new()),
+            samples: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Start the clock of a transaction created at `created`.
+    pub fn track(&self, txid: H256, created: u128) {
+        self.pending.lock().unwrap().entry(txid).or_insert(created);
+    }
+
+    /// Record the latency of the pending transactions that reached the confirmation depth.
+    pub fn update(&self, blockchain: &Blockchain, now: u128) {
+        let mut pending = self.pending.lock().unwrap();
+        let mut samples = self.samples.lock().unwrap();
+        pending.retain(|txid, created| {
+            let confirmed = blockchain
+                .get_transaction(txid)
+                .is_some_and(|location| location.confirmations >= self.depth);
+            if confirmed {
+                samples.push(now.saturating_sub(*created));
+            }
+            !confirmed
+        });
+    }
+
+    pub fn summary(&self) -> LatencySummary {
+        let pending = self.pending.lock().unwrap().len();
+        let mut samples = self.samples.lock().unwrap().clone();
+        samples.sort_unstable();
+        let to_ms = |micros: u128| micros as f64 / 1000.0;
+        // nearest rank
+        let percentile = |p: usize| match samples.len() {
+            0 => 0.0,
+            n => to_ms(samples[((p * n).saturating_sub(1) / 100).min(n - 1)]),
+        };
+        let mean_ms = if samples.is_empty() {
+            0.0
+        } else {
+            to_ms(samples.iter().sum::<u128>() / samples.len() as u128)
+        };
+        LatencySummary {
+            depth: self.depth,
+            confirmed: samples.len(),
+            pending,
+            mean_ms,
+            p50_ms: percentile(50),
+            p90_ms: percentile(90),
+            p99_ms: percentile(99),
+        }
+    }
+}
+
+/// Measure the confirmation latency of the transactions announced by `TxGenerated` events into
+/// `metrics`, checking their depth at every new head of `blockchain`.
+pub fn start(events: &Arc<EventBus>, blockchain: &Arc<Blockchain>, metrics: &Arc<Metrics>) {
+    let receiver = events.subscribe();
+    let blockchain = Arc::clone(blockchain);
+    let metrics = Arc::clone(metrics);
+    thread::Builder::new()
+        .name("confirmation-latency".to_string())
+        .spawn(move || {
+            for event in receiver.iter() {
+                match event {
+                    NodeEvent::TxGenerated(txid) => metrics.confirmations.track(txid, clock::now_micros()),
+                    NodeEvent::NewHead { .. } => metrics.confirmations.update(&blockchain, clock::now_micros()),
+                    _ => {}
+                }
+            }
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+    use crate::transaction::SignedTransaction;
+
+    #[test]
+    fn latency_at_depth() {
+        let blockchain = Blockchain::new();
+        let latency = ConfirmationLatency::new(2);
+        let tx = SignedTransaction::default();
+        latency.track(tx.hash(), 1_000);
+        latency.track(H256::default(), 1_000);
+
+        let mut block = generate_random_block(&blockchain.tip());
+        block.content.transactions.push(tx);
+        blockchain.insert(&block, &Default::default());
+        latency.update(&blockchain, 3_000);
+        assert_eq!(latency.summary().confirmed, 0);
+        blockchain.insert(&generate_random_block(&block.hash()), &Default::default());
+        latency.update(&blockchain, 5_000);
+
+        let summary = latency.summary();
+        assert_eq!(summary.confirmed, 1);
+        assert_eq!(summary.pending, 1);
+        assert_eq!(summary.mean_ms, 4.0);
+        assert_eq!(summary.p99_ms, 4.0);
+    }
+}
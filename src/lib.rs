@@ -0,0 +1,38 @@
+//! A Bitcoin-like full node: the blockchain, mempool, P2P network, miner and transaction
+//! generator, composed by `node::Builder`.
+
+#[cfg(test)]
+#[macro_use]
+extern crate hex_literal;
+
+pub mod api;
+pub mod archive;
+pub mod block;
+pub mod blockchain;
+pub mod cli;
+pub mod clock;
+pub mod crypto;
+pub mod events;
+pub mod faucet;
+pub mod fee;
+pub mod genesis;
+#[cfg(any(test, feature = "test-utilities"))]
+pub mod harness;
+pub mod latency;
+pub mod light;
+pub mod mempool;
+pub mod miner;
+pub mod network;
+pub mod node;
+pub mod notify;
+pub mod orphan;
+pub mod pow;
+pub mod receipt;
+pub mod schema;
+pub mod shutdown;
+pub mod snapshot;
+pub mod stratum;
+pub mod template;
+pub mod transaction;
+pub mod txgenerator;
+pub mod wallet;
@@ -0,0 +1,329 @@
+use crate::block::{AccountProof, AccountState, Header, MerkleMultiProof, MerkleProof};
+use crate::crypto::address::H160;
+use crate::crypto::trie;
+use crate::crypto::hash::{H256, Hashable, Work};
+use crate::crypto::merkle;
+use log::{debug, info};
+use std::collections::HashMap;
+
+/// Maximum number of headers whose parent is unknown kept by a `HeaderChain`.
+pub static ORPHAN_HEADERS_CAPACITY: usize = 1024;
+
+/// The outcome of `HeaderChain::insert`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HeaderInsert {
+    /// The header and the orphans waiting for it were connected.
+    Connected,
+    /// The parent is unknown: the header is kept until the parent arrives.
+    Orphan,
+    /// Already known, or failing the proof of work.
+    Rejected,
+}
+
+/// The chain of a light client: block headers only, validated for proof of work and linkage.
+/// Transactions are checked by merkle proofs against the headers instead of being replayed.
+pub struct HeaderChain {
+    headers: HashMap<H256, Header>,
+    /// 0 for the genesis
+    heights: HashMap<H256, u32>,
+    /// Work of the chain ending at each header, see `Header::work`.
+    work: HashMap<H256, Work>,
+    tip: H256,
+    // parent -> headers waiting for it
+    orphans: HashMap<H256, Vec<Header>>,
+    num_orphans: usize,
+    // txid -> block of the transactions proven by `record_proof` and `record_multiproof`
+    proven: HashMap<H256, H256>,
+    // (block, address) -> account of the accounts proven by `record_account_proof`
+    proven_accounts: HashMap<(H256, H160), Option<AccountState>>,
+}
+
+impl HeaderChain {
+    pub fn new(genesis: Header) -> Self {
+        let hash = genesis.hash();
+        let mut headers = HashMap::new();
+        headers.insert(hash, genesis);
+        let mut heights = HashMap::new();
+        heights.insert(hash, 0);
+        let mut work = HashMap::new();
+        work.insert(hash, genesis.work());
+        HeaderChain {
+            headers,
+            heights,
+            work,
+            tip: hash,
+            orphans: HashMap::new(),
+            num_orphans: 0,
+            proven: HashMap::new(),
+            proven_accounts: HashMap::new(),
+        }
+    }
+
+    pub fn tip(&self) -> H256 {
+        self.tip
+    }
+
+    /// Height of the tip, the genesis being at height 0.
+    pub fn height(&self) -> u32 {
+        self.heights[&self.tip]
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<Header> {
+        self.headers.get(hash).copied()
+    }
+
+    pub fn get_height(&self, hash: &H256) -> Option<u32> {
+        self.heights.get(hash).copied()
+    }
+
+    pub fn contains_key(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash) || self.orphans.values().flatten().any(|h| h.hash() == *hash)
+    }
+
+    /// Insert a header. Like a full node, a header must have the difficulty of its parent and a
+    /// hash under it; the chain with the most work wins.
+    pub fn insert(&mut self, header: Header) -> HeaderInsert {
+        let hash = header.hash();
+        if self.contains_key(&hash) {
+            return HeaderInsert::Rejected;
+        }
+        let parent = match self.headers.get(&header.parent) {
+            Some(parent) => *parent,
+            None => {
+                if self.num_orphans >= ORPHAN_HEADERS_CAPACITY {
+                    debug!("Orphan headers full, dropping {}", hash);
+                    return HeaderInsert::Rejected;
+                }
+                self.orphans.entry(header.parent).or_default().push(header);
+                self.num_orphans += 1;
+                return HeaderInsert::Orphan;
+            }
+        };
+        if !header.meets_difficulty(Some(&parent)) {
+            debug!("Header {} fails the proof of work", hash);
+            return HeaderInsert::Rejected;
+        }
+        self.connect(hash, header);
+
+        // connect the orphans waiting for this header
+        let mut waiting = vec![hash];
+        while let Some(parent_hash) = waiting.pop() {
+            let parent = self.headers[&parent_hash];
+            for orphan in self.orphans.remove(&parent_hash).unwrap_or_default() {
+                self.num_orphans -= 1;
+                let orphan_hash = orphan.hash();
+                if orphan.meets_difficulty(Some(&parent)) {
+                    self.connect(orphan_hash, orphan);
+                    waiting.push(orphan_hash);
+                }
+            }
+        }
+        HeaderInsert::Connected
+    }
+
+    fn connect(&mut self, hash: H256, header: Header) {
+        let height = self.heights[&header.parent] + 1;
+        let work = self.work[&header.parent].saturating_add(header.work());
+        self.headers.insert(hash, header);
+        self.heights.insert(hash, height);
+        self.work.insert(hash, work);
+        if work > self.work[&self.tip] {
+            self.tip = hash;
+            info!("Header chain: tip {} at height {}", hash, height);
+        }
+    }
+
+    /// Number of blocks on top of and including `hash` if it is in the longest chain, 0 otherwise.
+    pub fn confirmations(&self, hash: &H256) -> u32 {
+        let height = match self.heights.get(hash) {
+            Some(height) => *height,
+            None => return 0,
+        };
+        let mut ancestor = self.tip;
+        while self.heights[&ancestor] > height {
+            ancestor = self.headers[&ancestor].parent;
+        }
+        if ancestor == *hash {
+            self.height() - height + 1
+        } else {
+            0
+        }
+    }
+
+    /// Check that the transaction `txid` is the `index`-th of the `leaf_size` transactions
+    /// committed to by the header of `block`.
+    pub fn verify_inclusion(&self, block: &H256, txid: &H256, proof: &[H256], index: usize, leaf_size: usize) -> bool {
+        match self.headers.get(block) {
+            Some(header) => merkle::verify(&header.merkle_root, txid, proof, index, leaf_size),
+            None => false,
+        }
+    }
+
+    /// Verify a proof received from a full node and remember the block of the transaction.
+    pub fn record_proof(&mut self, proof: &MerkleProof) -> bool {
+        if !self.verify_inclusion(&proof.block_hash, &proof.txid, &proof.proof, proof.index, proof.leaf_size) {
+            return false;
+        }
+        self.proven.insert(proof.txid, proof.block_hash);
+        true
+    }
+
+    /// Verify a proof of several transactions received from a full node and remember their block.
+    pub fn record_multiproof(&mut self, proof: &MerkleMultiProof) -> bool {
+        let header = match self.headers.get(&proof.block_hash) {
+            Some(header) => header,
+            None => return false,
+        };
+        if proof.txids.len() != proof.indices.len() {
+            return false;
+        }
+        let data: Vec<(usize, H256)> = proof.indices.iter().copied().zip(proof.txids.iter().copied()).collect();
+        if !merkle::verify_multi(&header.merkle_root, &data, &proof.proof, proof.leaf_size) {
+            return false;
+        }
+        for txid in proof.txids.iter() {
+            self.proven.insert(*txid, proof.block_hash);
+        }
+        true
+    }
+
+    /// The block of a transaction proven by `record_proof` or `record_multiproof`.
+    pub fn proven_block(&self, txid: &H256) -> Option<H256> {
+        self.proven.get(txid).copied()
+    }
+
+    /// Verify an account proof received from a full node against the state root of the block.
+    pub fn record_account_proof(&mut self, proof: &AccountProof) -> bool {
+        let header = match self.headers.get(&proof.block_hash) {
+            Some(header) => header,
+            None => return false,
+        };
+        if !trie::verify(&header.state_root, &proof.address, proof.account.as_ref(), &proof.proof) {
+            return false;
+        }
+        self.proven_accounts.insert((proof.block_hash, proof.address), proof.account.clone());
+        true
+    }
+
+    /// The account of `address` after `block`, if proven by `record_account_proof`. The inner
+    /// `None` is a proven absence.
+    pub fn proven_account(&self, block: &H256, address: &H160) -> Option<Option<AccountState>> {
+        self.proven_accounts.get(&(*block, *address)).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::{Block, Content};
+    use crate::crypto::merkle::MerkleTree;
+    use crate::genesis::GenesisConfig;
+    use crate::transaction::SignedTransaction;
+
+    fn chain() -> HeaderChain {
+        let (genesis, _) = GenesisConfig::default().build().unwrap();
+        HeaderChain::new(genesis.header)
+    }
+
+    fn mine(headers: &HeaderChain, parent: &H256, merkle_root: H256) -> Header {
+        let parent_header = headers.get(parent).unwrap();
+        let mut header = Header {
+            parent: *parent,
+            difficulty: parent_header.difficulty,
+            gas_limit: parent_header.gas_limit,
+            merkle_root,
+            ..Default::default()
+        };
+        while header.hash() > header.difficulty {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    #[test]
+    fn linkage_and_orphans() {
+        let mut headers = chain();
+        let genesis = headers.tip();
+        let a = mine(&headers, &genesis, H256::default());
+        let mut b = a;
+        b.parent = a.hash();
+        while b.hash() > b.difficulty {
+            b.nonce += 1;
+        }
+        assert_eq!(headers.insert(b), HeaderInsert::Orphan);
+        assert_eq!(headers.height(), 0);
+        assert_eq!(headers.insert(a), HeaderInsert::Connected);
+        assert_eq!((headers.tip(), headers.height()), (b.hash(), 2));
+        assert_eq!(headers.insert(a), HeaderInsert::Rejected);
+        assert_eq!(headers.confirmations(&a.hash()), 2);
+
+        let mut unsolved = mine(&headers, &genesis, a.hash());
+        while unsolved.hash() <= unsolved.difficulty {
+            unsolved.nonce += 1;
+        }
+        assert_eq!(headers.insert(unsolved), HeaderInsert::Rejected);
+        let fork = mine(&headers, &genesis, a.hash());
+        assert_eq!(headers.insert(fork), HeaderInsert::Connected);
+        assert_eq!(headers.tip(), b.hash());
+        assert_eq!(headers.confirmations(&fork.hash()), 0);
+    }
+
+    #[test]
+    fn inclusion_proof() {
+        let mut headers = chain();
+        let txs: Vec<SignedTransaction> = (1..=3).map(|value| {
+            let mut tx: SignedTransaction = Default::default();
+            tx.transaction.outputs = vec![(H160::default(), value)];
+            tx
+        }).collect();
+        let tree = MerkleTree::new(&txs);
+        let header = mine(&headers, &headers.tip(), tree.root());
+        assert_eq!(headers.insert(header), HeaderInsert::Connected);
+        let hash = header.hash();
+        assert!(headers.verify_inclusion(&hash, &txs[1].hash(), &tree.proof(1), 1, 3));
+        assert!(!headers.verify_inclusion(&hash, &txs[1].hash(), &tree.proof(1), 2, 3));
+        assert!(!headers.verify_inclusion(&H256::default(), &txs[1].hash(), &tree.proof(1), 1, 3));
+
+        // the proof a full node serves for the block
+        let block = Block {
+            header,
+            content: Content::new(txs.clone()),
+        };
+        let mut proof = block.merkle_proof(&txs[2].hash()).unwrap();
+        assert_eq!((proof.index, proof.leaf_size), (2, 3));
+        proof.txid = txs[0].hash();
+        assert!(!headers.record_proof(&proof));
+        proof.txid = txs[2].hash();
+        assert!(headers.record_proof(&proof));
+        assert_eq!(headers.proven_block(&txs[2].hash()), Some(hash));
+        assert!(block.merkle_proof(&H256::default()).is_none());
+
+        let mut multiproof = block.merkle_multiproof(&[txs[0].hash(), txs[1].hash()]).unwrap();
+        assert_eq!(multiproof.indices, vec![0, 1]);
+        multiproof.txids.swap(0, 1);
+        assert!(!headers.record_multiproof(&multiproof));
+        multiproof.txids.swap(0, 1);
+        assert!(headers.record_multiproof(&multiproof));
+        assert_eq!(headers.proven_block(&txs[0].hash()), Some(hash));
+        assert_eq!(headers.proven_block(&txs[1].hash()), Some(hash));
+        assert!(block.merkle_multiproof(&[txs[0].hash(), H256::default()]).is_none());
+    }
+
+    #[test]
+    fn account_proof() {
+        let (genesis, state) = GenesisConfig::default().build().unwrap();
+        let mut headers = HeaderChain::new(genesis.header);
+        let address = state.address_list[0];
+        let mut proof = AccountProof {
+            block_hash: genesis.hash(),
+            address,
+            account: state.account_state.get(&address).cloned(),
+            proof: state.account_proof(&address),
+        };
+        proof.account.as_mut().unwrap().balance += 1;
+        assert!(!headers.record_account_proof(&proof));
+        proof.account.as_mut().unwrap().balance -= 1;
+        assert!(headers.record_account_proof(&proof));
+        assert_eq!(headers.proven_account(&genesis.hash(), &address), Some(state.account_state.get(&address).cloned()));
+    }
+}
@@ -1,36 +1,35 @@
-#[cfg(test)]
-#[macro_use]
-extern crate hex_literal;
-
-pub mod api;
-pub mod block;
-pub mod blockchain;
-pub mod crypto;
-pub mod miner;
-pub mod network;
-pub mod transaction;
-pub mod txgenerator;
-
+use bitcoin::api::{self, Server as ApiServer};
+use bitcoin::archive;
+use bitcoin::blockchain::{self, Blockchain, MIN_PRUNE_DEPTH};
+use bitcoin::cli;
+use bitcoin::crypto::hash::H256;
+use bitcoin::events::{self, EventBus, Metrics};
+use bitcoin::faucet::Faucet;
+use bitcoin::genesis::GenesisConfig;
+use bitcoin::latency;
+use bitcoin::light::HeaderChain;
+use bitcoin::mempool::{self, Mempool, TX_MEMPOOL_CAPACITY};
+use bitcoin::miner::{Identity, Strategy};
+use bitcoin::network::compression::Compression;
+use bitcoin::network::limits::{Limits, WORKER_QUEUE_CAPACITY};
+use bitcoin::network::peer::{self, PublicKey, StaticKey};
+use bitcoin::network::shim::{Latency, LinkConditions, Shim};
+use bitcoin::network::{light_worker, nat, server};
+use bitcoin::node;
+use bitcoin::orphan::OrphanPool;
+use bitcoin::shutdown::{self, Shutdown};
+use bitcoin::snapshot::{self, CHECKPOINT_INTERVAL};
+use bitcoin::stratum;
+use bitcoin::wallet::Wallet;
 use clap::clap_app;
 use crossbeam::channel;
 use log::{error, info};
-use api::Server as ApiServer;
-use network::{server, worker};
 use std::net;
 use std::process;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
 
-use crate::blockchain::{Blockchain};
-use crate::crypto::hash::{H256};
-use crate::transaction::{SignedTransaction};
-use crate::miner::Identity;
-//use crate::crypto::address::{H160};
-use std::sync::{Arc,Mutex};
-use log::debug;
-
-use std::collections::{HashMap};
-
 fn main() {
     // parse command line arguments
     let matches = clap_app!(Bitcoin =>
@@ -39,11 +38,118 @@ fn main() {
      (@arg verbose: -v ... "Increases the verbosity of logging")
      (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
-     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg ws_addr: --ws [ADDR] "Sets the IP address and the port of the WebSocket subscription server")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start, as ADDR or KEY@ADDR to require the hex static key KEY")
+     (@arg p2p_key: --("p2p-key") [FILE] "Loads the static key authenticating this node to its peers from FILE, creating it if missing (defaults to a new key)")
+     (@arg libp2p: --libp2p "Runs the P2P server on libp2p, with a peer id derived from the static key, instead of TCP (needs the libp2p feature)")
+     (@arg no_port_mapping: --("no-port-mapping") "Never maps the P2P port on the router with UPnP or NAT-PMP, so that a node behind a NAT stays outbound-only")
+     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of block and of transaction worker threads for P2P server")
+     (@arg genesis: --genesis [FILE] "Loads the genesis accounts, difficulty and chain id from a JSON file")
+     (@arg keystore: --keystore [DIR] "Loads the node identity from the encrypted keystore in DIR, creating a key if it is empty")
+     (@arg passphrase: --passphrase [PASS] "Sets the keystore passphrase (defaults to the PRISM_PASSPHRASE environment variable)")
+     (@arg new_mnemonic: --("new-mnemonic") "Creates the empty keystore from a new mnemonic phrase, printed once")
+     (@arg restore_mnemonic: --("restore-mnemonic") [PHRASE] conflicts_with[new_mnemonic] "Restores the empty keystore, and so the identity and HD accounts, from a mnemonic phrase")
+     (@arg hd_accounts: --("hd-accounts") [INT] default_value("0") "Also generates transactions from the first INT HD accounts of the keystore, deriving them if needed")
+     (@arg faucet: --faucet "Hands out the coins of the faucet account of the genesis through the API server")
+     (@arg generator_keys: --("generator-keys") [BYTES] "Also generates transactions from the well-known keys of these comma separated bytes, as funded by the genesis")
+     (@arg confirmation_depth: --("confirmation-depth") [INT] default_value("6") "Sets the depth at which the latency of a generated transaction is measured")
+     (@arg selfish: --selfish "Withholds the mined blocks and releases them strategically (selfish mining)")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of PoW mining threads")
+     (@arg min_fee: --("min-fee") [FEE] default_value("0") "Refuses the transactions paying less than FEE, and asks the peers not to relay them")
+     (@arg mempool_file: --("mempool-file") [FILE] "Saves the transaction mempool to FILE on shutdown and reloads it at start")
+     (@arg import_chain: --("import-chain") [FILE] "Replays the blocks of a block file written by the export command at start")
+     (@arg export_dir: --("export-dir") [DIR] "Lets the export command write block files into DIR, and nowhere else (no export when absent)")
+     (@arg reindex: --reindex "Rebuilds the states, heights and indices at start by replaying the loaded blocks through validation")
+     (@arg snapshot_file: --("snapshot-file") [FILE] "Saves the latest checkpoint to FILE on shutdown and starts from it at start")
+     (@arg prune: --prune [DEPTH] "Discards the blocks and states more than DEPTH blocks deep, keeping their headers and the latest checkpoint")
+     (@arg tx_batch: --("tx-batch") [INT] default_value("1") "Pays INT recipients in each generated transaction")
+     (@arg fast_sync: --("fast-sync") "Starts from the latest checkpoint of a peer when far behind, instead of downloading all the blocks")
+     (@arg stratum_addr: --stratum [ADDR] "Sets the IP address and the port of the stratum server for external miners")
+     (@arg stratum_share_target: --("stratum-share-target") [HEX] "Sets the hash target of a stratum share (defaults to the block difficulty)")
+     (@arg light: --light "Runs a light client, keeping only the block headers")
+     (@arg orphan_capacity: --("orphan-capacity") [INT] default_value("1024") "Sets the maximum number of orphan blocks kept")
+     (@arg orphan_ttl: --("orphan-ttl") [SECS] default_value("600") "Sets how long an orphan block is kept before eviction")
+     (@arg compress_above: --("compress-above") [BYTES] default_value("1024") "Compresses the messages of at least BYTES sent to the peers that support it")
+     (@arg no_compression: --("no-compression") "Never compresses the messages sent to the peers")
+     (@arg max_message_size: --("max-message-size") [BYTES] default_value("16777216") "Disconnects the peers sending a message larger than BYTES")
+     (@arg peer_rate: --("peer-rate") [MSGS] default_value("1000") "Sets the messages per second a peer can sustain before its messages are dropped")
+     (@arg peer_burst: --("peer-burst") [MSGS] default_value("5000") "Sets the messages a peer can send at once above its rate")
+     (@arg max_inbound: --("max-inbound") [INT] default_value("128") "Sets the maximum number of peers connected to this node")
+     (@arg max_outbound: --("max-outbound") [INT] default_value("128") "Sets the maximum number of peers this node connects to")
+     (@arg peer_read_buffer: --("peer-read-buffer") [BYTES] default_value("8192") "Sets the size of the buffer each peer connection is read through")
+     (@arg peer_write_buffer: --("peer-write-buffer") [BYTES] default_value("8192") "Sets the size of the buffer each peer connection is written through")
+     (@arg latency: --latency [MS] default_value("0") "Delays each message to a peer by MS milliseconds on average")
+     (@arg latency_distribution: --("latency-distribution") [DIST] default_value("fixed") possible_values(&["fixed", "uniform", "exponential"]) "Sets the distribution of the message delays")
+     (@arg loss: --loss [PROB] default_value("0") "Drops each message to a peer with probability PROB")
+     (@arg orphan_memory: --("orphan-memory") [BYTES] default_value("16777216") "Sets the memory budget of the orphan block pool")
+     (@subcommand cli =>
+      (about: "Controls a running node through its API server")
+      (@arg node: --node [ADDR] default_value("127.0.0.1:7000") "Sets the API server address of the node")
+      (@subcommand miner =>
+       (about: "Controls the miner")
+       (@subcommand start => (about: "Starts the miner") (@arg lambda: +required "Sets the mining interval lambda"))
+       (@subcommand stop => (about: "Stops the miner and the tx generator"))
+       (@subcommand rate => (about: "Changes the mining interval lambda without stopping") (@arg lambda: +required "Sets the mining interval lambda"))
+       (@subcommand pause => (about: "Pauses the miner, keeping its lambda"))
+       (@subcommand resume => (about: "Resumes the paused miner")))
+      (@subcommand generator =>
+       (about: "Controls the transaction generator")
+       (@subcommand start => (about: "Starts the tx generator") (@arg lambda: +required "Sets the generation interval lambda"))
+       (@subcommand stop => (about: "Stops the tx generator"))
+       (@subcommand rate => (about: "Changes the generation interval lambda without stopping") (@arg lambda: +required "Sets the generation interval lambda"))
+       (@subcommand pause => (about: "Pauses the tx generator, keeping its lambda"))
+       (@subcommand resume => (about: "Resumes the paused tx generator"))
+       (@subcommand targets => (about: "Pays these addresses only, every address when none is given") (@arg addresses: ... "Sets the hex addresses"))
+       (@subcommand burst => (about: "Generates transactions back to back") (@arg count: +required "Sets the number of transactions")))
+      (@subcommand submit => (about: "Submits a hex encoded signed transaction") (@arg tx: +required "Sets the raw transaction"))
+      (@subcommand unsigned =>
+       (about: "Builds an unsigned transfer at the tip, for an external signer")
+       (@arg from: +required "Sets the hex address of the sender")
+       (@arg to: +required "Sets the hex address of the recipient")
+       (@arg value: +required "Sets the value")
+       (@arg fee: --fee [INT] "Sets the fee (defaults to 0)"))
+      (@subcommand sign =>
+       (about: "Signs an unsigned transaction with a key of a keystore, without contacting the node")
+       (@arg keystore: --keystore +required [DIR] "Sets the keystore holding the key")
+       (@arg passphrase: --passphrase [PASS] "Sets the keystore passphrase (defaults to the PRISM_PASSPHRASE environment variable)")
+       (@arg from: +required "Sets the hex address of the key")
+       (@arg tx: +required "Sets the hex encoded unsigned transaction"))
+      (@subcommand assemble =>
+       (about: "Submits an unsigned transaction along with its detached signature")
+       (@arg tx: +required "Sets the hex encoded unsigned transaction")
+       (@arg signature: +required "Sets the hex signature")
+       (@arg public_key: +required "Sets the hex public key of the signer")
+       (@arg scheme: --scheme [SCHEME] default_value("ed25519") possible_values(&["ed25519", "secp256k1"]) "Sets the signature scheme"))
+      (@subcommand balance => (about: "Queries the balance of an address at the tip") (@arg address: +required "Sets the hex address"))
+      (@subcommand transaction => (about: "Looks up the block containing a transaction") (@arg txid: +required "Sets the hex transaction hash"))
+      (@subcommand block =>
+       (about: "Dumps the block at a height of the longest chain")
+       (@arg height: +required "Sets the block height")
+       (@arg full: --full "Dumps the whole block, transactions included, as JSON"))
+      (@subcommand state => (about: "Dumps the state after the block at a height of the longest chain as JSON") (@arg height: +required "Sets the block height"))
+      (@subcommand tip => (about: "Dumps the tip of the longest chain"))
+      (@subcommand forks => (about: "Dumps the fork and stale block statistics"))
+      (@subcommand faucet =>
+       (about: "Asks the faucet of the node for coins")
+       (@arg address: +required "Sets the hex address to fund")
+       (@arg amount: +required "Sets the amount"))
+      (@subcommand dot => (about: "Dumps the block tree, forks included, as a Graphviz DOT file"))
+      (@subcommand export =>
+       (about: "Writes the blocks of the longest chain to a block file in the export directory of the node")
+       (@arg file: +required "Sets the name of the block file")
+       (@arg from: default_value("1") "Sets the first height")
+       (@arg to: default_value("4294967295") "Sets the last height (defaults to the tip)")
+       (@arg json: --json "Writes one JSON block per line instead, which cannot be imported back"))
+      (@subcommand fee => (about: "Estimates the fee for a confirmation within a number of blocks") (@arg target: default_value("1") "Sets the number of blocks"))
+      (@subcommand peers => (about: "Lists the connected peers and their statistics")))
     )
     .get_matches();
 
+    // run a cli command against a running node instead of starting one
+    if let Some(cli_matches) = matches.subcommand_matches("cli") {
+        process::exit(cli::run(cli_matches));
+    }
+
     // init logger
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
@@ -69,108 +175,521 @@ fn main() {
         });
 
     // create channels between server and worker
-    let (msg_tx, msg_rx) = channel::unbounded();
+    let (msg_tx, msg_rx) = channel::bounded(WORKER_QUEUE_CAPACITY);
+
+    // create the event bus, and its logger and metrics subscribers
+    let events = Arc::new(EventBus::default());
+    events::start_logger(&events);
+    let confirmation_depth = matches
+        .value_of("confirmation_depth")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing confirmation depth: {}", e);
+            process::exit(1);
+        });
+    let metrics = events::start_metrics(&events, confirmation_depth);
 
     // start the p2p server
-    let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    let shim = Shim::new(parse_link_conditions(&matches));
+    let p2p_key = match matches.value_of("p2p_key") {
+        Some(path) => StaticKey::load_or_create(std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading P2P key from {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => StaticKey::generate(),
+    };
+    info!("P2P static key {}", hex::encode(p2p_key.public));
+    // make the p2p server reachable from outside the NAT, if any, else stay outbound-only
+    let port_mapping = if matches.is_present("no_port_mapping") {
+        None
+    } else {
+        nat::start(p2p_addr)
+    };
+    let server = if matches.is_present("libp2p") {
+        start_libp2p(p2p_addr, msg_tx, &events, shim, parse_limits(&matches), &p2p_key)
+    } else {
+        let (mut server_ctx, server) =
+            server::new(p2p_addr, msg_tx, &events, shim, parse_limits(&matches), p2p_key, parse_compression(&matches, &metrics))
+                .unwrap();
+        if let Some(mapping) = &port_mapping {
+            server_ctx.set_external_addr(mapping.external);
+        }
+        server_ctx.start().unwrap();
+        server
+    };
 
     // initialize public/private key pair
     let id: Arc<Identity>;
-    let p2p_addr_str = matches.value_of("peer_addr").unwrap();
-
-    if p2p_addr_str == "127.0.0.1:6000" {
-        id = Arc::new(Identity::new(0 as u8));
+    let mut hd_accounts: Vec<Arc<Identity>> = Vec::new();
+    let hd_account_count = matches.value_of("hd_accounts").unwrap().parse::<u32>().unwrap_or_else(|e| {
+        error!("Error parsing the number of HD accounts: {}", e);
+        process::exit(1);
+    });
+    if (hd_account_count > 0 || matches.is_present("new_mnemonic") || matches.is_present("restore_mnemonic"))
+        && matches.value_of("keystore").is_none() {
+        error!("HD accounts and mnemonics need a keystore");
+        process::exit(1);
     }
-    else if p2p_addr_str == "127.0.0.1:6001" {
-        id = Arc::new(Identity::new(1 as u8));
+    if let Some(keystore_dir) = matches.value_of("keystore") {
+        let passphrase = match matches.value_of("passphrase") {
+            Some(p) => p.to_string(),
+            None => std::env::var("PRISM_PASSPHRASE").unwrap_or_default(),
+        };
+        let mut wallet = Wallet::open(std::path::Path::new(keystore_dir), &passphrase).unwrap_or_else(|e| {
+            error!("Error loading keystore {}: {}", keystore_dir, e);
+            process::exit(1);
+        });
+        let mnemonic = if matches.is_present("new_mnemonic") {
+            wallet.new_mnemonic().map(|phrase| println!("Mnemonic of keystore {}, write it down: {}", keystore_dir, phrase))
+        } else if let Some(phrase) = matches.value_of("restore_mnemonic") {
+            wallet.restore_mnemonic(phrase)
+        } else {
+            Ok(())
+        };
+        if let Err(e) = mnemonic {
+            error!("Error setting the mnemonic of keystore {}: {}", keystore_dir, e);
+            process::exit(1);
+        }
+        let identity = wallet.identity().unwrap_or_else(|e| {
+            error!("Error loading identity from keystore {}: {}", keystore_dir, e);
+            process::exit(1);
+        });
+        info!("Loaded identity {:#} from keystore", identity.address);
+        id = Arc::new(identity);
+        let addresses = wallet.hd_accounts(hd_account_count).unwrap_or_else(|e| {
+            error!("Error deriving HD accounts in keystore {}: {}", keystore_dir, e);
+            process::exit(1);
+        });
+        hd_accounts = addresses.iter()
+            .map(|address| Arc::new(Identity::from_key_pair(wallet.key_pair(address).unwrap())))
+            .collect();
     }
-    else if p2p_addr_str == "127.0.0.1:6002" {
-        id = Arc::new(Identity::new(2 as u8));
+    else {
+        // without a keystore, fall back to the well-known keys funded at the genesis
+        let p2p_addr_str = matches.value_of("peer_addr").unwrap();
+
+        if p2p_addr_str == "127.0.0.1:6000" {
+            id = Arc::new(Identity::new(0 as u8));
+        }
+        else if p2p_addr_str == "127.0.0.1:6001" {
+            id = Arc::new(Identity::new(1 as u8));
+        }
+        else if p2p_addr_str == "127.0.0.1:6002" {
+            id = Arc::new(Identity::new(2 as u8));
+        }
+        else if p2p_addr_str == "127.0.0.1:6003" {
+            id = Arc::new(Identity::new(3 as u8));
+        }
+        else if p2p_addr_str == "127.0.0.1:6004" {
+            id = Arc::new(Identity::new(4 as u8));
+        }
+        else if p2p_addr_str == "127.0.0.1:6005" {
+            id = Arc::new(Identity::new(5 as u8));
+        }
+        else if p2p_addr_str == "127.0.0.1:6006" {
+            id = Arc::new(Identity::new(6 as u8));
+        }
+        else {
+            id = Arc::new(Identity::new(7 as u8));
+        }
     }
-    else if p2p_addr_str == "127.0.0.1:6003" {
-        id = Arc::new(Identity::new(3 as u8));
+
+    // initialize blockchain
+    let genesis_config = match matches.value_of("genesis") {
+        Some(path) => GenesisConfig::load(std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error loading genesis file {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => GenesisConfig::default(),
+    };
+    if !genesis_config.hash.is_available() {
+        error!("Error building genesis: hash function {:?} requires the blake3 feature", genesis_config.hash);
+        process::exit(1);
     }
-    else if p2p_addr_str == "127.0.0.1:6004" {
-        id = Arc::new(Identity::new(4 as u8));
+    let (genesis_block, genesis_state) = genesis_config.build().unwrap_or_else(|e| {
+        error!("Error building genesis: {}", e);
+        process::exit(1);
+    });
+
+    // a light client follows the headers only, without mining nor relaying
+    if matches.is_present("light") {
+        let headers = Arc::new(Mutex::new(HeaderChain::new(genesis_block.header)));
+        let light_worker_ctx = light_worker::new(parse_p2p_workers(&matches), msg_rx, &headers);
+        let workers = light_worker_ctx.start();
+        connect_known_peers(&matches, &server);
+        api::start_light(api_addr, &server, &headers);
+
+        let shutdown = Arc::new(Shutdown::default());
+        stop_network(&shutdown, &server, workers);
+        remove_port_mapping(&shutdown, port_mapping);
+        shutdown::install(&shutdown);
+        loop {
+            std::thread::park();
+        }
     }
-    else if p2p_addr_str == "127.0.0.1:6005" {
-        id = Arc::new(Identity::new(5 as u8));
+
+    let blockchain = Arc::new(Blockchain::from_genesis(genesis_block, genesis_state, &events));
+    if let Some(depth) = matches.value_of("prune") {
+        let depth = depth.parse::<u32>().ok().filter(|depth| *depth >= MIN_PRUNE_DEPTH).unwrap_or_else(|| {
+            error!("Error parsing pruning depth: expected an integer of at least {}", MIN_PRUNE_DEPTH);
+            process::exit(1);
+        });
+        blockchain::start_pruning(&events, &blockchain, depth);
     }
-    else if p2p_addr_str == "127.0.0.1:6006" {
-        id = Arc::new(Identity::new(6 as u8));
+    let snapshot_file = matches.value_of("snapshot_file").map(std::path::PathBuf::from);
+    if let Some(path) = &snapshot_file {
+        match snapshot::load(path) {
+            Ok(Some(snapshot)) if snapshot.verify(&blockchain.tip()) => {
+                blockchain.import_snapshot(&snapshot);
+            }
+            Ok(Some(_)) => {
+                error!("The checkpoint in {} does not extend the genesis", path.display());
+                process::exit(1);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!("Error loading checkpoint file {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
     }
-    else {
-        id = Arc::new(Identity::new(7 as u8));
+
+    if let Some(path) = matches.value_of("import_chain") {
+        archive::import_chain(&blockchain, std::path::Path::new(path)).unwrap_or_else(|e| {
+            error!("Error importing block file {}: {}", path, e);
+            process::exit(1);
+        });
     }
 
-    // initialize blockchain
-    let blockchain = Arc::new(Mutex::new(Blockchain::new()));
+    if matches.is_present("reindex") {
+        let dropped = blockchain.reindex().unwrap_or_else(|e| {
+            error!("Error reindexing the blockchain: {}", e);
+            process::exit(1);
+        });
+        info!("Reindexed the blockchain, {} invalid blocks dropped", dropped);
+    }
 
     // initialize mempool for orphaned blocks
-    let orphan_blocks = Arc::new(Mutex::new(HashMap::<H256,block::Block>::new()));
+    let parse_orphan_arg = |name: &str| {
+        matches
+            .value_of(name)
+            .unwrap()
+            .parse::<usize>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing {}: {}", name, e);
+                process::exit(1);
+            })
+    };
+    let orphan_blocks = OrphanPool::new(
+        parse_orphan_arg("orphan_capacity"),
+        time::Duration::from_secs(parse_orphan_arg("orphan_ttl") as u64),
+        parse_orphan_arg("orphan_memory"),
+    );
 
-    // initialize transaction mempool
-    let tx_mempool = Arc::new(Mutex::new(HashMap::<H256,SignedTransaction>::new()));
+    // initialize transaction mempool, with the transactions saved by the previous run if any
+    let mempool_file = matches.value_of("mempool_file").map(std::path::PathBuf::from);
+    let mut tx_mempool = match &mempool_file {
+        Some(path) => {
+            let (_, tip_state) = blockchain.tip_with_state();
+            mempool::load(path, &tip_state, &events).unwrap_or_else(|e| {
+                error!("Error loading mempool file {}: {}", path.display(), e);
+                process::exit(1);
+            })
+        }
+        None => Mempool::new(TX_MEMPOOL_CAPACITY, &events),
+    };
+    tx_mempool.set_min_fee(matches.value_of("min_fee").unwrap().parse::<u64>().unwrap_or_else(|e| {
+        error!("Error parsing minimum fee: {}", e);
+        process::exit(1);
+    }));
 
-    // initialize variable to record block delay
-    let delay_time_sum = Arc::new(Mutex::new(0));
-    let recv_block_sum = Arc::new(Mutex::new(0));
+    // start the workers, the miner and the TXs generator
+    let miner_threads = matches
+        .value_of("miner_threads")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing miner threads: {}", e);
+            process::exit(1);
+        });
+    let tx_batch = matches.value_of("tx_batch").unwrap().parse::<usize>().unwrap_or_else(|e| {
+        error!("Error parsing transaction batch size: {}", e);
+        process::exit(1);
+    });
+    let mut builder = node::Builder::new(&events, &server, msg_rx, &id)
+        .blockchain(&blockchain)
+        .tx_mempool(tx_mempool)
+        .orphan_blocks(orphan_blocks)
+        .workers(parse_p2p_workers(&matches))
+        .fast_sync(matches.is_present("fast_sync"))
+        .miner_threads(miner_threads)
+        .strategy(if matches.is_present("selfish") { Strategy::Selfish } else { Strategy::Honest })
+        .tx_batch(tx_batch);
+    for account in hd_accounts.into_iter().chain(parse_generator_keys(&matches)) {
+        builder = builder.account(account);
+    }
+    let node = builder.start();
+    latency::start(&events, &blockchain, &metrics);
 
-    // start the TXs generator
-    let (tx_gen_ctx, generator) = txgenerator::new(
-        &server,
-        &blockchain,
-        &tx_mempool,
-        &id,
-    );
-    tx_gen_ctx.start();
+    // start the stratum server for external miners
+    if let Some(stratum_addr) = matches.value_of("stratum_addr") {
+        let stratum_addr = stratum_addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing stratum server address: {}", e);
+            process::exit(1);
+        });
+        let share_target = matches.value_of("stratum_share_target").map(|target| {
+            target.parse::<H256>().unwrap_or_else(|e| {
+                error!("Error parsing stratum share target: {}", e);
+                process::exit(1);
+            })
+        });
+        stratum::start(stratum_addr, &node.miner, share_target).unwrap_or_else(|e| {
+            error!("Error starting stratum server: {}", e);
+            process::exit(1);
+        });
+    }
+
+    connect_known_peers(&matches, &server);
+
+    // start the API server
+    ApiServer::start(api_addr, api::Context {
+        miner: node.miner.clone(),
+        generator: node.generator.clone(),
+        network: server.clone(),
+        blockchain: Arc::clone(&blockchain),
+        tx_mempool: Arc::clone(&node.tx_mempool),
+        fee_estimator: Arc::clone(&node.fee_estimator),
+        faucet: if matches.is_present("faucet") { Some(Arc::new(Faucet::default())) } else { None },
+        metrics: Arc::clone(&metrics),
+        export_dir: matches.value_of("export_dir").map(std::path::PathBuf::from),
+    });
+
+    // start the WebSocket subscription server
+    if let Some(ws_addr) = matches.value_of("ws_addr") {
+        let ws_addr = ws_addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing WebSocket server address: {}", e);
+            process::exit(1);
+        });
+        api::ws::start(ws_addr, &events).unwrap_or_else(|e| {
+            error!("Error starting WebSocket server: {}", e);
+            process::exit(1);
+        });
+    }
+
+    // on SIGINT or SIGTERM, stop producing blocks and transactions, then disconnect the peers
+    // and let the workers finish, then save the mempool
+    let shutdown = Arc::new(Shutdown::default());
+    let tx_mempool = Arc::clone(&node.tx_mempool);
+    shutdown.on_shutdown("node", move || {
+        node.stop();
+        node.join();
+    });
+    remove_port_mapping(&shutdown, port_mapping);
+    if let Some(path) = mempool_file {
+        shutdown.on_shutdown("mempool", move || {
+            // save what is there even if a thread panicked while holding the lock
+            let tx_mempool = tx_mempool.lock().unwrap_or_else(|e| e.into_inner());
+            if let Err(e) = mempool::save(&path, &tx_mempool) {
+                error!("Error saving mempool file {}: {}", path.display(), e);
+            }
+        });
+    }
+    if let Some(path) = snapshot_file {
+        shutdown.on_shutdown("checkpoint", move || {
+            if let Some(snapshot) = blockchain.snapshot(CHECKPOINT_INTERVAL) {
+                if let Err(e) = snapshot::save(&path, &snapshot) {
+                    error!("Error saving checkpoint file {}: {}", path.display(), e);
+                }
+            }
+        });
+    }
+    shutdown::install(&shutdown);
+
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Register the shutdown of the P2P server, then wait for the workers to drain their queue.
+fn stop_network(shutdown: &Shutdown, server: &server::Handle, workers: Vec<thread::JoinHandle<()>>) {
+    let server = server.clone();
+    shutdown.on_shutdown("network", move || server.shutdown());
+    shutdown.on_shutdown("workers", move || {
+        for worker in workers {
+            let _ = worker.join();
+        }
+    });
+}
 
-    // start the worker
-    let p2p_workers = matches
+/// Register the removal of the mapping of the P2P port from the router.
+fn remove_port_mapping(shutdown: &Shutdown, mapping: Option<Arc<nat::Mapping>>) {
+    if let Some(mapping) = mapping {
+        shutdown.on_shutdown("port mapping", move || {
+            if let Err(e) = mapping.remove() {
+                error!("Error removing the port mapping of {}: {}", mapping.external, e);
+            }
+        });
+    }
+}
+
+/// Start the libp2p server instead of the TCP server, see `network::libp2p`.
+#[cfg(feature = "libp2p")]
+fn start_libp2p(
+    addr: net::SocketAddr,
+    msg_tx: channel::Sender<(Vec<u8>, peer::Handle)>,
+    events: &Arc<EventBus>,
+    shim: Shim,
+    limits: Limits,
+    key: &StaticKey,
+) -> server::Handle {
+    bitcoin::network::libp2p::start(addr, msg_tx, events, shim, limits, key).unwrap_or_else(|e| {
+        error!("Error starting the libp2p server: {}", e);
+        process::exit(1);
+    })
+}
+
+#[cfg(not(feature = "libp2p"))]
+fn start_libp2p(
+    _: net::SocketAddr,
+    _: channel::Sender<(Vec<u8>, peer::Handle)>,
+    _: &Arc<EventBus>,
+    _: Shim,
+    _: Limits,
+    _: &StaticKey,
+) -> server::Handle {
+    error!("--libp2p needs a build with the libp2p feature");
+    process::exit(1);
+}
+
+fn parse_limits(matches: &clap::ArgMatches) -> Limits {
+    let parse = |name: &str| {
+        matches
+            .value_of(name)
+            .unwrap()
+            .parse::<f64>()
+            .ok()
+            .filter(|x| *x > 0.0)
+            .unwrap_or_else(|| {
+                error!("Error parsing {}: expected a positive number", name);
+                process::exit(1);
+            })
+    };
+    Limits {
+        max_message_size: parse("max_message_size") as usize,
+        messages_per_sec: parse("peer_rate"),
+        burst: parse("peer_burst"),
+        max_inbound: parse("max_inbound") as usize,
+        max_outbound: parse("max_outbound") as usize,
+        read_buffer: parse("peer_read_buffer") as usize,
+        write_buffer: parse("peer_write_buffer") as usize,
+    }
+}
+
+fn parse_link_conditions(matches: &clap::ArgMatches) -> LinkConditions {
+    let mean = matches
+        .value_of("latency")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing latency: {}", e);
+            process::exit(1);
+        });
+    let mean = time::Duration::from_millis(mean);
+    let latency = match matches.value_of("latency_distribution").unwrap() {
+        "uniform" => Latency::Uniform(time::Duration::from_millis(0), mean * 2),
+        "exponential" => Latency::Exponential(mean),
+        _ => Latency::Fixed(mean),
+    };
+    let loss = matches
+        .value_of("loss")
+        .unwrap()
+        .parse::<f64>()
+        .ok()
+        .filter(|p| (0.0..=1.0).contains(p))
+        .unwrap_or_else(|| {
+            error!("Error parsing loss: expected a probability between 0 and 1");
+            process::exit(1);
+        });
+    LinkConditions { latency, loss }
+}
+
+fn parse_p2p_workers(matches: &clap::ArgMatches) -> usize {
+    matches
         .value_of("p2p_workers")
         .unwrap()
         .parse::<usize>()
         .unwrap_or_else(|e| {
             error!("Error parsing P2P workers: {}", e);
             process::exit(1);
+        })
+}
+
+/// The identities of the well-known keys of `--generator-keys`, see `key_pair::frombyte`.
+fn parse_generator_keys(matches: &clap::ArgMatches) -> Vec<Arc<Identity>> {
+    let keys = match matches.value_of("generator_keys") {
+        Some(keys) => keys,
+        None => return Vec::new(),
+    };
+    keys.split(',')
+        .map(|byte| {
+            let byte = byte.trim().parse::<u8>().unwrap_or_else(|e| {
+                error!("Error parsing generator key {}: {}", byte, e);
+                process::exit(1);
+            });
+            Arc::new(Identity::new(byte))
+        })
+        .collect()
+}
+
+/// The compression of the messages sent to the peers, accounted in `metrics`.
+fn parse_compression(matches: &clap::ArgMatches, metrics: &Metrics) -> Compression {
+    let threshold = if matches.is_present("no_compression") {
+        None
+    } else {
+        let threshold = matches.value_of("compress_above").unwrap().parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing compression threshold: {}", e);
+            process::exit(1);
         });
-    let worker_ctx = worker::new(
-        p2p_workers,
-        msg_rx,
-        &server,
-        &blockchain,
-        &orphan_blocks,
-        &tx_mempool,
-        &delay_time_sum,
-        &recv_block_sum
-    );
-    worker_ctx.start();
-    
-    // start the miner
-    let (miner_ctx, miner) = miner::new(
-        &server,
-        &blockchain,
-        &tx_mempool,
-        &id,
-    );
-    miner_ctx.start();
+        Some(threshold)
+    };
+    Compression::new(threshold, &metrics.compression)
+}
 
-    // connect to known peers
+/// Parse a peer given as ADDR, or as KEY@ADDR with KEY the hex static key of the peer.
+fn parse_known_peer(peer: &str) -> Result<(Option<PublicKey>, net::SocketAddr), String> {
+    let (remote_key, addr) = match peer.split_once('@') {
+        Some((key, addr)) => {
+            let mut remote_key = PublicKey::default();
+            hex::decode_to_slice(key, &mut remote_key).map_err(|e| e.to_string())?;
+            (Some(remote_key), addr)
+        }
+        None => (None, peer),
+    };
+    let addr = addr.parse::<net::SocketAddr>().map_err(|e| e.to_string())?;
+    Ok((remote_key, addr))
+}
+
+/// Connect to the peers given on the command line, retrying each one until it answers.
+fn connect_known_peers(matches: &clap::ArgMatches, server: &server::Handle) {
     if let Some(known_peers) = matches.values_of("known_peer") {
         let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
         let server = server.clone();
         thread::spawn(move || {
             for peer in known_peers {
                 loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
+                    let (remote_key, addr) = match parse_known_peer(&peer) {
                         Ok(x) => x,
                         Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
+                            error!("Error parsing peer {}: {}", &peer, e);
                             break;
                         }
                     };
-                    match server.connect(addr) {
+                    let connected = match remote_key {
+                        Some(remote_key) => server.connect_authenticated(addr, remote_key),
+                        None => server.connect(addr),
+                    };
+                    match connected {
                         Ok(_) => {
                             info!("Connected to outgoing peer {}", &addr);
                             break;
@@ -188,17 +707,4 @@ fn main() {
             }
         });
     }
-
-
-    // start the API server
-    ApiServer::start(
-        api_addr,
-        &miner,
-        &generator,
-        &server,
-    );
-
-    loop {
-        std::thread::park();
-    }
 }
@@ -0,0 +1,676 @@
+use crate::block::{Model, State};
+use crate::clock;
+use crate::crypto::address::H160;
+use crate::crypto::hash::{H256, Hashable};
+use crate::events::{EventBus, NodeEvent};
+use crate::schema::{self, Store};
+use crate::transaction::{OutPoint, SignedTransaction, TxValidationError};
+use log::{debug, info};
+use rand::seq::IteratorRandom;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Number of transactions the mempool holds by default.
+pub static TX_MEMPOOL_CAPACITY: usize = 1000;
+/// A transaction replaces the one of its sender with the same nonce if it pays at least this
+/// percentage more fee.
+pub static MIN_FEE_BUMP_PERCENT: u64 = 10;
+/// Number of dropped transactions whose reason is remembered, see `Mempool::drop_reason`.
+pub static DROPPED_CAPACITY: usize = 10000;
+
+/// Why a transaction left the mempool without being included by the longest chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// By a transaction paying a higher fee for the same nonce or the same outputs.
+    Replaced(H256),
+    /// To make room for another transaction in the full mempool.
+    Evicted,
+    /// Not included before its expiry height.
+    Expired,
+    /// Its nonce or one of its outputs was used by another transaction.
+    Conflicted,
+}
+
+impl std::fmt::Display for DropReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DropReason::Replaced(hash) => write!(f, "replaced by {}", hash),
+            DropReason::Evicted => write!(f, "evicted from the full mempool"),
+            DropReason::Expired => write!(f, "expired"),
+            DropReason::Conflicted => write!(f, "nonce or outputs used by another transaction"),
+        }
+    }
+}
+
+/// The unconfirmed transactions.
+///
+/// A transaction is pending when its nonce follows the nonce of its sender at the tip, or the
+/// nonce of another pending transaction of the same sender; blocks are built from pending
+/// transactions only. A transaction further ahead is queued by sender, and promoted once the
+/// nonces before it are pending or confirmed. A sender has at most one transaction per nonce; a
+/// new one replaces it only if it bumps the fee by `MIN_FEE_BUMP_PERCENT`.
+///
+/// In the UTXO model, the transactions spend confirmed outputs, so they are all pending. An output
+/// is spent by at most one transaction; a new one replaces those spending the same outputs only if
+/// it bumps the fee of each of them.
+pub struct Mempool {
+    capacity: usize,
+    /// Transactions paying a lower fee are refused.
+    min_fee: u64,
+    pending: HashMap<H256, SignedTransaction>,
+    // (sender, nonce) of the pending transactions
+    pending_nonces: HashMap<(H160, u64), H256>,
+    queued: HashMap<H160, BTreeMap<u64, SignedTransaction>>,
+    // hash -> (sender, nonce) of the queued transactions
+    queued_hashes: HashMap<H256, (H160, u64)>,
+    // output -> the pending transaction spending it, in the UTXO model
+    spenders: HashMap<OutPoint, H256>,
+    /// Height of the block after the tip of the last `update`: the transactions it can no
+    /// longer include are refused, and dropped.
+    next_height: u32,
+    /// Gets a `TxAccepted` event for every transaction taken by `insert`, replacements included.
+    events: Arc<EventBus>,
+    // hash -> reason of the last `DROPPED_CAPACITY` dropped transactions, oldest first in the queue
+    dropped: HashMap<H256, DropReason>,
+    dropped_order: VecDeque<H256>,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Mempool::new(TX_MEMPOOL_CAPACITY, &Default::default())
+    }
+}
+
+impl Mempool {
+    pub fn new(capacity: usize, events: &Arc<EventBus>) -> Self {
+        Mempool {
+            capacity,
+            min_fee: 0,
+            pending: HashMap::new(),
+            pending_nonces: HashMap::new(),
+            queued: HashMap::new(),
+            queued_hashes: HashMap::new(),
+            spenders: HashMap::new(),
+            next_height: 1,
+            events: Arc::clone(events),
+            dropped: HashMap::new(),
+            dropped_order: VecDeque::new(),
+        }
+    }
+
+    /// The lowest fee of the transactions taken by `insert`, which the workers ask their peers
+    /// not to relay transactions below.
+    pub fn min_fee(&self) -> u64 {
+        self.min_fee
+    }
+
+    pub fn set_min_fee(&mut self, min_fee: u64) {
+        self.min_fee = min_fee;
+    }
+
+    /// Number of pending and queued transactions.
+    pub fn len(&self) -> usize {
+        self.pending.len() + self.queued_hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn queued_len(&self) -> usize {
+        self.queued_hashes.len()
+    }
+
+    pub fn contains_key(&self, hash: &H256) -> bool {
+        self.pending.contains_key(hash) || self.queued_hashes.contains_key(hash)
+    }
+
+    /// Whether a transaction of `sender` with `nonce` is pending or queued, in the account model.
+    pub fn has_nonce(&self, sender: &H160, nonce: u64) -> bool {
+        self.pending_nonces.contains_key(&(*sender, nonce))
+            || self.queued.get(sender).is_some_and(|queued| queued.contains_key(&nonce))
+    }
+
+    /// Whether a pending transaction spends `outpoint`, in the UTXO model.
+    pub fn is_spent(&self, outpoint: &OutPoint) -> bool {
+        self.spenders.contains_key(outpoint)
+    }
+
+    /// Whether the transaction is pending, rather than queued or unknown.
+    pub fn is_pending(&self, hash: &H256) -> bool {
+        self.pending.contains_key(hash)
+    }
+
+    /// Why the transaction was dropped, if it was among the last `DROPPED_CAPACITY` ones. A
+    /// dropped transaction may have been inserted again since.
+    pub fn drop_reason(&self, hash: &H256) -> Option<DropReason> {
+        self.dropped.get(hash).copied()
+    }
+
+    fn record_drop(&mut self, hash: H256, reason: DropReason) {
+        if self.dropped.insert(hash, reason).is_none() {
+            self.dropped_order.push_back(hash);
+        }
+        if self.dropped_order.len() > DROPPED_CAPACITY {
+            let oldest = self.dropped_order.pop_front().unwrap();
+            self.dropped.remove(&oldest);
+        }
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&SignedTransaction> {
+        match self.queued_hashes.get(hash) {
+            Some((sender, nonce)) => self.queued.get(sender).and_then(|txs| txs.get(nonce)),
+            None => self.pending.get(hash),
+        }
+    }
+
+    /// The transactions blocks can be built from.
+    pub fn pending(&self) -> impl Iterator<Item = &SignedTransaction> {
+        self.pending.values()
+    }
+
+    /// All the transactions, pending then queued.
+    pub fn iter(&self) -> impl Iterator<Item = &SignedTransaction> {
+        self.pending.values().chain(self.queued.values().flat_map(|txs| txs.values()))
+    }
+
+    /// The transaction of `sender` with `nonce`, pending or queued.
+    fn get_by_nonce(&self, sender: &H160, nonce: u64) -> Option<&SignedTransaction> {
+        match self.pending_nonces.get(&(*sender, nonce)) {
+            Some(hash) => self.pending.get(hash),
+            None => self.queued.get(sender).and_then(|txs| txs.get(&nonce)),
+        }
+    }
+
+    /// Insert a transaction received on top of the tip `state`, possibly replacing the transaction
+    /// of the sender with the same nonce, or those spending the same outputs. Callers relay the
+    /// transactions taken, replacements like new transactions. It is refused if it is known, can
+    /// never become valid, has expired, pays less than the minimum fee, or does not bump the fee
+    /// of the transaction it would replace enough.
+    /// When the pool is full, a queued transaction is evicted first, a random pending one otherwise.
+    pub fn insert(&mut self, tx: SignedTransaction, state: &State) -> std::result::Result<(), TxValidationError> {
+        let hash = tx.hash();
+        if self.contains_key(&hash) {
+            return Err(TxValidationError::AlreadyKnown);
+        }
+        if tx.transaction.fee < self.min_fee {
+            return Err(TxValidationError::FeeTooLow);
+        }
+        if tx.transaction.is_expired(self.next_height) {
+            return Err(TxValidationError::Expired);
+        }
+        if let Some(error) = tx.erasable_reason(state) {
+            return Err(error);
+        }
+        let sender = tx.sender();
+        let replaced = match state.model {
+            Model::Account => self.get_by_nonce(&sender, tx.transaction.account_nonce)
+                .map(|old| vec![(old.hash(), old.transaction.fee)]),
+            Model::Utxo => {
+                let spenders: HashSet<H256> = tx.transaction.inputs.iter()
+                    .filter_map(|input| self.spenders.get(input))
+                    .cloned()
+                    .collect();
+                if spenders.is_empty() {
+                    None
+                } else {
+                    Some(spenders.into_iter().map(|hash| (hash, self.pending[&hash].transaction.fee)).collect())
+                }
+            }
+        };
+        if let Some(replaced) = replaced {
+            if !replaced.iter().all(|(_, old_fee)| is_fee_bump(*old_fee, tx.transaction.fee)) {
+                return Err(TxValidationError::FeeTooLow);
+            }
+            for (old_hash, old_fee) in replaced {
+                debug!("Transaction {} replaces {} with fee {} over {}", hash, old_hash, tx.transaction.fee, old_fee);
+                self.discard(&old_hash, DropReason::Replaced(hash));
+            }
+        } else if self.len() >= self.capacity && !self.evict() {
+            return Err(TxValidationError::OverCapacity);
+        }
+        self.events.publish(NodeEvent::TxAccepted(tx.clone()));
+        self.place(hash, tx, state);
+        Ok(())
+    }
+
+    /// Put a transaction whose nonce is ahead of the sender nonce in `state` in the pending or the
+    /// queued pool, promoting the queued transactions that follow it. In the UTXO model, it is
+    /// pending.
+    fn place(&mut self, hash: H256, tx: SignedTransaction, state: &State) {
+        if state.model == Model::Utxo {
+            for input in tx.transaction.inputs.iter() {
+                self.spenders.insert(*input, hash);
+            }
+            self.pending.insert(hash, tx);
+            return;
+        }
+        let sender = tx.sender();
+        let nonce = tx.transaction.account_nonce;
+        let mut next = state.account_state.get(&sender).map_or(0, |account| account.nonce) + 1;
+        while self.pending_nonces.contains_key(&(sender, next)) {
+            next += 1;
+        }
+        if nonce != next {
+            self.queued_hashes.insert(hash, (sender, nonce));
+            self.queued.entry(sender).or_default().insert(nonce, tx);
+            return;
+        }
+        self.pending_nonces.insert((sender, nonce), hash);
+        self.pending.insert(hash, tx);
+
+        // the gap before the queued transactions of the sender may be filled now
+        next += 1;
+        if let Some(txs) = self.queued.get_mut(&sender) {
+            while let Some(tx) = txs.remove(&next) {
+                let hash = tx.hash();
+                self.queued_hashes.remove(&hash);
+                self.pending_nonces.insert((sender, next), hash);
+                self.pending.insert(hash, tx);
+                next += 1;
+            }
+            if txs.is_empty() {
+                self.queued.remove(&sender);
+            }
+        }
+    }
+
+    fn evict(&mut self) -> bool {
+        // the queued transaction furthest ahead of some sender
+        let queued = self.queued.iter().next().map(|(_, txs)| txs.values().next_back().unwrap().hash());
+        let victim = match queued {
+            Some(hash) => Some(hash),
+            None => self.pending.keys().choose(&mut clock::rng()).cloned(),
+        };
+        match victim {
+            Some(hash) => self.discard(&hash, DropReason::Evicted).is_some(),
+            None => false,
+        }
+    }
+
+    /// Remove a transaction that will not be included, remembering why.
+    pub fn discard(&mut self, hash: &H256, reason: DropReason) -> Option<SignedTransaction> {
+        let tx = self.remove(hash)?;
+        self.record_drop(*hash, reason);
+        Some(tx)
+    }
+
+    /// Remove a transaction. The pending transactions of its sender with later nonces are queued
+    /// again, behind the gap it leaves.
+    pub fn remove(&mut self, hash: &H256) -> Option<SignedTransaction> {
+        if let Some(tx) = self.pending.remove(hash) {
+            let sender = tx.sender();
+            if self.pending_nonces.remove(&(sender, tx.transaction.account_nonce)).is_some() {
+                self.demote_successors(&sender, tx.transaction.account_nonce);
+            }
+            for input in tx.transaction.inputs.iter() {
+                self.spenders.remove(input);
+            }
+            return Some(tx);
+        }
+        let (sender, nonce) = self.queued_hashes.remove(hash)?;
+        let txs = self.queued.get_mut(&sender)?;
+        let tx = txs.remove(&nonce);
+        if txs.is_empty() {
+            self.queued.remove(&sender);
+        }
+        tx
+    }
+
+    /// Move the pending transactions of `sender` after `nonce` to the queued ones.
+    fn demote_successors(&mut self, sender: &H160, nonce: u64) {
+        let mut next = nonce + 1;
+        while let Some(hash) = self.pending_nonces.remove(&(*sender, next)) {
+            let tx = self.pending.remove(&hash).unwrap();
+            self.queued_hashes.insert(hash, (*sender, next));
+            self.queued.entry(*sender).or_default().insert(next, tx);
+            next += 1;
+        }
+    }
+
+    /// Follow the tip to `state` at `height`: drop the transactions whose nonce is confirmed, or
+    /// whose sender can no longer afford them, or whose outputs are spent in the UTXO model, or
+    /// which have expired, and sort the others again between pending and queued. The dropped transactions are recorded as conflicted, unless
+    /// expired, even those confirmed themselves: the chain knows those.
+    pub fn update(&mut self, state: &State, height: u32) {
+        self.next_height = height + 1;
+        let mut transactions: Vec<SignedTransaction> = self.pending.drain().map(|(_, tx)| tx).collect();
+        for (_, txs) in self.queued.drain() {
+            transactions.extend(txs.into_values());
+        }
+        self.pending_nonces.clear();
+        self.queued_hashes.clear();
+        self.spenders.clear();
+        // predecessors first, so that chains of nonces end up pending
+        transactions.sort_by_key(|tx| tx.transaction.account_nonce);
+        for tx in transactions {
+            let (confirmed, balance) = state.account_state.get(&tx.sender())
+                .map_or((0, 0), |account| (account.nonce, account.balance));
+            // as checked by `insert`, the balance may have moved since
+            let affordable = tx.transaction.cost().is_some_and(|cost| cost <= balance);
+            if tx.transaction.is_expired(self.next_height) {
+                debug!("Transaction {} expired at height {}", tx.hash(), height);
+                self.record_drop(tx.hash(), DropReason::Expired);
+            } else if state.model == Model::Utxo {
+                if tx.is_valid_in_state(state) {
+                    self.place(tx.hash(), tx, state);
+                } else {
+                    self.record_drop(tx.hash(), DropReason::Conflicted);
+                }
+            } else if tx.transaction.account_nonce > confirmed && affordable {
+                self.place(tx.hash(), tx, state);
+            } else {
+                self.record_drop(tx.hash(), DropReason::Conflicted);
+            }
+        }
+    }
+}
+
+/// Whether `new_fee` is enough to replace a transaction paying `old_fee`.
+fn is_fee_bump(old_fee: u64, new_fee: u64) -> bool {
+    let min_fee = old_fee.saturating_add(old_fee.saturating_mul(MIN_FEE_BUMP_PERCENT) / 100);
+    new_fee > old_fee && new_fee >= min_fee
+}
+
+/// Write the transactions of the mempool to `path`. The file is replaced atomically, so a crash
+/// while saving leaves the previous file intact.
+pub fn save(path: &Path, tx_mempool: &Mempool) -> Result<()> {
+    let transactions: Vec<&SignedTransaction> = tx_mempool.iter().collect();
+    let bytes = bincode::serialize(&transactions).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    schema::save(path, Store::Mempool, &bytes)?;
+    info!("Saved {} mempool transactions to {}", transactions.len(), path.display());
+    Ok(())
+}
+
+/// Read the transactions saved at `path`, dropping those that can never become valid on top of
+/// `state`. A missing file is an empty mempool.
+pub fn load(path: &Path, state: &State, events: &Arc<EventBus>) -> Result<Mempool> {
+    let mut tx_mempool = Mempool::new(TX_MEMPOOL_CAPACITY, events);
+    let bytes = match schema::load(path, Store::Mempool)? {
+        Some(bytes) => bytes,
+        None => return Ok(tx_mempool),
+    };
+    let transactions: Vec<SignedTransaction> = bincode::deserialize(&bytes)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let saved = transactions.len();
+    for tx in transactions {
+        let _ = tx_mempool.insert(tx, state);
+    }
+    info!("Loaded {} of {} saved mempool transactions from {}", tx_mempool.len(), saved, path.display());
+    Ok(tx_mempool)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::crypto::key_pair;
+    use crate::block::INIT_COINS;
+    use crate::genesis::GenesisConfig;
+    use crate::transaction::{sign, SignatureScheme, Transaction};
+    use ring::signature::KeyPair;
+
+    fn signed_transaction(byte: u8, value: u64, account_nonce: u64) -> SignedTransaction {
+        signed_transaction_with_fee(byte, value, 0, account_nonce)
+    }
+
+    fn signed_transaction_with_fee(byte: u8, value: u64, fee: u64, account_nonce: u64) -> SignedTransaction {
+        let key = key_pair::frombyte(byte);
+        let transaction = Transaction {
+            inputs: Vec::new(),
+            outputs: vec![(H160::default(), value)],
+            fee,
+            account_nonce,
+            data: Vec::new(),
+            expires_at_block: None,
+            token: None,
+        };
+        let signature = sign(&transaction, &key);
+        SignedTransaction {
+            transaction,
+            signature: signature.as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            scheme: SignatureScheme::Ed25519,
+            multisig: None,
+            sender_cache: Default::default(),
+        }
+    }
+
+    #[test]
+    fn save_and_revalidate() {
+        let path = std::env::temp_dir().join(format!("prism-mempool-{}", rand::random::<u64>()));
+        let (_, state) = Blockchain::new().tip_with_state();
+        assert!(load(&path, &state, &Default::default()).unwrap().is_empty());
+
+        let valid = signed_transaction(0, 1, 1);
+        let gapped = signed_transaction(0, 1, 3);
+        let unaffordable = signed_transaction(1, 1000, 1);
+        let mut tx_mempool = Mempool::default();
+        assert!(tx_mempool.insert(valid.clone(), &state).is_ok());
+        assert!(tx_mempool.insert(gapped.clone(), &state).is_ok());
+        // bypass the checks of insert to save an invalid transaction
+        tx_mempool.pending.insert(unaffordable.hash(), unaffordable);
+        save(&path, &tx_mempool).unwrap();
+
+        let loaded = load(&path, &state, &Default::default()).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert!(loaded.contains_key(&valid.hash()));
+        assert_eq!(loaded.queued_len(), 1);
+        assert!(loaded.contains_key(&gapped.hash()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn nonce_gaps_are_queued() {
+        let (_, mut state) = Blockchain::new().tip_with_state();
+        let mut tx_mempool = Mempool::default();
+        let first = signed_transaction(0, 1, 1);
+        let second = signed_transaction(0, 1, 2);
+        let third = signed_transaction(0, 1, 3);
+        assert!(tx_mempool.insert(third.clone(), &state).is_ok());
+        assert!(tx_mempool.insert(second.clone(), &state).is_ok());
+        assert_eq!(tx_mempool.pending().count(), 0);
+        assert_eq!(tx_mempool.queued_len(), 2);
+        let sender = first.sender();
+        assert!(tx_mempool.has_nonce(&sender, 2));
+        assert!(!tx_mempool.has_nonce(&sender, 1));
+
+        // the predecessor fills the gap and promotes the whole chain
+        assert!(tx_mempool.insert(first.clone(), &state).is_ok());
+        assert_eq!(tx_mempool.pending().count(), 3);
+        assert_eq!(tx_mempool.queued_len(), 0);
+        assert!(tx_mempool.has_nonce(&sender, 1));
+
+        // a conflicting transaction for a taken nonce is refused
+        assert_eq!(tx_mempool.insert(signed_transaction(0, 2, 2), &state), Err(TxValidationError::FeeTooLow));
+        assert_eq!(tx_mempool.insert(first.clone(), &state), Err(TxValidationError::AlreadyKnown));
+
+        // the first two confirm in a block
+        first.update_state(&mut state);
+        second.update_state(&mut state);
+        tx_mempool.update(&state, 1);
+        assert_eq!(tx_mempool.len(), 1);
+        assert!(tx_mempool.contains_key(&third.hash()));
+        assert_eq!(tx_mempool.pending().count(), 1);
+    }
+
+    #[test]
+    fn removal_queues_the_successors() {
+        let (_, state) = Blockchain::new().tip_with_state();
+        let mut tx_mempool = Mempool::default();
+        let chain: Vec<SignedTransaction> = (1..=3).map(|nonce| signed_transaction(0, 1, nonce)).collect();
+        for tx in chain.iter() {
+            assert!(tx_mempool.insert(tx.clone(), &state).is_ok());
+        }
+        assert_eq!(tx_mempool.pending().count(), 3);
+
+        // the middle of the chain is evicted, the last one waits behind the gap
+        assert!(tx_mempool.discard(&chain[1].hash(), DropReason::Evicted).is_some());
+        assert_eq!(tx_mempool.pending().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![chain[0].hash()]);
+        assert_eq!(tx_mempool.queued_len(), 1);
+        assert!(!tx_mempool.is_pending(&chain[2].hash()));
+
+        // and is promoted again once the gap is filled
+        assert!(tx_mempool.insert(chain[1].clone(), &state).is_ok());
+        assert_eq!(tx_mempool.pending().count(), 3);
+        assert_eq!(tx_mempool.queued_len(), 0);
+    }
+
+    #[test]
+    fn update_promotes_after_confirmation() {
+        let (_, mut state) = Blockchain::new().tip_with_state();
+        let mut tx_mempool = Mempool::default();
+        let first = signed_transaction(0, 1, 1);
+        let second = signed_transaction(0, 1, 2);
+        assert!(tx_mempool.insert(second.clone(), &state).is_ok());
+        assert_eq!(tx_mempool.queued_len(), 1);
+        // the predecessor confirms without ever reaching this mempool
+        first.update_state(&mut state);
+        tx_mempool.update(&state, 1);
+        assert_eq!(tx_mempool.queued_len(), 0);
+        assert_eq!(tx_mempool.pending().next().unwrap().hash(), second.hash());
+    }
+
+    #[test]
+    fn update_drops_unaffordable() {
+        let (_, mut state) = Blockchain::new().tip_with_state();
+        let mut tx_mempool = Mempool::default();
+        let first = signed_transaction(0, 1, 1);
+        let second = signed_transaction(0, 10, 2);
+        assert!(tx_mempool.insert(first.clone(), &state).is_ok());
+        assert!(tx_mempool.insert(second.clone(), &state).is_ok());
+
+        // after a reorg, another transaction of the sender took its nonce 1 and most of its balance
+        let spender = signed_transaction(0, state.balance(&first.sender()) - 5, 1);
+        assert!(spender.update_state(&mut state));
+        tx_mempool.update(&state, 1);
+        assert!(tx_mempool.is_empty());
+        assert_eq!(tx_mempool.drop_reason(&first.hash()), Some(DropReason::Conflicted));
+        assert_eq!(tx_mempool.drop_reason(&second.hash()), Some(DropReason::Conflicted));
+    }
+
+    #[test]
+    fn drops_expired() {
+        let (_, state) = Blockchain::new().tip_with_state();
+        let mut tx_mempool = Mempool::default();
+        let expiring = |byte, last| {
+            let key = key_pair::frombyte(byte);
+            let mut tx = signed_transaction(byte, 1, 1);
+            tx.transaction.expires_at_block = Some(last);
+            tx.signature = sign(&tx.transaction, &key).as_ref().to_vec();
+            tx
+        };
+        assert!(tx_mempool.insert(expiring(0, 2), &state).is_ok());
+        assert!(tx_mempool.insert(expiring(1, 3), &state).is_ok());
+        // the next block is at height 3
+        tx_mempool.update(&state, 2);
+        assert_eq!(tx_mempool.len(), 1);
+        assert_eq!(tx_mempool.drop_reason(&expiring(0, 2).hash()), Some(DropReason::Expired));
+        assert_eq!(tx_mempool.drop_reason(&expiring(1, 3).hash()), None);
+        assert_eq!(tx_mempool.insert(expiring(2, 2), &state), Err(TxValidationError::Expired));
+        assert!(tx_mempool.insert(expiring(2, 3), &state).is_ok());
+    }
+
+    #[test]
+    fn eviction_prefers_queued() {
+        let (_, state) = Blockchain::new().tip_with_state();
+        let mut tx_mempool = Mempool::new(2, &Default::default());
+        let pending = signed_transaction(0, 1, 1);
+        let queued = signed_transaction(1, 1, 5);
+        assert!(tx_mempool.insert(pending.clone(), &state).is_ok());
+        assert!(tx_mempool.insert(queued.clone(), &state).is_ok());
+        assert!(tx_mempool.insert(signed_transaction(2, 1, 1), &state).is_ok());
+        assert_eq!(tx_mempool.len(), 2);
+        assert!(tx_mempool.contains_key(&pending.hash()));
+        assert!(!tx_mempool.contains_key(&queued.hash()));
+        assert_eq!(tx_mempool.drop_reason(&queued.hash()), Some(DropReason::Evicted));
+    }
+
+    #[test]
+    fn refuses_below_min_fee() {
+        let (_, state) = Blockchain::new().tip_with_state();
+        let mut tx_mempool = Mempool::default();
+        tx_mempool.set_min_fee(5);
+        assert_eq!(tx_mempool.insert(signed_transaction_with_fee(0, 1, 4, 1), &state), Err(TxValidationError::FeeTooLow));
+        assert!(tx_mempool.insert(signed_transaction_with_fee(0, 1, 5, 1), &state).is_ok());
+    }
+
+    #[test]
+    fn utxo_conflicts() {
+        let (_, mut state) = GenesisConfig { model: Model::Utxo, ..Default::default() }.build().unwrap();
+        let mut tx_mempool = Mempool::default();
+        // the genesis output of the well-known key `byte`
+        let spend = |byte: u8, fee: u64| {
+            let key = key_pair::frombyte(byte);
+            let transaction = Transaction {
+                inputs: vec![OutPoint { txid: Default::default(), index: byte as u32 }],
+                outputs: vec![(H160::default(), INIT_COINS - fee)],
+                fee,
+                ..Default::default()
+            };
+            let signature = sign(&transaction, &key);
+            SignedTransaction {
+                transaction,
+                signature: signature.as_ref().to_vec(),
+                public_key: key.public_key().as_ref().to_vec(),
+                scheme: SignatureScheme::Ed25519,
+                multisig: None,
+                sender_cache: Default::default(),
+            }
+        };
+        // pending whatever their nonce
+        assert!(tx_mempool.insert(spend(0, 10), &state).is_ok());
+        assert!(tx_mempool.insert(spend(1, 0), &state).is_ok());
+        assert_eq!(tx_mempool.pending().count(), 2);
+
+        // a spend of the same output must bump the fee
+        assert!(tx_mempool.insert(spend(0, 10 + 10 * MIN_FEE_BUMP_PERCENT / 100 - 1), &state).is_err());
+        let replacement = spend(0, 10 + 10 * MIN_FEE_BUMP_PERCENT / 100);
+        assert!(tx_mempool.insert(replacement.clone(), &state).is_ok());
+        assert_eq!(tx_mempool.len(), 2);
+
+        // a confirmed spend drops the transactions spending the same output
+        assert!(spend(1, 1).update_state(&mut state));
+        tx_mempool.update(&state, 1);
+        assert_eq!(tx_mempool.pending().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![replacement.hash()]);
+        assert_eq!(tx_mempool.drop_reason(&spend(1, 0).hash()), Some(DropReason::Conflicted));
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let (_, state) = Blockchain::new().tip_with_state();
+        let events = Arc::new(EventBus::default());
+        let mut tx_mempool = Mempool::new(TX_MEMPOOL_CAPACITY, &events);
+        let accepted = events.subscribe();
+        let original = signed_transaction_with_fee(0, 1, 10, 1);
+        let next = signed_transaction_with_fee(0, 1, 0, 2);
+        assert!(tx_mempool.insert(original.clone(), &state).is_ok());
+        assert!(tx_mempool.insert(next.clone(), &state).is_ok());
+
+        // a bump below the minimum is refused
+        assert!(tx_mempool.insert(signed_transaction_with_fee(0, 2, 10, 1), &state).is_err());
+        assert!(tx_mempool.insert(signed_transaction_with_fee(0, 2, 10 + 10 * MIN_FEE_BUMP_PERCENT / 100 - 1, 1), &state).is_err());
+
+        let replacement = signed_transaction_with_fee(0, 2, 10 + 10 * MIN_FEE_BUMP_PERCENT / 100, 1);
+        assert!(tx_mempool.insert(replacement.clone(), &state).is_ok());
+        assert!(!tx_mempool.contains_key(&original.hash()));
+        assert_eq!(tx_mempool.drop_reason(&original.hash()), Some(DropReason::Replaced(replacement.hash())));
+        assert_eq!(tx_mempool.len(), 2);
+        // the replacement keeps the place of the original, its successor stays pending
+        assert_eq!(tx_mempool.pending().count(), 2);
+        let accepted: Vec<H256> = accepted.try_iter().filter_map(|event| match event {
+            NodeEvent::TxAccepted(tx) => Some(tx.hash()),
+            _ => None,
+        }).collect();
+        assert_eq!(accepted, vec![original.hash(), next.hash(), replacement.hash()]);
+
+        // queued transactions are replaced the same way
+        let queued = signed_transaction_with_fee(1, 1, 0, 3);
+        assert!(tx_mempool.insert(queued.clone(), &state).is_ok());
+        assert!(tx_mempool.insert(signed_transaction_with_fee(1, 1, 1, 3), &state).is_ok());
+        assert!(!tx_mempool.contains_key(&queued.hash()));
+        assert_eq!(tx_mempool.queued_len(), 1);
+    }
+}
@@ -1,23 +1,51 @@
 use crate::network::server::Handle as ServerHandle;
 use log::{info};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use std::collections::VecDeque;
+use ring::signature::{Ed25519KeyPair, KeyPair};
 use std::time;
 use std::thread;
 use std::sync::{Arc,Mutex};
-use std::collections::{HashMap};
 use crate::blockchain::{Blockchain};
-use crate::block::{Block, Header, Content, State, BLOCK_CAPACITY};
-use crate::crypto::merkle::{MerkleTree};
-use crate::crypto::hash::{H256, Hashable};
+use crate::block::{Block, Header, State};
+use crate::crypto::hash::{Hashable, H256};
 use crate::crypto::key_pair;
 use crate::crypto::address::H160;
 use crate::network::message::Message;
-use crate::transaction::{SignedTransaction};
+use crate::events::{EventBus, NodeEvent};
+use crate::mempool::Mempool;
+use crate::pow::Engine;
+use crate::clock;
+use crate::template::{BlockTemplate, TemplateBuilder};
+
+/// How long the miner waits for a solution before refreshing its block template.
+static POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
+/// Number of templates handed out through the control handle that can still be submitted.
+static EXTERNAL_TEMPLATES: usize = 16;
 
 pub enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
         Exit,
+    /// Change the lambda, taking effect at once when running, on `Resume` when paused
+    SetRate(u64),
+    /// Stop until `Resume`, keeping the lambda
+    Pause,
+    /// Run again at the last lambda after `Pause`
+    Resume,
+    /// Build a template on the current tip for an external miner
+    GetTemplate(Sender<Option<BlockTemplate>>),
+    /// A header solved by an external miner, answered with whether the block was accepted
+    SubmitHeader(Header, Sender<bool>),
+}
+
+/// How the miner releases the blocks it finds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Announce every block as soon as it is mined.
+    Honest,
+    /// Keep a private chain and only release its blocks to win the races against the public
+    /// chain, after Eyal and Sirer's selfish mining.
+    Selfish,
 }
 
 pub enum OperatingState {
@@ -30,11 +58,28 @@ pub struct Context {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
+    /// The lambda of the last `Start` or `SetRate`, to resume with.
+    lambda: Option<u64>,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
+    blockchain: Arc<Blockchain>,
     mined_blocks: u64,
-    tx_mempool: Arc<Mutex<HashMap<H256,SignedTransaction>>>,
+    tx_mempool: Arc<Mutex<Mempool>>,
+    events: Arc<EventBus>,
     id: Arc<Identity>,
+    builder: TemplateBuilder,
+    engine: Engine,
+    template: Option<(u64, BlockTemplate)>,
+    /// Templates handed out through the control handle, most recent last.
+    external_templates: Vec<BlockTemplate>,
+    strategy: Strategy,
+    /// Blocks accepted from the peers, only followed by a selfish miner.
+    accepted_blocks: Option<Receiver<NodeEvent>>,
+    /// Mined blocks not announced yet, lowest first.
+    withheld: VecDeque<(H256, u32)>,
+    /// Height of the longest chain known to the peers.
+    public_height: u32,
+    /// Whether a released block is tied with a public block of the same height.
+    racing: bool,
 }
 
 #[derive(Clone)]
@@ -50,31 +95,51 @@ pub struct Identity {
 }
 
 impl Identity {
-    pub fn new(randbyte: u8) -> Identity {
-        let _key_pair = key_pair::frombyte(randbyte);
-        let _address: H160 = ring::digest::digest(&ring::digest::SHA256, _key_pair.public_key().as_ref()).into();
+    pub fn from_key_pair(key_pair: Ed25519KeyPair) -> Identity {
+        let address: H160 = ring::digest::digest(&ring::digest::SHA256, key_pair.public_key().as_ref()).into();
         Identity {
-            key_pair: _key_pair,
-            address: _address,
+            key_pair,
+            address,
         }
     }
+
+    pub fn new(randbyte: u8) -> Identity {
+        Identity::from_key_pair(key_pair::frombyte(randbyte))
+    }
 }
 
 pub fn new(
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>,
-    tx_mempool: &Arc<Mutex<HashMap<H256,SignedTransaction>>>,
+    blockchain: &Arc<Blockchain>,
+    tx_mempool: &Arc<Mutex<Mempool>>,
+    events: &Arc<EventBus>,
     id: &Arc<Identity>,
+    num_threads: usize,
+    strategy: Strategy,
     ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
+        lambda: None,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         mined_blocks: 0,
         tx_mempool: Arc::clone(tx_mempool),
+        events: Arc::clone(events),
         id: Arc::clone(id),
+        builder: TemplateBuilder::new(blockchain, tx_mempool),
+        engine: Engine::new(num_threads),
+        template: None,
+        external_templates: Vec::new(),
+        strategy,
+        accepted_blocks: match strategy {
+            Strategy::Honest => None,
+            Strategy::Selfish => Some(events.subscribe()),
+        },
+        withheld: VecDeque::new(),
+        public_height: blockchain.height(),
+        racing: false,
     };
 
     let handle = Handle {
@@ -85,8 +150,9 @@ pub fn new(
 }
 
 impl Handle {
+    /// Stop the miner. Does nothing if it has stopped already.
     pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+        let _ = self.control_chan.send(ControlSignal::Exit);
     }
 
     pub fn start(&self, lambda: u64) {
@@ -95,6 +161,38 @@ impl Handle {
             .unwrap();
     }
 
+    /// Change the lambda without stopping. Does nothing if it has stopped already.
+    pub fn set_rate(&self, lambda: u64) {
+        let _ = self.control_chan.send(ControlSignal::SetRate(lambda));
+    }
+
+    /// Stop until `resume`, keeping the lambda. Does nothing if it has stopped already.
+    pub fn pause(&self) {
+        let _ = self.control_chan.send(ControlSignal::Pause);
+    }
+
+    /// Run again at the last lambda after `pause`. Does nothing if it was never started.
+    pub fn resume(&self) {
+        let _ = self.control_chan.send(ControlSignal::Resume);
+    }
+
+    /// Request a template on the current tip. Returns `None` when there are not enough
+    /// transactions to fill a block, or when the miner has exited.
+    pub fn get_template(&self) -> Option<BlockTemplate> {
+        let (sender, receiver) = unbounded();
+        self.control_chan.send(ControlSignal::GetTemplate(sender)).ok()?;
+        receiver.recv().ok().flatten()
+    }
+
+    /// Submit a header solving one of the recent templates returned by `get_template`. Returns
+    /// whether the block was accepted and broadcast.
+    pub fn submit_header(&self, header: Header) -> bool {
+        let (sender, receiver) = unbounded();
+        if self.control_chan.send(ControlSignal::SubmitHeader(header, sender)).is_err() {
+            return false;
+        }
+        receiver.recv().unwrap_or(false)
+    }
 }
 
 impl Context {
@@ -116,8 +214,160 @@ impl Context {
             }
             ControlSignal::Start(i) => {
                 info!("Miner starting in continuous mode with lambda {}", i);
+                self.lambda = Some(i);
                 self.operating_state = OperatingState::Run(i);
             }
+            ControlSignal::SetRate(i) => {
+                info!("Miner lambda set to {}", i);
+                self.lambda = Some(i);
+                if let OperatingState::Run(_) = self.operating_state {
+                    self.operating_state = OperatingState::Run(i);
+                    // the engine only takes the lambda with a new template
+                    self.cancel_template();
+                }
+            }
+            ControlSignal::Pause => {
+                if let OperatingState::Run(_) = self.operating_state {
+                    info!("Miner paused");
+                    self.operating_state = OperatingState::Paused;
+                    self.cancel_template();
+                }
+            }
+            ControlSignal::Resume => {
+                if let (OperatingState::Paused, Some(i)) = (&self.operating_state, self.lambda) {
+                    info!("Miner resuming with lambda {}", i);
+                    self.operating_state = OperatingState::Run(i);
+                }
+            }
+            ControlSignal::GetTemplate(reply) => {
+                let template = self.builder.build();
+                if let Some(template) = &template {
+                    // a template with the same work as a recent one is solved by the same headers
+                    if !self.external_templates.iter().any(|t| t.same_work(template)) {
+                        if self.external_templates.len() == EXTERNAL_TEMPLATES {
+                            self.external_templates.remove(0);
+                        }
+                        self.external_templates.push(template.clone());
+                    }
+                }
+                let _ = reply.send(template);
+            }
+            ControlSignal::SubmitHeader(header, reply) => {
+                let position = self.external_templates.iter().position(|t| t.is_solved_by(&header));
+                let accepted = match position {
+                    Some(i) => {
+                        let template = self.external_templates.remove(i);
+                        let block = template.solve(header);
+                        info!("Accepted an externally mined block: hash: {:#?}", block.hash());
+                        self.publish(block, &template.state);
+                        true
+                    }
+                    None => false,
+                };
+                let _ = reply.send(accepted);
+            }
+        }
+    }
+
+    /// Stop mining the current template, if any.
+    fn cancel_template(&mut self) {
+        if self.template.take().is_some() {
+            self.engine.cancel();
+        }
+    }
+
+    /// Insert a mined block, drop its transactions from the mempool and announce it.
+    fn publish(&mut self, block: Block, state: &State) {
+        self.mined_blocks += 1;
+        self.events.publish(NodeEvent::BlockMined {
+            hash: block.hash(),
+            num_transactions: block.content.len(),
+        });
+        self.blockchain.insert(&block, state);
+
+        if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
+            if block.hash() == self.blockchain.tip() {
+                // the successors of the mined transactions may be pending now
+                _tx_mempool.update(state, self.blockchain.height());
+            } else {
+                for tx in block.content.transactions.iter() {
+                    _tx_mempool.remove(&tx.hash());
+                }
+            }
+        }
+
+        match self.strategy {
+            Strategy::Honest => {
+                self.server.broadcast(Message::NewBlockHashes(vec![block.hash()]));
+            }
+            Strategy::Selfish => {
+                let height = self.blockchain.get_block_height(&block.hash()).unwrap();
+                self.withheld.push_back((block.hash(), height));
+                if self.racing {
+                    // the new block breaks the tie in favor of the private chain
+                    self.racing = false;
+                    self.release(height);
+                } else {
+                    info!("Withholding block {:?}, {} blocks withheld", block.hash(), self.withheld.len());
+                }
+            }
+        }
+    }
+
+    /// Announce the withheld blocks up to `height`.
+    fn release(&mut self, height: u32) {
+        let mut hashes = vec![];
+        while let Some(&(hash, h)) = self.withheld.front() {
+            if h > height {
+                break;
+            }
+            hashes.push(hash);
+            self.withheld.pop_front();
+            self.public_height = self.public_height.max(h);
+        }
+        if !hashes.is_empty() {
+            info!("Releasing {} withheld blocks, {} still withheld", hashes.len(), self.withheld.len());
+            self.server.broadcast(Message::NewBlockHashes(hashes));
+        }
+    }
+
+    /// Let a selfish miner react to the blocks the peers found since the last call: give up a
+    /// private chain that fell behind, and release enough of it to override the public chain
+    /// otherwise.
+    fn follow_public_chain(&mut self) {
+        let accepted_blocks = match &self.accepted_blocks {
+            Some(receiver) => receiver.clone(),
+            None => return,
+        };
+        for event in accepted_blocks.try_iter() {
+            let hash = match event {
+                NodeEvent::BlockAccepted { hash, .. } => hash,
+                _ => continue,
+            };
+            let height = match self.blockchain.get_block_height(&hash) {
+                Some(height) if height > self.public_height => height,
+                _ => continue,
+            };
+            self.public_height = height;
+            self.racing = false;
+            let private_height = match self.withheld.back() {
+                Some(&(_, h)) => h,
+                None => continue,
+            };
+            if private_height < height {
+                info!("Private chain fell behind, dropping {} withheld blocks", self.withheld.len());
+                self.withheld.clear();
+            } else if private_height == height {
+                // publish the tying block and race on it
+                self.racing = true;
+                self.release(private_height);
+            } else if private_height == height + 1 {
+                // the private chain wins by one block
+                self.release(private_height);
+            } else {
+                // stay ahead, matching the public chain
+                self.release(height);
+            }
         }
     }
 
@@ -143,140 +393,59 @@ impl Context {
                 },
             }
             if let OperatingState::ShutDown = self.operating_state {
-                thread::sleep(time::Duration::from_secs(3));
-                if let Ok(chain) = self.blockchain.lock() {
-                    let longest_chain = chain.all_blocks_in_longest_chain();
-                    info!("Exit, Longest chain: {:?}", longest_chain);
-                }
+                self.engine.cancel();
+                clock::sleep(time::Duration::from_secs(3));
+                let longest_chain = self.blockchain.all_blocks_in_longest_chain();
+                info!("Exit, Longest chain: {:?}", longest_chain);
                 return;
             }
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
-                }
-            }
-
-            // TODO: actual mining 
-            if let Ok(mut chain) = self.blockchain.lock(){
-                // Initialize block header.
-                let parent = chain.tip().clone();
-                let timestamp = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_micros();
-                let difficulty: H256 = chain.get_block(&parent).unwrap().header.difficulty;
-
-                // Collect transactions to generate content
-                if let Some(state) = chain.get_state(&parent) {
-                    let (content, new_state) = self.collect_txs(&state);
-                    if content.len() == 0 {
-                        continue;
-                    }
-                    if content.len() < BLOCK_CAPACITY {
-                        continue;
-                    }
-                    //debug!("\r miner collected txs: {:?}", content.len());
-                    let merkle_root = MerkleTree::new(&content.transactions).root();
-                    // Create block with random nonce.
-                    let mut block = Block {
-                        header: Header{
-                            parent: parent,
-                            nonce: rand::random::<u32>(),
-                            difficulty: difficulty,
-                            timestamp: timestamp,
-                            merkle_root: merkle_root,
-                        },
-                        content: content.clone(), 
-                    };
-
-                    for _ in 0..1000{
-                        block.header.nonce = rand::random::<u32>();
-                        if block.hash() < difficulty {
-                            break;
-                        }
-                    }
-
-                    // If block hash <= difficulty, block is successfully mined.
-                    if block.hash() < difficulty {
-                        info!("Mined a new block: hash: {:#?}, num transactions: {:#?}, num blocks mined: {:#?}", 
-                            block.hash(), 
-                            content.len(),
-                            self.mined_blocks);
-                        self.mined_blocks += 1;
-                        chain.insert(&block, &new_state);
-
-                        if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
-                            for tx in content.transactions {
-                                _tx_mempool.remove(&tx.hash());
-                            }
-                        }
-
-                        self.server.broadcast(Message::NewBlockHashes(vec![block.hash()]));
-                    }
-                }
-            }
-        }
-    }
-
-    fn collect_txs(&self, _state: &State) -> (Content, State) {
-        let mut valid_transactions = vec![];
-        let mut erase_transactions = vec![];
-        let mut state = _state.clone();
-
-        if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
-            loop{
-                let mut finished = true;
-                erase_transactions.clear();
-
-                for tx_signed in _tx_mempool.values() {
-                    let address: H160 = ring::digest::digest(&ring::digest::SHA256, tx_signed.public_key.as_ref()).into();
-                    let public_key = UnparsedPublicKey::new(&ED25519, tx_signed.public_key.clone());
-                    let tx = tx_signed.transaction.clone();
-                    // verification fails
-                    if public_key.verify(tx.hash().as_ref(), tx_signed.signature.as_ref()).is_err() {
-                        erase_transactions.push(tx.hash());
-                        continue;
-                    }
-                    // get the peer state
-                    if let Some(peer_state) = state.account_state.get(&address) {
-                        // the nonce is incorrect
-                        if tx.account_nonce != peer_state.nonce+1 {
-                            // only erase txs whose nonce are smaller than the state
-                            if tx.account_nonce <= peer_state.nonce {
-                                erase_transactions.push(tx.hash());
-                            }
-                            continue;
-                        }
-                        // the balance is not enough
-                        if peer_state.balance < tx.value {
-                            erase_transactions.push(tx.hash());
-                            continue;
-                        }
-                        // the valid transaction
-                        tx_signed.update_state(&mut state);
-                        valid_transactions.push(tx_signed.clone());
-                        finished = false;
-                    }
-                    if valid_transactions.len() == BLOCK_CAPACITY {
-                        finished = true;
-                        break;
-                    }
+            self.follow_public_chain();
+            let lambda = match self.operating_state {
+                OperatingState::Run(i) => i,
+                _ => 0,
+            };
 
+            // Build a template on the current tip.
+            let template = match self.builder.build() {
+                Some(template) => template,
+                None => {
+                    // not enough transactions, stop mining the current template if any
+                    self.cancel_template();
+                    clock::sleep(POLL_INTERVAL);
+                    continue;
                 }
+            };
 
-                // remove invalid txs
-                for tx in erase_transactions.iter() {
-                    _tx_mempool.remove(&tx.hash());
-                }
+            // Hand a new template to the PoW engine when the parent or the transactions changed.
+            let changed = match &self.template {
+                Some((_, current)) => !current.same_work(&template),
+                None => true,
+            };
+            if changed {
+                let generation = self.engine.submit(template.header(), time::Duration::from_micros(lambda));
+                self.template = Some((generation, template));
+            }
 
-                // if no more transactions can be added, return
-                if finished {
-                    break;
-                }
+            // Wait for a solution of the current template.
+            let solution = match self.engine.solutions().recv_timeout(POLL_INTERVAL) {
+                Ok(solution) => solution,
+                Err(_) => continue,
+            };
+            let is_current = match &self.template {
+                Some((generation, _)) => *generation == solution.generation,
+                None => false,
+            };
+            if !is_current {
+                continue;
             }
+            let (_, template) = self.template.take().unwrap();
+            let block = template.solve(solution.header);
+
+            info!("Mined a new block: hash: {:#?}, num transactions: {:#?}, num blocks mined: {:#?}", 
+                block.hash(), 
+                block.content.len(),
+                self.mined_blocks);
+            self.publish(block, &template.state);
         }
-        
-        let content = Content {
-            transactions: valid_transactions,
-        };
-        (content, state)
     }
 }
@@ -0,0 +1,109 @@
+//! Snappy compression of the large messages, on the links to the peers that announced they
+//! understand it during the handshake.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Messages at least this large are compressed by default, in bytes.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Marks a message body sent as is.
+pub(super) const RAW: u8 = 0;
+/// Marks a message body compressed with Snappy.
+pub(super) const SNAPPY: u8 = 1;
+
+/// When the messages written to the peers are compressed.
+#[derive(Clone, Default)]
+pub struct Compression {
+    /// Messages at least this large are compressed. `None` never compresses; the compressed
+    /// messages of the peers are still understood.
+    pub threshold: Option<usize>,
+    pub stats: Arc<CompressionStats>,
+}
+
+impl Compression {
+    pub fn new(threshold: Option<usize>, stats: &Arc<CompressionStats>) -> Self {
+        Compression {
+            threshold,
+            stats: Arc::clone(stats),
+        }
+    }
+
+    /// Compress `msg` if it is over the threshold and compression pays off. Returns the body to
+    /// write and its marker.
+    pub(super) fn compress(&self, msg: &[u8]) -> (u8, Vec<u8>) {
+        if self.threshold.is_some_and(|threshold| msg.len() >= threshold) {
+            if let Ok(compressed) = snap::raw::Encoder::new().compress_vec(msg) {
+                if compressed.len() < msg.len() {
+                    self.stats.record(msg.len(), compressed.len());
+                    return (SNAPPY, compressed);
+                }
+            }
+        }
+        (RAW, msg.to_vec())
+    }
+}
+
+/// Compression of the messages written by this node, across all peers.
+#[derive(Default)]
+pub struct CompressionStats {
+    messages: AtomicU64,
+    bytes_before: AtomicU64,
+    bytes_after: AtomicU64,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CompressionSummary {
+    pub messages_compressed: u64,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// Compressed over original size of the compressed messages, 1 when none was.
+    pub ratio: f64,
+}
+
+impl CompressionStats {
+    fn record(&self, before: usize, after: usize) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes_before.fetch_add(before as u64, Ordering::Relaxed);
+        self.bytes_after.fetch_add(after as u64, Ordering::Relaxed);
+    }
+
+    pub fn summary(&self) -> CompressionSummary {
+        let bytes_before = self.bytes_before.load(Ordering::Relaxed);
+        let bytes_after = self.bytes_after.load(Ordering::Relaxed);
+        CompressionSummary {
+            messages_compressed: self.messages.load(Ordering::Relaxed),
+            bytes_before,
+            bytes_after,
+            ratio: if bytes_before == 0 { 1.0 } else { bytes_after as f64 / bytes_before as f64 },
+        }
+    }
+}
+
+/// Decompress a body marked `SNAPPY`, refusing to inflate it over `max_size` bytes.
+pub(super) fn decompress(body: &[u8], max_size: usize) -> Option<Vec<u8>> {
+    let size = snap::raw::decompress_len(body).ok()?;
+    if size > max_size {
+        return None;
+    }
+    snap::raw::Decoder::new().decompress_vec(body).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_above_threshold() {
+        let compression = Compression::new(Some(100), &Default::default());
+        let small = vec![0; 99];
+        assert_eq!(compression.compress(&small), (RAW, small));
+        let large = vec![0; 1000];
+        let (marker, body) = compression.compress(&large);
+        assert_eq!(marker, SNAPPY);
+        assert_eq!(decompress(&body, 1000), Some(large));
+        assert_eq!(decompress(&body, 999), None);
+        assert_eq!(compression.stats.summary().messages_compressed, 1);
+    }
+}
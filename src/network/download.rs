@@ -0,0 +1,212 @@
+//! Download of a long chain from several peers at once. The hashes of the chain are learned with
+//! `GetBlocksFrom`, and `GetChainHashes` for the next batches, then the blocks are requested from
+//! the peers that announced them, a window at a time. They arrive in any order, and the orphan
+//! pool puts them back in order.
+
+use super::message::Message;
+use super::peer;
+use crate::crypto::hash::H256;
+use log::{debug, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most hashes asked for, and answered, at once.
+pub const SYNC_BATCH: u32 = 512;
+/// Most blocks requested from one peer and not received yet.
+pub const MAX_IN_FLIGHT_PER_PEER: usize = 16;
+/// Blocks requested at most this far after the first missing one, so that the orphan pool
+/// holding the blocks received out of order stays small.
+pub const DOWNLOAD_WINDOW: usize = 256;
+/// How long a peer has to send a requested block before it is asked to another peer.
+pub const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A peer serving the chain being downloaded.
+struct Source {
+    handle: peer::Handle,
+    /// The hashes it announced, and which are still missing.
+    hashes: HashSet<H256>,
+    in_flight: usize,
+}
+
+#[derive(Default)]
+pub struct BlockDownload {
+    /// seq -> hash of the blocks not received yet, in chain order.
+    missing: BTreeMap<u64, H256>,
+    seq: HashMap<H256, u64>,
+    next_seq: u64,
+    /// hash -> (the peer it is requested from, when)
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    sources: HashMap<SocketAddr, Source>,
+    /// The last hash of the last batch, if it was full and the chain may go on.
+    continue_from: Option<H256>,
+    /// When the peers were last asked for hashes.
+    asked: Option<Instant>,
+}
+
+impl BlockDownload {
+    /// Whether to ask the peers for the hashes of their chain: no download is running, and the
+    /// peers were not asked recently.
+    pub fn should_start(&mut self, now: Instant) -> bool {
+        if !self.missing.is_empty() || self.asked.is_some_and(|asked| now.duration_since(asked) < BLOCK_REQUEST_TIMEOUT) {
+            return false;
+        }
+        self.asked = Some(now);
+        true
+    }
+
+    /// Queue the hashes of the chain announced by `source`, but the `known` ones.
+    pub fn add_hashes(&mut self, source: &peer::Handle, hashes: &[H256], known: impl Fn(&H256) -> bool) {
+        let entry = self.sources.entry(source.addr()).or_insert_with(|| Source {
+            handle: source.clone(),
+            hashes: HashSet::new(),
+            in_flight: 0,
+        });
+        for hash in hashes.iter().filter(|hash| !known(hash)) {
+            entry.hashes.insert(*hash);
+            if !self.seq.contains_key(hash) {
+                self.seq.insert(*hash, self.next_seq);
+                self.missing.insert(self.next_seq, *hash);
+                self.next_seq += 1;
+            }
+        }
+        if hashes.len() == SYNC_BATCH as usize {
+            self.continue_from = hashes.last().copied();
+        }
+        debug!("Peer {} announced {} blocks to download, {} missing", source.addr(), hashes.len(), self.missing.len());
+    }
+
+    /// Whether the block is to be downloaded, or being downloaded.
+    pub fn expects(&self, hash: &H256) -> bool {
+        self.seq.contains_key(hash)
+    }
+
+    /// Mark a block as received. Returns whether it was expected.
+    pub fn received(&mut self, hash: &H256) -> bool {
+        let seq = match self.seq.remove(hash) {
+            Some(seq) => seq,
+            None => return false,
+        };
+        self.missing.remove(&seq);
+        if let Some((addr, _)) = self.in_flight.remove(hash) {
+            if let Some(source) = self.sources.get_mut(&addr) {
+                source.in_flight -= 1;
+            }
+        }
+        for source in self.sources.values_mut() {
+            source.hashes.remove(hash);
+        }
+        true
+    }
+
+    /// The requests to write now. The peers that let a request time out are dropped, and their
+    /// blocks requested again. The blocks of the window not requested yet go to the peers with
+    /// the fewest blocks in flight. Once the blocks are all received, the peers are asked for
+    /// the rest of the chain.
+    pub fn requests(&mut self, now: Instant) -> Vec<(peer::Handle, Message)> {
+        let expired: Vec<H256> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (_, requested))| now.duration_since(*requested) >= BLOCK_REQUEST_TIMEOUT)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            let (addr, _) = self.in_flight.remove(&hash).unwrap();
+            if self.sources.remove(&addr).is_some() {
+                warn!("Peer {} did not send block {} in time, downloading from the others", addr, hash);
+            }
+        }
+        if self.sources.is_empty() && !self.missing.is_empty() {
+            debug!("No peer left to download {} blocks from", self.missing.len());
+            *self = BlockDownload {
+                asked: self.asked,
+                ..Default::default()
+            };
+            return vec![];
+        }
+
+        let mut batches: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        for hash in self.missing.values().take(DOWNLOAD_WINDOW) {
+            if self.in_flight.contains_key(hash) {
+                continue;
+            }
+            let source = self
+                .sources
+                .iter_mut()
+                .filter(|(_, source)| source.in_flight < MAX_IN_FLIGHT_PER_PEER && source.hashes.contains(hash))
+                .min_by_key(|(_, source)| source.in_flight);
+            if let Some((addr, source)) = source {
+                source.in_flight += 1;
+                self.in_flight.insert(*hash, (*addr, now));
+                batches.entry(*addr).or_default().push(*hash);
+            }
+        }
+        let mut requests: Vec<(peer::Handle, Message)> = batches
+            .into_iter()
+            .map(|(addr, hashes)| (self.sources[&addr].handle.clone(), Message::GetBlocks(hashes)))
+            .collect();
+
+        if self.missing.is_empty() {
+            match self.continue_from.take() {
+                Some(last) => {
+                    self.asked = Some(now);
+                    for source in self.sources.values() {
+                        requests.push((source.handle.clone(), Message::GetChainHashes(last, SYNC_BATCH)));
+                    }
+                }
+                None => self.sources.clear(),
+            }
+        }
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+    use crate::network::shim::Shim;
+
+    fn requested(requests: &[(peer::Handle, Message)], addr: SocketAddr) -> Vec<H256> {
+        requests
+            .iter()
+            .filter(|(peer, _)| peer.addr() == addr)
+            .flat_map(|(_, msg)| match msg {
+                Message::GetBlocks(hashes) => hashes.clone(),
+                _ => vec![],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spreads_requests_over_peers() {
+        let hashes: Vec<H256> = (0..4).map(|_| generate_random_hash()).collect();
+        let (first, _) = peer::Handle::with_queue(([127, 0, 0, 1], 6001).into(), &Shim::default());
+        let (second, _) = peer::Handle::with_queue(([127, 0, 0, 1], 6002).into(), &Shim::default());
+        let mut download = BlockDownload::default();
+        let start = Instant::now();
+        assert!(download.should_start(start));
+        download.add_hashes(&first, &hashes, |hash| *hash == hashes[0]);
+        download.add_hashes(&second, &hashes, |hash| *hash == hashes[0]);
+        assert!(!download.expects(&hashes[0]));
+        assert!(!download.should_start(start + BLOCK_REQUEST_TIMEOUT));
+
+        let requests = download.requests(start);
+        let (from_first, from_second) = (requested(&requests, first.addr()), requested(&requests, second.addr()));
+        assert_eq!(from_first.len() + from_second.len(), 3);
+        assert!(!from_first.is_empty() && !from_second.is_empty());
+
+        // the second peer does not answer, its blocks are asked to the first one
+        for hash in &from_first {
+            assert!(download.received(hash));
+        }
+        assert!(download.requests(start + Duration::from_secs(1)).is_empty());
+        let requests = download.requests(start + BLOCK_REQUEST_TIMEOUT);
+        assert_eq!(requested(&requests, first.addr()), from_second);
+        for hash in &from_second {
+            assert!(download.received(hash));
+        }
+        assert!(download.requests(start + BLOCK_REQUEST_TIMEOUT).is_empty());
+        assert!(download.should_start(start + BLOCK_REQUEST_TIMEOUT * 2));
+    }
+}
@@ -0,0 +1,84 @@
+//! Optional capabilities of a node, announced during the handshake. A capability is used on a
+//! link only if both ends announce it, so that nodes interoperate with older peers, and with
+//! newer ones announcing capabilities this node does not know.
+
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Features(u64);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    /// Messages may be compressed, see `compression`.
+    pub const COMPRESSION: Features = Features(1);
+    /// The peers exchange their mempool inventories on connection.
+    pub const MEMPOOL_SYNC: Features = Features(1 << 1);
+    /// The peers ask each other not to relay transactions below their minimum fee.
+    pub const FEE_FILTER: Features = Features(1 << 2);
+    /// The peers serve the hashes of their chain, to download it from several peers at once.
+    pub const BLOCK_SYNC: Features = Features(1 << 3);
+    /// The peers serve checkpoint snapshots of their state, see `snapshot`.
+    pub const SNAPSHOT: Features = Features(1 << 4);
+    /// The peers locate the fork point of their chains from a block locator, see
+    /// `Blockchain::locator`.
+    pub const LOCATOR: Features = Features(1 << 5);
+    /// The peers serve single inclusion proofs of several transactions, see
+    /// `MerkleTree::proof_multi`.
+    pub const MULTIPROOF: Features = Features(1 << 6);
+    /// The messages are encoded in protobuf instead of bincode, see `proto`.
+    pub const PROTOBUF: Features = Features(1 << 7);
+    /// Everything this node implements.
+    pub const SUPPORTED: Features = Features(0b11111111);
+
+    const NAMES: [(Features, &'static str); 8] = [
+        (Features::COMPRESSION, "compression"),
+        (Features::MEMPOOL_SYNC, "mempool-sync"),
+        (Features::FEE_FILTER, "fee-filter"),
+        (Features::BLOCK_SYNC, "block-sync"),
+        (Features::SNAPSHOT, "snapshot"),
+        (Features::LOCATOR, "locator"),
+        (Features::MULTIPROOF, "multiproof"),
+        (Features::PROTOBUF, "protobuf"),
+    ];
+
+    pub fn from_bits(bits: u64) -> Self {
+        Features(bits)
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The features of both.
+    pub fn intersection(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    /// The names of the known features.
+    pub fn names(self) -> Vec<&'static str> {
+        Features::NAMES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_common_features() {
+        // a newer peer announcing an unknown feature
+        let remote = Features::from_bits(Features::COMPRESSION.bits() | 1 << 40);
+        let negotiated = Features::SUPPORTED.intersection(remote);
+        assert_eq!(negotiated, Features::COMPRESSION);
+        assert!(!negotiated.contains(Features::MEMPOOL_SYNC));
+        assert_eq!(Features::SUPPORTED.names(), vec!["compression", "mempool-sync", "fee-filter", "block-sync", "snapshot", "locator", "multiproof", "protobuf"]);
+    }
+}
@@ -0,0 +1,92 @@
+use crate::crypto::hash::H256;
+use std::collections::{BTreeMap, HashMap};
+
+/// Number of hashes remembered by each `RecentInventory` set.
+pub static INVENTORY_CACHE_CAPACITY: usize = 4096;
+
+/// A set of the most recently seen hashes, forgetting the least recently seen one when full.
+pub struct LruSet {
+    capacity: usize,
+    /// hash -> when it was last seen
+    seen: HashMap<H256, u64>,
+    /// when -> hash, the least recently seen first
+    order: BTreeMap<u64, H256>,
+    clock: u64,
+}
+
+impl LruSet {
+    pub fn new(capacity: usize) -> Self {
+        LruSet {
+            capacity,
+            seen: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Mark `hash` as seen now. Returns whether it was not in the set.
+    pub fn insert(&mut self, hash: H256) -> bool {
+        self.clock += 1;
+        let is_new = match self.seen.insert(hash, self.clock) {
+            Some(last_seen) => {
+                self.order.remove(&last_seen);
+                false
+            }
+            None => true,
+        };
+        self.order.insert(self.clock, hash);
+        if self.seen.len() > self.capacity {
+            let (&oldest, _) = self.order.iter().next().unwrap();
+            let hash = self.order.remove(&oldest).unwrap();
+            self.seen.remove(&hash);
+        }
+        is_new
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.seen.contains_key(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+/// What the workers recently relayed and asked for, shared between the worker threads so that
+/// the same block is announced, and the same block or transaction requested, only once.
+pub struct RecentInventory {
+    pub announced: LruSet,
+    pub requested: LruSet,
+}
+
+impl Default for RecentInventory {
+    fn default() -> Self {
+        RecentInventory {
+            announced: LruSet::new(INVENTORY_CACHE_CAPACITY),
+            requested: LruSet::new(INVENTORY_CACHE_CAPACITY),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    #[test]
+    fn evicts_least_recently_seen() {
+        let hashes: Vec<H256> = (0..3).map(|_| generate_random_hash()).collect();
+        let mut set = LruSet::new(2);
+        assert!(set.insert(hashes[0]));
+        assert!(set.insert(hashes[1]));
+        assert!(!set.insert(hashes[0]));
+        assert!(set.insert(hashes[2]));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&hashes[0]));
+        assert!(!set.contains(&hashes[1]));
+    }
+}
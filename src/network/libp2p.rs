@@ -0,0 +1,437 @@
+//! A P2P server on libp2p instead of the TCP server, behind the same `server::Handle`, so the
+//! workers, miner and generator run unchanged. The connections are authenticated with noise and
+//! multiplexed with yamux; the broadcasts go out over gossipsub, on a topic for the blocks and one
+//! for the transactions, and the messages to a single peer, such as `GetBlocks` and its `Blocks`
+//! answer, over a request-response protocol acknowledged with an empty response. A peer is known
+//! by its libp2p peer id, derived from the static key of the node.
+
+use super::features::Features;
+use super::limits::Limits;
+use super::message::{Encoding, Message};
+use super::peer::{self, StaticKey};
+use super::server::{ControlSignal, Handle, BAN_SCORE};
+use super::shim::Shim;
+use super::stats::PeerInfo;
+use crate::events::{EventBus, NodeEvent};
+use ::libp2p::core::ConnectedPoint;
+use ::libp2p::futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use ::libp2p::futures::io::{AsyncRead, AsyncWrite};
+use ::libp2p::multiaddr::Protocol;
+use ::libp2p::swarm::dial_opts::DialOpts;
+use ::libp2p::swarm::{ConnectionId, NetworkBehaviour, SwarmEvent};
+use ::libp2p::{gossipsub, identity, noise, request_response, tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, SwarmBuilder};
+use crossbeam::channel as cbchannel;
+use log::{debug, error, info, trace, warn};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// The protocol of the messages to a single peer.
+const DIRECT_PROTOCOL: StreamProtocol = StreamProtocol::new("/prism/direct/1");
+/// The gossipsub topic of the block messages.
+const BLOCK_TOPIC: &str = "prism/blocks/1";
+/// The gossipsub topic of the transaction messages.
+const TRANSACTION_TOPIC: &str = "prism/transactions/1";
+/// How long a connection without traffic is kept open.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+/// Most messages in flight to a peer at once.
+const MAX_CONCURRENT_STREAMS: usize = 1024;
+/// What the peer id is derived from, see `StaticKey::derive_secret`.
+const IDENTITY_CONTEXT: &[u8] = b"prism libp2p identity";
+
+type MsgSink = cbchannel::Sender<(Vec<u8>, peer::Handle)>;
+
+/// Reads and writes a message to a single peer as its length, a big endian u32, then its bytes.
+/// The acknowledgement is empty.
+#[derive(Clone)]
+struct Codec {
+    max_message_size: usize,
+}
+
+#[async_trait::async_trait]
+impl request_response::Codec for Codec {
+    type Protocol = StreamProtocol;
+    type Request = Vec<u8>;
+    type Response = ();
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> std::io::Result<Vec<u8>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut length = [0; 4];
+        io.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length) as usize;
+        if length > self.max_message_size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("message of {} bytes", length)));
+        }
+        let mut msg = vec![0; length];
+        io.read_exact(&mut msg).await?;
+        Ok(msg)
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, _: &mut T) -> std::io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        Ok(())
+    }
+
+    async fn write_request<T>(&mut self, _: &StreamProtocol, io: &mut T, msg: Vec<u8>) -> std::io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&(msg.len() as u32).to_be_bytes()).await?;
+        io.write_all(&msg).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(&mut self, _: &StreamProtocol, io: &mut T, _: ()) -> std::io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.close().await
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: gossipsub::Behaviour,
+    direct: request_response::Behaviour<Codec>,
+}
+
+/// A connected peer.
+struct PeerEntry {
+    addr: SocketAddr,
+    direction: peer::Direction,
+    /// Its messages are sent as requests by the event loop.
+    handle: peer::Handle,
+    misbehavior: u32,
+}
+
+/// The libp2p swarm and the peers of a node.
+struct Context {
+    swarm: Swarm<Behaviour>,
+    peers: HashMap<PeerId, PeerEntry>,
+    /// The connections being dialed on behalf of `Handle::connect`.
+    dials: HashMap<ConnectionId, cbchannel::Sender<std::io::Result<peer::Handle>>>,
+    msg_sink: MsgSink,
+    events: Arc<EventBus>,
+    shim: Shim,
+    limits: Limits,
+    /// The messages the peer handles write, sent as requests.
+    outgoing_sender: mpsc::UnboundedSender<(PeerId, Vec<u8>)>,
+    block_topic: gossipsub::IdentTopic,
+    transaction_topic: gossipsub::IdentTopic,
+}
+
+fn to_multiaddr(addr: &SocketAddr) -> Multiaddr {
+    Multiaddr::from(addr.ip()).with(Protocol::Tcp(addr.port()))
+}
+
+fn to_socket_addr(addr: &Multiaddr) -> Option<SocketAddr> {
+    let mut ip = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::Ip4(v4) => ip = Some(v4.into()),
+            Protocol::Ip6(v6) => ip = Some(v6.into()),
+            Protocol::Tcp(port) => return ip.map(|ip| SocketAddr::new(ip, port)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Start a libp2p server listening at `addr`, whose peer id is derived from `key`. The messages
+/// its peers send are delivered to `msg_sink`.
+pub fn start(
+    addr: SocketAddr,
+    msg_sink: MsgSink,
+    events: &Arc<EventBus>,
+    shim: Shim,
+    limits: Limits,
+    key: &StaticKey,
+) -> std::io::Result<Handle> {
+    let other = |e: &dyn std::fmt::Display| std::io::Error::other(e.to_string());
+    let keypair = identity::Keypair::ed25519_from_bytes(key.derive_secret(IDENTITY_CONTEXT)).map_err(|e| other(&e))?;
+    let max_message_size = limits.max_message_size;
+    let mut swarm = SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_tcp(tcp::Config::default().nodelay(true), noise::Config::new, yamux::Config::default)
+        .map_err(|e| other(&e))?
+        .with_behaviour(|keypair| {
+            let gossip_config = gossipsub::ConfigBuilder::default()
+                .max_transmit_size(max_message_size)
+                // the same block announced by several nodes is one message
+                .message_id_fn(|msg: &gossipsub::Message| {
+                    let mut hasher = DefaultHasher::new();
+                    msg.data.hash(&mut hasher);
+                    gossipsub::MessageId::from(hasher.finish().to_string())
+                })
+                .build()?;
+            Ok(Behaviour {
+                gossipsub: gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(keypair.clone()), gossip_config)?,
+                direct: request_response::Behaviour::with_codec(
+                    Codec { max_message_size },
+                    [(DIRECT_PROTOCOL, request_response::ProtocolSupport::Full)],
+                    request_response::Config::default().with_max_concurrent_streams(MAX_CONCURRENT_STREAMS),
+                ),
+            })
+        })
+        .map_err(|e| other(&e))?
+        .with_swarm_config(|config| config.with_idle_connection_timeout(IDLE_TIMEOUT))
+        .build();
+    let block_topic = gossipsub::IdentTopic::new(BLOCK_TOPIC);
+    let transaction_topic = gossipsub::IdentTopic::new(TRANSACTION_TOPIC);
+    for topic in [&block_topic, &transaction_topic] {
+        swarm.behaviour_mut().gossipsub.subscribe(topic).map_err(|e| other(&e))?;
+    }
+    info!("libp2p peer id {}", swarm.local_peer_id());
+
+    let (control_sender, control_receiver) = mpsc::unbounded_channel();
+    let (outgoing_sender, outgoing_receiver) = mpsc::unbounded_channel();
+    let mut ctx = Context {
+        swarm,
+        peers: HashMap::new(),
+        dials: HashMap::new(),
+        msg_sink,
+        events: Arc::clone(events),
+        shim,
+        limits,
+        outgoing_sender,
+        block_topic,
+        transaction_topic,
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .thread_name("libp2p")
+        .enable_all()
+        .build()?;
+    runtime.block_on(async { ctx.swarm.listen_on(to_multiaddr(&addr)) }).map_err(|e| other(&e))?;
+    thread::Builder::new()
+        .name("p2p-server".to_string())
+        .spawn(move || {
+            runtime.block_on(ctx.run(control_receiver, outgoing_receiver));
+        })?;
+    Ok(Handle::from_control_chan(control_sender))
+}
+
+impl Context {
+    /// The event loop, until the server is shut down or every handle is dropped.
+    async fn run(
+        &mut self,
+        mut control_chan: mpsc::UnboundedReceiver<ControlSignal>,
+        mut outgoing: mpsc::UnboundedReceiver<(PeerId, Vec<u8>)>,
+    ) {
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.handle_event(event),
+                Some((peer_id, msg)) = outgoing.recv() => {
+                    self.swarm.behaviour_mut().direct.send_request(&peer_id, msg);
+                }
+                signal = control_chan.recv() => match signal {
+                    Some(ControlSignal::Shutdown(result_chan)) => {
+                        self.peers.clear();
+                        let _ = result_chan.send(());
+                        break;
+                    }
+                    Some(signal) => self.process_control(signal),
+                    None => break,
+                },
+            }
+        }
+        info!("libp2p server stopped");
+    }
+
+    fn process_control(&mut self, signal: ControlSignal) {
+        match signal {
+            ControlSignal::ConnectNewPeer(req) => {
+                if req.remote_key.is_some() {
+                    let e = std::io::Error::new(std::io::ErrorKind::Unsupported, "libp2p peers are known by their peer id, not a static key");
+                    let _ = req.result_chan.send(Err(e));
+                    return;
+                }
+                let opts = DialOpts::unknown_peer_id().address(to_multiaddr(&req.addr)).build();
+                let connection_id = opts.connection_id();
+                match self.swarm.dial(opts) {
+                    Ok(()) => {
+                        self.dials.insert(connection_id, req.result_chan);
+                    }
+                    Err(e) => {
+                        let _ = req.result_chan.send(Err(std::io::Error::other(e.to_string())));
+                    }
+                }
+            }
+            ControlSignal::BroadcastMessage(msg) => {
+                let topic = if msg.is_transaction() { &self.transaction_topic } else { &self.block_topic };
+                let bytes = msg.encode_as(Encoding::of(Features::SUPPORTED));
+                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), bytes) {
+                    debug!("Could not gossip a {} message: {}", msg.kind(), e);
+                }
+            }
+            ControlSignal::Penalize(addr, points) => {
+                let peer = self.peers.iter_mut().find(|(_, peer)| peer.addr == addr);
+                if let Some((peer_id, peer)) = peer {
+                    peer.misbehavior += points;
+                    if peer.misbehavior >= BAN_SCORE {
+                        warn!("Disconnecting misbehaving peer {}", addr);
+                        let peer_id = *peer_id;
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                    }
+                }
+            }
+            ControlSignal::GetPeers(result_chan) => {
+                let peers: Vec<PeerInfo> = self.peers.iter()
+                    .map(|(peer_id, peer)| {
+                        let handle = &peer.handle;
+                        handle.stats().info(peer.addr, peer.direction, peer::PROTOCOL_VERSION, handle.features(), Some(peer_id.to_string()), peer.misbehavior)
+                    })
+                    .collect();
+                let _ = result_chan.send(peers);
+            }
+            // handled by the event loop
+            ControlSignal::Shutdown(_) => unreachable!(),
+        }
+    }
+
+    fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => info!("libp2p server listening at {}", address),
+            SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, num_established, .. } => {
+                let handle = match num_established.get() {
+                    1 => self.register(peer_id, &endpoint),
+                    // another connection to the same peer
+                    _ => self.peers.get(&peer_id).map(|peer| Ok(peer.handle.clone())),
+                };
+                if let Some(result_chan) = self.dials.remove(&connection_id) {
+                    let handle = handle.unwrap_or_else(|| Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "peer without an address")));
+                    let _ = result_chan.send(handle);
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { connection_id, error, .. } => {
+                if let Some(result_chan) = self.dials.remove(&connection_id) {
+                    let _ = result_chan.send(Err(std::io::Error::other(error.to_string())));
+                }
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, cause, .. } => {
+                if let Some(peer) = self.peers.remove(&peer_id) {
+                    match cause {
+                        Some(e) => info!("Peer {} dropped connection: {}", peer.addr, e),
+                        None => debug!("Peer {} disconnected", peer.addr),
+                    }
+                    self.events.publish(NodeEvent::PeerDisconnected(peer.addr));
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message { propagation_source, message, .. })) => {
+                self.deliver(&propagation_source, message.data);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Direct(request_response::Event::Message { peer, message })) => {
+                if let request_response::Message::Request { request, channel, .. } = message {
+                    self.deliver(&peer, request);
+                    let _ = self.swarm.behaviour_mut().direct.send_response(channel, ());
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Direct(request_response::Event::OutboundFailure { peer, error, .. })) => {
+                debug!("Failed to send a message to peer {}: {}", peer, error);
+            }
+            event => trace!("libp2p event {:?}", event),
+        }
+    }
+
+    /// Register a peer on its first connection, refusing it above the peer caps. Returns its
+    /// handle, `None` if its address is not a TCP address.
+    fn register(&mut self, peer_id: PeerId, endpoint: &ConnectedPoint) -> Option<std::io::Result<peer::Handle>> {
+        let (remote, direction) = match endpoint {
+            ConnectedPoint::Dialer { address, .. } => (address, peer::Direction::Outgoing),
+            ConnectedPoint::Listener { send_back_addr, .. } => (send_back_addr, peer::Direction::Incoming),
+        };
+        let addr = to_socket_addr(remote)?;
+        let max_peers = match direction {
+            peer::Direction::Incoming => self.limits.max_inbound,
+            peer::Direction::Outgoing => self.limits.max_outbound,
+        };
+        if self.peers.values().filter(|peer| peer.direction == direction).count() >= max_peers {
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+            return Some(Err(std::io::Error::other(format!("max {:?} peers reached, cannot accept new connections", direction))));
+        }
+        let (handle, mut queue) = peer::Handle::with_queue(addr, &self.shim);
+        let outgoing = self.outgoing_sender.clone();
+        tokio::spawn(async move {
+            while let Some(msg) = queue.recv().await {
+                if outgoing.send((peer_id, msg)).is_err() {
+                    break;
+                }
+            }
+        });
+        self.peers.insert(peer_id, PeerEntry { addr, direction, handle: handle.clone(), misbehavior: 0 });
+        self.events.publish(NodeEvent::PeerConnected(addr));
+        info!("Peer {} connected with peer id {}", addr, peer_id);
+        // learn about the transactions broadcast before the connection
+        handle.write(Message::MempoolRequest);
+        Some(Ok(handle))
+    }
+
+    /// Pass a message of a peer to the workers.
+    fn deliver(&self, peer_id: &PeerId, msg: Vec<u8>) {
+        match self.peers.get(peer_id) {
+            Some(peer) => {
+                if self.msg_sink.send((msg, peer.handle.clone())).is_err() {
+                    error!("Workers gone, dropping a message from {}", peer.addr);
+                }
+            }
+            None => debug!("Dropping a message from unknown peer {}", peer_id),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+
+    #[test]
+    fn gossips_and_sends_to_a_peer() {
+        let events = Arc::new(EventBus::default());
+        let start = |port: u16| {
+            let (msg_tx, msg_rx) = cbchannel::unbounded();
+            let addr: SocketAddr = ([127, 0, 0, 1], port).into();
+            let handle = super::start(addr, msg_tx, &events, Shim::default(), Limits::default(), &StaticKey::generate()).unwrap();
+            (addr, handle, msg_rx)
+        };
+        let (first_addr, first, first_rx) = start(16114);
+        let (_, second, second_rx) = start(16115);
+        let peer = second.connect(first_addr).unwrap();
+        assert_eq!(peer.addr(), first_addr);
+        // each end asks for the mempool of the other
+        let (msg, reply_to) = first_rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert!(matches!(Message::decode(&msg), Ok(Some(Message::MempoolRequest))));
+        assert!(matches!(Message::decode(&second_rx.recv_timeout(Duration::from_secs(10)).unwrap().0), Ok(Some(Message::MempoolRequest))));
+
+        // the answer goes to the peer alone
+        reply_to.write(Message::Ping("direct".to_string()));
+        match Message::decode(&second_rx.recv_timeout(Duration::from_secs(10)).unwrap().0) {
+            Ok(Some(Message::Ping(nonce))) => assert_eq!(nonce, "direct"),
+            _ => panic!("expected a ping"),
+        }
+
+        // the subscriptions are exchanged once connected
+        let hashes = vec![H256::from([1u8; 32])];
+        let gossiped = (0..50).any(|_| {
+            second.broadcast(Message::NewBlockHashes(hashes.clone()));
+            matches!(
+                first_rx.recv_timeout(Duration::from_millis(200)).map(|(msg, _)| Message::decode(&msg)),
+                Ok(Ok(Some(Message::NewBlockHashes(_))))
+            )
+        });
+        assert!(gossiped);
+        assert_eq!(first.peers().len(), 1);
+        // known by their peer ids
+        assert!(second.peers()[0].static_key.as_ref().unwrap().starts_with("12D3KooW"));
+        first.shutdown();
+        second.shutdown();
+    }
+}
@@ -0,0 +1,127 @@
+use super::message::Message;
+use super::peer;
+use crate::crypto::hash::Hashable;
+use crate::light::{HeaderChain, HeaderInsert};
+use crossbeam::channel;
+use log::{debug, info, warn};
+
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// The worker of a light client: follows the longest chain by fetching the headers of the
+/// advertised blocks, and ignores the blocks and transactions relayed by full nodes.
+#[derive(Clone)]
+pub struct Context {
+    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    num_worker: usize,
+    headers: Arc<Mutex<HeaderChain>>,
+}
+
+pub fn new(
+    num_worker: usize,
+    msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    headers: &Arc<Mutex<HeaderChain>>,
+) -> Context {
+    Context {
+        msg_chan: msg_src,
+        num_worker,
+        headers: Arc::clone(headers),
+    }
+}
+
+impl Context {
+    /// Start the worker threads. They exit once the P2P server is shut down.
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
+        let num_worker = self.num_worker;
+        (0..num_worker).map(|i| {
+            let cloned = self.clone();
+            thread::spawn(move || {
+                cloned.worker_loop();
+                info!("Light worker thread {} exited", i);
+            })
+        }).collect()
+    }
+
+    fn worker_loop(&self) {
+        loop {
+            let (msg, peer) = match self.msg_chan.recv() {
+                Ok(msg) => msg,
+                // the server is gone
+                Err(_) => return,
+            };
+            let size = msg.len();
+            let msg: Message = match Message::decode(&msg) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => {
+                    debug!("Skipping a message of an unknown type from peer {}", peer.addr());
+                    continue;
+                }
+                Err(e) => {
+                    debug!("Dropping a malformed message: {}", e);
+                    continue;
+                }
+            };
+            peer.stats().received(&msg, size);
+            match msg {
+                Message::Ping(nonce) => {
+                    peer.write(Message::Pong(nonce));
+                }
+
+                // Ask the peer for the headers of the blocks we do not know.
+                Message::NewBlockHashes(hashes) => {
+                    let headers = self.headers.lock().unwrap();
+                    let unknown: Vec<_> = hashes.into_iter().filter(|hash| !headers.contains_key(hash)).collect();
+                    if !unknown.is_empty() {
+                        peer.write(Message::GetHeaders(unknown));
+                    }
+                }
+
+                // Serve the headers we have to other light clients.
+                Message::GetHeaders(hashes) => {
+                    let headers = self.headers.lock().unwrap();
+                    let found: Vec<_> = hashes.iter().filter_map(|hash| headers.get(hash)).collect();
+                    if !found.is_empty() {
+                        peer.write(Message::Headers(found));
+                    }
+                }
+
+                // Insert the headers, walking back to the known chain through the missing parents.
+                Message::Headers(received) => {
+                    let mut headers = self.headers.lock().unwrap();
+                    for header in received {
+                        let parent = header.parent;
+                        match headers.insert(header) {
+                            HeaderInsert::Orphan => peer.write(Message::GetHeaders(vec![parent])),
+                            HeaderInsert::Connected => debug!("Connected header {}", header.hash()),
+                            HeaderInsert::Rejected => {}
+                        }
+                    }
+                }
+
+                // Keep the proofs that check out against our headers, for the API to pick up.
+                Message::MerkleProof(proof) => {
+                    let valid = self.headers.lock().unwrap().record_proof(&proof);
+                    if !valid {
+                        warn!("Invalid merkle proof of {} in {}", proof.txid, proof.block_hash);
+                    }
+                }
+
+                Message::MerkleMultiProof(proof) => {
+                    let valid = self.headers.lock().unwrap().record_multiproof(&proof);
+                    if !valid {
+                        warn!("Invalid merkle multiproof of {} transactions in {}", proof.txids.len(), proof.block_hash);
+                    }
+                }
+
+                Message::AccountProof(proof) => {
+                    let valid = self.headers.lock().unwrap().record_account_proof(&proof);
+                    if !valid {
+                        warn!("Invalid account proof of {} in {}", proof.address, proof.block_hash);
+                    }
+                }
+
+                _ => {}
+            }
+        }
+    }
+}
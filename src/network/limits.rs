@@ -0,0 +1,124 @@
+//! Limits on the peers of a server and on what a single peer can send, so that it cannot
+//! saturate the worker channel.
+
+use super::message::Priority;
+use std::time::Instant;
+
+/// Number of messages the queue between the P2P server and the workers holds. A peer whose
+/// message does not fit waits for the workers.
+pub static WORKER_QUEUE_CAPACITY: usize = 4096;
+/// Fraction of the worker queue above which the low priority messages are dropped.
+pub static LOW_PRIORITY_WATERMARK: f64 = 0.5;
+/// Fraction of the worker queue above which the normal priority messages are dropped too.
+pub static NORMAL_PRIORITY_WATERMARK: f64 = 0.8;
+
+/// The limits applied to every peer of a server.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Largest message accepted, in bytes. A peer announcing a larger one is disconnected.
+    pub max_message_size: usize,
+    /// Messages per second a peer can sustain. The messages above the rate are dropped.
+    pub messages_per_sec: f64,
+    /// Messages a peer can send at once above the sustained rate.
+    pub burst: f64,
+    /// Most peers connected to this node at once. The others are refused.
+    pub max_inbound: usize,
+    /// Most peers this node connects to at once.
+    pub max_outbound: usize,
+    /// Capacity of the buffer a peer connection is read through, in bytes.
+    pub read_buffer: usize,
+    /// Capacity of the buffer a peer connection is written through, in bytes.
+    pub write_buffer: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_message_size: 16 * 1024 * 1024,
+            messages_per_sec: 1000.0,
+            burst: 5000.0,
+            max_inbound: 128,
+            max_outbound: 128,
+            read_buffer: 8 * 1024,
+            write_buffer: 8 * 1024,
+        }
+    }
+}
+
+/// A token bucket holding up to `capacity` tokens, refilled at `rate` tokens per second.
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        TokenBucket {
+            rate,
+            capacity,
+            tokens: capacity,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token at time `now`. Returns false if the bucket is empty.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Whether a message of `priority` is queued for the workers while `len` messages wait in a
+/// queue of `capacity`, unbounded when `None`.
+pub fn admits(priority: Priority, len: usize, capacity: Option<usize>) -> bool {
+    let capacity = match capacity {
+        Some(capacity) => capacity as f64,
+        None => return true,
+    };
+    match priority {
+        Priority::Low => (len as f64) < capacity * LOW_PRIORITY_WATERMARK,
+        Priority::Normal => (len as f64) < capacity * NORMAL_PRIORITY_WATERMARK,
+        Priority::High => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills_at_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(10.0, 2.0);
+        assert!(bucket.try_take(start));
+        assert!(bucket.try_take(start));
+        assert!(!bucket.try_take(start));
+        assert!(bucket.try_take(start + Duration::from_millis(100)));
+        assert!(!bucket.try_take(start + Duration::from_millis(150)));
+        // never more than the capacity
+        let later = start + Duration::from_secs(10);
+        assert!(bucket.try_take(later));
+        assert!(bucket.try_take(later));
+        assert!(!bucket.try_take(later));
+    }
+
+    #[test]
+    fn drops_low_priority_first() {
+        assert!(admits(Priority::Low, 49, Some(100)));
+        assert!(!admits(Priority::Low, 50, Some(100)));
+        assert!(admits(Priority::Normal, 50, Some(100)));
+        assert!(!admits(Priority::Normal, 80, Some(100)));
+        assert!(admits(Priority::High, 100, Some(100)));
+        assert!(admits(Priority::Low, 1_000_000, None));
+    }
+}
@@ -0,0 +1,137 @@
+use super::message::Message;
+use super::peer;
+use super::server::{ControlSignal, Handle};
+use super::stats::PeerInfo;
+use super::shim::Shim;
+use crate::events::{EventBus, NodeEvent};
+use crossbeam::channel as cbchannel;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tokio::sync::mpsc;
+
+type MsgSink = cbchannel::Sender<(Vec<u8>, peer::Handle)>;
+
+struct Node {
+    msg_sink: MsgSink,
+    peers: Arc<Mutex<Vec<(peer::Handle, peer::Direction)>>>,
+    events: Arc<EventBus>,
+}
+
+/// A network of in-process nodes. A node registers a server under an address, and gets a
+/// `server::Handle` that connects to and broadcasts to the other nodes through channels instead
+/// of TCP sockets, so the workers, miner and generator run unchanged.
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    nodes: Arc<Mutex<HashMap<SocketAddr, Node>>>,
+    shim: Arc<Mutex<Shim>>,
+}
+
+impl MemoryNetwork {
+    /// Set the latency and loss of the links made from now on.
+    pub fn set_shim(&self, shim: Shim) {
+        *self.shim.lock().unwrap() = shim;
+    }
+
+    /// Start the server of a node. The messages its peers send are delivered to `msg_sink`.
+    pub fn start_server(&self, addr: SocketAddr, msg_sink: MsgSink, events: &Arc<EventBus>) -> Handle {
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        self.nodes.lock().unwrap().insert(addr, Node {
+            msg_sink,
+            peers: Arc::clone(&peers),
+            events: Arc::clone(events),
+        });
+        let (control_sender, control_receiver) = mpsc::unbounded_channel();
+        let network = self.clone();
+        thread::Builder::new()
+            .name(format!("memory-server-{}", addr))
+            .spawn(move || {
+                for signal in receive(control_receiver) {
+                    if !network.process_control(addr, &peers, signal) {
+                        break;
+                    }
+                }
+                network.nodes.lock().unwrap().remove(&addr);
+            })
+            .unwrap();
+        info!("In-memory P2P server registered at {}", addr);
+        Handle::from_control_chan(control_sender)
+    }
+
+    /// Returns false once the server is shut down.
+    fn process_control(&self, addr: SocketAddr, peers: &Mutex<Vec<(peer::Handle, peer::Direction)>>, signal: ControlSignal) -> bool {
+        match signal {
+            ControlSignal::ConnectNewPeer(req) => {
+                let _ = req.result_chan.send(self.link(addr, req.addr));
+            }
+            ControlSignal::BroadcastMessage(msg) => {
+                for (peer, _) in peers.lock().unwrap().iter() {
+                    peer.relay(&msg);
+                }
+            }
+            ControlSignal::Penalize(peer, _) => {
+                debug!("In-memory node {} penalized peer {}", addr, peer);
+            }
+            ControlSignal::GetPeers(result_chan) => {
+                let peers: Vec<PeerInfo> = peers
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(peer, direction)| {
+                        peer.stats().info(peer.addr(), *direction, peer::PROTOCOL_VERSION, peer.features(), None, 0)
+                    })
+                    .collect();
+                let _ = result_chan.send(peers);
+            }
+            ControlSignal::Shutdown(result_chan) => {
+                peers.lock().unwrap().clear();
+                let _ = result_chan.send(());
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Connect `from` to `to` with a pair of queues, one per direction, and return the handle
+    /// `from` writes to.
+    fn link(&self, from: SocketAddr, to: SocketAddr) -> std::io::Result<peer::Handle> {
+        let nodes = self.nodes.lock().unwrap();
+        let (source, target) = match (nodes.get(&from), nodes.get(&to)) {
+            (Some(source), Some(target)) => (source, target),
+            _ => return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no in-memory node at {}", to))),
+        };
+        let shim = self.shim.lock().unwrap();
+        let (to_target, target_queue) = peer::Handle::with_queue(to, &shim);
+        let (to_source, source_queue) = peer::Handle::with_queue(from, &shim);
+        forward(target_queue, target.msg_sink.clone(), to_source.clone());
+        forward(source_queue, source.msg_sink.clone(), to_target.clone());
+        // learn about the transactions broadcast before the link
+        to_target.write(Message::MempoolRequest);
+        to_source.write(Message::MempoolRequest);
+        source.peers.lock().unwrap().push((to_target.clone(), peer::Direction::Outgoing));
+        target.peers.lock().unwrap().push((to_source, peer::Direction::Incoming));
+        source.events.publish(NodeEvent::PeerConnected(to));
+        target.events.publish(NodeEvent::PeerConnected(from));
+        debug!("Linked in-memory nodes {} and {}", from, to);
+        Ok(to_target)
+    }
+}
+
+/// Iterate over the items of a channel until all its senders are dropped.
+fn receive<T>(mut receiver: mpsc::UnboundedReceiver<T>) -> impl Iterator<Item = T> {
+    std::iter::from_fn(move || receiver.blocking_recv())
+}
+
+/// Deliver what is written to a peer handle to the workers of the receiving node, along with
+/// the handle they answer on.
+fn forward(queue: mpsc::UnboundedReceiver<Vec<u8>>, msg_sink: MsgSink, reply: peer::Handle) {
+    thread::spawn(move || {
+        for bytes in receive(queue) {
+            if msg_sink.send((bytes, reply.clone())).is_err() {
+                return;
+            }
+        }
+    });
+}
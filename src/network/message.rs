@@ -1,7 +1,81 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::H256;
-use crate::block::Block;
+use crate::block::{AccountProof, Block, Header, MerkleMultiProof, MerkleProof};
+use crate::crypto::address::H160;
+use crate::snapshot::Snapshot;
 use crate::transaction::SignedTransaction;
+use super::features::Features;
+use super::proto;
+use std::convert::TryInto;
+
+/// Version of the envelope around every message on the wire, whose payload is bincode encoded.
+pub static ENVELOPE_VERSION: u8 = 1;
+
+/// Version of the envelope whose payload is protobuf encoded, see `proto`.
+pub static PROTOBUF_ENVELOPE_VERSION: u8 = 2;
+
+/// Bytes of the envelope ahead of the payload: the version, the type id as a big endian u16 and
+/// the payload length as a big endian u32.
+pub const ENVELOPE_HEADER_LEN: usize = 7;
+
+/// Number of message types, the type ids from 0. The ids at or above it belong to the messages
+/// of newer peers, which are skipped.
+pub const MESSAGE_TYPES: u16 = 24;
+
+/// How the payloads of the messages written to a peer are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Bincode,
+    /// On the links negotiating `Features::PROTOBUF`.
+    Protobuf,
+}
+
+impl Encoding {
+    /// The encoding of a link using `features`.
+    pub fn of(features: Features) -> Self {
+        if features.contains(Features::PROTOBUF) {
+            Encoding::Protobuf
+        } else {
+            Encoding::Bincode
+        }
+    }
+}
+
+/// Why an envelope could not be read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// Shorter than the header.
+    Truncated,
+    /// A version of the envelope this node does not know.
+    UnsupportedVersion(u8),
+    /// The payload length of the header does not match the rest of the message.
+    LengthMismatch,
+    /// The payload is not a message of its type.
+    MalformedPayload,
+}
+
+impl std::fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            EnvelopeError::Truncated => write!(f, "truncated envelope"),
+            EnvelopeError::UnsupportedVersion(version) => write!(f, "unsupported envelope version {}", version),
+            EnvelopeError::LengthMismatch => write!(f, "payload length mismatch"),
+            EnvelopeError::MalformedPayload => write!(f, "malformed payload"),
+        }
+    }
+}
+
+/// How much a message matters when the workers fall behind: the low priority messages are
+/// dropped first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Transaction announcements.
+    Low,
+    /// The other transaction messages.
+    Normal,
+    /// Block and control messages, never dropped.
+    High,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
@@ -11,8 +85,278 @@ pub enum Message {
     NewBlockHashes(Vec<H256>),
     GetBlocks(Vec<H256>),
     Blocks(Vec<Block>),
+    /// (block hash, count) Ask a peer for the hashes of the blocks after the block in its
+    /// longest chain, at most count.
+    GetChainHashes(H256, u32),
+    /// (block hash, the hashes after it) Empty if the block is not in the longest chain.
+    ChainHashes(H256, Vec<H256>),
+    GetHeaders(Vec<H256>),
+    Headers(Vec<Header>),
+    /// (block hash, txid)
+    GetMerkleProof(H256, H256),
+    MerkleProof(MerkleProof),
+    /// (block hash, address)
+    GetAccountProof(H256, H160),
+    AccountProof(AccountProof),
 
     NewTransactionHashes(Vec<H256>),
+    /// Ask a peer for the hashes of all the transactions in its mempool, sent on connection.
+    MempoolRequest,
+    MempoolInv(Vec<H256>),
+    /// Ask a peer not to relay transactions paying less than this fee.
+    FeeFilter(u64),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+
+    /// Ask a peer for its latest checkpoint, if it is higher than this height.
+    GetSnapshot(u32),
+    Snapshot(Box<Snapshot>),
+
+    /// (locator) Ask a peer for the hashes of the blocks of its longest chain after the first
+    /// block of the locator in that chain, at most `SYNC_BATCH`. Answered with `ChainHashes`.
+    GetBlocksFrom(Vec<H256>),
+
+    /// (block hash, txids) Ask for a single inclusion proof of several transactions of a block.
+    GetMerkleMultiProof(H256, Vec<H256>),
+    MerkleMultiProof(MerkleMultiProof),
+}
+
+impl Message {
+    /// The name of the message type, as accounted in the peer statistics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "Ping",
+            Message::Pong(_) => "Pong",
+            Message::NewBlockHashes(_) => "NewBlockHashes",
+            Message::GetBlocks(_) => "GetBlocks",
+            Message::Blocks(_) => "Blocks",
+            Message::GetChainHashes(..) => "GetChainHashes",
+            Message::ChainHashes(..) => "ChainHashes",
+            Message::GetHeaders(_) => "GetHeaders",
+            Message::Headers(_) => "Headers",
+            Message::GetMerkleProof(..) => "GetMerkleProof",
+            Message::MerkleProof(_) => "MerkleProof",
+            Message::GetAccountProof(..) => "GetAccountProof",
+            Message::AccountProof(_) => "AccountProof",
+            Message::NewTransactionHashes(_) => "NewTransactionHashes",
+            Message::MempoolRequest => "MempoolRequest",
+            Message::MempoolInv(_) => "MempoolInv",
+            Message::FeeFilter(_) => "FeeFilter",
+            Message::GetTransactions(_) => "GetTransactions",
+            Message::Transactions(_) => "Transactions",
+            Message::GetSnapshot(_) => "GetSnapshot",
+            Message::Snapshot(_) => "Snapshot",
+            Message::GetBlocksFrom(_) => "GetBlocksFrom",
+            Message::GetMerkleMultiProof(..) => "GetMerkleMultiProof",
+            Message::MerkleMultiProof(_) => "MerkleMultiProof",
+        }
+    }
+
+    /// The id of the type of the message in its envelope, its position in the enum.
+    pub fn type_id(&self) -> u16 {
+        match self {
+            Message::Ping(_) => 0,
+            Message::Pong(_) => 1,
+            Message::NewBlockHashes(_) => 2,
+            Message::GetBlocks(_) => 3,
+            Message::Blocks(_) => 4,
+            Message::GetChainHashes(..) => 5,
+            Message::ChainHashes(..) => 6,
+            Message::GetHeaders(_) => 7,
+            Message::Headers(_) => 8,
+            Message::GetMerkleProof(..) => 9,
+            Message::MerkleProof(_) => 10,
+            Message::GetAccountProof(..) => 11,
+            Message::AccountProof(_) => 12,
+            Message::NewTransactionHashes(_) => 13,
+            Message::MempoolRequest => 14,
+            Message::MempoolInv(_) => 15,
+            Message::FeeFilter(_) => 16,
+            Message::GetTransactions(_) => 17,
+            Message::Transactions(_) => 18,
+            Message::GetSnapshot(_) => 19,
+            Message::Snapshot(_) => 20,
+            Message::GetBlocksFrom(_) => 21,
+            Message::GetMerkleMultiProof(..) => 22,
+            Message::MerkleMultiProof(_) => 23,
+        }
+    }
+
+    /// The features a peer needs to understand the message. It is not relayed to the others.
+    pub fn required_features(&self) -> Features {
+        match self {
+            Message::MempoolRequest | Message::MempoolInv(_) => Features::MEMPOOL_SYNC,
+            Message::FeeFilter(_) => Features::FEE_FILTER,
+            Message::GetChainHashes(..) | Message::ChainHashes(..) => Features::BLOCK_SYNC,
+            Message::GetSnapshot(_) | Message::Snapshot(_) => Features::SNAPSHOT,
+            Message::GetBlocksFrom(_) => Features::LOCATOR,
+            Message::GetMerkleMultiProof(..) | Message::MerkleMultiProof(_) => Features::MULTIPROOF,
+            _ => Features::NONE,
+        }
+    }
+
+    /// Whether the message is about transactions, and handled by the transaction workers
+    /// instead of the block workers.
+    pub fn is_transaction(&self) -> bool {
+        matches!(
+            self,
+            Message::NewTransactionHashes(_)
+                | Message::MempoolRequest
+                | Message::MempoolInv(_)
+                | Message::FeeFilter(_)
+                | Message::GetTransactions(_)
+                | Message::Transactions(_)
+        )
+    }
+
+    pub fn priority(&self) -> Priority {
+        match self {
+            Message::NewTransactionHashes(_) | Message::MempoolInv(_) => Priority::Low,
+            _ if self.is_transaction() => Priority::Normal,
+            _ => Priority::High,
+        }
+    }
+
+    /// The message in its envelope: the header, then the fields of the message, bincode encoded.
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_as(Encoding::Bincode)
+    }
+
+    /// The message in its envelope, the fields of the message in `encoding`.
+    pub fn encode_as(&self, encoding: Encoding) -> Vec<u8> {
+        let (version, type_id, payload) = match encoding {
+            Encoding::Bincode => {
+                // bincode starts an enum with the variant index, as a little endian u32, which is
+                // the type id
+                let mut bytes = bincode::serialize(self).unwrap();
+                let type_id = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as u16;
+                bytes.drain(..4);
+                (ENVELOPE_VERSION, type_id, bytes)
+            }
+            Encoding::Protobuf => (PROTOBUF_ENVELOPE_VERSION, self.type_id(), proto::encode(self)),
+        };
+        let mut encoded = Vec::with_capacity(ENVELOPE_HEADER_LEN + payload.len());
+        encoded.push(version);
+        encoded.extend_from_slice(&type_id.to_be_bytes());
+        encoded.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(&payload);
+        encoded
+    }
+
+    /// The message in the envelope `bytes`, in either encoding, `None` if its type is unknown,
+    /// as sent by a newer peer.
+    pub fn decode(bytes: &[u8]) -> Result<Option<Message>, EnvelopeError> {
+        let (version, type_id, payload) = Self::envelope(bytes)?;
+        if version == PROTOBUF_ENVELOPE_VERSION {
+            return proto::decode(type_id, payload);
+        }
+        if version != ENVELOPE_VERSION {
+            return Err(EnvelopeError::UnsupportedVersion(version));
+        }
+        if type_id >= MESSAGE_TYPES {
+            return Ok(None);
+        }
+        let mut encoded = Vec::with_capacity(4 + payload.len());
+        encoded.extend_from_slice(&(type_id as u32).to_le_bytes());
+        encoded.extend_from_slice(payload);
+        bincode::deserialize(&encoded).map(Some).map_err(|_| EnvelopeError::MalformedPayload)
+    }
+
+    /// The version, the type id and the payload of an envelope.
+    fn envelope(bytes: &[u8]) -> Result<(u8, u16, &[u8]), EnvelopeError> {
+        if bytes.len() < ENVELOPE_HEADER_LEN {
+            return Err(EnvelopeError::Truncated);
+        }
+        let type_id = u16::from_be_bytes(bytes[1..3].try_into().unwrap());
+        let length = u32::from_be_bytes(bytes[3..ENVELOPE_HEADER_LEN].try_into().unwrap()) as usize;
+        let payload = &bytes[ENVELOPE_HEADER_LEN..];
+        if payload.len() != length {
+            return Err(EnvelopeError::LengthMismatch);
+        }
+        Ok((bytes[0], type_id, payload))
+    }
+
+    /// The priority of a message in its envelope, read from its type id without decoding it.
+    /// The bytes that are not a message, and the unknown types, get a low priority.
+    pub fn priority_of(bytes: &[u8]) -> Priority {
+        let variant = match Self::envelope(bytes) {
+            Ok((_, type_id, _)) => type_id,
+            Err(_) => return Priority::Low,
+        };
+        match variant {
+            0..=12 | 19..=23 => Priority::High,
+            13 | 15 => Priority::Low,
+            14 | 16..=18 => Priority::Normal,
+            _ => Priority::Low,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priority_of_serialized_messages() {
+        let messages = vec![
+            Message::Ping(String::new()),
+            Message::Pong(String::new()),
+            Message::NewBlockHashes(vec![]),
+            Message::GetBlocks(vec![]),
+            Message::Blocks(vec![]),
+            Message::GetChainHashes(H256::default(), 0),
+            Message::ChainHashes(H256::default(), vec![]),
+            Message::GetHeaders(vec![]),
+            Message::Headers(vec![]),
+            Message::GetMerkleProof(H256::default(), H256::default()),
+            Message::GetAccountProof(H256::default(), H160::default()),
+            Message::NewTransactionHashes(vec![]),
+            Message::MempoolRequest,
+            Message::MempoolInv(vec![]),
+            Message::FeeFilter(0),
+            Message::GetTransactions(vec![]),
+            Message::Transactions(vec![]),
+            Message::GetSnapshot(0),
+            Message::GetBlocksFrom(vec![]),
+            Message::GetMerkleMultiProof(H256::default(), vec![]),
+        ];
+        for msg in messages {
+            let bytes = msg.encode();
+            assert_eq!(Message::priority_of(&bytes), msg.priority(), "{}", msg.kind());
+        }
+        assert_eq!(Message::priority_of(&[0xff, 0xff, 0xff]), Priority::Low);
+    }
+
+    #[test]
+    fn envelope_skips_unknown_types() {
+        let msg = Message::GetChainHashes(H256::from([7u8; 32]), 3);
+        let bytes = msg.encode();
+        assert_eq!(bytes[0], ENVELOPE_VERSION);
+        assert_eq!(bytes.len(), ENVELOPE_HEADER_LEN + 36);
+        assert!(matches!(Message::decode(&bytes), Ok(Some(Message::GetChainHashes(_, 3)))));
+        let last = Message::MerkleMultiProof(MerkleMultiProof {
+            block_hash: H256::default(),
+            txids: vec![],
+            indices: vec![],
+            leaf_size: 0,
+            proof: vec![],
+        });
+        assert_eq!(u16::from_be_bytes([last.encode()[1], last.encode()[2]]), MESSAGE_TYPES - 1);
+
+        // a message of a newer peer
+        let mut unknown = bytes.clone();
+        unknown[1..3].copy_from_slice(&MESSAGE_TYPES.to_be_bytes());
+        assert!(matches!(Message::decode(&unknown), Ok(None)));
+        assert_eq!(Message::priority_of(&unknown), Priority::Low);
+
+        let mut newer = bytes.clone();
+        newer[0] = PROTOBUF_ENVELOPE_VERSION + 1;
+        assert_eq!(Message::decode(&newer).err(), Some(EnvelopeError::UnsupportedVersion(PROTOBUF_ENVELOPE_VERSION + 1)));
+        assert_eq!(Message::decode(&bytes[..bytes.len() - 1]).err(), Some(EnvelopeError::LengthMismatch));
+        assert_eq!(Message::decode(&bytes[..3]).err(), Some(EnvelopeError::Truncated));
+        let mut malformed = bytes;
+        malformed.truncate(ENVELOPE_HEADER_LEN + 2);
+        malformed[3..ENVELOPE_HEADER_LEN].copy_from_slice(&2u32.to_be_bytes());
+        assert_eq!(Message::decode(&malformed).err(), Some(EnvelopeError::MalformedPayload));
+    }
 }
@@ -1,4 +1,17 @@
+pub mod compression;
+pub mod download;
+pub mod features;
+pub mod inventory;
+#[cfg(feature = "libp2p")]
+pub mod libp2p;
+pub mod light_worker;
+pub mod limits;
+pub mod memory;
 pub mod message;
+pub mod nat;
 pub mod peer;
+pub mod proto;
 pub mod server;
+pub mod shim;
+pub mod stats;
 pub mod worker;
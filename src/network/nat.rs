@@ -0,0 +1,304 @@
+//! Port mapping on the router of a node behind a NAT, so that its peers can connect to it: at
+//! startup the P2P port is mapped with UPnP, or failing that with NAT-PMP, and the mapping is
+//! renewed until shutdown. Without a mapping the node runs outbound-only, it connects to its
+//! peers but announces no address to them.
+
+use igd_next::{AddPortError, PortMappingProtocol, SearchOptions};
+use log::{debug, info, warn};
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How long the routers keep a mapping, renewed halfway.
+const LEASE: Duration = Duration::from_secs(3600);
+/// How long to wait for the UPnP routers to answer the search.
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(2);
+/// The name of the mappings on the router.
+const DESCRIPTION: &str = "prism";
+/// The port of the NAT-PMP server of the gateway.
+const NAT_PMP_PORT: u16 = 5351;
+/// How long to wait for the first NAT-PMP answer, doubled at each try.
+const NAT_PMP_TIMEOUT: Duration = Duration::from_millis(250);
+const NAT_PMP_TRIES: usize = 3;
+
+/// How the P2P port is reachable from outside.
+enum Method {
+    /// The node listens on a public address, no mapping is needed.
+    Direct,
+    Upnp(igd_next::Gateway),
+    /// A NAT-PMP mapping on the gateway at this address.
+    NatPmp(Ipv4Addr),
+}
+
+/// A port mapping of the P2P server.
+pub struct Mapping {
+    /// The address at which the peers reach the node.
+    pub external: SocketAddr,
+    /// The address on the local network the router forwards to.
+    local: SocketAddr,
+    method: Method,
+    removed: AtomicBool,
+}
+
+impl Mapping {
+    fn new(external: SocketAddr, local: SocketAddr, method: Method) -> Self {
+        Mapping {
+            external,
+            local,
+            method,
+            removed: AtomicBool::new(false),
+        }
+    }
+
+    /// Renew the lease of the mapping on the router.
+    pub fn renew(&self) -> Result<()> {
+        if self.removed.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        match &self.method {
+            Method::Direct => Ok(()),
+            Method::Upnp(gateway) => add_upnp(gateway, self.local),
+            Method::NatPmp(gateway) => {
+                let port = add_nat_pmp(*gateway, self.local.port(), self.external.port(), LEASE)?;
+                if port != self.external.port() {
+                    warn!("NAT-PMP gateway moved the mapping of {} to port {}", self.local, port);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Remove the mapping from the router, the node then accepts no more connections from
+    /// outside.
+    pub fn remove(&self) -> Result<()> {
+        if self.removed.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        match &self.method {
+            Method::Direct => Ok(()),
+            Method::Upnp(gateway) => gateway
+                .remove_port(PortMappingProtocol::TCP, self.external.port())
+                .map_err(Error::other),
+            // a lifetime of 0 deletes the mapping
+            Method::NatPmp(gateway) => add_nat_pmp(*gateway, self.local.port(), 0, Duration::from_secs(0)).map(|_| ()),
+        }
+    }
+}
+
+/// Make the P2P server listening at `addr` reachable from outside, and renew the mapping in the
+/// background. Returns `None` if the node is only reachable from its own network: it listens on
+/// the loopback, or no router accepted the mapping.
+pub fn start(addr: SocketAddr) -> Option<Arc<Mapping>> {
+    let mapping = Arc::new(map_port(addr)?);
+    info!("P2P server reachable at {}", mapping.external);
+    if let Method::Direct = mapping.method {
+        return Some(mapping);
+    }
+    let renewed = Arc::clone(&mapping);
+    thread::Builder::new()
+        .name("port-mapping".to_string())
+        .spawn(move || loop {
+            thread::sleep(LEASE / 2);
+            if renewed.removed.load(Ordering::SeqCst) {
+                return;
+            }
+            match renewed.renew() {
+                Ok(()) => debug!("Renewed the port mapping of {}", renewed.external),
+                Err(e) => warn!("Error renewing the port mapping of {}: {}", renewed.external, e),
+            }
+        })
+        .unwrap();
+    Some(mapping)
+}
+
+/// Map the port of `addr` on the router, with UPnP then NAT-PMP.
+pub fn map_port(addr: SocketAddr) -> Option<Mapping> {
+    if addr.ip().is_loopback() {
+        debug!("P2P server listening on the loopback, no port mapping");
+        return None;
+    }
+    if is_public(addr.ip()) {
+        return Some(Mapping::new(addr, addr, Method::Direct));
+    }
+    if addr.is_ipv6() {
+        warn!("No port mapping for the IPv6 address {}, running outbound-only", addr);
+        return None;
+    }
+    match map_upnp(addr) {
+        Ok(mapping) => return Some(mapping),
+        Err(e) => debug!("UPnP port mapping failed: {}", e),
+    }
+    match map_nat_pmp(addr) {
+        Ok(mapping) => return Some(mapping),
+        Err(e) => debug!("NAT-PMP port mapping failed: {}", e),
+    }
+    warn!("No router mapped the port of {}, running outbound-only", addr);
+    None
+}
+
+/// Whether the peers may reach `ip` without a mapping.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            // the carrier-grade NAT range 100.64.0.0/10
+            let shared = first == 100 && second & 0xc0 == 64;
+            !(ip.is_unspecified() || ip.is_loopback() || ip.is_private() || ip.is_link_local() || shared)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            // unique local fc00::/7, link local fe80::/10
+            !(ip.is_unspecified() || ip.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// The address of this host on the network of `gateway`, for a server listening at `addr`.
+fn lan_addr(addr: SocketAddr, gateway: IpAddr) -> Result<SocketAddr> {
+    if !addr.ip().is_unspecified() {
+        return Ok(addr);
+    }
+    // connecting a UDP socket sends nothing, but picks the interface toward the gateway
+    let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))?;
+    socket.connect(SocketAddr::new(gateway, NAT_PMP_PORT))?;
+    Ok(SocketAddr::new(socket.local_addr()?.ip(), addr.port()))
+}
+
+fn map_upnp(addr: SocketAddr) -> Result<Mapping> {
+    let gateway = igd_next::search_gateway(SearchOptions {
+        timeout: Some(SEARCH_TIMEOUT),
+        ..Default::default()
+    })
+    .map_err(Error::other)?;
+    let local = lan_addr(addr, gateway.addr.ip())?;
+    let external_ip = gateway.get_external_ip().map_err(Error::other)?;
+    add_upnp(&gateway, local)?;
+    info!("Mapped port {} with UPnP on {}", local.port(), gateway.addr);
+    Ok(Mapping::new(SocketAddr::new(external_ip, local.port()), local, Method::Upnp(gateway)))
+}
+
+/// Map the same port as `local` to it, for the lease if the gateway supports one.
+fn add_upnp(gateway: &igd_next::Gateway, local: SocketAddr) -> Result<()> {
+    let lease = LEASE.as_secs() as u32;
+    match gateway.add_port(PortMappingProtocol::TCP, local.port(), local, lease, DESCRIPTION) {
+        Err(AddPortError::OnlyPermanentLeasesSupported) => {
+            gateway.add_port(PortMappingProtocol::TCP, local.port(), local, 0, DESCRIPTION)
+        }
+        result => result,
+    }
+    .map_err(Error::other)
+}
+
+fn map_nat_pmp(addr: SocketAddr) -> Result<Mapping> {
+    let gateway = default_gateway()?;
+    let local = lan_addr(addr, gateway.into())?;
+    let response = nat_pmp_request(gateway, &[0, 0])?;
+    let external_ip: [u8; 4] = nat_pmp_response(&response, 0, 12)?.try_into().unwrap();
+    let port = add_nat_pmp(gateway, local.port(), local.port(), LEASE)?;
+    info!("Mapped port {} to {} with NAT-PMP on {}", local.port(), port, gateway);
+    Ok(Mapping::new(SocketAddr::new(Ipv4Addr::from(external_ip).into(), port), local, Method::NatPmp(gateway)))
+}
+
+/// Map the TCP port `external`, or another one at the choice of the gateway, to the port
+/// `internal` of this host for `lifetime`. Returns the external port.
+fn add_nat_pmp(gateway: Ipv4Addr, internal: u16, external: u16, lifetime: Duration) -> Result<u16> {
+    let mut request = vec![0, 2, 0, 0];
+    request.extend_from_slice(&internal.to_be_bytes());
+    request.extend_from_slice(&external.to_be_bytes());
+    request.extend_from_slice(&(lifetime.as_secs() as u32).to_be_bytes());
+    let response = nat_pmp_request(gateway, &request)?;
+    let mapping = nat_pmp_response(&response, 2, 16)?;
+    Ok(u16::from_be_bytes(mapping[2..4].try_into().unwrap()))
+}
+
+/// Send `request` to the NAT-PMP server of `gateway`, retrying with longer timeouts.
+fn nat_pmp_request(gateway: Ipv4Addr, request: &[u8]) -> Result<Vec<u8>> {
+    let socket = UdpSocket::bind(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0))?;
+    socket.connect(SocketAddr::new(gateway.into(), NAT_PMP_PORT))?;
+    let mut timeout = NAT_PMP_TIMEOUT;
+    let mut response = [0; 16];
+    for _ in 0..NAT_PMP_TRIES {
+        socket.set_read_timeout(Some(timeout))?;
+        socket.send(request)?;
+        match socket.recv(&mut response) {
+            Ok(len) => return Ok(response[..len].to_vec()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => timeout *= 2,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(Error::new(ErrorKind::TimedOut, format!("no NAT-PMP answer from {}", gateway)))
+}
+
+/// Check the response of `len` bytes to the NAT-PMP request `opcode`, and return what follows
+/// its header: the version, the opcode, the result code then the epoch of the gateway.
+fn nat_pmp_response(response: &[u8], opcode: u8, len: usize) -> Result<&[u8]> {
+    if response.len() != len || response[0] != 0 || response[1] != 128 + opcode {
+        return Err(Error::new(ErrorKind::InvalidData, "malformed NAT-PMP response"));
+    }
+    match u16::from_be_bytes([response[2], response[3]]) {
+        0 => Ok(&response[8..]),
+        code => Err(Error::other(format!("NAT-PMP gateway refused with result code {}", code))),
+    }
+}
+
+/// The gateway of the default route, read from the routing table of Linux.
+fn default_gateway() -> Result<Ipv4Addr> {
+    let table = std::fs::read_to_string("/proc/net/route")?;
+    parse_default_gateway(&table).ok_or_else(|| Error::new(ErrorKind::NotFound, "no default gateway"))
+}
+
+/// The lines of the table are the interface, the destination, the gateway, then the flags, the
+/// addresses being the bytes of the IP in hex, in the order of the host.
+fn parse_default_gateway(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "00000000", gateway, ..] if *gateway != "00000000" => {
+                u32::from_str_radix(gateway, 16).ok().map(|ip| Ipv4Addr::from(ip.to_ne_bytes()))
+            }
+            _ => None,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_public_addresses() {
+        assert!(is_public("203.0.113.7".parse().unwrap()));
+        assert!(is_public("2001:db8::1".parse().unwrap()));
+        for ip in &["0.0.0.0", "127.0.0.1", "192.168.1.20", "10.0.0.3", "100.64.0.1", "::", "fe80::1", "fd00::1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        assert!(map_port(([127, 0, 0, 1], 6000).into()).is_none());
+        assert_eq!(map_port(([203, 0, 113, 7], 6000).into()).unwrap().external, ([203, 0, 113, 7], 6000).into());
+    }
+
+    #[test]
+    fn parses_nat_pmp_responses() {
+        let mut response = vec![0, 130, 0, 0, 0, 0, 0, 9, 0x17, 0x70, 0x17, 0x71, 0, 0, 0x0e, 0x10];
+        assert_eq!(nat_pmp_response(&response, 2, 16).unwrap(), &[0x17, 0x70, 0x17, 0x71, 0, 0, 0x0e, 0x10]);
+        // the external address response to a mapping request
+        assert!(nat_pmp_response(&response, 0, 16).is_err());
+        assert!(nat_pmp_response(&response[..12], 2, 16).is_err());
+        // not authorized
+        response[3] = 2;
+        assert!(nat_pmp_response(&response, 2, 16).is_err());
+    }
+
+    #[test]
+    #[cfg(target_endian = "little")]
+    fn finds_default_gateway() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     eth0\t0002A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+                     eth0\t00000000\t0102A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(parse_default_gateway(table), Some(Ipv4Addr::new(192, 168, 2, 1)));
+        assert_eq!(parse_default_gateway(table.lines().take(2).collect::<Vec<_>>().join("\n").as_str()), None);
+    }
+}
@@ -1,202 +1,318 @@
+use super::compression::{self, Compression};
+use super::features::Features;
+use super::limits::{self, Limits, TokenBucket};
 use super::message;
+use super::shim::Shim;
+use super::stats::PeerStats;
+use serde::Serialize;
+use crossbeam::channel as cbchannel;
 use log::{trace, warn};
-use mio;
-use mio_extras::channel;
 use std::convert::TryInto;
-use std::io::{Read, Write};
-use std::sync::mpsc;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 
-enum DecodeState {
-    Length,
-    Payload,
+/// The Noise protocol run on every peer link: the peers exchange their static keys and prove
+/// they own them, then encrypt the traffic with ChaCha20-Poly1305.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message, handshake or transport, in bytes.
+const MAX_FRAME: usize = 65535;
+/// Size of the authentication tag of a transport message.
+const TAG_LEN: usize = 16;
+/// Version of the peer protocol spoken by this node, announced during the handshake.
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Peers announcing an older version are refused.
+const MIN_PROTOCOL_VERSION: u32 = 1;
+/// How long the handshake of a new connection may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often a reader waiting for room in the worker queue tries again.
+const QUEUE_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+
+/// The static public key of a node.
+pub type PublicKey = [u8; 32];
+
+/// Why a peer connection ended.
+#[derive(Debug)]
+pub enum Disconnect {
+    /// The peer closed the connection, or the socket failed.
+    Closed(std::io::Error),
+    /// The peer announced a message over the size limit.
+    Oversized(usize),
+    /// A frame failed to decrypt: it was forged, altered or replayed.
+    Forged,
+    /// A compressed message failed to decompress, or to fit the size limit once decompressed.
+    BadCompression,
+    /// The misbehavior score of the peer reached the ban score.
+    Banned,
+    /// The handles of the peer are all dropped, nothing more can be written.
+    Detached,
 }
 
-pub enum ReadResult {
-    Continue,
-    Message(Vec<u8>),
-    EOF,
+fn noise_error(e: snow::Error) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("noise: {}", e))
 }
 
-pub struct ReadContext {
-    reader: std::io::BufReader<mio::net::TcpStream>,
-    buffer: Vec<u8>,
-    msg_length: usize,
-    read_length: usize,
-    state: DecodeState,
+/// The static X25519 key pair authenticating this node to its peers.
+#[derive(Clone)]
+pub struct StaticKey {
+    private: Vec<u8>,
+    pub public: PublicKey,
 }
 
-impl ReadContext {
-    pub fn read(&mut self) -> std::io::Result<ReadResult> {
-        let bytes_read = self
-            .reader
-            .read(&mut self.buffer[self.read_length..self.msg_length]);
-        match bytes_read {
-            Ok(0) => {
-                trace!("Detected socket EOF");
-                Ok(ReadResult::EOF)
-            }
-            Ok(size) => {
-                trace!("Read {} bytes from socket", size);
-                // we got some data, move the cursor
-                self.read_length += size;
-                if self.read_length == self.msg_length {
-                    // buffer filled, process the buffer
-                    match self.state {
-                        DecodeState::Length => {
-                            let message_length =
-                                u32::from_be_bytes(self.buffer[0..4].try_into().unwrap());
-                            self.state = DecodeState::Payload;
-                            self.read_length = 0;
-                            self.msg_length = message_length as usize;
-                            if self.buffer.len() < self.msg_length {
-                                self.buffer.resize(self.msg_length, 0);
-                            }
-                            trace!("Received message length={}", message_length);
-                            Ok(ReadResult::Continue)
-                        }
-                        DecodeState::Payload => {
-                            let new_payload: Vec<u8> = self.buffer[0..self.msg_length].to_vec();
-                            self.state = DecodeState::Length;
-                            self.read_length = 0;
-                            self.msg_length = std::mem::size_of::<u32>();
-                            trace!("Received full message");
-                            Ok(ReadResult::Message(new_payload))
-                        }
-                    }
-                } else {
-                    Ok(ReadResult::Continue)
+impl StaticKey {
+    pub fn generate() -> Self {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .generate_keypair()
+            .unwrap();
+        StaticKey {
+            private: keypair.private,
+            public: keypair.public[..].try_into().unwrap(),
+        }
+    }
+
+    /// A secret derived from the private key for `context`, so that the other identities of
+    /// the node follow the key file without reusing the key itself.
+    #[cfg(feature = "libp2p")]
+    pub(super) fn derive_secret(&self, context: &[u8]) -> [u8; 32] {
+        let mut ctx = ring::digest::Context::new(&ring::digest::SHA256);
+        ctx.update(context);
+        ctx.update(&self.private);
+        ctx.finish().as_ref().try_into().unwrap()
+    }
+
+    /// Load the key pair stored hex encoded in `path`, generating and storing one if the file
+    /// does not exist.
+    pub fn load_or_create(path: &Path) -> std::io::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => {
+                let bytes = hex::decode(contents.trim())
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "bad key encoding"))?;
+                if bytes.len() != 64 {
+                    return Err(Error::new(ErrorKind::InvalidData, "bad key length"));
                 }
+                Ok(StaticKey {
+                    private: bytes[..32].to_vec(),
+                    public: bytes[32..].try_into().unwrap(),
+                })
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let key = StaticKey::generate();
+                let mut bytes = key.private.clone();
+                bytes.extend_from_slice(&key.public);
+                std::fs::write(path, hex::encode(bytes))?;
+                Ok(key)
             }
             Err(e) => Err(e),
         }
     }
 }
 
-pub enum WriteResult {
-    Complete,
-    EOF,
-    ChanClosed,
+/// A connection whose handshake is done.
+pub struct Session {
+    stream: TcpStream,
+    transport: snow::StatelessTransportState,
+    /// The static key of the peer, which the peer proved it owns during the handshake.
+    pub remote_key: PublicKey,
+    /// The protocol version announced by the peer.
+    pub remote_version: u32,
+    /// The features announced by both ends, which the link uses.
+    pub features: Features,
+    /// The address at which the peer accepts connections, if it announced one.
+    pub external_addr: Option<SocketAddr>,
 }
 
-enum WriteState {
-    Length,
-    Payload,
+/// Run the Noise handshake on a new connection, the outgoing side being the initiator, during
+/// which the ends announce their protocol version, `features` and the `external_addr` they
+/// accept connections at. If `expected_key` is given, the connection fails unless the peer owns
+/// that static key.
+pub async fn handshake(
+    stream: TcpStream,
+    direction: Direction,
+    key: &StaticKey,
+    expected_key: Option<PublicKey>,
+    features: Features,
+    external_addr: Option<SocketAddr>,
+) -> std::io::Result<Session> {
+    tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        run_handshake(stream, direction, key, expected_key, features, external_addr),
+    )
+        .await
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "handshake timed out"))?
 }
 
-pub struct WriteContext {
-    writer: std::io::BufWriter<mio::net::TcpStream>,
-    pub queue: channel::Receiver<Vec<u8>>,
-    len_buffer: [u8; std::mem::size_of::<u32>()],
-    msg_buffer: Vec<u8>,
-    msg_length: usize,
-    written_length: usize,
-    state: WriteState,
+async fn run_handshake(
+    mut stream: TcpStream,
+    direction: Direction,
+    key: &StaticKey,
+    expected_key: Option<PublicKey>,
+    features: Features,
+    external_addr: Option<SocketAddr>,
+) -> std::io::Result<Session> {
+    let builder = snow::Builder::new(NOISE_PARAMS.parse().unwrap()).local_private_key(&key.private);
+    let mut state = match direction {
+        Direction::Outgoing => builder.build_initiator(),
+        Direction::Incoming => builder.build_responder(),
+    }
+    .map_err(noise_error)?;
+    let mut payload = vec![0; MAX_FRAME];
+    let mut frame = vec![0; MAX_FRAME];
+    let mut remote_version = None;
+    let mut remote_features = Features::NONE;
+    let mut remote_external_addr = None;
+    // the protocol version, the features, then the external address if any, which the older
+    // versions ignore
+    let mut hello = PROTOCOL_VERSION.to_be_bytes().to_vec();
+    hello.extend_from_slice(&features.bits().to_be_bytes());
+    if let Some(addr) = external_addr {
+        encode_addr(addr, &mut hello);
+    }
+    // XX takes three messages, each prefixed with its length and carrying the hello
+    while !state.is_handshake_finished() {
+        if state.is_my_turn() {
+            let len = state.write_message(&hello, &mut frame).map_err(noise_error)?;
+            stream.write_u16(len as u16).await?;
+            stream.write_all(&frame[..len]).await?;
+        } else {
+            let len = stream.read_u16().await? as usize;
+            stream.read_exact(&mut frame[..len]).await?;
+            let len = state.read_message(&frame[..len], &mut payload).map_err(noise_error)?;
+            let remote_hello = &payload[..len];
+            remote_version = remote_hello.get(..4).map(|version| u32::from_be_bytes(version.try_into().unwrap()));
+            remote_features = match remote_hello.get(4..) {
+                Some(bits) if bits.len() >= 8 => Features::from_bits(u64::from_be_bytes(bits[..8].try_into().unwrap())),
+                // version 1 had a byte of flags, whose only flag is the compression bit
+                Some(&[flags, ..]) => Features::from_bits(u64::from(flags)),
+                _ => Features::NONE,
+            };
+            remote_external_addr = remote_hello.get(12..).and_then(decode_addr);
+        }
+    }
+    let remote_version =
+        remote_version.ok_or_else(|| Error::new(ErrorKind::InvalidData, "peer sent no protocol version"))?;
+    if remote_version < MIN_PROTOCOL_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("peer speaks protocol version {}, older than {}", remote_version, MIN_PROTOCOL_VERSION),
+        ));
+    }
+    let remote_key: PublicKey = state
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "peer sent no static key"))?;
+    if expected_key.is_some_and(|expected| expected != remote_key) {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("peer static key {} is not the expected one", hex::encode(remote_key)),
+        ));
+    }
+    let transport = state.into_stateless_transport_mode().map_err(noise_error)?;
+    Ok(Session {
+        stream,
+        transport,
+        remote_key,
+        remote_version,
+        features: features.intersection(remote_features),
+        external_addr: remote_external_addr,
+    })
 }
 
-impl WriteContext {
-    pub fn write(&mut self) -> std::io::Result<WriteResult> {
-        loop {
-            match self.state {
-                WriteState::Length => {
-                    if self.written_length == std::mem::size_of::<u32>() {
-                        // if the length part has been fully sent
-                        self.written_length = 0;
-                        self.state = WriteState::Payload;
-                        continue;
-                    } else {
-                        // we are still sending the length part
-                        let written = self.writer.write(
-                            &self.len_buffer[self.written_length..std::mem::size_of::<u32>()],
-                        )?;
-                        if written == 0 {
-                            return Ok(WriteResult::EOF);
-                        }
-                        self.written_length += written;
-                        continue;
-                    }
-                }
-                WriteState::Payload => {
-                    if self.written_length == self.msg_length {
-                        // if the previous message has been fully written, try to get the next message
-                        // first flush the writer
-                        self.writer.flush()?;
-                        let msg = match self.queue.try_recv() {
-                            Ok(msg) => msg,
-                            Err(e) => match e {
-                                mpsc::TryRecvError::Empty => return Ok(WriteResult::Complete),
-                                mpsc::TryRecvError::Disconnected => {
-                                    return Ok(WriteResult::ChanClosed);
-                                }
-                            },
-                        };
-
-                        // encode the message and the length
-                        self.msg_buffer = msg;
-                        self.msg_length = self.msg_buffer.len();
-                        self.len_buffer[..4]
-                            .copy_from_slice(&(self.msg_length as u32).to_be_bytes());
-                        self.written_length = 0;
-                        self.state = WriteState::Length;
-                        continue;
-                    } else {
-                        // we are still sending the payload
-                        let written = self
-                            .writer
-                            .write(&self.msg_buffer[self.written_length..self.msg_length])?;
-                        if written == 0 {
-                            return Ok(WriteResult::EOF);
-                        }
-                        self.written_length += written;
-                        continue;
-                    }
-                }
-            }
+/// Append `addr` to `bytes`: the IP version as a byte, the IP, then the port.
+fn encode_addr(addr: SocketAddr, bytes: &mut Vec<u8>) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes.push(4);
+            bytes.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(6);
+            bytes.extend_from_slice(&ip.octets());
         }
     }
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// The address at the start of `bytes`, see `encode_addr`.
+fn decode_addr(bytes: &[u8]) -> Option<SocketAddr> {
+    let (ip, port) = match bytes.split_first()? {
+        (4, rest) if rest.len() >= 6 => {
+            let ip: [u8; 4] = rest[..4].try_into().unwrap();
+            (IpAddr::from(ip), &rest[4..6])
+        }
+        (6, rest) if rest.len() >= 18 => {
+            let ip: [u8; 16] = rest[..16].try_into().unwrap();
+            (IpAddr::from(ip), &rest[16..18])
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
 }
 
 pub fn new(
-    stream: mio::net::TcpStream,
+    session: Session,
     direction: Direction,
+    shim: &Shim,
+    limits: &Limits,
+    compression: &Compression,
 ) -> std::io::Result<(Context, Handle)> {
-    let reader_stream = stream.try_clone()?;
-    let writer_stream = stream.try_clone()?;
+    let stream = session.stream;
     let addr = stream.peer_addr()?;
-    let bufreader = std::io::BufReader::new(reader_stream);
-    let read_ctx = ReadContext {
-        reader: bufreader,
-        buffer: vec![0; std::mem::size_of::<u32>()],
-        msg_length: std::mem::size_of::<u32>(),
-        read_length: 0,
-        state: DecodeState::Length,
-    };
-    let bufwriter = std::io::BufWriter::new(writer_stream);
-    let (write_sender, write_receiver) = channel::channel();
-    let write_ctx = WriteContext {
-        writer: bufwriter,
-        queue: write_receiver,
-        len_buffer: [0; std::mem::size_of::<u32>()],
-        msg_buffer: Vec::new(),
-        msg_length: 0,
-        written_length: 0,
-        state: WriteState::Payload,
-    };
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+    // the reader and the writer each count their own nonces
+    let transport = Arc::new(session.transport);
+    let (write_sender, write_receiver) = mpsc::unbounded_channel();
     let handle = Handle {
-        write_queue: write_sender,
+        write_queue: shim.wrap(addr, write_sender),
         addr,
+        stats: Arc::new(PeerStats::default()),
+        fee_filter: Arc::new(AtomicU64::new(0)),
+        features: session.features,
     };
     let ctx = Context {
         addr,
-        stream,
-        reader: read_ctx,
-        writer: write_ctx,
+        remote_key: session.remote_key,
+        remote_version: session.remote_version,
+        external_addr: session.external_addr,
+        reader: ReadContext {
+            reader: BufReader::with_capacity(limits.read_buffer, reader),
+            transport: Arc::clone(&transport),
+            nonce: 0,
+            frame: vec![0; MAX_FRAME],
+            plaintext: vec![0; MAX_FRAME],
+            plaintext_start: 0,
+            plaintext_end: 0,
+            marked: session.features.contains(Features::COMPRESSION),
+            max_message_size: limits.max_message_size,
+            rate_limit: TokenBucket::new(limits.messages_per_sec, limits.burst),
+        },
+        writer: WriteContext {
+            writer: BufWriter::with_capacity(limits.write_buffer, writer),
+            transport,
+            nonce: 0,
+            frame: vec![0; MAX_FRAME],
+            compression: if session.features.contains(Features::COMPRESSION) {
+                Some(compression.clone())
+            } else {
+                None
+            },
+            queue: write_receiver,
+        },
         handle: handle.clone(),
         direction,
+        misbehavior: Arc::new(AtomicU32::new(0)),
     };
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Serialize, Copy, Clone, Debug, PartialEq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -204,25 +320,307 @@ pub enum Direction {
 
 pub struct Context {
     pub addr: std::net::SocketAddr,
-    pub stream: mio::net::TcpStream,
+    /// The authenticated static key of the peer.
+    pub remote_key: PublicKey,
+    pub remote_version: u32,
+    /// The address at which the peer accepts connections, if it announced one.
+    pub external_addr: Option<SocketAddr>,
     pub reader: ReadContext,
     pub writer: WriteContext,
     pub handle: Handle,
     pub direction: Direction,
+    /// Penalty points of the peer, see `server::Handle::penalize`. Shared with the server so
+    /// that it can disconnect the peer.
+    pub misbehavior: Arc<AtomicU32>,
+}
+
+/// The receiving half of a peer connection.
+pub struct ReadContext {
+    reader: BufReader<OwnedReadHalf>,
+    transport: Arc<snow::StatelessTransportState>,
+    /// Nonce of the next frame.
+    nonce: u64,
+    frame: Vec<u8>,
+    /// The decrypted frame, of which `plaintext_start..plaintext_end` is not consumed yet.
+    plaintext: Vec<u8>,
+    plaintext_start: usize,
+    plaintext_end: usize,
+    /// Whether each message starts with a compression marker.
+    marked: bool,
+    max_message_size: usize,
+    /// Messages the peer can still send before the ones above its rate limit are dropped.
+    rate_limit: TokenBucket,
+}
+
+impl ReadContext {
+    /// Read and decrypt the next frame.
+    async fn read_frame(&mut self) -> Result<(), Disconnect> {
+        let len = self.reader.read_u16().await.map_err(Disconnect::Closed)? as usize;
+        self.reader
+            .read_exact(&mut self.frame[..len])
+            .await
+            .map_err(Disconnect::Closed)?;
+        self.plaintext_end = self
+            .transport
+            .read_message(self.nonce, &self.frame[..len], &mut self.plaintext)
+            .map_err(|_| Disconnect::Forged)?;
+        self.plaintext_start = 0;
+        self.nonce += 1;
+        Ok(())
+    }
+
+    /// Fill `buffer` with decrypted bytes, reading as many frames as needed.
+    async fn read_exact(&mut self, buffer: &mut [u8]) -> Result<(), Disconnect> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            if self.plaintext_start == self.plaintext_end {
+                self.read_frame().await?;
+                continue;
+            }
+            let n = (buffer.len() - filled).min(self.plaintext_end - self.plaintext_start);
+            buffer[filled..filled + n]
+                .copy_from_slice(&self.plaintext[self.plaintext_start..self.plaintext_start + n]);
+            self.plaintext_start += n;
+            filled += n;
+        }
+        Ok(())
+    }
+
+    /// Read one length-prefixed message.
+    async fn read(&mut self) -> Result<Vec<u8>, Disconnect> {
+        let mut length = [0; 4];
+        self.read_exact(&mut length).await?;
+        let msg_length = u32::from_be_bytes(length) as usize;
+        if msg_length > self.max_message_size {
+            return Err(Disconnect::Oversized(msg_length));
+        }
+        let mut buffer = vec![0; msg_length];
+        self.read_exact(&mut buffer).await?;
+        trace!("Received message length={}", msg_length);
+        if !self.marked {
+            return Ok(buffer);
+        }
+        match buffer.first() {
+            Some(&compression::RAW) => {
+                buffer.remove(0);
+                Ok(buffer)
+            }
+            Some(&compression::SNAPPY) => {
+                compression::decompress(&buffer[1..], self.max_message_size).ok_or(Disconnect::BadCompression)
+            }
+            _ => Err(Disconnect::BadCompression),
+        }
+    }
+
+    /// Pass the messages of the peer to the workers until the connection ends. The messages
+    /// above the rate limit are dropped, and cost the peer `rate_limit_penalty` points.
+    /// When the workers fall behind, the low priority messages are dropped and the others wait
+    /// for room in the queue, which stops reading from the peer.
+    pub async fn run(
+        mut self,
+        handle: Handle,
+        msg_sink: cbchannel::Sender<(Vec<u8>, Handle)>,
+        misbehavior: Arc<AtomicU32>,
+        rate_limit_penalty: u32,
+        ban_score: u32,
+    ) -> Disconnect {
+        loop {
+            let msg = match self.read().await {
+                Ok(msg) => msg,
+                Err(e) => return e,
+            };
+            if !self.rate_limit.try_take(std::time::Instant::now()) {
+                trace!("Peer {} over its rate limit, dropping a message", handle.addr);
+                let score = misbehavior.fetch_add(rate_limit_penalty, Ordering::SeqCst) + rate_limit_penalty;
+                if score >= ban_score {
+                    return Disconnect::Banned;
+                }
+                continue;
+            }
+            let priority = message::Message::priority_of(&msg);
+            if !limits::admits(priority, msg_sink.len(), msg_sink.capacity()) {
+                trace!("Worker queue over its watermark, dropping a {:?} priority message from {}", priority, handle.addr);
+                handle.stats.dropped();
+                continue;
+            }
+            let mut item = (msg, handle.clone());
+            loop {
+                match msg_sink.try_send(item) {
+                    Ok(()) => break,
+                    Err(cbchannel::TrySendError::Full(back)) => {
+                        item = back;
+                        tokio::time::sleep(QUEUE_RETRY_INTERVAL).await;
+                    }
+                    Err(cbchannel::TrySendError::Disconnected(_)) => return Disconnect::Detached,
+                }
+            }
+        }
+    }
+}
+
+/// The sending half of a peer connection.
+pub struct WriteContext {
+    writer: BufWriter<OwnedWriteHalf>,
+    transport: Arc<snow::StatelessTransportState>,
+    /// Nonce of the next frame.
+    nonce: u64,
+    frame: Vec<u8>,
+    /// How the messages are compressed, if the peer understands compressed messages.
+    compression: Option<Compression>,
+    queue: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl WriteContext {
+    /// Write the queued messages, each prefixed with its length and encrypted, until the
+    /// connection ends.
+    pub async fn run(mut self) -> Disconnect {
+        while let Some(msg) = self.queue.recv().await {
+            if let Err(e) = self.write(&msg).await {
+                return Disconnect::Closed(e);
+            }
+        }
+        Disconnect::Detached
+    }
+
+    async fn write(&mut self, msg: &[u8]) -> std::io::Result<()> {
+        let mut plaintext = Vec::with_capacity(5 + msg.len());
+        match &self.compression {
+            Some(compression) => {
+                let (marker, body) = compression.compress(msg);
+                plaintext.extend_from_slice(&(body.len() as u32 + 1).to_be_bytes());
+                plaintext.push(marker);
+                plaintext.extend_from_slice(&body);
+            }
+            None => {
+                plaintext.extend_from_slice(&(msg.len() as u32).to_be_bytes());
+                plaintext.extend_from_slice(msg);
+            }
+        }
+        for chunk in plaintext.chunks(MAX_FRAME - TAG_LEN) {
+            let len = self
+                .transport
+                .write_message(self.nonce, chunk, &mut self.frame)
+                .map_err(noise_error)?;
+            self.nonce += 1;
+            self.writer.write_u16(len as u16).await?;
+            self.writer.write_all(&self.frame[..len]).await?;
+        }
+        // only flush once the queue is drained, so that a burst goes out in few segments
+        if self.queue.is_empty() {
+            self.writer.flush().await?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
-    write_queue: channel::Sender<Vec<u8>>,
+    write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    stats: Arc<PeerStats>,
+    /// The lowest fee of the transactions the peer wants relayed, see `Message::FeeFilter`.
+    fee_filter: Arc<AtomicU64>,
+    /// The features the link uses.
+    features: Features,
 }
 
 impl Handle {
+    /// A handle whose messages are read from the returned queue instead of written to a socket.
+    pub(super) fn with_queue(
+        addr: std::net::SocketAddr,
+        shim: &Shim,
+    ) -> (Handle, mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (write_sender, receiver) = mpsc::unbounded_channel();
+        let write_queue = shim.wrap(addr, write_sender);
+        let handle = Handle {
+            addr,
+            write_queue,
+            stats: Arc::new(PeerStats::default()),
+            fee_filter: Arc::new(AtomicU64::new(0)),
+            features: Features::SUPPORTED,
+        };
+        (handle, receiver)
+    }
+
+    pub fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    /// The traffic with the peer. The workers account for the messages they receive.
+    pub fn stats(&self) -> &PeerStats {
+        &self.stats
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    pub fn set_fee_filter(&self, min_fee: u64) {
+        self.fee_filter.store(min_fee, Ordering::Relaxed);
+    }
+
+    /// Write a message broadcast to every peer, without the transactions below the fee filter of
+    /// the peer. Nothing is written if no transaction is left, or if the peer does not have the
+    /// features to understand the message.
+    pub fn relay(&self, msg: &message::Message) {
+        if !self.features.contains(msg.required_features()) {
+            return;
+        }
+        let min_fee = self.fee_filter.load(Ordering::Relaxed);
+        match msg {
+            message::Message::Transactions(txs) if txs.iter().any(|tx| tx.transaction.fee < min_fee) => {
+                let txs: Vec<_> = txs.iter().filter(|tx| tx.transaction.fee >= min_fee).cloned().collect();
+                if !txs.is_empty() {
+                    self.write(message::Message::Transactions(txs));
+                }
+            }
+            _ => self.write(msg.clone()),
+        }
+    }
+
     pub fn write(&self, msg: message::Message) {
         // TODO: return result
-        let buffer = bincode::serialize(&msg).unwrap();
+        let buffer = msg.encode_as(message::Encoding::of(self.features));
+        self.stats.sent(&msg, buffer.len());
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::SignedTransaction;
+
+    #[test]
+    fn relays_above_fee_filter() {
+        let (peer, mut queue) = Handle::with_queue(([127, 0, 0, 1], 6000).into(), &Shim::default());
+        let with_fee = |fee| {
+            let mut tx = SignedTransaction::default();
+            tx.transaction.fee = fee;
+            tx
+        };
+        peer.set_fee_filter(5);
+        peer.relay(&message::Message::Transactions(vec![with_fee(4)]));
+        peer.relay(&message::Message::Transactions(vec![with_fee(4), with_fee(5)]));
+        match message::Message::decode(&queue.try_recv().unwrap()).unwrap().unwrap() {
+            message::Message::Transactions(txs) => assert_eq!(txs.len(), 1),
+            _ => panic!("expected transactions"),
+        }
+        assert!(queue.try_recv().is_err());
+    }
+
+    #[test]
+    fn encodes_external_addresses() {
+        for addr in &["203.0.113.7:6000", "[2001:db8::1]:6001"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let mut bytes = vec![];
+            encode_addr(addr, &mut bytes);
+            assert_eq!(decode_addr(&bytes), Some(addr));
+            assert_eq!(decode_addr(&bytes[..bytes.len() - 1]), None);
+        }
+        assert_eq!(decode_addr(&[]), None);
+        assert_eq!(decode_addr(&[5, 0, 0, 0, 0, 0, 0]), None);
+    }
+}
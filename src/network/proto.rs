@@ -0,0 +1,843 @@
+//! The protobuf form of the messages, used on the links that negotiate `Features::PROTOBUF`, so
+//! that tools written in other languages can speak to the nodes. The schema is
+//! `proto/prism.proto`; the type id of the envelope tells which of its messages the payload is,
+//! see `Message::encode_as`. Hashes and addresses are raw bytes.
+
+use super::message::{EnvelopeError, Message};
+use crate::block;
+use crate::crypto::address::H160;
+use crate::crypto::hash::H256;
+use crate::crypto::trie;
+use crate::snapshot;
+use crate::transaction as tx;
+use prost::Message as _;
+use std::convert::{TryFrom, TryInto};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Text {
+    #[prost(string, tag = "1")]
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Number {
+    #[prost(uint64, tag = "1")]
+    pub value: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Empty {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Hashes {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub hashes: Vec<Vec<u8>>,
+}
+
+/// A block and hashes after it or in it, for `GetChainHashes`, `ChainHashes` and
+/// `GetMerkleMultiProof`. `count` is only set for `GetChainHashes`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BlockHashes {
+    #[prost(bytes = "vec", tag = "1")]
+    pub block_hash: Vec<u8>,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub hashes: Vec<Vec<u8>>,
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+}
+
+/// A block and a transaction or an address in it, for `GetMerkleProof` and `GetAccountProof`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BlockKey {
+    #[prost(bytes = "vec", tag = "1")]
+    pub block_hash: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub key: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Header {
+    #[prost(bytes = "vec", tag = "1")]
+    pub parent: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub nonce: u32,
+    #[prost(uint64, tag = "3")]
+    pub extra_nonce: u64,
+    #[prost(bytes = "vec", tag = "4")]
+    pub difficulty: Vec<u8>,
+    /// The low 64 bits of the timestamp, in microseconds.
+    #[prost(uint64, tag = "5")]
+    pub timestamp: u64,
+    #[prost(bytes = "vec", tag = "6")]
+    pub merkle_root: Vec<u8>,
+    #[prost(bytes = "vec", tag = "7")]
+    pub state_root: Vec<u8>,
+    #[prost(uint64, tag = "8")]
+    pub gas_limit: u64,
+    /// The high 64 bits of the timestamp, 0 for any real time.
+    #[prost(uint64, tag = "9")]
+    pub timestamp_high: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Headers {
+    #[prost(message, repeated, tag = "1")]
+    pub headers: Vec<Header>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct OutPoint {
+    #[prost(bytes = "vec", tag = "1")]
+    pub txid: Vec<u8>,
+    #[prost(uint32, tag = "2")]
+    pub index: u32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Output {
+    #[prost(bytes = "vec", tag = "1")]
+    pub recipient: Vec<u8>,
+    #[prost(uint64, tag = "2")]
+    pub value: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TokenOp {
+    #[prost(oneof = "token_op::Op", tags = "1, 2")]
+    pub op: Option<token_op::Op>,
+}
+
+pub mod token_op {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Op {
+        #[prost(bool, tag = "1")]
+        Create(bool),
+        #[prost(bytes, tag = "2")]
+        Transfer(Vec<u8>),
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Transaction {
+    #[prost(message, repeated, tag = "1")]
+    pub inputs: Vec<OutPoint>,
+    #[prost(message, repeated, tag = "2")]
+    pub outputs: Vec<Output>,
+    #[prost(uint64, tag = "3")]
+    pub fee: u64,
+    #[prost(uint64, tag = "4")]
+    pub account_nonce: u64,
+    #[prost(bytes = "vec", tag = "5")]
+    pub data: Vec<u8>,
+    #[prost(uint32, optional, tag = "6")]
+    pub expires_at_block: Option<u32>,
+    #[prost(message, optional, tag = "7")]
+    pub token: Option<TokenOp>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum SignatureScheme {
+    Ed25519 = 0,
+    Secp256k1 = 1,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MultisigPolicy {
+    #[prost(uint32, tag = "1")]
+    pub threshold: u32,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub public_keys: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct CosignerSignature {
+    #[prost(uint32, tag = "1")]
+    pub position: u32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Multisig {
+    #[prost(message, optional, tag = "1")]
+    pub policy: Option<MultisigPolicy>,
+    #[prost(message, repeated, tag = "2")]
+    pub signatures: Vec<CosignerSignature>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SignedTransaction {
+    #[prost(message, optional, tag = "1")]
+    pub transaction: Option<Transaction>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub signature: Vec<u8>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub public_key: Vec<u8>,
+    #[prost(enumeration = "SignatureScheme", tag = "4")]
+    pub scheme: i32,
+    #[prost(message, optional, tag = "5")]
+    pub multisig: Option<Multisig>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Transactions {
+    #[prost(message, repeated, tag = "1")]
+    pub transactions: Vec<SignedTransaction>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Block {
+    #[prost(message, optional, tag = "1")]
+    pub header: Option<Header>,
+    #[prost(message, repeated, tag = "2")]
+    pub transactions: Vec<SignedTransaction>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Blocks {
+    #[prost(message, repeated, tag = "1")]
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MerkleProof {
+    #[prost(bytes = "vec", tag = "1")]
+    pub block_hash: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub txid: Vec<u8>,
+    #[prost(uint64, tag = "3")]
+    pub index: u64,
+    #[prost(uint64, tag = "4")]
+    pub leaf_size: u64,
+    #[prost(bytes = "vec", repeated, tag = "5")]
+    pub proof: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct MerkleMultiProof {
+    #[prost(bytes = "vec", tag = "1")]
+    pub block_hash: Vec<u8>,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub txids: Vec<Vec<u8>>,
+    #[prost(uint64, repeated, tag = "3")]
+    pub indices: Vec<u64>,
+    #[prost(uint64, tag = "4")]
+    pub leaf_size: u64,
+    #[prost(bytes = "vec", repeated, tag = "5")]
+    pub proof: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TokenBalance {
+    #[prost(bytes = "vec", tag = "1")]
+    pub token: Vec<u8>,
+    #[prost(uint64, tag = "2")]
+    pub balance: u64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AccountState {
+    #[prost(uint64, tag = "1")]
+    pub nonce: u64,
+    #[prost(uint64, tag = "2")]
+    pub balance: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub tokens: Vec<TokenBalance>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TrieProof {
+    #[prost(bytes = "vec", tag = "1")]
+    pub bitmap: Vec<u8>,
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub siblings: Vec<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AccountProof {
+    #[prost(bytes = "vec", tag = "1")]
+    pub block_hash: Vec<u8>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub address: Vec<u8>,
+    /// Unset for an address without an account.
+    #[prost(message, optional, tag = "3")]
+    pub account: Option<AccountState>,
+    #[prost(message, optional, tag = "4")]
+    pub proof: Option<TrieProof>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Account {
+    #[prost(bytes = "vec", tag = "1")]
+    pub address: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub state: Option<AccountState>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Utxo {
+    /// The key of the output in the trie, see `OutPoint::key`.
+    #[prost(bytes = "vec", tag = "1")]
+    pub key: Vec<u8>,
+    #[prost(message, optional, tag = "2")]
+    pub outpoint: Option<OutPoint>,
+    #[prost(bytes = "vec", tag = "3")]
+    pub owner: Vec<u8>,
+    #[prost(uint64, tag = "4")]
+    pub value: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Model {
+    Account = 0,
+    Utxo = 1,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct State {
+    #[prost(bytes = "vec", repeated, tag = "1")]
+    pub address_list: Vec<Vec<u8>>,
+    #[prost(message, repeated, tag = "2")]
+    pub accounts: Vec<Account>,
+    #[prost(message, repeated, tag = "3")]
+    pub utxos: Vec<Utxo>,
+    #[prost(enumeration = "Model", tag = "4")]
+    pub model: i32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Snapshot {
+    #[prost(message, repeated, tag = "1")]
+    pub headers: Vec<Header>,
+    #[prost(message, optional, tag = "2")]
+    pub block: Option<Block>,
+    #[prost(message, optional, tag = "3")]
+    pub state: Option<State>,
+}
+
+fn malformed<T>(_: T) -> EnvelopeError {
+    EnvelopeError::MalformedPayload
+}
+
+fn hash(bytes: &[u8]) -> Result<H256, EnvelopeError> {
+    <[u8; 32]>::try_from(bytes).map(H256::from).map_err(malformed)
+}
+
+fn hashes(list: &[Vec<u8>]) -> Result<Vec<H256>, EnvelopeError> {
+    list.iter().map(|bytes| hash(bytes)).collect()
+}
+
+fn address(bytes: &[u8]) -> Result<H160, EnvelopeError> {
+    <[u8; 20]>::try_from(bytes).map(H160::from).map_err(malformed)
+}
+
+fn required<T>(field: Option<T>) -> Result<T, EnvelopeError> {
+    field.ok_or(EnvelopeError::MalformedPayload)
+}
+
+fn to_vecs(hashes: &[H256]) -> Vec<Vec<u8>> {
+    hashes.iter().map(|hash| hash.as_ref().to_vec()).collect()
+}
+
+impl From<&block::Header> for Header {
+    fn from(header: &block::Header) -> Self {
+        Header {
+            parent: header.parent.as_ref().to_vec(),
+            nonce: header.nonce,
+            extra_nonce: header.extra_nonce,
+            difficulty: header.difficulty.as_ref().to_vec(),
+            timestamp: header.timestamp as u64,
+            merkle_root: header.merkle_root.as_ref().to_vec(),
+            state_root: header.state_root.as_ref().to_vec(),
+            gas_limit: header.gas_limit,
+            timestamp_high: (header.timestamp >> 64) as u64,
+        }
+    }
+}
+
+impl TryFrom<Header> for block::Header {
+    type Error = EnvelopeError;
+
+    fn try_from(header: Header) -> Result<Self, EnvelopeError> {
+        Ok(block::Header {
+            parent: hash(&header.parent)?,
+            nonce: header.nonce,
+            extra_nonce: header.extra_nonce,
+            difficulty: hash(&header.difficulty)?,
+            timestamp: u128::from(header.timestamp_high) << 64 | u128::from(header.timestamp),
+            merkle_root: hash(&header.merkle_root)?,
+            state_root: hash(&header.state_root)?,
+            gas_limit: header.gas_limit,
+        })
+    }
+}
+
+impl From<&tx::OutPoint> for OutPoint {
+    fn from(outpoint: &tx::OutPoint) -> Self {
+        OutPoint { txid: outpoint.txid.as_ref().to_vec(), index: outpoint.index }
+    }
+}
+
+impl TryFrom<OutPoint> for tx::OutPoint {
+    type Error = EnvelopeError;
+
+    fn try_from(outpoint: OutPoint) -> Result<Self, EnvelopeError> {
+        Ok(tx::OutPoint { txid: hash(&outpoint.txid)?, index: outpoint.index })
+    }
+}
+
+impl From<&tx::Transaction> for Transaction {
+    fn from(transaction: &tx::Transaction) -> Self {
+        Transaction {
+            inputs: transaction.inputs.iter().map(OutPoint::from).collect(),
+            outputs: transaction.outputs.iter()
+                .map(|(recipient, value)| Output { recipient: recipient.as_ref().to_vec(), value: *value })
+                .collect(),
+            fee: transaction.fee,
+            account_nonce: transaction.account_nonce,
+            data: transaction.data.clone(),
+            expires_at_block: transaction.expires_at_block,
+            token: transaction.token.map(|token| TokenOp {
+                op: Some(match token {
+                    tx::TokenOp::Create => token_op::Op::Create(true),
+                    tx::TokenOp::Transfer(token) => token_op::Op::Transfer(token.as_ref().to_vec()),
+                }),
+            }),
+        }
+    }
+}
+
+impl TryFrom<Transaction> for tx::Transaction {
+    type Error = EnvelopeError;
+
+    fn try_from(transaction: Transaction) -> Result<Self, EnvelopeError> {
+        Ok(tx::Transaction {
+            inputs: transaction.inputs.into_iter().map(tx::OutPoint::try_from).collect::<Result<_, _>>()?,
+            outputs: transaction.outputs.iter()
+                .map(|output| Ok((address(&output.recipient)?, output.value)))
+                .collect::<Result<_, _>>()?,
+            fee: transaction.fee,
+            account_nonce: transaction.account_nonce,
+            data: transaction.data,
+            expires_at_block: transaction.expires_at_block,
+            token: match transaction.token.map(|token| token.op) {
+                None => None,
+                Some(Some(token_op::Op::Create(true))) => Some(tx::TokenOp::Create),
+                Some(Some(token_op::Op::Transfer(token))) => Some(tx::TokenOp::Transfer(address(&token)?)),
+                Some(_) => return Err(EnvelopeError::MalformedPayload),
+            },
+        })
+    }
+}
+
+impl From<&tx::SignedTransaction> for SignedTransaction {
+    fn from(signed: &tx::SignedTransaction) -> Self {
+        SignedTransaction {
+            transaction: Some(Transaction::from(&signed.transaction)),
+            signature: signed.signature.clone(),
+            public_key: signed.public_key.clone(),
+            scheme: match signed.scheme {
+                tx::SignatureScheme::Ed25519 => SignatureScheme::Ed25519,
+                tx::SignatureScheme::Secp256k1 => SignatureScheme::Secp256k1,
+            } as i32,
+            multisig: signed.multisig.as_ref().map(|multisig| Multisig {
+                policy: Some(MultisigPolicy {
+                    threshold: u32::from(multisig.policy.threshold),
+                    public_keys: multisig.policy.public_keys.clone(),
+                }),
+                signatures: multisig.signatures.iter()
+                    .map(|(position, signature)| CosignerSignature { position: u32::from(*position), signature: signature.clone() })
+                    .collect(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<SignedTransaction> for tx::SignedTransaction {
+    type Error = EnvelopeError;
+
+    fn try_from(signed: SignedTransaction) -> Result<Self, EnvelopeError> {
+        let multisig = match signed.multisig {
+            Some(multisig) => {
+                let policy = required(multisig.policy)?;
+                Some(tx::Multisig {
+                    // not `MultisigPolicy::new`, which would sort the keys the sender signed
+                    policy: tx::MultisigPolicy {
+                        threshold: policy.threshold.try_into().map_err(malformed)?,
+                        public_keys: policy.public_keys,
+                    },
+                    signatures: multisig.signatures.into_iter()
+                        .map(|cosigner| Ok((cosigner.position.try_into().map_err(malformed)?, cosigner.signature)))
+                        .collect::<Result<_, _>>()?,
+                })
+            }
+            None => None,
+        };
+        Ok(tx::SignedTransaction {
+            transaction: required(signed.transaction)?.try_into()?,
+            signature: signed.signature,
+            public_key: signed.public_key,
+            scheme: match SignatureScheme::try_from(signed.scheme).map_err(malformed)? {
+                SignatureScheme::Ed25519 => tx::SignatureScheme::Ed25519,
+                SignatureScheme::Secp256k1 => tx::SignatureScheme::Secp256k1,
+            },
+            multisig,
+            sender_cache: Default::default(),
+        })
+    }
+}
+
+fn transactions(transactions: &[tx::SignedTransaction]) -> Vec<SignedTransaction> {
+    transactions.iter().map(SignedTransaction::from).collect()
+}
+
+fn signed_transactions(transactions: Vec<SignedTransaction>) -> Result<Vec<tx::SignedTransaction>, EnvelopeError> {
+    transactions.into_iter().map(tx::SignedTransaction::try_from).collect()
+}
+
+impl From<&block::Block> for Block {
+    fn from(block: &block::Block) -> Self {
+        Block {
+            header: Some(Header::from(&block.header)),
+            transactions: transactions(&block.content.transactions),
+        }
+    }
+}
+
+impl TryFrom<Block> for block::Block {
+    type Error = EnvelopeError;
+
+    fn try_from(block: Block) -> Result<Self, EnvelopeError> {
+        Ok(block::Block {
+            header: required(block.header)?.try_into()?,
+            content: block::Content::new(signed_transactions(block.transactions)?),
+        })
+    }
+}
+
+impl From<&block::AccountState> for AccountState {
+    fn from(account: &block::AccountState) -> Self {
+        AccountState {
+            nonce: account.nonce,
+            balance: account.balance,
+            tokens: account.tokens.iter()
+                .map(|(token, balance)| TokenBalance { token: token.as_ref().to_vec(), balance: *balance })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<AccountState> for block::AccountState {
+    type Error = EnvelopeError;
+
+    fn try_from(account: AccountState) -> Result<Self, EnvelopeError> {
+        Ok(block::AccountState {
+            nonce: account.nonce,
+            balance: account.balance,
+            tokens: account.tokens.iter()
+                .map(|token| Ok((address(&token.token)?, token.balance)))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl From<&block::State> for State {
+    fn from(state: &block::State) -> Self {
+        State {
+            address_list: state.address_list.iter().map(|address| address.as_ref().to_vec()).collect(),
+            accounts: state.account_state.iter()
+                .map(|(address, account)| Account { address: address.as_ref().to_vec(), state: Some(account.into()) })
+                .collect(),
+            utxos: state.utxos.iter()
+                .map(|(key, utxo)| Utxo {
+                    key: key.as_ref().to_vec(),
+                    outpoint: Some((&utxo.outpoint).into()),
+                    owner: utxo.owner.as_ref().to_vec(),
+                    value: utxo.value,
+                })
+                .collect(),
+            model: match state.model {
+                block::Model::Account => Model::Account,
+                block::Model::Utxo => Model::Utxo,
+            } as i32,
+        }
+    }
+}
+
+impl TryFrom<State> for block::State {
+    type Error = EnvelopeError;
+
+    fn try_from(state: State) -> Result<Self, EnvelopeError> {
+        let mut decoded = block::State {
+            address_list: state.address_list.iter().map(|bytes| address(bytes)).collect::<Result<_, _>>()?,
+            model: match Model::try_from(state.model).map_err(malformed)? {
+                Model::Account => block::Model::Account,
+                Model::Utxo => block::Model::Utxo,
+            },
+            ..Default::default()
+        };
+        for account in state.accounts {
+            decoded.account_state.insert(address(&account.address)?, required(account.state)?.try_into()?);
+        }
+        for utxo in state.utxos {
+            let outpoint = required(utxo.outpoint)?.try_into()?;
+            decoded.utxos.insert(address(&utxo.key)?, block::Utxo { outpoint, owner: address(&utxo.owner)?, value: utxo.value });
+        }
+        Ok(decoded)
+    }
+}
+
+/// The payload of `msg`.
+pub fn encode(msg: &Message) -> Vec<u8> {
+    match msg {
+        Message::Ping(text) | Message::Pong(text) => Text { text: text.clone() }.encode_to_vec(),
+        Message::NewBlockHashes(list)
+        | Message::GetBlocks(list)
+        | Message::GetHeaders(list)
+        | Message::NewTransactionHashes(list)
+        | Message::MempoolInv(list)
+        | Message::GetTransactions(list)
+        | Message::GetBlocksFrom(list) => Hashes { hashes: to_vecs(list) }.encode_to_vec(),
+        Message::Blocks(blocks) => Blocks { blocks: blocks.iter().map(Block::from).collect() }.encode_to_vec(),
+        Message::GetChainHashes(block_hash, count) => {
+            BlockHashes { block_hash: block_hash.as_ref().to_vec(), hashes: vec![], count: *count }.encode_to_vec()
+        }
+        Message::ChainHashes(block_hash, list) | Message::GetMerkleMultiProof(block_hash, list) => {
+            BlockHashes { block_hash: block_hash.as_ref().to_vec(), hashes: to_vecs(list), count: 0 }.encode_to_vec()
+        }
+        Message::Headers(headers) => Headers { headers: headers.iter().map(Header::from).collect() }.encode_to_vec(),
+        Message::GetMerkleProof(block_hash, txid) => {
+            BlockKey { block_hash: block_hash.as_ref().to_vec(), key: txid.as_ref().to_vec() }.encode_to_vec()
+        }
+        Message::GetAccountProof(block_hash, address) => {
+            BlockKey { block_hash: block_hash.as_ref().to_vec(), key: address.as_ref().to_vec() }.encode_to_vec()
+        }
+        Message::MerkleProof(proof) => MerkleProof {
+            block_hash: proof.block_hash.as_ref().to_vec(),
+            txid: proof.txid.as_ref().to_vec(),
+            index: proof.index as u64,
+            leaf_size: proof.leaf_size as u64,
+            proof: to_vecs(&proof.proof),
+        }
+        .encode_to_vec(),
+        Message::AccountProof(proof) => AccountProof {
+            block_hash: proof.block_hash.as_ref().to_vec(),
+            address: proof.address.as_ref().to_vec(),
+            account: proof.account.as_ref().map(AccountState::from),
+            proof: Some(TrieProof { bitmap: proof.proof.bitmap.clone(), siblings: to_vecs(&proof.proof.siblings) }),
+        }
+        .encode_to_vec(),
+        Message::MempoolRequest => Empty {}.encode_to_vec(),
+        Message::FeeFilter(fee) => Number { value: *fee }.encode_to_vec(),
+        Message::GetSnapshot(height) => Number { value: u64::from(*height) }.encode_to_vec(),
+        Message::Transactions(list) => Transactions { transactions: transactions(list) }.encode_to_vec(),
+        Message::Snapshot(snapshot) => Snapshot {
+            headers: snapshot.headers.iter().map(Header::from).collect(),
+            block: Some(Block::from(&snapshot.block)),
+            state: Some(State::from(&snapshot.state)),
+        }
+        .encode_to_vec(),
+        Message::MerkleMultiProof(proof) => MerkleMultiProof {
+            block_hash: proof.block_hash.as_ref().to_vec(),
+            txids: to_vecs(&proof.txids),
+            indices: proof.indices.iter().map(|index| *index as u64).collect(),
+            leaf_size: proof.leaf_size as u64,
+            proof: to_vecs(&proof.proof),
+        }
+        .encode_to_vec(),
+    }
+}
+
+/// The message of type `type_id` whose payload is `payload`, `None` if the type is unknown.
+pub fn decode(type_id: u16, payload: &[u8]) -> Result<Option<Message>, EnvelopeError> {
+    let usize_of = |value: u64| usize::try_from(value).map_err(malformed);
+    let msg = match type_id {
+        0 => Message::Ping(Text::decode(payload).map_err(malformed)?.text),
+        1 => Message::Pong(Text::decode(payload).map_err(malformed)?.text),
+        2 | 3 | 7 | 13 | 15 | 17 | 21 => {
+            let list = hashes(&Hashes::decode(payload).map_err(malformed)?.hashes)?;
+            match type_id {
+                2 => Message::NewBlockHashes(list),
+                3 => Message::GetBlocks(list),
+                7 => Message::GetHeaders(list),
+                13 => Message::NewTransactionHashes(list),
+                15 => Message::MempoolInv(list),
+                17 => Message::GetTransactions(list),
+                _ => Message::GetBlocksFrom(list),
+            }
+        }
+        4 => Message::Blocks(
+            Blocks::decode(payload).map_err(malformed)?.blocks.into_iter()
+                .map(block::Block::try_from)
+                .collect::<Result<_, _>>()?,
+        ),
+        5 | 6 | 22 => {
+            let decoded = BlockHashes::decode(payload).map_err(malformed)?;
+            let block_hash = hash(&decoded.block_hash)?;
+            match type_id {
+                5 => Message::GetChainHashes(block_hash, decoded.count),
+                6 => Message::ChainHashes(block_hash, hashes(&decoded.hashes)?),
+                _ => Message::GetMerkleMultiProof(block_hash, hashes(&decoded.hashes)?),
+            }
+        }
+        8 => Message::Headers(
+            Headers::decode(payload).map_err(malformed)?.headers.into_iter()
+                .map(block::Header::try_from)
+                .collect::<Result<_, _>>()?,
+        ),
+        9 => {
+            let decoded = BlockKey::decode(payload).map_err(malformed)?;
+            Message::GetMerkleProof(hash(&decoded.block_hash)?, hash(&decoded.key)?)
+        }
+        10 => {
+            let decoded = MerkleProof::decode(payload).map_err(malformed)?;
+            Message::MerkleProof(block::MerkleProof {
+                block_hash: hash(&decoded.block_hash)?,
+                txid: hash(&decoded.txid)?,
+                index: usize_of(decoded.index)?,
+                leaf_size: usize_of(decoded.leaf_size)?,
+                proof: hashes(&decoded.proof)?,
+            })
+        }
+        11 => {
+            let decoded = BlockKey::decode(payload).map_err(malformed)?;
+            Message::GetAccountProof(hash(&decoded.block_hash)?, address(&decoded.key)?)
+        }
+        12 => {
+            let decoded = AccountProof::decode(payload).map_err(malformed)?;
+            let proof = required(decoded.proof)?;
+            Message::AccountProof(block::AccountProof {
+                block_hash: hash(&decoded.block_hash)?,
+                address: address(&decoded.address)?,
+                account: decoded.account.map(block::AccountState::try_from).transpose()?,
+                proof: trie::TrieProof { bitmap: proof.bitmap, siblings: hashes(&proof.siblings)? },
+            })
+        }
+        14 => Message::MempoolRequest,
+        16 => Message::FeeFilter(Number::decode(payload).map_err(malformed)?.value),
+        18 => Message::Transactions(signed_transactions(Transactions::decode(payload).map_err(malformed)?.transactions)?),
+        19 => Message::GetSnapshot(Number::decode(payload).map_err(malformed)?.value.try_into().map_err(malformed)?),
+        20 => {
+            let decoded = Snapshot::decode(payload).map_err(malformed)?;
+            Message::Snapshot(Box::new(snapshot::Snapshot {
+                headers: decoded.headers.into_iter().map(block::Header::try_from).collect::<Result<_, _>>()?,
+                block: required(decoded.block)?.try_into()?,
+                state: required(decoded.state)?.try_into()?,
+            }))
+        }
+        23 => {
+            let decoded = MerkleMultiProof::decode(payload).map_err(malformed)?;
+            Message::MerkleMultiProof(block::MerkleMultiProof {
+                block_hash: hash(&decoded.block_hash)?,
+                txids: hashes(&decoded.txids)?,
+                indices: decoded.indices.into_iter().map(usize_of).collect::<Result<_, _>>()?,
+                leaf_size: usize_of(decoded.leaf_size)?,
+                proof: hashes(&decoded.proof)?,
+            })
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::network::message::{Encoding, MESSAGE_TYPES};
+
+    #[test]
+    fn round_trips_every_message() {
+        let mut block = generate_random_block(&H256::from([1u8; 32]));
+        block.header.timestamp = u128::MAX - 1;
+        let mut signed = tx::SignedTransaction {
+            transaction: tx::Transaction {
+                inputs: vec![tx::OutPoint { txid: H256::from([2u8; 32]), index: 3 }],
+                outputs: vec![(H160::from([4u8; 20]), 5), (H160::from([6u8; 20]), 7)],
+                fee: 8,
+                account_nonce: 9,
+                data: vec![10, 11],
+                expires_at_block: Some(12),
+                token: Some(tx::TokenOp::Transfer(H160::from([13u8; 20]))),
+            },
+            signature: vec![14; 64],
+            public_key: vec![15; 32],
+            scheme: tx::SignatureScheme::Secp256k1,
+            ..Default::default()
+        };
+        block.content.transactions.push(signed.clone());
+        signed.transaction.token = Some(tx::TokenOp::Create);
+        signed.multisig = Some(tx::Multisig {
+            policy: tx::MultisigPolicy { threshold: 2, public_keys: vec![vec![17; 32], vec![16; 32]] },
+            signatures: vec![(1, vec![18; 64])],
+        });
+        block.content.transactions.push(signed.clone());
+
+        let mut state = block::State { address_list: vec![H160::from([19u8; 20])], model: block::Model::Utxo, ..Default::default() };
+        let mut account = block::AccountState { nonce: 20, balance: 21, ..Default::default() };
+        account.tokens.insert(H160::from([22u8; 20]), 23);
+        state.account_state.insert(H160::from([19u8; 20]), account.clone());
+        let outpoint = tx::OutPoint { txid: H256::from([24u8; 32]), index: 0 };
+        state.utxos.insert(outpoint.key(), block::Utxo { outpoint, owner: H160::from([19u8; 20]), value: 25 });
+
+        let hash = H256::from([26u8; 32]);
+        let messages = vec![
+            Message::Ping("ping".to_string()),
+            Message::Pong(String::new()),
+            Message::NewBlockHashes(vec![hash]),
+            Message::GetBlocks(vec![hash, hash]),
+            Message::Blocks(vec![block.clone()]),
+            Message::GetChainHashes(hash, 27),
+            Message::ChainHashes(hash, vec![hash]),
+            Message::GetHeaders(vec![]),
+            Message::Headers(vec![block.header]),
+            Message::GetMerkleProof(hash, hash),
+            Message::MerkleProof(block::MerkleProof { block_hash: hash, txid: hash, index: 1, leaf_size: 2, proof: vec![hash] }),
+            Message::GetAccountProof(hash, H160::from([28u8; 20])),
+            Message::AccountProof(block::AccountProof {
+                block_hash: hash,
+                address: H160::from([19u8; 20]),
+                account: Some(account),
+                proof: state.account_proof(&H160::from([19u8; 20])),
+            }),
+            Message::NewTransactionHashes(vec![hash]),
+            Message::MempoolRequest,
+            Message::MempoolInv(vec![hash]),
+            Message::FeeFilter(29),
+            Message::GetTransactions(vec![hash]),
+            Message::Transactions(vec![signed]),
+            Message::GetSnapshot(30),
+            Message::Snapshot(Box::new(snapshot::Snapshot { headers: vec![block.header], block, state })),
+            Message::GetBlocksFrom(vec![hash]),
+            Message::GetMerkleMultiProof(hash, vec![hash]),
+            Message::MerkleMultiProof(block::MerkleMultiProof {
+                block_hash: hash,
+                txids: vec![hash],
+                indices: vec![4],
+                leaf_size: 5,
+                proof: vec![],
+            }),
+        ];
+        for msg in messages {
+            let encoded = msg.encode_as(Encoding::Protobuf);
+            let decoded = Message::decode(&encoded).unwrap().unwrap();
+            assert_eq!(bincode::serialize(&decoded).unwrap(), bincode::serialize(&msg).unwrap(), "{}", msg.kind());
+            assert_eq!(Message::priority_of(&encoded), msg.priority(), "{}", msg.kind());
+            // the type ids are the same in both encodings
+            assert_eq!(msg.encode()[1..3], encoded[1..3], "{}", msg.kind());
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_payloads() {
+        let short_hash = Hashes { hashes: vec![vec![0; 31]] }.encode_to_vec();
+        assert_eq!(decode(2, &short_hash).err(), Some(EnvelopeError::MalformedPayload));
+        assert_eq!(decode(4, &[0xff]).err(), Some(EnvelopeError::MalformedPayload));
+        let scheme = SignedTransaction { transaction: Some(Transaction::default()), scheme: 7, ..Default::default() };
+        let transactions = Transactions { transactions: vec![scheme] }.encode_to_vec();
+        assert_eq!(decode(18, &transactions).err(), Some(EnvelopeError::MalformedPayload));
+        assert!(matches!(decode(MESSAGE_TYPES, &[]), Ok(None)));
+    }
+}
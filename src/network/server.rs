@@ -1,119 +1,255 @@
+use super::compression::Compression;
+use super::features::Features;
+use super::limits::Limits;
 use super::message;
-use super::peer::{self, ReadResult, WriteResult};
+use super::peer::{self, Disconnect, PublicKey, StaticKey};
+use super::shim::Shim;
+use super::stats::PeerInfo;
+use crate::clock;
+use crate::events::{EventBus, NodeEvent};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
-use mio::{self, net};
-use mio_extras::channel;
-use std::sync::mpsc;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-const MAX_INCOMING_CLIENT: usize = 256;
-const MAX_EVENT: usize = 1024;
+/// Number of threads of the runtime driving the peer connections.
+const NETWORK_THREADS: usize = 2;
+/// How long an outgoing connection may take to establish.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Penalty points at which a peer is disconnected.
+pub(super) const BAN_SCORE: u32 = 100;
+/// Penalty points of a peer for each message above its rate limit.
+const RATE_LIMIT_PENALTY: u32 = 1;
+/// How often the peers are pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a peer may leave a ping unanswered before it is disconnected.
+const PING_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    events: &Arc<EventBus>,
+    shim: Shim,
+    limits: Limits,
+    key: StaticKey,
+    compression: Compression,
 ) -> std::io::Result<(Context, Handle)> {
-    let (control_signal_sender, control_signal_receiver) = channel::channel();
+    let (control_signal_sender, control_signal_receiver) = mpsc::unbounded_channel();
     let handle = Handle {
         control_chan: control_signal_sender,
     };
+    let (closed_sender, closed_receiver) = mpsc::unbounded_channel();
+    let (handshake_sender, handshake_receiver) = mpsc::unbounded_channel();
     let ctx = Context {
-        peers: slab::Slab::new(),
-        peer_list: vec![],
+        peers: HashMap::new(),
+        next_peer_id: 0,
         addr,
-        poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
-        _handle: handle.clone(),
+        closed_sender,
+        closed_chan: closed_receiver,
+        handshake_sender,
+        handshake_chan: handshake_receiver,
+        events: Arc::clone(events),
+        shim,
+        limits,
+        key,
+        compression,
+        features: Features::SUPPORTED,
+        external_addr: None,
+        ping_interval: PING_INTERVAL,
+        ping_timeout: PING_TIMEOUT,
+        next_ping: 0,
     };
     Ok((ctx, handle))
 }
 
+/// A connected peer, as seen by the supervisor.
+struct PeerEntry {
+    addr: std::net::SocketAddr,
+    direction: peer::Direction,
+    remote_key: PublicKey,
+    remote_version: u32,
+    external_addr: Option<std::net::SocketAddr>,
+    handle: peer::Handle,
+    misbehavior: Arc<AtomicU32>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+}
+
+impl PeerEntry {
+    fn close(&self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+
+    fn info(&self) -> PeerInfo {
+        PeerInfo {
+            external_addr: self.external_addr,
+            ..self.handle.stats().info(
+                self.addr,
+                self.direction,
+                self.remote_version,
+                self.handle.features(),
+                Some(hex::encode(self.remote_key)),
+                self.misbehavior.load(Ordering::SeqCst),
+            )
+        }
+    }
+}
+
+/// The connection supervisor. It owns the peer connections, each served by a reader and a
+/// writer task, accepts and opens connections, and carries out the requests of the handles.
 pub struct Context {
-    peers: slab::Slab<peer::Context>,
-    peer_list: Vec<usize>,
+    peers: HashMap<u64, PeerEntry>,
+    next_peer_id: u64,
     addr: std::net::SocketAddr,
-    poll: mio::Poll,
-    control_chan: channel::Receiver<ControlSignal>,
+    control_chan: mpsc::UnboundedReceiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
-    _handle: Handle,
+    /// The peer tasks report the end of their connection here.
+    closed_sender: mpsc::UnboundedSender<(u64, Disconnect)>,
+    closed_chan: mpsc::UnboundedReceiver<(u64, Disconnect)>,
+    /// The handshakes of the incoming connections, run in their own tasks, end here.
+    handshake_sender: mpsc::UnboundedSender<(std::net::SocketAddr, std::io::Result<peer::Session>)>,
+    handshake_chan: mpsc::UnboundedReceiver<(std::net::SocketAddr, std::io::Result<peer::Session>)>,
+    events: Arc<EventBus>,
+    /// The latency and loss put on the links to the peers.
+    shim: Shim,
+    limits: Limits,
+    /// The static key authenticating this node to its peers.
+    key: StaticKey,
+    compression: Compression,
+    /// The features announced to the peers.
+    features: Features,
+    /// The address announced to the peers, at which they can connect to this node, see `nat`.
+    external_addr: Option<std::net::SocketAddr>,
+    ping_interval: Duration,
+    ping_timeout: Duration,
+    /// Nonce of the next round of pings.
+    next_ping: u64,
 }
 
 impl Context {
+    /// Announce `addr` to the peers as the address they can connect to this node at, instead of
+    /// none, which leaves the node outbound-only.
+    pub fn set_external_addr(&mut self, addr: std::net::SocketAddr) {
+        self.external_addr = Some(addr);
+    }
+
     /// Start a new server context.
     pub fn start(mut self) -> std::io::Result<()> {
-        thread::spawn(move || {
-            self.listen().unwrap_or_else(|e| {
-                error!("P2P server error: {}", e);
-            });
-        });
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(NETWORK_THREADS)
+            .thread_name("p2p")
+            .enable_all()
+            .build()?;
+        thread::Builder::new()
+            .name("p2p-server".to_string())
+            .spawn(move || {
+                runtime.block_on(self.listen()).unwrap_or_else(|e| {
+                    error!("P2P server error: {}", e);
+                });
+                // dropping the runtime ends the peer tasks
+            })?;
         Ok(())
     }
 
-    /// Register a TCP stream in the event loop, and initialize peer context.
-    fn register(
-        &mut self,
-        stream: net::TcpStream,
-        direction: peer::Direction,
-    ) -> std::io::Result<peer::Handle> {
-        // get a new slot in the connection set
-        let vacant = self.peers.vacant_entry();
-        let key: usize = vacant.key();
-        if key >= MAX_INCOMING_CLIENT {
+    /// Spawn the tasks of a new connection whose handshake is done, and register the peer.
+    fn register(&mut self, session: peer::Session, direction: peer::Direction) -> std::io::Result<peer::Handle> {
+        let max_peers = match direction {
+            peer::Direction::Incoming => self.limits.max_inbound,
+            peer::Direction::Outgoing => self.limits.max_outbound,
+        };
+        if self.peers.values().filter(|peer| peer.direction == direction).count() >= max_peers {
             // too many connections
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "max peer reached, cannot accept new connections",
+            return Err(std::io::Error::other(
+                format!("max {:?} peers reached, cannot accept new connections", direction),
             ));
         }
+        let (ctx, handle) = peer::new(session, direction, &self.shim, &self.limits, &self.compression)?;
+        let peer_id = self.next_peer_id;
+        self.next_peer_id += 1;
+
+        let reader = {
+            let closed = self.closed_sender.clone();
+            let read = ctx.reader.run(
+                handle.clone(),
+                self.new_msg_chan.clone(),
+                Arc::clone(&ctx.misbehavior),
+                RATE_LIMIT_PENALTY,
+                BAN_SCORE,
+            );
+            tokio::spawn(async move {
+                let _ = closed.send((peer_id, read.await));
+            })
+        };
+        let writer = {
+            let closed = self.closed_sender.clone();
+            let write = ctx.writer.run();
+            tokio::spawn(async move {
+                let _ = closed.send((peer_id, write.await));
+            })
+        };
 
-        // set two tokens, one for socket and one for write queue
-        let socket_token = mio::Token(key * 2);
-        let writer_token = mio::Token(key * 2 + 1);
-
-        // register the new connection
-        self.poll.register(
-            &stream,
-            socket_token,
-            mio::Ready::readable(),
-            mio::PollOpt::edge(),
-        )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
-
-        // register the writer queue
-        self.poll.register(
-            &ctx.writer.queue,
-            writer_token,
-            mio::Ready::readable(),
-            mio::PollOpt::edge() | mio::PollOpt::oneshot(),
-        )?;
-
-        // insert the context and return the handle
-        vacant.insert(ctx);
-        // record the key of this peer
-        self.peer_list.push(key);
-        trace!("Registering peer with event token={}", key);
+        self.events.publish(NodeEvent::PeerConnected(ctx.addr));
+        info!("Peer {} authenticated with static key {}", ctx.addr, hex::encode(ctx.remote_key));
+        trace!("Registering {:?} peer {} as {}", ctx.direction, ctx.addr, peer_id);
+        self.peers.insert(peer_id, PeerEntry {
+            addr: ctx.addr,
+            direction: ctx.direction,
+            remote_key: ctx.remote_key,
+            remote_version: ctx.remote_version,
+            external_addr: ctx.external_addr,
+            handle: handle.clone(),
+            misbehavior: ctx.misbehavior,
+            reader,
+            writer,
+        });
+        // learn about the transactions broadcast before the connection
+        if handle.features().contains(Features::MEMPOOL_SYNC) {
+            handle.write(message::Message::MempoolRequest);
+        }
         Ok(handle)
     }
 
-    /// Connect to a peer, and register this peer
-    fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
-        // we need to estabilsh a stdlib tcp stream, since we need it to block
+    /// Connect to a peer, run the handshake, and register this peer. If `remote_key` is given,
+    /// the peer must own that static key.
+    async fn connect(
+        &mut self,
+        addr: &std::net::SocketAddr,
+        remote_key: Option<PublicKey>,
+    ) -> std::io::Result<peer::Handle> {
         debug!("Establishing connection to peer {}", addr);
-        let stream = std::net::TcpStream::connect(addr)?;
-        let mio_stream = net::TcpStream::from_stream(stream)?;
-        self.register(mio_stream, peer::Direction::Outgoing)
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connection timed out"))??;
+        let session = peer::handshake(stream, peer::Direction::Outgoing, &self.key, remote_key, self.features, self.external_addr).await?;
+        self.register(session, peer::Direction::Outgoing)
     }
 
-    /// Accept an incoming peer and register it
-    fn accept(
-        &mut self,
-        stream: net::TcpStream,
-        addr: std::net::SocketAddr,
-    ) -> std::io::Result<()> {
+    /// Run the handshake of an incoming connection in its own task, so that a slow peer does
+    /// not hold up the others. The peer is registered once it is done, see `listen`.
+    fn accept(&mut self, stream: TcpStream, addr: std::net::SocketAddr) {
         debug!("New incoming connection from {}", addr);
-        match self.register(stream, peer::Direction::Incoming) {
+        let key = self.key.clone();
+        let features = self.features;
+        let external_addr = self.external_addr;
+        let done = self.handshake_sender.clone();
+        tokio::spawn(async move {
+            let session = peer::handshake(stream, peer::Direction::Incoming, &key, None, features, external_addr).await;
+            let _ = done.send((addr, session));
+        });
+    }
+
+    /// Register an incoming peer once its handshake is done.
+    fn accepted(&mut self, addr: std::net::SocketAddr, session: std::io::Result<peer::Session>) {
+        match session.and_then(|session| self.register(session, peer::Direction::Incoming)) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
             }
@@ -121,242 +257,129 @@ impl Context {
                 error!("Error initializing incoming peer {}: {}", addr, e);
             }
         }
-        Ok(())
     }
 
-    fn process_control(&mut self, req: ControlSignal) -> std::io::Result<()> {
+    /// Returns false once the server is shut down.
+    async fn process_control(&mut self, req: ControlSignal) -> bool {
         match req {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
-                let handle = self.connect(&req.addr);
-                req.result_chan.send(handle).unwrap();
+                let handle = self.connect(&req.addr, req.remote_key).await;
+                let _ = req.result_chan.send(handle);
             }
             ControlSignal::BroadcastMessage(msg) => {
                 trace!("Processing BroadcastMessage command");
-                for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                for peer in self.peers.values() {
+                    peer.handle.relay(&msg);
+                }
+            }
+            ControlSignal::Shutdown(result_chan) => {
+                info!("P2P server shutting down, disconnecting {} peers", self.peers.len());
+                let peer_ids: Vec<u64> = self.peers.keys().copied().collect();
+                for peer_id in peer_ids {
+                    self.drop_peer(peer_id);
+                }
+                let _ = result_chan.send(());
+                return false;
+            }
+            ControlSignal::GetPeers(result_chan) => {
+                let _ = result_chan.send(self.peers.values().map(PeerEntry::info).collect());
+            }
+            ControlSignal::Penalize(addr, points) => {
+                let peer_id = self.peers.iter().find(|(_, peer)| peer.addr == addr).map(|(id, _)| *id);
+                if let Some(peer_id) = peer_id {
+                    self.penalize(peer_id, points);
                 }
             }
         }
-        Ok(())
+        true
     }
 
-    fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
-        trace!("Registering socket write interest for peer {}", peer_id);
-        let peer = &mut self.peers[peer_id];
-        // we have stuff to write at the writer queue
-        let socket_token = mio::Token(peer_id * 2);
-        // register for writable event
-        self.poll.reregister(
-            &peer.stream,
-            socket_token,
-            mio::Ready::readable() | mio::Ready::writable(),
-            mio::PollOpt::edge(),
-        )?;
-        Ok(())
+    /// Add to the misbehavior score of a peer, and disconnect it once the score reaches
+    /// `BAN_SCORE`.
+    fn penalize(&mut self, peer_id: u64, points: u32) {
+        let peer = &self.peers[&peer_id];
+        let score = peer.misbehavior.fetch_add(points, Ordering::SeqCst).saturating_add(points);
+        if score >= BAN_SCORE {
+            warn!("Disconnecting misbehaving peer {}", peer.addr);
+            self.drop_peer(peer_id);
+        }
     }
 
-    fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
-        // we are using edge-triggered events, loop until block
-        let peer = &mut self.peers[peer_id];
-        loop {
-            match peer.reader.read() {
-                Ok(ReadResult::EOF) => {
-                    // EOF, remove it from the connections set
-                    info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
-                    break;
-                }
-                Ok(ReadResult::Continue) => {
-                    trace!("Peer {} reading continue", peer_id);
-                    // no full message has been received
-                    continue;
-                }
-                Ok(ReadResult::Message(m)) => {
-                    trace!("Peer {} yield message", peer_id);
-                    // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
-                    continue;
-                }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        trace!("Peer {} finished reading", peer_id);
-                        // socket is not ready anymore, stop reading
-                        break;
-                    } else {
-                        warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
-                        break;
-                    }
-                }
-            }
+    /// Disconnect the peers that left a ping unanswered for `ping_timeout`, and ping the others.
+    /// The workers record the pongs, and with them the round trip time, in the peer statistics.
+    fn ping_peers(&mut self) {
+        let now = clock::now_micros();
+        let timeout = self.ping_timeout.as_micros();
+        let silent: Vec<u64> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                peer.handle
+                    .stats()
+                    .unanswered_ping()
+                    .is_some_and(|sent| now.saturating_sub(sent) > timeout)
+            })
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        for peer_id in silent {
+            warn!("Peer {} did not answer a ping in {:?}, disconnecting", self.peers[&peer_id].addr, self.ping_timeout);
+            self.drop_peer(peer_id);
+        }
+        self.next_ping += 1;
+        for peer in self.peers.values() {
+            peer.handle.write(message::Message::Ping(self.next_ping.to_string()));
         }
-        Ok(())
     }
 
-    fn process_writable(&mut self, peer_id: usize) -> std::io::Result<()> {
-        let peer = &mut self.peers[peer_id];
-        match peer.writer.write() {
-            Ok(WriteResult::Complete) => {
-                trace!("Peer {} outgoing queue drained", peer_id);
-                // we wrote everything in the write queue
-                let socket_token = mio::Token(peer_id * 2);
-                let writer_token = mio::Token(peer_id * 2 + 1);
-                // we've done writing. no longer interested.
-                self.poll.reregister(
-                    &peer.stream,
-                    socket_token,
-                    mio::Ready::readable(),
-                    mio::PollOpt::edge(),
-                )?;
-                // we're interested in write queue again.
-                self.poll.reregister(
-                    &peer.writer.queue,
-                    writer_token,
-                    mio::Ready::readable(),
-                    mio::PollOpt::edge() | mio::PollOpt::oneshot(),
-                )?;
-            }
-            Ok(WriteResult::EOF) => {
-                // EOF, remove it from the connections set
-                info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
-            }
-            Ok(WriteResult::ChanClosed) => {
-                // the channel is closed. no more writes.
-                warn!("Peer {} outgoing queue closed", peer_id);
-                let socket_token = mio::Token(peer_id * 2);
-                self.poll.reregister(
-                    &peer.stream,
-                    socket_token,
-                    mio::Ready::readable(),
-                    mio::PollOpt::edge(),
-                )?;
-                self.poll.deregister(&peer.writer.queue)?;
-            }
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::WouldBlock {
-                    trace!("Peer {} finished writing", peer_id);
-                // socket is not ready anymore, stop reading
-                } else {
-                    warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
-                }
-            }
+    /// Close the connection to a peer and forget it.
+    fn drop_peer(&mut self, peer_id: u64) {
+        if let Some(peer) = self.peers.remove(&peer_id) {
+            peer.close();
+            self.events.publish(NodeEvent::PeerDisconnected(peer.addr));
         }
-        Ok(())
     }
 
-    /// The main event loop of the server.
-    fn listen(&mut self) -> std::io::Result<()> {
-        // bind server to passed addr and register to the poll
-        let server = net::TcpListener::bind(&self.addr)?;
-
-        // token for new incoming connection
-        const INCOMING: mio::Token = mio::Token(std::usize::MAX - 1);
-        self.poll.register(
-            &server,
-            INCOMING,
-            mio::Ready::readable(),
-            mio::PollOpt::edge(),
-        )?;
-
-        // token for new control signal from the handle
-        const CONTROL: mio::Token = mio::Token(std::usize::MAX - 2);
-        self.poll.register(
-            &self.control_chan,
-            CONTROL,
-            mio::Ready::readable(),
-            mio::PollOpt::edge(),
-        )?;
-
+    /// The main loop of the supervisor.
+    async fn listen(&mut self) -> std::io::Result<()> {
+        let server = TcpListener::bind(&self.addr).await?;
         info!("P2P server listening at {}", server.local_addr()?);
-
-        // initialize space for polled events
-        let mut events = mio::Events::with_capacity(MAX_EVENT);
+        let mut has_handles = true;
+        let mut pings = tokio::time::interval_at(
+            tokio::time::Instant::now() + self.ping_interval,
+            self.ping_interval,
+        );
 
         loop {
-            self.poll.poll(&mut events, None)?;
-
-            for event in events.iter() {
-                match event.token() {
-                    CONTROL => {
-                        trace!("Server control channel readable");
-                        // process until we drain the control message queue
-                        loop {
-                            // get the new control singal from the channel
-                            match self.control_chan.try_recv() {
-                                Ok(req) => {
-                                    self.process_control(req).unwrap();
-                                }
-                                Err(e) => match e {
-                                    mpsc::TryRecvError::Empty => break,
-                                    mpsc::TryRecvError::Disconnected => {
-                                        warn!("P2P server dropped, disconnecting all peers");
-                                        self.poll.deregister(&self.control_chan)?;
-                                        break;
-                                    }
-                                },
-                            }
+            tokio::select! {
+                accepted = server.accept() => match accepted {
+                    Ok((stream, client_addr)) => self.accept(stream, client_addr),
+                    Err(e) => warn!("Error accepting a connection: {}", e),
+                },
+                signal = self.control_chan.recv(), if has_handles => match signal {
+                    Some(signal) => {
+                        if !self.process_control(signal).await {
+                            return Ok(());
                         }
                     }
-                    INCOMING => {
-                        trace!("P2P server listener readable");
-                        // we have a new connection
-                        // we are using edge-triggered events, loop until block
-                        loop {
-                            // accept the connection
-                            match server.accept() {
-                                Ok((stream, client_addr)) => {
-                                    self.accept(stream, client_addr).unwrap();
-                                }
-                                Err(e) => {
-                                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                                        // socket is not ready anymore, stop reading here
-                                        break;
-                                    } else {
-                                        return Err(e);
-                                    }
-                                }
-                            }
-                        }
+                    None => {
+                        warn!("P2P server handles dropped, no more control signals");
+                        has_handles = false;
                     }
-                    mio::Token(token_id) => {
-                        // peer id (the index in the peers list) is token_id/2
-                        let peer_id = token_id >> 1;
-                        // if the token_id is odd, it's new write request, else it's socket
-                        match token_id & 0x01 {
-                            0 => {
-                                let readiness = event.readiness();
-                                if readiness.is_readable() {
-                                    trace!("Peer {} readable", peer_id);
-                                    if !self.peers.contains(peer_id) {
-                                        continue;
-                                    }
-                                    self.process_readable(peer_id).unwrap();
-                                }
-                                if readiness.is_writable() {
-                                    trace!("Peer {} writable", peer_id);
-                                    if !self.peers.contains(peer_id) {
-                                        continue;
-                                    }
-                                    self.process_writable(peer_id).unwrap();
-                                }
-                            }
-                            1 => {
-                                trace!("Peer {} outgoing queue readable", peer_id);
-                                self.register_write_interest(peer_id)?;
-                            }
-                            _ => unreachable!(),
+                },
+                Some((addr, session)) = self.handshake_chan.recv() => self.accepted(addr, session),
+                _ = pings.tick() => self.ping_peers(),
+                Some((peer_id, reason)) = self.closed_chan.recv() => {
+                    if let Some(peer) = self.peers.get(&peer_id) {
+                        match reason {
+                            Disconnect::Closed(e) => info!("Peer {} dropped connection: {}", peer.addr, e),
+                            Disconnect::Oversized(size) => warn!("Peer {} sent a message of {} bytes, disconnecting", peer.addr, size),
+                            Disconnect::BadCompression => warn!("Peer {} sent a message that failed to decompress, disconnecting", peer.addr),
+                            Disconnect::Forged => warn!("Peer {} sent a frame that failed to decrypt, disconnecting", peer.addr),
+                            Disconnect::Banned => warn!("Disconnecting misbehaving peer {}", peer.addr),
+                            Disconnect::Detached => debug!("Peer {} detached", peer.addr),
                         }
+                        self.drop_peer(peer_id);
                     }
                 }
             }
@@ -366,35 +389,326 @@ impl Context {
 
 #[derive(Clone)]
 pub struct Handle {
-    control_chan: channel::Sender<ControlSignal>,
+    control_chan: mpsc::UnboundedSender<ControlSignal>,
 }
 
 impl Handle {
+    /// A handle driven by another implementation of the server, see `memory`.
+    pub(super) fn from_control_chan(control_chan: mpsc::UnboundedSender<ControlSignal>) -> Self {
+        Handle { control_chan }
+    }
+
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        self.request_connection(addr, None)
+    }
+
+    /// Connect to the peer at `addr`, failing unless it owns the static key `remote_key`.
+    pub fn connect_authenticated(
+        &self,
+        addr: std::net::SocketAddr,
+        remote_key: PublicKey,
+    ) -> std::io::Result<peer::Handle> {
+        self.request_connection(addr, Some(remote_key))
+    }
+
+    fn request_connection(
+        &self,
+        addr: std::net::SocketAddr,
+        remote_key: Option<PublicKey>,
+    ) -> std::io::Result<peer::Handle> {
         let (sender, receiver) = cbchannel::unbounded();
         let request = ConnectRequest {
             addr,
+            remote_key,
             result_chan: sender,
         };
+        let gone = || std::io::Error::new(std::io::ErrorKind::NotConnected, "P2P server shut down");
         self.control_chan
             .send(ControlSignal::ConnectNewPeer(request))
-            .unwrap();
-        receiver.recv().unwrap()
+            .map_err(|_| gone())?;
+        receiver.recv().map_err(|_| gone())?
     }
 
     pub fn broadcast(&self, msg: message::Message) {
-        self.control_chan
-            .send(ControlSignal::BroadcastMessage(msg))
-            .unwrap();
+        if self.control_chan.send(ControlSignal::BroadcastMessage(msg)).is_err() {
+            debug!("P2P server shut down, dropping a broadcast");
+        }
+    }
+
+    /// Add `points` to the misbehavior score of the peer at `addr`, which is disconnected once
+    /// the score reaches `BAN_SCORE`.
+    pub fn penalize(&self, addr: std::net::SocketAddr, points: u32) {
+        let _ = self.control_chan.send(ControlSignal::Penalize(addr, points));
+    }
+
+    /// The connected peers and their statistics. Empty once the server is shut down.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = cbchannel::unbounded();
+        if self.control_chan.send(ControlSignal::GetPeers(sender)).is_err() {
+            return vec![];
+        }
+        receiver.recv().unwrap_or_default()
+    }
+
+    /// Disconnect every peer and stop the server, which also stops the workers once they have
+    /// handled the messages already received. Returns once the peers are disconnected.
+    pub fn shutdown(&self) {
+        let (sender, receiver) = cbchannel::unbounded();
+        if self.control_chan.send(ControlSignal::Shutdown(sender)).is_ok() {
+            let _ = receiver.recv();
+        }
     }
 }
 
-enum ControlSignal {
+pub(super) enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
     BroadcastMessage(message::Message),
+    Shutdown(cbchannel::Sender<()>),
+    Penalize(std::net::SocketAddr, u32),
+    GetPeers(cbchannel::Sender<Vec<PeerInfo>>),
 }
 
-struct ConnectRequest {
-    addr: std::net::SocketAddr,
-    result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
+pub(super) struct ConnectRequest {
+    pub(super) addr: std::net::SocketAddr,
+    /// The static key the peer must own, if any.
+    pub(super) remote_key: Option<PublicKey>,
+    pub(super) result_chan: cbchannel::Sender<std::io::Result<peer::Handle>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::compression::{CompressionStats, COMPRESSION_THRESHOLD};
+    use std::time::Duration;
+
+    #[test]
+    fn exchange_messages() {
+        let events = Arc::new(EventBus::default());
+        let start = |port: u16| {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            let (msg_tx, msg_rx) = cbchannel::unbounded();
+            let (ctx, handle) =
+                new(addr, msg_tx, &events, Shim::default(), Limits::default(), StaticKey::generate(), Compression::default())
+                    .unwrap();
+            ctx.start().unwrap();
+            (addr, handle, msg_rx)
+        };
+        let (_, first, first_rx) = start(16100);
+        let (second_addr, second, second_rx) = start(16101);
+        thread::sleep(Duration::from_millis(100));
+
+        let peer = first.connect(second_addr).unwrap();
+        peer.write(message::Message::Ping("hello".to_string()));
+        // each side asks for the mempool of the other first
+        let (msg, _) = second_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(message::Message::decode(&msg).unwrap().unwrap(), message::Message::MempoolRequest));
+        let (msg, reply_to) = second_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        match message::Message::decode(&msg).unwrap().unwrap() {
+            message::Message::Ping(nonce) => assert_eq!(nonce, "hello"),
+            _ => panic!("expected a ping"),
+        }
+        reply_to.write(message::Message::Pong("hello".to_string()));
+        assert!(first_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(first_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        let peers = first.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, second_addr);
+        assert_eq!(peers[0].direction, peer::Direction::Outgoing);
+        assert_eq!(peers[0].protocol_version, peer::PROTOCOL_VERSION);
+        assert_eq!(peers[0].sent["Ping"].messages, 1);
+        assert_eq!(second.peers()[0].sent["Pong"].messages, 1);
+
+        // the workers see the message channel close once the server is shut down
+        second.shutdown();
+        assert!(second_rx.recv_timeout(Duration::from_secs(5)).is_err());
+        first.shutdown();
+    }
+
+    #[test]
+    fn authenticates_peers() {
+        let events = Arc::new(EventBus::default());
+        let key = StaticKey::generate();
+        let stats = Arc::new(CompressionStats::default());
+        let start = |port: u16, key: StaticKey| {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            let (msg_tx, msg_rx) = cbchannel::unbounded();
+            let compression = Compression::new(Some(COMPRESSION_THRESHOLD), &stats);
+            let (ctx, handle) = new(addr, msg_tx, &events, Shim::default(), Limits::default(), key, compression).unwrap();
+            ctx.start().unwrap();
+            (addr, handle, msg_rx)
+        };
+        let (_, first, _first_rx) = start(16102, StaticKey::generate());
+        let (second_addr, second, second_rx) = start(16103, key.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let wrong_key = StaticKey::generate().public;
+        let err = first.connect_authenticated(second_addr, wrong_key).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::PermissionDenied);
+
+        // a message larger than a Noise frame is compressed, split and put back together
+        let peer = first.connect_authenticated(second_addr, key.public).unwrap();
+        let nonce = "x".repeat(200_000);
+        peer.write(message::Message::Ping(nonce.clone()));
+        let (msg, _) = second_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(message::Message::decode(&msg).unwrap().unwrap(), message::Message::MempoolRequest));
+        let (msg, _) = second_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        match message::Message::decode(&msg).unwrap().unwrap() {
+            message::Message::Ping(received) => assert_eq!(received, nonce),
+            _ => panic!("expected a ping"),
+        }
+        assert!(stats.summary().messages_compressed >= 1);
+        second.shutdown();
+        first.shutdown();
+    }
+
+    #[test]
+    fn negotiates_features() {
+        let events = Arc::new(EventBus::default());
+        let start = |port: u16, features: Features| {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            let (msg_tx, msg_rx) = cbchannel::unbounded();
+            let (mut ctx, handle) =
+                new(addr, msg_tx, &events, Shim::default(), Limits::default(), StaticKey::generate(), Compression::default())
+                    .unwrap();
+            ctx.features = features;
+            if features == Features::SUPPORTED {
+                ctx.set_external_addr(([203, 0, 113, 7], port).into());
+            }
+            ctx.start().unwrap();
+            (addr, handle, msg_rx)
+        };
+        // an older node, without any optional feature
+        let (_, first, _first_rx) = start(16107, Features::NONE);
+        let (second_addr, second, second_rx) = start(16108, Features::SUPPORTED);
+        thread::sleep(Duration::from_millis(100));
+
+        let peer = first.connect(second_addr).unwrap();
+        assert_eq!(peer.features(), Features::NONE);
+        peer.write(message::Message::Ping("hello".to_string()));
+        // no mempool request, which the older node might not understand
+        let (msg, reply_to) = second_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(message::Message::decode(&msg).unwrap().unwrap(), message::Message::Ping(_)));
+        assert_eq!(reply_to.features(), Features::NONE);
+        assert!(second.peers()[0].features.is_empty());
+        // the older node announced no address, the other its external one
+        assert_eq!(second.peers()[0].external_addr, None);
+        assert_eq!(first.peers()[0].external_addr, Some(([203, 0, 113, 7], 16108).into()));
+        first.shutdown();
+        second.shutdown();
+    }
+
+    #[test]
+    fn applies_backpressure() {
+        let events = Arc::new(EventBus::default());
+        let start = |port: u16, msg_tx| {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            let (ctx, handle) =
+                new(addr, msg_tx, &events, Shim::default(), Limits::default(), StaticKey::generate(), Compression::default())
+                    .unwrap();
+            ctx.start().unwrap();
+            (addr, handle)
+        };
+        let (first_tx, _first_rx) = cbchannel::unbounded();
+        let (_, first) = start(16109, first_tx);
+        // workers that are stuck, with room for two messages
+        let (msg_tx, second_rx) = cbchannel::bounded(2);
+        let (second_addr, second) = start(16110, msg_tx);
+        thread::sleep(Duration::from_millis(100));
+
+        // the mempool request fills the queue half way
+        let peer = first.connect(second_addr).unwrap();
+        peer.write(message::Message::Ping("first".to_string()));
+        peer.write(message::Message::NewTransactionHashes(vec![]));
+        peer.write(message::Message::Ping("second".to_string()));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(second_rx.len(), 2);
+
+        // the announcement was dropped, the second ping waited
+        let received: Vec<String> = second_rx
+            .iter()
+            .take(3)
+            .map(|(msg, _)| message::Message::decode(&msg).unwrap().unwrap().kind().to_string())
+            .collect();
+        assert_eq!(received, vec!["MempoolRequest", "Ping", "Ping"]);
+        assert!(second_rx.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(second.peers()[0].dropped, 1);
+        first.shutdown();
+        second.shutdown();
+    }
+
+    #[test]
+    fn limits_peers_by_direction() {
+        let events = Arc::new(EventBus::default());
+        let start = |port: u16| {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            let (msg_tx, msg_rx) = cbchannel::unbounded();
+            let limits = Limits { max_inbound: 1, max_outbound: 1, ..Default::default() };
+            let (ctx, handle) =
+                new(addr, msg_tx, &events, Shim::default(), limits, StaticKey::generate(), Compression::default()).unwrap();
+            ctx.start().unwrap();
+            (addr, handle, msg_rx)
+        };
+        let (_, first, _first_rx) = start(16111);
+        let (second_addr, second, _second_rx) = start(16112);
+        let (third_addr, third, _third_rx) = start(16113);
+        thread::sleep(Duration::from_millis(100));
+
+        first.connect(second_addr).unwrap();
+        assert!(first.connect(third_addr).is_err());
+        // the second node refuses a second incoming peer once the handshake is done
+        let _ = third.connect(second_addr);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(first.peers().len(), 1);
+        assert_eq!(second.peers().len(), 1);
+        assert_eq!(second.peers()[0].direction, peer::Direction::Incoming);
+        first.shutdown();
+        second.shutdown();
+        third.shutdown();
+    }
+
+    #[test]
+    fn disconnects_silent_peers() {
+        let events = Arc::new(EventBus::default());
+        let start = |port: u16| {
+            let addr = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+            let (msg_tx, msg_rx) = cbchannel::unbounded();
+            let (mut ctx, handle) =
+                new(addr, msg_tx, &events, Shim::default(), Limits::default(), StaticKey::generate(), Compression::default())
+                    .unwrap();
+            ctx.ping_interval = Duration::from_millis(100);
+            ctx.ping_timeout = Duration::from_millis(300);
+            ctx.start().unwrap();
+            (addr, handle, msg_rx)
+        };
+        let (_, first, first_rx) = start(16104);
+        let (responsive_addr, responsive, responsive_rx) = start(16105);
+        let (silent_addr, silent, _silent_rx) = start(16106);
+        thread::sleep(Duration::from_millis(100));
+        // answer the pings, and account for the pongs, as the workers do
+        let answer = |receiver: cbchannel::Receiver<(Vec<u8>, peer::Handle)>| {
+            thread::spawn(move || {
+                for (msg, peer) in receiver.iter() {
+                    let msg: message::Message = message::Message::decode(&msg).unwrap().unwrap();
+                    peer.stats().received(&msg, 0);
+                    if let message::Message::Ping(nonce) = msg {
+                        peer.write(message::Message::Pong(nonce));
+                    }
+                }
+            });
+        };
+        answer(first_rx);
+        answer(responsive_rx);
+
+        first.connect(responsive_addr).unwrap();
+        first.connect(silent_addr).unwrap();
+        thread::sleep(Duration::from_millis(1000));
+        let peers = first.peers();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].addr, responsive_addr);
+        assert!(peers[0].ping_rtt_ms.is_some());
+        first.shutdown();
+        responsive.shutdown();
+        silent.shutdown();
+    }
 }
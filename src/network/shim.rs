@@ -0,0 +1,190 @@
+//! Impairs the links to the peers, to study block propagation under realistic network
+//! conditions. Each outgoing message of a peer is dropped with some probability, or held back
+//! for a delay drawn from a latency distribution. Since the delays are drawn independently, a
+//! message can overtake the one written before it, so a jittery link also reorders messages.
+
+use crate::clock;
+use log::trace;
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::time;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Latency {
+    Fixed(Duration),
+    /// Uniform between the two bounds.
+    Uniform(Duration, Duration),
+    /// Exponential with the given mean.
+    Exponential(Duration),
+}
+
+impl Latency {
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> Duration {
+        match *self {
+            Latency::Fixed(delay) => delay,
+            Latency::Uniform(low, high) => {
+                if high <= low {
+                    low
+                } else {
+                    low + (high - low).mul_f64(rng.gen::<f64>())
+                }
+            }
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - rng.gen::<f64>()).ln()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkConditions {
+    pub latency: Latency,
+    /// Probability that a message is dropped.
+    pub loss: f64,
+}
+
+impl Default for LinkConditions {
+    fn default() -> Self {
+        LinkConditions {
+            latency: Latency::Fixed(Duration::from_millis(0)),
+            loss: 0.0,
+        }
+    }
+}
+
+impl LinkConditions {
+    /// Whether the link is left untouched, in which case no shim is put in front of it.
+    pub fn is_perfect(&self) -> bool {
+        *self == LinkConditions::default()
+    }
+}
+
+/// The conditions of the links of a server: a default, and overrides for some peers.
+#[derive(Clone, Debug, Default)]
+pub struct Shim {
+    default: LinkConditions,
+    peers: HashMap<SocketAddr, LinkConditions>,
+}
+
+impl Shim {
+    pub fn new(default: LinkConditions) -> Self {
+        Shim {
+            default,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn set_peer(&mut self, addr: SocketAddr, conditions: LinkConditions) {
+        self.peers.insert(addr, conditions);
+    }
+
+    pub fn conditions(&self, addr: &SocketAddr) -> LinkConditions {
+        *self.peers.get(addr).unwrap_or(&self.default)
+    }
+
+    /// Put the link to `addr` behind the shim. Returns the sender the messages to the peer are
+    /// written to, and passes them on to `queue` once they are due.
+    pub fn wrap(&self, addr: SocketAddr, queue: mpsc::UnboundedSender<Vec<u8>>) -> mpsc::UnboundedSender<Vec<u8>> {
+        let conditions = self.conditions(&addr);
+        if conditions.is_perfect() {
+            return queue;
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        thread::Builder::new()
+            .name(format!("shim-{}", addr))
+            .spawn(move || relay(addr, conditions, receiver, queue))
+            .unwrap();
+        sender
+    }
+}
+
+/// Move the messages from `receiver` to `queue`, dropping or delaying them, until either side
+/// is closed.
+fn relay(
+    addr: SocketAddr,
+    conditions: LinkConditions,
+    mut receiver: mpsc::UnboundedReceiver<Vec<u8>>,
+    queue: mpsc::UnboundedSender<Vec<u8>>,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_time().build().unwrap();
+    runtime.block_on(async move {
+        let mut rng = clock::rng();
+        // messages waiting for their time, ordered by when they are due and then by arrival
+        let mut pending: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>> = BinaryHeap::new();
+        let mut arrivals: u64 = 0;
+        let mut closed = false;
+        loop {
+            let now = Instant::now();
+            while matches!(pending.peek(), Some(Reverse((due, _, _))) if *due <= now) {
+                let Reverse((_, _, msg)) = pending.pop().unwrap();
+                if queue.send(msg).is_err() {
+                    return;
+                }
+            }
+            if closed && pending.is_empty() {
+                return;
+            }
+            let next_due = pending.peek().map(|Reverse((due, _, _))| *due);
+            tokio::select! {
+                msg = receiver.recv(), if !closed => match msg {
+                    Some(msg) => {
+                        if rng.gen::<f64>() < conditions.loss {
+                            trace!("Dropped a message to {}", addr);
+                            continue;
+                        }
+                        let due = Instant::now() + conditions.latency.sample(&mut rng);
+                        pending.push(Reverse((due, arrivals, msg)));
+                        arrivals += 1;
+                    }
+                    None => closed = true,
+                },
+                _ = time::sleep_until(next_due.unwrap_or(now).into()), if next_due.is_some() => {}
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_and_drops() {
+        let addr: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let (queue, mut receiver) = mpsc::unbounded_channel();
+        let mut shim = Shim::default();
+        assert!(shim.conditions(&addr).is_perfect());
+        shim.set_peer(addr, LinkConditions {
+            latency: Latency::Fixed(Duration::from_millis(100)),
+            loss: 0.0,
+        });
+        let sender = shim.wrap(addr, queue);
+        let start = Instant::now();
+        sender.send(vec![1]).unwrap();
+        sender.send(vec![2]).unwrap();
+        drop(sender);
+        let mut received = vec![];
+        while received.len() < 2 {
+            if let Ok(msg) = receiver.try_recv() {
+                received.push(msg);
+            }
+            thread::yield_now();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert_eq!(received, vec![vec![1], vec![2]]);
+
+        let (queue, mut receiver) = mpsc::unbounded_channel();
+        shim.set_peer(addr, LinkConditions {
+            latency: Latency::Uniform(Duration::from_millis(0), Duration::from_millis(10)),
+            loss: 1.0,
+        });
+        let sender = shim.wrap(addr, queue);
+        sender.send(vec![3]).unwrap();
+        drop(sender);
+        thread::sleep(Duration::from_millis(50));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,172 @@
+//! Per-peer accounting of the traffic and responsiveness of a connection, listed by the
+//! `/network/peers` API.
+
+use super::features::Features;
+use super::message::Message;
+use super::peer::Direction;
+use crate::clock;
+use crate::transaction::TxValidationError;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Messages and bytes of one type of message.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub messages: u64,
+    pub bytes: u64,
+}
+
+/// Traffic with a peer, shared by all the clones of its handle.
+#[derive(Default)]
+pub struct PeerStats {
+    sent: Mutex<BTreeMap<&'static str, Traffic>>,
+    received: Mutex<BTreeMap<&'static str, Traffic>>,
+    /// When the last message of the peer arrived, in microseconds. 0 before the first one.
+    last_seen: AtomicU64,
+    /// Nonce -> when the ping was sent, in microseconds, for the pings not answered yet.
+    pings: Mutex<HashMap<String, u128>>,
+    /// Round trip time of the last answered ping, in microseconds.
+    ping_rtt: Mutex<Option<u128>>,
+    /// Messages of the peer dropped because the workers were behind.
+    dropped: AtomicU64,
+    /// Reason -> transactions of the peer refused by the mempool.
+    rejected: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl PeerStats {
+    /// Account for `msg`, `size` bytes once serialized, written to the peer.
+    pub fn sent(&self, msg: &Message, size: usize) {
+        add(&self.sent, msg, size);
+        if let Message::Ping(nonce) = msg {
+            self.pings.lock().unwrap().insert(nonce.clone(), clock::now_micros());
+        }
+    }
+
+    /// Account for `msg`, `size` bytes once serialized, received from the peer.
+    pub fn received(&self, msg: &Message, size: usize) {
+        let now = clock::now_micros();
+        add(&self.received, msg, size);
+        self.last_seen.store(now as u64, Ordering::Relaxed);
+        if let Message::Pong(nonce) = msg {
+            if let Some(sent) = self.pings.lock().unwrap().remove(nonce) {
+                *self.ping_rtt.lock().unwrap() = Some(now.saturating_sub(sent));
+            }
+        }
+    }
+
+    /// Account for a message of the peer dropped because the workers were behind.
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Account for a transaction of the peer refused by the mempool.
+    pub fn rejected(&self, error: TxValidationError) {
+        *self.rejected.lock().unwrap().entry(error.name()).or_default() += 1;
+    }
+
+    /// When the oldest ping the peer did not answer yet was sent, in microseconds.
+    pub fn unanswered_ping(&self) -> Option<u128> {
+        self.pings.lock().unwrap().values().min().copied()
+    }
+
+    /// Round trip time of the last answered ping, in microseconds.
+    pub fn ping_rtt(&self) -> Option<u128> {
+        *self.ping_rtt.lock().unwrap()
+    }
+
+    /// A snapshot of the statistics, completed with what the server knows of the peer.
+    pub fn info(
+        &self,
+        addr: std::net::SocketAddr,
+        direction: Direction,
+        protocol_version: u32,
+        features: Features,
+        static_key: Option<String>,
+        ban_score: u32,
+    ) -> PeerInfo {
+        let last_seen = self.last_seen.load(Ordering::Relaxed);
+        PeerInfo {
+            addr,
+            direction,
+            protocol_version,
+            features: features.names(),
+            static_key,
+            external_addr: None,
+            ping_rtt_ms: self.ping_rtt().map(|micros| micros as f64 / 1000.0),
+            sent: self.sent.lock().unwrap().clone(),
+            received: self.received.lock().unwrap().clone(),
+            last_seen: if last_seen == 0 { None } else { Some(last_seen as u128) },
+            dropped: self.dropped.load(Ordering::Relaxed),
+            rejected: self.rejected.lock().unwrap().clone(),
+            ban_score,
+        }
+    }
+}
+
+fn add(traffic: &Mutex<BTreeMap<&'static str, Traffic>>, msg: &Message, size: usize) {
+    let mut traffic = traffic.lock().unwrap();
+    let entry = traffic.entry(msg.kind()).or_default();
+    entry.messages += 1;
+    entry.bytes += size as u64;
+}
+
+/// What the `/network/peers` API lists of each peer.
+#[derive(Serialize, Debug)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: Direction,
+    pub protocol_version: u32,
+    /// The features the link uses.
+    pub features: Vec<&'static str>,
+    /// The hex static key the peer authenticated with, none over the in-memory network.
+    pub static_key: Option<String>,
+    /// The address at which the peer accepts connections, none for an outbound-only peer.
+    pub external_addr: Option<std::net::SocketAddr>,
+    pub ping_rtt_ms: Option<f64>,
+    /// Message type -> traffic.
+    pub sent: BTreeMap<&'static str, Traffic>,
+    pub received: BTreeMap<&'static str, Traffic>,
+    /// When the last message of the peer arrived, in microseconds since the epoch.
+    pub last_seen: Option<u128>,
+    /// Messages dropped because the workers were behind.
+    pub dropped: u64,
+    /// Reason -> transactions refused by the mempool.
+    pub rejected: BTreeMap<&'static str, u64>,
+    pub ban_score: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accounts_traffic_and_ping() {
+        let stats = PeerStats::default();
+        stats.sent(&Message::Ping("1".to_string()), 10);
+        stats.sent(&Message::GetBlocks(vec![]), 12);
+        stats.sent(&Message::GetBlocks(vec![]), 12);
+        assert_eq!(stats.ping_rtt(), None);
+        assert!(stats.unanswered_ping().is_some());
+        stats.received(&Message::Pong("1".to_string()), 10);
+        assert!(stats.ping_rtt().is_some());
+        assert_eq!(stats.unanswered_ping(), None);
+
+        let info = stats.info(([127, 0, 0, 1], 6000).into(), Direction::Outgoing, 1, Features::NONE, None, 0);
+        assert_eq!(info.sent["GetBlocks"], Traffic { messages: 2, bytes: 24 });
+        assert_eq!(info.received["Pong"], Traffic { messages: 1, bytes: 10 });
+        assert!(info.last_seen.is_some());
+    }
+
+    #[test]
+    fn accounts_rejected_transactions() {
+        let stats = PeerStats::default();
+        stats.rejected(TxValidationError::NonceTooLow);
+        stats.rejected(TxValidationError::NonceTooLow);
+        stats.rejected(TxValidationError::FeeTooLow);
+        let info = stats.info(([127, 0, 0, 1], 6000).into(), Direction::Outgoing, 1, Features::NONE, None, 0);
+        assert_eq!(info.rejected["nonce-too-low"], 2);
+        assert_eq!(info.rejected["fee-too-low"], 1);
+    }
+}
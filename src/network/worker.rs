@@ -1,41 +1,71 @@
-use super::message::Message;
+use super::download::{BlockDownload, SYNC_BATCH};
+use super::features::Features;
+use super::inventory::RecentInventory;
+use super::limits::{self, WORKER_QUEUE_CAPACITY};
+use super::message::{Message, Priority};
 use super::peer;
 use crate::network::server::Handle as ServerHandle;
 use crossbeam::channel;
-use log::{debug, warn, info};
+use log::{debug, info, warn};
 
 use std::thread;
+use std::time::Instant;
 use std::sync::{Mutex, Arc};
-use std::collections::{HashMap};
-use std::time;
-use crate::{Blockchain, block::{Block, State, AccountState}};
-use crate::crypto::hash::{Hashable, H256};
-use crate::crypto::address::H160;
-use crate::transaction::{SignedTransaction,verify};
-use ring::signature::{UnparsedPublicKey, ED25519};
-use rand::seq::IteratorRandom;
-use rand::thread_rng;
-use crate::txgenerator::{TX_MEMPOOL_CAPACITY};
-
+use crate::blockchain::{verify_block, Blockchain};
+use crate::{block::{AccountProof, Block}};
+use crate::crypto::hash::{H256, Hashable};
+use crate::transaction::{verify, TxValidationError};
+use crate::mempool::Mempool;
+use crate::orphan::OrphanPool;
+use crate::clock;
+use crate::blockchain::Reorg;
+use crate::events::{EventBus, NodeEvent};
+use crate::snapshot::CHECKPOINT_INTERVAL;
+
+/// Misbehavior points of a peer for a message that cannot be decoded.
+static MALFORMED_MESSAGE_PENALTY: u32 = 20;
+/// Misbehavior points of a peer for a block whose proof of work fails.
+static INVALID_HEADER_PENALTY: u32 = 50;
+/// Misbehavior points of a peer for a block whose transactions or state root are invalid.
+static INVALID_BLOCK_PENALTY: u32 = 50;
+/// A block received longer than this after its timestamp, in microseconds, is being caught up
+/// on rather than propagated, and left out of the propagation delay statistic.
+static MAX_PROPAGATION_DELAY: u128 = 60 * 1_000_000;
+
+/// The workers run in two pools of `num_worker` threads, each with its own queue and its own
+/// inventory: the block workers handle the block and control messages, the transaction workers
+/// the transaction messages, so that a flood of transactions cannot delay the blocks.
+///
+/// Lock ordering for the shared state held by the workers: `orphan_blocks` -> the internal
+/// locks of `blockchain` -> `tx_mempool` -> `block_inventory` or `tx_inventory` -> `download`.
+/// The blockchain never hands out guards, so it only matters that `orphan_blocks` is never
+/// requested while `tx_mempool` is held. The transaction workers never take `orphan_blocks`.
 #[derive(Clone)]
 pub struct Context {
     msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
     num_worker: usize,
     server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    orphan_blocks: Arc<Mutex<HashMap<H256,Block>>>,
-    tx_mempool: Arc<Mutex<HashMap<H256,SignedTransaction>>>,
+    blockchain: Arc<Blockchain>,
+    orphan_blocks: Arc<Mutex<OrphanPool>>,
+    tx_mempool: Arc<Mutex<Mempool>>,
+    events: Arc<EventBus>,
+    block_inventory: Arc<Mutex<RecentInventory>>,
+    tx_inventory: Arc<Mutex<RecentInventory>>,
+    download: Arc<Mutex<BlockDownload>>,
     delay_time_sum: Arc<Mutex<u128>>,
     recv_block_sum: Arc<Mutex<u32>>,
+    /// Whether to start from a checkpoint of a peer when far behind, see `snapshot`.
+    fast_sync: bool,
 }
 
 pub fn new(
     num_worker: usize,
     msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>,
-    orphan_blocks: &Arc<Mutex<HashMap<H256,Block>>>,
-    tx_mempool: &Arc<Mutex<HashMap<H256,SignedTransaction>>>,
+    blockchain: &Arc<Blockchain>,
+    orphan_blocks: &Arc<Mutex<OrphanPool>>,
+    tx_mempool: &Arc<Mutex<Mempool>>,
+    events: &Arc<EventBus>,
     delay_time_sum: &Arc<Mutex<u128>>,
     recv_block_sum: &Arc<Mutex<u32>>,
 ) -> Context {
@@ -46,272 +76,769 @@ pub fn new(
         blockchain: blockchain.clone(),
         orphan_blocks: orphan_blocks.clone(),
         tx_mempool: tx_mempool.clone(),
+        events: Arc::clone(events),
+        block_inventory: Arc::new(Mutex::new(RecentInventory::default())),
+        tx_inventory: Arc::new(Mutex::new(RecentInventory::default())),
+        download: Arc::new(Mutex::new(BlockDownload::default())),
         delay_time_sum: Arc::clone(delay_time_sum),
         recv_block_sum: Arc::clone(recv_block_sum),
+        fast_sync: false,
     }
 }
 
- // verify a block wrt the state
-    // If the block is valid, return the updated state
-    fn verify_block(block: &Block, _state: &State) -> Option<State> {
-        let mut txs_map = HashMap::<H160, Vec<SignedTransaction>>::new();
-        let address_list = _state.clone().address_list;
-        let mut state = _state.clone();
-        for address in address_list.iter() {
-            let txs = vec![];
-            txs_map.insert(address.clone(), txs);
-        }
-        for tx in block.content.transactions.iter() {
-            let address: H160 = ring::digest::digest(&ring::digest::SHA256, tx.public_key.as_ref()).into();
-            if let Some(mut _txs) = txs_map.get_mut(&address) {
-                _txs.push(tx.clone());
+/// Sort the messages of the peers by priority: the block and control messages go to the block
+/// workers, the transaction messages to the transaction workers. It only reads the variant of
+/// each message, so the blocks never wait behind transactions being decoded or verified.
+/// Returns once the P2P server is shut down.
+fn dispatch(
+    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+    block_sink: channel::Sender<(Vec<u8>, peer::Handle)>,
+    tx_sink: channel::Sender<(Vec<u8>, peer::Handle)>,
+) {
+    for (msg, peer) in msg_chan.iter() {
+        match Message::priority_of(&msg) {
+            // a full block queue holds the peers back
+            Priority::High => block_sink.send((msg, peer)).unwrap(),
+            // never wait for the transaction workers, drop the message if they are behind
+            priority if limits::admits(priority, tx_sink.len(), tx_sink.capacity()) => {
+                tx_sink.send((msg, peer)).unwrap()
+            }
+            priority => {
+                debug!("Transaction workers behind, dropping a {:?} priority message from {}", priority, peer.addr());
+                peer.stats().dropped();
             }
         }
-        // sort it by the nonce
-        for address in address_list.iter() {
-            if let Some(mut _txs) = txs_map.get_mut(address) {
-                _txs.sort_by(|a, b| a.transaction.account_nonce.cmp(&b.transaction.account_nonce));
-                for tx in _txs.iter() {
-                    if !tx.is_valid(&state) {
-                        return None;
-                    }
-                    tx.update_state(&mut state);
+    }
+}
+
+impl Context {
+    /// When far behind, ask the peers for their latest checkpoint and start from it instead of
+    /// downloading all the blocks before it.
+    pub fn set_fast_sync(&mut self, fast_sync: bool) {
+        self.fast_sync = fast_sync;
+    }
+
+    /// After a reorg, drop the transactions of the newly connected blocks from the mempool and
+    /// put back the evicted transactions that are still valid on top of the new tip.
+    fn apply_reorg_to_mempool(&self, reorg: &Reorg) {
+        let (_, tip_state) = self.blockchain.tip_with_state();
+        if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
+            _tx_mempool.update(&tip_state, self.blockchain.height());
+            let mut returned = 0;
+            for tx in reorg.evicted_transactions.iter() {
+                if _tx_mempool.insert(tx.clone(), &tip_state).is_ok() {
+                    returned += 1;
                 }
             }
+            debug!("Reorg returned {} of {} evicted transactions to the mempool", returned, reorg.evicted_transactions.len());
         }
-        return Some(state);
     }
 
-impl Context {
-    pub fn start(self) {
+    /// Start the block and the transaction worker threads, fed by a dispatcher thread. They
+    /// exit once the P2P server is shut down.
+    pub fn start(self) -> Vec<thread::JoinHandle<()>> {
         let num_worker = self.num_worker;
-        for i in 0..num_worker {
-            let mut cloned = self.clone();
+        let (block_sink, block_chan) = channel::bounded(WORKER_QUEUE_CAPACITY);
+        let (tx_sink, tx_chan) = channel::bounded(WORKER_QUEUE_CAPACITY);
+        let msg_chan = self.msg_chan.clone();
+        // the workers exit once the dispatcher has, and drops the sinks
+        let mut handles = vec![thread::spawn(move || dispatch(msg_chan, block_sink, tx_sink))];
+        handles.extend((0..num_worker).map(|i| {
+            let cloned = self.clone();
+            let block_chan = block_chan.clone();
             thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
-        }
+                cloned.worker_loop(block_chan);
+                info!("Worker thread {} exited", i);
+            })
+        }));
+        handles.extend((0..num_worker).map(|i| {
+            let cloned = self.clone();
+            let tx_chan = tx_chan.clone();
+            thread::spawn(move || {
+                cloned.worker_loop(tx_chan);
+                info!("Transaction worker thread {} exited", i);
+            })
+        }));
+        handles
     }
 
-    fn worker_loop(&mut self) {
-        loop {
-            let msg = self.msg_chan.recv().unwrap();
-            let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+    fn worker_loop(&self, msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>) {
+        for (msg, peer) in msg_chan.iter() {
+            let size = msg.len();
+            let msg: Message = match Message::decode(&msg) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => {
+                    debug!("Skipping a message of an unknown type from peer {}", peer.addr());
+                    continue;
                 }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+                Err(e) => {
+                    warn!("Dropping a malformed message from peer {}: {}", peer.addr(), e);
+                    self.events.publish(NodeEvent::MalformedMessage(peer.addr()));
+                    self.server.penalize(peer.addr(), MALFORMED_MESSAGE_PENALTY);
+                    continue;
                 }
+            };
+            peer.stats().received(&msg, size);
+            let is_transaction = msg.is_transaction();
+            self.handle_message(msg, &peer);
+            if !is_transaction {
+                self.request_blocks();
+            }
+        }
+    }
 
-                // If a peer advertises that it has a block that we don't have, request it from the peer.
-                Message::NewBlockHashes(hashes) => {
-                    //debug!("NewBlockHashes: {:#?}", hashes);
+    /// Write the block requests of the running download, if any.
+    fn request_blocks(&self) {
+        let requests = self.download.lock().unwrap().requests(Instant::now());
+        for (peer, msg) in requests {
+            peer.write(msg);
+        }
+    }
 
-                    for hash in &hashes {
-                        if let Ok(chain) = self.blockchain.lock(){ 
-                            if let Ok(orphans) = self.orphan_blocks.lock(){
-                                if chain.get_block(hash).is_none() && !orphans.contains_key(hash) {
-                                    self.server.broadcast(Message::GetBlocks(vec![*hash]));
-                                }
-                            }
+    fn handle_message(&self, msg: Message, peer: &peer::Handle) {
+        match msg {
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                peer.write(Message::Pong(nonce.to_string()));
+            }
+            Message::Pong(nonce) => {
+                debug!("Pong: {}", nonce);
+            }
+
+            // If a peer advertises that it has a block that we don't have, request it from the peer.
+            Message::NewBlockHashes(hashes) => {
+                //debug!("NewBlockHashes: {:#?}", hashes);
+
+                for hash in &hashes {
+                    if let Ok(orphans) = self.orphan_blocks.lock(){
+                        if !self.blockchain.contains_key(hash) && !orphans.contains_key(hash)
+                            && self.block_inventory.lock().unwrap().requested.insert(*hash) {
+                            self.server.broadcast(Message::GetBlocks(vec![*hash]));
                         }
                     }
                 }
+            }
 
-                // If a peer asks us for a block we have, give it to them.
-                Message::GetBlocks(hashes) => {
-                    //debug!("GetBlocks: {:#?}", hashes);
+            // If a peer asks us for a block we have, give it to them. The orphans are not served,
+            // their proof of work was not checked against their parent.
+            Message::GetBlocks(hashes) => {
+                //debug!("GetBlocks: {:#?}", hashes);
 
-                    for hash in &hashes {
-                        if let Ok(chain) = self.blockchain.lock() {
-                            if let Ok(orphans) = self.orphan_blocks.lock(){
-                                if let Some(block) = chain.get_block(hash) {
-                                    peer.write(Message::Blocks(vec![block.clone()]));
-                                }
-                                else if let Some(block) = orphans.get(hash){
-                                    peer.write(Message::Blocks(vec![block.clone()]));
-                                }
-                            }
-                        }
+                for hash in &hashes {
+                    if let Some(block) = self.blockchain.get_block(hash) {
+                        peer.write(Message::Blocks(vec![block]));
                     }
                 }
+            }
 
-                // If we receive a block, check if we already have it. If so dump it.
-                // Otherwise the block is new. Check if we can commit it.
-                // If it can, commit it and all of its children in the orphan block pool.
-                // If it can't add it to the orphan block pool and request its parent from the peer if necessary.
-                Message::Blocks(blocks) => {
-                    //let mut broadcast_hashes: Vec<H256> = Vec::new();
-                    let timestamp_rcv = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_micros();
-                    
-                    {
-                        let mut delay = self.delay_time_sum.lock().unwrap();
-                        let mut num = self.recv_block_sum.lock().unwrap();
-                        for block in &blocks {
+            // A node catching up learns the hashes of our longest chain after its tip.
+            Message::GetChainHashes(from, count) => {
+                let hashes: Vec<H256> = match self.blockchain.get_block_height(&from) {
+                    Some(height) if self.blockchain.get_hash_by_height(height) == Some(from) => {
+                        let last = self.blockchain.height().min(height.saturating_add(count.min(SYNC_BATCH)));
+                        self.blockchain.canonical_range(height + 1, last).collect()
+                    }
+                    _ => vec![],
+                };
+                peer.write(Message::ChainHashes(from, hashes));
+            }
+            // A node that may be on a fork learns the hashes of our longest chain after the fork
+            // point.
+            Message::GetBlocksFrom(locator) => {
+                let (from, hashes) = match self.blockchain.locate(&locator) {
+                    Some((from, height)) => {
+                        (from, self.blockchain.canonical_range(height + 1, height.saturating_add(SYNC_BATCH)).collect())
+                    }
+                    None => (locator.first().copied().unwrap_or_default(), vec![]),
+                };
+                peer.write(Message::ChainHashes(from, hashes));
+            }
+            Message::ChainHashes(from, hashes) => {
+                debug!("Peer {} has {} blocks after {}", peer.addr(), hashes.len(), from);
+                let orphans = self.orphan_blocks.lock().unwrap();
+                self.download.lock().unwrap().add_hashes(peer, &hashes, |hash| {
+                    self.blockchain.contains_key(hash) || orphans.contains_key(hash)
+                });
+            }
+
+            // A node catching up may start from our latest checkpoint instead.
+            Message::GetSnapshot(height) => {
+                if let Some(snapshot) = self.blockchain.snapshot(CHECKPOINT_INTERVAL).filter(|s| s.height() > height) {
+                    debug!("Sending the checkpoint at height {} to {}", snapshot.height(), peer.addr());
+                    peer.write(Message::Snapshot(Box::new(snapshot)));
+                }
+            }
+            Message::Snapshot(snapshot) => {
+                if !self.fast_sync {
+                    return;
+                }
+                let genesis = self.blockchain.get_hash_by_height(0).unwrap();
+                if !snapshot.verify(&genesis) {
+                    warn!("Peer {} sent an invalid checkpoint", peer.addr());
+                    self.server.penalize(peer.addr(), INVALID_HEADER_PENALTY);
+                    return;
+                }
+                let _orphans = self.orphan_blocks.lock().unwrap();
+                if self.blockchain.import_snapshot(&snapshot) {
+                    self.tx_mempool.lock().unwrap().update(&snapshot.state, snapshot.height());
+                    // the blocks after the checkpoint are fetched as usual
+                    self.server.broadcast(Message::GetBlocksFrom(self.blockchain.locator()));
+                }
+            }
+
+            // Light clients only ask for the headers.
+            Message::GetHeaders(hashes) => {
+                let headers: Vec<_> = hashes.iter().filter_map(|hash| self.blockchain.get_header(hash)).collect();
+                if !headers.is_empty() {
+                    peer.write(Message::Headers(headers));
+                }
+            }
+            Message::GetMerkleProof(block_hash, txid) => {
+                if let Some(proof) = self.blockchain.get_block(&block_hash).and_then(|block| block.merkle_proof(&txid)) {
+                    peer.write(Message::MerkleProof(proof));
+                }
+            }
+            Message::GetMerkleMultiProof(block_hash, txids) => {
+                if let Some(proof) = self.blockchain.get_block(&block_hash).and_then(|block| block.merkle_multiproof(&txids)) {
+                    peer.write(Message::MerkleMultiProof(proof));
+                }
+            }
+            Message::GetAccountProof(block_hash, address) => {
+                if let Some(state) = self.blockchain.get_state(&block_hash) {
+                    peer.write(Message::AccountProof(AccountProof {
+                        block_hash,
+                        address,
+                        account: state.account_state.get(&address).cloned(),
+                        proof: state.account_proof(&address),
+                    }));
+                }
+            }
+            // A full node fetches the whole blocks instead.
+            Message::Headers(_) | Message::MerkleProof(_) | Message::MerkleMultiProof(_) | Message::AccountProof(_) => {}
+
+            // If we receive a block, check if we already have it. If so dump it.
+            // Otherwise the block is new. Check if we can commit it.
+            // If it can, commit it and all of its children in the orphan block pool.
+            // If it can't add it to the orphan block pool and request its parent from the peer if necessary.
+            // Header first: a block whose proof of work holds against the difficulty of its parent
+            // is relayed right away, its transactions and state are only validated afterwards. A
+            // block whose parent is unknown is only relayed once it is committed.
+            Message::Blocks(blocks) => {
+                //let mut broadcast_hashes: Vec<H256> = Vec::new();
+                let timestamp_rcv = clock::now_micros();
+                let blocks: Vec<Block> = blocks.into_iter().filter(|block| {
+                    let parent = self.blockchain.get_header(&block.header.parent);
+                    if !block.header.meets_difficulty(parent.as_ref()) {
+                        warn!("Dropping block {:?} from peer {}, its proof of work fails", block.hash(), peer.addr());
+                        self.server.penalize(peer.addr(), INVALID_HEADER_PENALTY);
+                        return false;
+                    }
+                    // maybe the clock of the peer is wrong rather than the peer malicious
+                    if block.header.is_from_future(timestamp_rcv) {
+                        warn!("Dropping block {:?} from peer {}, its timestamp is too far ahead", block.hash(), peer.addr());
+                        return false;
+                    }
+                    true
+                }).collect();
+
+                {
+                    let mut delay = self.delay_time_sum.lock().unwrap();
+                    let mut num = self.recv_block_sum.lock().unwrap();
+                    for block in &blocks {
+                        // a timestamp ahead of the local clock is skew, not a delay
+                        if block.header.timestamp <= timestamp_rcv
+                            && timestamp_rcv - block.header.timestamp <= MAX_PROPAGATION_DELAY {
                             *delay += timestamp_rcv - block.header.timestamp;
                             *num += 1;
-                            //broadcast_hashes.push(block.hash());
+                        }
+                        //broadcast_hashes.push(block.hash());
+                        // relay each block once, however many peers send it
+                        if self.blockchain.get_header(&block.header.parent).is_some()
+                            && self.block_inventory.lock().unwrap().announced.insert(block.hash()) {
                             self.server.broadcast(Message::NewBlockHashes(vec![block.hash()]));
                         }
-                        //println!("Block recv ave latency: {}", *delay as f64 / *num as f64);
                     }
+                    //println!("Block recv ave latency: {}", *delay as f64 / *num as f64);
+                }
 
-                    // Fast relay blocks
-                    /*
-                    if !broadcast_hashes.is_empty() {
-                        self.server.broadcast(Message::NewBlockHashes(broadcast_hashes));
-                    }
-                    */
-                    //let mut requested_hashes: Vec<H256> = Vec::new();
-                    for block in &blocks {
-                        info!("Received a block: hash: {:?}, num transactions: {:?}", 
-                            block.hash(),
-                            block.content.len(),
-                        );
-                        if let Ok(mut chain) = self.blockchain.lock(){
-                            if let Ok(mut orphans) = self.orphan_blocks.lock(){
-
-                                let parent_hash = block.header.parent;
-                                let block_hash = block.hash();
-
-                                // Check if already have block. If so, skip.
-                                if chain.contains_key(&block_hash) || orphans.contains_key(&block_hash){
-                                    continue;
-                                }
+                // Fast relay blocks
+                /*
+                if !broadcast_hashes.is_empty() {
+                    self.server.broadcast(Message::NewBlockHashes(broadcast_hashes));
+                }
+                */
+                //let mut requested_hashes: Vec<H256> = Vec::new();
+                for block in &blocks {
+                    info!("Received a block: hash: {:?}, num transactions: {:?}", 
+                        block.hash(),
+                        block.content.len(),
+                    );
+                    if let Ok(mut orphans) = self.orphan_blocks.lock(){
+
+                        let parent_hash = block.header.parent;
+                        let block_hash = block.hash();
+                        self.download.lock().unwrap().received(&block_hash);
+
+                        // Check if already have block. If so, skip.
+                        if self.blockchain.contains_key(&block_hash) || orphans.contains_key(&block_hash){
+                            continue;
+                        }
 
-                                // Otherwise block is new. Find out where the parent is.
-                                if chain.contains_key(&parent_hash){
-                                    // Parent in blockchain. Commit as many blocks to the chain as possible.
-                                    orphans.insert(block_hash,block.clone());
-
-                                    let mut committed_hashes = Vec::new();
-                                    loop{
-                                        // Reset everything
-                                        let mut no_commits = true;
-                                        committed_hashes.clear();
-
-                                        // Loop through orphan pool and commit as many blocks as possible.
-                                        for (block_hash, block) in orphans.iter() {
-                                            let parent_hash = block.header.parent;
-                                            // Commit if parent in blockchain and nonce is valid.
-                                            if chain.contains_key(&parent_hash)
-                                            && block_hash <= &chain.get_block(&parent_hash).unwrap().header.difficulty {
-                                                let parent_state = chain.get_state(&parent_hash).unwrap();
-                                                match verify_block(block, parent_state) {
-                                                    Some(new_state) => {
-                                                        no_commits = false;
-                                                        chain.insert(&block, &new_state);
-
-                                                        // If added block is not stale, drain its txns from the tx_mempool.
-                                                        if parent_hash == *chain.tip(){
-                                                            if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
-                                                                for tx in block.content.transactions.iter() {
-                                                                    _tx_mempool.remove(&tx.hash());
-                                                                }
-                                                            }
-                                                        }
-
-                                                        committed_hashes.push(*block_hash);
-                                                    }
-                                                    None => {
-                                                    }
-                                                }
-                                            }
+                        // Otherwise block is new. Find out where the parent is.
+                        if self.blockchain.contains_key(&parent_hash){
+                            // Parent in blockchain. Commit as many blocks to the chain as possible.
+                            orphans.insert(block_hash,block.clone(),peer.addr());
+
+                            let mut committed_hashes = Vec::new();
+                            loop{
+                                // Reset everything
+                                let mut no_commits = true;
+                                committed_hashes.clear();
+                                // invalid blocks, with the penalty of their sender
+                                let mut invalid = Vec::new();
+
+                                // Loop through orphan pool and commit as many blocks as possible.
+                                for (block_hash, block) in orphans.iter() {
+                                    let parent_hash = block.header.parent;
+                                    // Commit if parent in blockchain and nonce is valid.
+                                    // the parent of a block off a pruned fork has a header but no state
+                                    let (parent_header, parent_state, parent_height) = match (
+                                        self.blockchain.get_header(&parent_hash),
+                                        self.blockchain.get_state(&parent_hash),
+                                        self.blockchain.get_block_height(&parent_hash),
+                                    ) {
+                                        (Some(header), Some(state), Some(height)) => (header, state, height),
+                                        _ => continue,
+                                    };
+                                    let after_median_time = self.blockchain.median_time_past(&parent_hash)
+                                        .is_some_and(|median| block.header.timestamp > median);
+                                    if !block.header.meets_difficulty(Some(&parent_header)) || !after_median_time {
+                                        invalid.push((*block_hash, INVALID_HEADER_PENALTY));
+                                        continue;
+                                    }
+                                    let new_state = match verify_block(block, &parent_state, parent_height + 1) {
+                                        Some(new_state) => new_state,
+                                        None => {
+                                            invalid.push((*block_hash, INVALID_BLOCK_PENALTY));
+                                            continue;
                                         }
-                                        // Clear all committed blocks from orphan pool.
-                                        for hash in &committed_hashes {
-                                            orphans.remove(&hash);
+                                    };
+                                    no_commits = false;
+                                    let result = self.blockchain.insert(block, &new_state);
+                                    if result.inserted {
+                                        self.events.publish(NodeEvent::BlockAccepted {
+                                            hash: *block_hash,
+                                            num_transactions: block.content.len(),
+                                        });
+                                    }
+                                    if let Some(reorg) = result.reorg {
+                                        self.apply_reorg_to_mempool(&reorg);
+                                    }
+                                    // If added block is not stale, drain its txns from the tx_mempool
+                                    // and promote the transactions that were waiting for them.
+                                    else if *block_hash == self.blockchain.tip(){
+                                        if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
+                                            _tx_mempool.update(&new_state, self.blockchain.height());
                                         }
+                                    }
 
-                                        // Repeat until convergence.
-                                        if no_commits {
-                                            break;
-                                        }
-                                    }                                   
+                                    committed_hashes.push(*block_hash);
+                                }
+                                // Clear all committed blocks from orphan pool.
+                                for hash in &committed_hashes {
+                                    orphans.remove(hash);
+                                }
+                                // Drop the invalid blocks rather than verifying them again on
+                                // every pass, and penalize their senders.
+                                for (hash, penalty) in invalid {
+                                    if let Some(sender) = orphans.sender(&hash) {
+                                        warn!("Dropping invalid block {:?} from peer {}", hash, sender);
+                                        self.server.penalize(sender, penalty);
+                                    }
+                                    orphans.remove(&hash);
                                 }
-                                else if orphans.contains_key(&parent_hash){
-                                    // Parent is also orphan, So block is orphan, don't request parent.
-                                    orphans.insert(block_hash,block.clone());
+
+                                // Repeat until convergence.
+                                if no_commits {
+                                    break;
                                 }
-                                else{
-                                    // Parent doesn't exist. So block is orphan, request parent.
-                                    orphans.insert(block_hash,block.clone());
+                            }                                   
+                        }
+                        else if orphans.contains_key(&parent_hash){
+                            // Parent is also orphan, So block is orphan, don't request parent.
+                            orphans.insert(block_hash,block.clone(),peer.addr());
+                        }
+                        else{
+                            // Parent doesn't exist. So block is orphan, ask the peer for its
+                            // chain after our fork point, unless the parent is being downloaded
+                            // already. Peers without locators are asked for the parent alone.
+                            orphans.insert(block_hash,block.clone(),peer.addr());
+                            let mut download = self.download.lock().unwrap();
+                            if !download.expects(&parent_hash) {
+                                if peer.features().contains(Features::LOCATOR) {
+                                    peer.write(Message::GetBlocksFrom(self.blockchain.locator()));
+                                } else {
                                     peer.write(Message::GetBlocks(vec![parent_hash]));
                                 }
+                                // we may be far behind, fetch the chain from all the peers at once
+                                if download.should_start(Instant::now()) {
+                                    self.server.broadcast(Message::GetBlocksFrom(self.blockchain.locator()));
+                                    if self.fast_sync {
+                                        self.server.broadcast(Message::GetSnapshot(self.blockchain.height()));
+                                    }
+                                }
                             }
                         }
                     }
                 }
+            }
 
-                // If a peer advertises that it has a transaction that we don't have, request it from the peer.
-                Message::NewTransactionHashes(hashes) => {
-                    //debug!("message: NewTransactionHashes: {:#?}", hashes);
+            // If a peer advertises that it has a transaction that we don't have, request it from the peer.
+            Message::NewTransactionHashes(hashes) => {
+                //debug!("message: NewTransactionHashes: {:#?}", hashes);
 
-                    for hash in &hashes {
-                        if let Ok(tx_pool) = self.tx_mempool.lock(){
-                            if !tx_pool.contains_key(hash) {
-                                self.server.broadcast(Message::GetTransactions(vec![hash.clone()]));
-                            }
+                for hash in &hashes {
+                    if let Ok(tx_pool) = self.tx_mempool.lock(){
+                        if !tx_pool.contains_key(hash) && self.tx_inventory.lock().unwrap().requested.insert(*hash) {
+                            self.server.broadcast(Message::GetTransactions(vec![*hash]));
                         }
                     }
+                }
 
+            }
+
+            // A peer that just connected learns what is in our mempool, and fetches the
+            // transactions it is missing.
+            // The peer also learns the fee below which we do not want transactions relayed.
+            Message::MempoolRequest => {
+                let (hashes, min_fee) = {
+                    let tx_pool = self.tx_mempool.lock().unwrap();
+                    let hashes: Vec<H256> = tx_pool.iter().map(|tx| tx.hash()).collect();
+                    (hashes, tx_pool.min_fee())
+                };
+                debug!("Sending the {} mempool transaction hashes to {}", hashes.len(), peer.addr());
+                if min_fee > 0 && peer.features().contains(Features::FEE_FILTER) {
+                    peer.write(Message::FeeFilter(min_fee));
+                }
+                peer.write(Message::MempoolInv(hashes));
+            }
+            Message::FeeFilter(min_fee) => {
+                debug!("Peer {} relays transactions paying at least {}", peer.addr(), min_fee);
+                peer.set_fee_filter(min_fee);
+            }
+            Message::MempoolInv(hashes) => {
+                let missing: Vec<H256> = {
+                    let tx_pool = self.tx_mempool.lock().unwrap();
+                    let mut inventory = self.tx_inventory.lock().unwrap();
+                    hashes
+                        .into_iter()
+                        .filter(|hash| !tx_pool.contains_key(hash) && inventory.requested.insert(*hash))
+                        .collect()
+                };
+                if !missing.is_empty() {
+                    debug!("Fetching {} mempool transactions from {}", missing.len(), peer.addr());
+                    peer.write(Message::GetTransactions(missing));
                 }
+            }
 
-                // If a peer requests a transaction that we have in our pool, give it to them.
-                Message::GetTransactions(hashes) => {
-                    //debug!("message: GetTransactions: {:#?}", hashes);
+            // If a peer requests a transaction that we have in our pool, give it to them.
+            Message::GetTransactions(hashes) => {
+                //debug!("message: GetTransactions: {:#?}", hashes);
 
-                    for hash in &hashes {
-                        if let Ok(tx_pool) = self.tx_mempool.lock(){
-                            if let Some(tx) = tx_pool.get(hash){
-                                peer.write(Message::Transactions(vec![tx.clone()]));
-                            }
+                for hash in &hashes {
+                    if let Ok(tx_pool) = self.tx_mempool.lock(){
+                        if let Some(tx) = tx_pool.get(hash){
+                            peer.write(Message::Transactions(vec![tx.clone()]));
                         }
                     }
-
                 }
 
-                // If transaction received, check if we have it. If so dump it
-                // Otherwise transaction is new. Check if it is signed correctly
-                // If so, add it to tx_mempool and rebroadcast it.
-                Message::Transactions(signed_transactions) => {
-                    //debug!("message: Transactions: {:#?}", signed_transactions);
-
-                    for tx_signed in signed_transactions {
-                        //info!("Receive Tx: {:#?}", tx_signed.transaction.clone());
-
-                        // Check if it is signed correctly. If not ignore it.
-                        let tx = tx_signed.transaction.clone();
-                        let public_key = UnparsedPublicKey::new(&ED25519, tx_signed.public_key.clone());
-                        if public_key.verify(tx.hash().as_ref(), tx_signed.signature.as_ref()).is_ok() {
-
-                            // If this is a new transaction, insert it and rebroadcast it.
-                            if let Ok(mut _tx_mempool) = self.tx_mempool.lock(){
-                                if !_tx_mempool.contains_key(&tx_signed.hash()){
-                                    //debug!("insert from message: sender_pub: {:?}, tx: {:?}", tx_signed.public_key, tx_signed.transaction.clone());
-                                    if _tx_mempool.len() >= TX_MEMPOOL_CAPACITY{
-                                        let random_key = {
-                                            let mut rng = thread_rng();
-                                            _tx_mempool.keys().choose(&mut rng).unwrap().clone()
-                                        };
-                                        _tx_mempool.remove(&random_key);
-                                    }
-                                    _tx_mempool.insert(tx_signed.hash(), tx_signed.clone());
-                                    self.server.broadcast(Message::Transactions(vec![tx_signed]));
-                                    //debug!("tx_pool size: {:?}", _tx_mempool.len());
-                                }
-                            }
+            }
 
+            // If transaction received, check if we have it. If so dump it
+            // Otherwise transaction is new. Check if it is signed correctly
+            // If so, add it to tx_mempool and rebroadcast it.
+            Message::Transactions(signed_transactions) => {
+                //debug!("message: Transactions: {:#?}", signed_transactions);
+
+                // the whole batch is checked against the same tip state
+                let (_, tip_state) = self.blockchain.tip_with_state();
+                for tx_signed in signed_transactions {
+                    //info!("Receive Tx: {:#?}", tx_signed.transaction.clone());
+
+                    // Check if it is signed correctly. If not ignore it.
+                    if !tx_signed.has_valid_signature() {
+                        peer.stats().rejected(TxValidationError::BadSignature);
+                        continue;
+                    }
+
+                    // If this is a new transaction, insert it and rebroadcast it.
+                    if let Ok(mut _tx_mempool) = self.tx_mempool.lock(){
+                        //debug!("insert from message: sender_pub: {:?}, tx: {:?}", tx_signed.public_key, tx_signed.transaction.clone());
+                        match _tx_mempool.insert(tx_signed.clone(), &tip_state) {
+                            Ok(()) => self.server.broadcast(Message::Transactions(vec![tx_signed])),
+                            Err(error) => {
+                                debug!("Transaction {} of peer {} rejected: {}", tx_signed.hash(), peer.addr(), error);
+                                peer.stats().rejected(error);
+                            }
                         }
                     }
+                }
 
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::network::memory::MemoryNetwork;
+    use crate::network::shim::Shim;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn survives_malformed_messages() {
+        let events = Arc::new(EventBus::default());
+        let malformed = events.subscribe();
+        let addr = "127.0.0.1:6000".parse().unwrap();
+        let server = MemoryNetwork::default().start_server(addr, channel::unbounded().0, &events);
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let workers = new(
+            1,
+            msg_rx,
+            &server,
+            &Arc::new(Blockchain::new()),
+            &Arc::new(Mutex::new(OrphanPool::default())),
+            &Arc::new(Mutex::new(Mempool::default())),
+            &events,
+            &Arc::new(Mutex::new(0)),
+            &Arc::new(Mutex::new(0)),
+        ).start();
+
+        let (peer, mut replies) = peer::Handle::with_queue(addr, &Shim::default());
+        msg_tx.send((vec![0xff, 0xff, 0xff], peer.clone())).unwrap();
+        msg_tx.send((Message::Ping("after".to_string()).encode(), peer)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let reply = loop {
+            if let Ok(reply) = replies.try_recv() {
+                break reply;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        };
+        match Message::decode(&reply).unwrap().unwrap() {
+            Message::Pong(nonce) => assert_eq!(nonce, "after"),
+            _ => panic!("expected a pong"),
+        }
+        assert!(malformed.try_iter().any(|event| matches!(event, NodeEvent::MalformedMessage(a) if a == addr)));
+
+        drop(msg_tx);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn exchanges_mempool_inventory() {
+        let events = Arc::new(EventBus::default());
+        let addr = "127.0.0.1:6000".parse().unwrap();
+        let server = MemoryNetwork::default().start_server(addr, channel::unbounded().0, &events);
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let workers = new(
+            1,
+            msg_rx,
+            &server,
+            &Arc::new(Blockchain::new()),
+            &Arc::new(Mutex::new(OrphanPool::default())),
+            &Arc::new(Mutex::new(Mempool::default())),
+            &events,
+            &Arc::new(Mutex::new(0)),
+            &Arc::new(Mutex::new(0)),
+        ).start();
+
+        let (peer, mut replies) = peer::Handle::with_queue(addr, &Shim::default());
+        let mut next_reply = || {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                if let Ok(reply) = replies.try_recv() {
+                    return Message::decode(&reply).unwrap().unwrap();
                 }
+                assert!(Instant::now() < deadline);
+                thread::sleep(Duration::from_millis(10));
             }
+        };
+        msg_tx.send((Message::MempoolRequest.encode(), peer.clone())).unwrap();
+        match next_reply() {
+            Message::MempoolInv(hashes) => assert!(hashes.is_empty()),
+            _ => panic!("expected a mempool inventory"),
+        }
+        let missing = H256::from([7; 32]);
+        msg_tx.send((Message::MempoolInv(vec![missing]).encode(), peer.clone())).unwrap();
+        match next_reply() {
+            Message::GetTransactions(hashes) => assert_eq!(hashes, vec![missing]),
+            _ => panic!("expected a transaction request"),
+        }
+
+        drop(msg_tx);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+    #[test]
+    fn transactions_do_not_stall_blocks() {
+        let events = Arc::new(EventBus::default());
+        let addr = "127.0.0.1:6000".parse().unwrap();
+        let server = MemoryNetwork::default().start_server(addr, channel::unbounded().0, &events);
+        let tx_mempool = Arc::new(Mutex::new(Mempool::default()));
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let workers = new(
+            1,
+            msg_rx,
+            &server,
+            &Arc::new(Blockchain::new()),
+            &Arc::new(Mutex::new(OrphanPool::default())),
+            &tx_mempool,
+            &events,
+            &Arc::new(Mutex::new(0)),
+            &Arc::new(Mutex::new(0)),
+        ).start();
+
+        let (peer, mut replies) = peer::Handle::with_queue(addr, &Shim::default());
+        let genesis = Blockchain::new().tip();
+        {
+            // the transaction worker waits for the mempool, the block worker goes on
+            let _busy = tx_mempool.lock().unwrap();
+            msg_tx.send((Message::MempoolRequest.encode(), peer.clone())).unwrap();
+            msg_tx.send((Message::GetBlocks(vec![genesis]).encode(), peer.clone())).unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let reply = loop {
+                if let Ok(reply) = replies.try_recv() {
+                    break reply;
+                }
+                assert!(Instant::now() < deadline);
+                thread::sleep(Duration::from_millis(10));
+            };
+            match Message::decode(&reply).unwrap().unwrap() {
+                Message::Blocks(blocks) => assert_eq!(blocks[0].hash(), genesis),
+                _ => panic!("expected the genesis block"),
+            }
+        }
+
+        drop(msg_tx);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn dispatches_by_priority() {
+        let addr = "127.0.0.1:6000".parse().unwrap();
+        let (peer, _replies) = peer::Handle::with_queue(addr, &Shim::default());
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let (block_sink, block_chan) = channel::unbounded();
+        // room for a single low priority message
+        let (tx_sink, tx_chan) = channel::bounded(2);
+        for msg in [
+            Message::NewTransactionHashes(vec![]),
+            Message::NewTransactionHashes(vec![]),
+            Message::GetBlocks(vec![]),
+            Message::Transactions(vec![]),
+        ] {
+            msg_tx.send((msg.encode(), peer.clone())).unwrap();
+        }
+        drop(msg_tx);
+        dispatch(msg_rx, block_sink, tx_sink);
+
+        let kinds = |chan: channel::Receiver<(Vec<u8>, peer::Handle)>| -> Vec<&'static str> {
+            chan.iter().map(|(msg, _)| Message::decode(&msg).unwrap().unwrap().kind()).collect()
+        };
+        assert_eq!(kinds(block_chan), vec!["GetBlocks"]);
+        assert_eq!(kinds(tx_chan), vec!["NewTransactionHashes", "Transactions"]);
+        assert_eq!(peer.stats().info(addr, peer::Direction::Incoming, 0, Features::NONE, None, 0).dropped, 1);
+    }
+
+    #[test]
+    fn relays_blocks_after_the_header_checks() {
+        let events = Arc::new(EventBus::default());
+        let network = MemoryNetwork::default();
+        let addr = "127.0.0.1:6000".parse().unwrap();
+        let observer_addr = "127.0.0.1:6001".parse().unwrap();
+        let server = network.start_server(addr, channel::unbounded().0, &events);
+        let (observer_tx, observer_rx) = channel::unbounded();
+        let _observer = network.start_server(observer_addr, observer_tx, &events);
+        server.connect(observer_addr).unwrap();
+        let blockchain = Arc::new(Blockchain::new());
+        let orphan_blocks = Arc::new(Mutex::new(OrphanPool::default()));
+        let (msg_tx, msg_rx) = channel::unbounded();
+        let workers = new(
+            1,
+            msg_rx,
+            &server,
+            &blockchain,
+            &orphan_blocks,
+            &Arc::new(Mutex::new(Mempool::default())),
+            &events,
+            &Arc::new(Mutex::new(0)),
+            &Arc::new(Mutex::new(0)),
+        ).start();
+
+        // none of the blocks applies to a state, but the header of a child of the genesis that
+        // solves the difficulty of its parent is enough to relay it
+        let genesis = blockchain.get_header(&blockchain.tip()).unwrap();
+        let mut solved = generate_random_block(&blockchain.tip());
+        solved.header.difficulty = genesis.difficulty;
+        solved.header.gas_limit = genesis.gas_limit;
+        while !solved.header.meets_difficulty(Some(&genesis)) {
+            solved.header.nonce = solved.header.nonce.wrapping_add(1);
+        }
+        // declaring its own, trivial, difficulty does not help a child of the genesis
+        let mut inflated = generate_random_block(&blockchain.tip());
+        inflated.header.difficulty = H256::from([0xff; 32]);
+        // nor a block whose parent is unknown, which is not relayed until committed
+        let mut orphan = generate_random_block(&H256::from([1; 32]));
+        orphan.header.difficulty = H256::from([0xff; 32]);
+        let (peer, _replies) = peer::Handle::with_queue(addr, &Shim::default());
+        msg_tx.send((Message::Blocks(vec![inflated.clone(), orphan.clone(), solved.clone()]).encode(), peer)).unwrap();
+
+        // only the solved block is announced
+        let announced = |timeout| {
+            while let Ok((msg, _)) = observer_rx.recv_timeout(timeout) {
+                if let Message::NewBlockHashes(hashes) = Message::decode(&msg).unwrap().unwrap() {
+                    return Some(hashes);
+                }
+            }
+            None
+        };
+        assert_eq!(announced(Duration::from_secs(5)), Some(vec![solved.hash()]));
+        assert_eq!(announced(Duration::from_millis(300)), None);
+
+        // the orphan is not served either, unlike the blocks of the chain
+        let (asking, mut replies) = peer::Handle::with_queue(addr, &Shim::default());
+        msg_tx.send((Message::GetBlocks(vec![orphan.hash(), blockchain.tip()]).encode(), asking)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let reply = loop {
+            if let Ok(reply) = replies.try_recv() {
+                break reply;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        };
+        match Message::decode(&reply).unwrap().unwrap() {
+            Message::Blocks(blocks) => assert_eq!(blocks[0].hash(), blockchain.tip()),
+            _ => panic!("expected blocks"),
+        }
+        assert!(replies.try_recv().is_err());
+        // the blocks that fail against their parent are dropped, not verified again and again
+        let orphans = orphan_blocks.lock().unwrap();
+        assert!(!orphans.contains_key(&inflated.hash()) && !orphans.contains_key(&solved.hash()));
+        assert!(orphans.contains_key(&orphan.hash()));
+        drop(orphans);
+
+        drop(msg_tx);
+        for worker in workers {
+            worker.join().unwrap();
         }
     }
 }
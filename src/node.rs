@@ -0,0 +1,200 @@
+//! A full node composed from its parts: the blockchain, the mempools, the workers behind a P2P
+//! server, the miner and the transaction generator of an identity.
+
+use crate::blockchain::Blockchain;
+use crate::events::EventBus;
+use crate::fee::{self, FeeEstimator};
+use crate::genesis::GenesisConfig;
+use crate::mempool::{Mempool, TX_MEMPOOL_CAPACITY};
+use crate::miner::{self, Identity, Strategy};
+use crate::network::peer;
+use crate::network::{server, worker};
+use crate::orphan::OrphanPool;
+use crate::txgenerator;
+use crossbeam::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Number of block and of transaction worker threads, by default.
+pub static NODE_WORKERS: usize = 4;
+
+/// The messages the P2P server hands to the workers.
+pub type MsgSource = channel::Receiver<(Vec<u8>, peer::Handle)>;
+
+/// Collects the parts of a node, defaulting the missing ones, and starts it.
+pub struct Builder {
+    events: Arc<EventBus>,
+    server: server::Handle,
+    msg_rx: MsgSource,
+    identity: Arc<Identity>,
+    blockchain: Option<Arc<Blockchain>>,
+    tx_mempool: Option<Mempool>,
+    orphan_blocks: Option<OrphanPool>,
+    workers: usize,
+    fast_sync: bool,
+    miner_threads: usize,
+    strategy: Strategy,
+    tx_batch: usize,
+    accounts: Vec<Arc<Identity>>,
+}
+
+impl Builder {
+    /// A node of `identity` behind `server`, which publishes on `events` and delivers its
+    /// messages to `msg_rx`.
+    pub fn new(events: &Arc<EventBus>, server: &server::Handle, msg_rx: MsgSource, identity: &Arc<Identity>) -> Self {
+        Builder {
+            events: Arc::clone(events),
+            server: server.clone(),
+            msg_rx,
+            identity: Arc::clone(identity),
+            blockchain: None,
+            tx_mempool: None,
+            orphan_blocks: None,
+            workers: NODE_WORKERS,
+            fast_sync: false,
+            miner_threads: 1,
+            strategy: Strategy::Honest,
+            tx_batch: 1,
+            accounts: Vec::new(),
+        }
+    }
+
+    /// Defaults to the chain of the default genesis.
+    pub fn blockchain(mut self, blockchain: &Arc<Blockchain>) -> Self {
+        self.blockchain = Some(Arc::clone(blockchain));
+        self
+    }
+
+    /// Defaults to an empty mempool of `TX_MEMPOOL_CAPACITY` transactions.
+    pub fn tx_mempool(mut self, tx_mempool: Mempool) -> Self {
+        self.tx_mempool = Some(tx_mempool);
+        self
+    }
+
+    pub fn orphan_blocks(mut self, orphan_blocks: OrphanPool) -> Self {
+        self.orphan_blocks = Some(orphan_blocks);
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    pub fn fast_sync(mut self, fast_sync: bool) -> Self {
+        self.fast_sync = fast_sync;
+        self
+    }
+
+    pub fn miner_threads(mut self, miner_threads: usize) -> Self {
+        self.miner_threads = miner_threads;
+        self
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Recipients paid in each generated transaction.
+    pub fn tx_batch(mut self, tx_batch: usize) -> Self {
+        self.tx_batch = tx_batch;
+        self
+    }
+
+    /// Also generate transactions from `account`, on top of the identity of the node.
+    pub fn account(mut self, account: Arc<Identity>) -> Self {
+        self.accounts.push(account);
+        self
+    }
+
+    /// Start the workers, and the miner and the generator, paused.
+    pub fn start(self) -> Node {
+        let events = self.events;
+        let blockchain = self.blockchain.unwrap_or_else(|| {
+            let (genesis_block, genesis_state) = GenesisConfig::default().build().unwrap();
+            Arc::new(Blockchain::from_genesis(genesis_block, genesis_state, &events))
+        });
+        let tx_mempool = self.tx_mempool.unwrap_or_else(|| Mempool::new(TX_MEMPOOL_CAPACITY, &events));
+        let tx_mempool = Arc::new(Mutex::new(tx_mempool));
+        let orphan_blocks = Arc::new(Mutex::new(self.orphan_blocks.unwrap_or_default()));
+        let fee_estimator = Arc::new(FeeEstimator::default());
+        fee::start(&events, &blockchain, &fee_estimator);
+
+        let mut worker_ctx = worker::new(
+            self.workers,
+            self.msg_rx,
+            &self.server,
+            &blockchain,
+            &orphan_blocks,
+            &tx_mempool,
+            &events,
+            &Arc::new(Mutex::new(0)),
+            &Arc::new(Mutex::new(0)),
+        );
+        worker_ctx.set_fast_sync(self.fast_sync);
+        let workers = worker_ctx.start();
+
+        let (miner_ctx, miner) = miner::new(
+            &self.server,
+            &blockchain,
+            &tx_mempool,
+            &events,
+            &self.identity,
+            self.miner_threads,
+            self.strategy,
+        );
+        miner_ctx.start();
+
+        let (mut generator_ctx, generator) = txgenerator::new(&self.server, &blockchain, &tx_mempool, &events, &self.identity);
+        generator_ctx.set_batch_size(self.tx_batch);
+        for account in self.accounts {
+            generator_ctx.add_account(account);
+        }
+        generator_ctx.set_fee_estimator(&fee_estimator);
+        generator_ctx.start();
+
+        Node {
+            identity: self.identity,
+            events,
+            server: self.server,
+            blockchain,
+            tx_mempool,
+            orphan_blocks,
+            fee_estimator,
+            miner,
+            generator,
+            workers,
+        }
+    }
+}
+
+pub struct Node {
+    pub identity: Arc<Identity>,
+    pub events: Arc<EventBus>,
+    pub server: server::Handle,
+    pub blockchain: Arc<Blockchain>,
+    pub tx_mempool: Arc<Mutex<Mempool>>,
+    pub orphan_blocks: Arc<Mutex<OrphanPool>>,
+    pub fee_estimator: Arc<FeeEstimator>,
+    pub miner: miner::Handle,
+    pub generator: txgenerator::Handle,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl Node {
+    /// Stop producing blocks and transactions, then disconnect the peers. The workers exit once
+    /// they have drained their queue, see `join`.
+    pub fn stop(&self) {
+        self.miner.exit();
+        self.generator.exit();
+        self.server.shutdown();
+    }
+
+    /// Wait for the workers to exit, after `stop`.
+    pub fn join(self) {
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use log::debug;
+use std::sync::Mutex;
+
+/// Number of notifications a subscriber can lag behind before it starts missing some.
+pub static SUBSCRIBER_QUEUE: usize = 1024;
+
+/// The subscribers to a stream of notifications. Each subscriber gets its own bounded queue, so
+/// a subscriber that falls behind misses notifications instead of stalling the notifier. A
+/// subscriber is forgotten once its receiver is dropped.
+pub struct Subscribers<T> {
+    senders: Mutex<Vec<Sender<T>>>,
+}
+
+impl<T: Clone> Default for Subscribers<T> {
+    fn default() -> Self {
+        Subscribers {
+            senders: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<T: Clone> Subscribers<T> {
+    pub fn subscribe(&self) -> Receiver<T> {
+        let (sender, receiver) = bounded(SUBSCRIBER_QUEUE);
+        self.senders.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn notify(&self, event: T) {
+        let mut senders = self.senders.lock().unwrap();
+        senders.retain(|sender| match sender.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("Dropped a notification for a lagging subscriber");
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lagging_and_dropped_subscribers() {
+        let subscribers = Subscribers::default();
+        let lagging = subscribers.subscribe();
+        let dropped = subscribers.subscribe();
+        drop(dropped);
+        for i in 0..SUBSCRIBER_QUEUE + 1 {
+            subscribers.notify(i);
+        }
+        assert_eq!(subscribers.senders.lock().unwrap().len(), 1);
+        assert_eq!(lagging.try_iter().count(), SUBSCRIBER_QUEUE);
+        subscribers.notify(0);
+        assert_eq!(lagging.try_recv(), Ok(0));
+    }
+}
@@ -0,0 +1,270 @@
+use crate::block::Block;
+use crate::crypto::hash::H256;
+use log::debug;
+use std::cmp::Reverse;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+pub static ORPHAN_POOL_CAPACITY: usize = 1024;
+pub static ORPHAN_POOL_TTL_SECS: u64 = 600;
+pub static ORPHAN_POOL_MEMORY_BUDGET: usize = 16 * 1024 * 1024;
+
+struct OrphanEntry {
+    block: Block,
+    // the peer that sent the block, penalized if it turns out invalid
+    sender: SocketAddr,
+    inserted: Instant,
+    size: usize,
+}
+
+/// A bounded pool of blocks whose parent is not in the blockchain yet.
+///
+/// Orphans expire after `ttl`. When the pool holds more than `capacity` blocks or more than
+/// `memory_budget` serialized bytes, the deepest orphans are evicted first, the oldest among
+/// those of equal depth. The depth of an orphan is the number of its ancestors in the pool, so the
+/// orphans closest to connecting to the chain are kept.
+pub struct OrphanPool {
+    blocks: HashMap<H256, OrphanEntry>,
+    // insertion order, may contain hashes that were removed already
+    order: VecDeque<(Instant, H256)>,
+    memory_used: usize,
+    capacity: usize,
+    ttl: Duration,
+    memory_budget: usize,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new(ORPHAN_POOL_CAPACITY, Duration::from_secs(ORPHAN_POOL_TTL_SECS), ORPHAN_POOL_MEMORY_BUDGET)
+    }
+}
+
+impl OrphanPool {
+    pub fn new(capacity: usize, ttl: Duration, memory_budget: usize) -> Self {
+        OrphanPool {
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+            memory_used: 0,
+            capacity,
+            ttl,
+            memory_budget,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Serialized bytes of all the orphans currently held.
+    pub fn memory_used(&self) -> usize {
+        self.memory_used
+    }
+
+    pub fn contains_key(&self, hash: &H256) -> bool {
+        self.blocks.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&Block> {
+        self.blocks.get(hash).map(|entry| &entry.block)
+    }
+
+    /// The peer that sent the orphan `hash`.
+    pub fn sender(&self, hash: &H256) -> Option<SocketAddr> {
+        self.blocks.get(hash).map(|entry| entry.sender)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&H256, &Block)> {
+        self.blocks.iter().map(|(hash, entry)| (hash, &entry.block))
+    }
+
+    /// Insert an orphan sent by `sender`, evicting expired and old orphans to stay within the
+    /// limits. Returns false if the block alone exceeds the memory budget.
+    pub fn insert(&mut self, hash: H256, block: Block, sender: SocketAddr) -> bool {
+        self.insert_at(hash, block, sender, Instant::now())
+    }
+
+    fn insert_at(&mut self, hash: H256, block: Block, sender: SocketAddr, now: Instant) -> bool {
+        let size = bincode::serialized_size(&block).unwrap() as usize;
+        if size > self.memory_budget {
+            return false;
+        }
+        self.remove(&hash);
+        self.evict_expired(now);
+        while self.blocks.len() >= self.capacity || self.memory_used + size > self.memory_budget {
+            if !self.evict_deepest() {
+                break;
+            }
+        }
+        self.memory_used += size;
+        self.order.push_back((now, hash));
+        self.blocks.insert(hash, OrphanEntry {
+            block,
+            sender,
+            inserted: now,
+            size,
+        });
+        true
+    }
+
+    pub fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let entry = self.blocks.remove(hash)?;
+        self.memory_used -= entry.size;
+        Some(entry.block)
+    }
+
+    /// Drop every orphan older than the TTL.
+    pub fn evict_expired(&mut self, now: Instant) {
+        while let Some(&(inserted, hash)) = self.order.front() {
+            if now.duration_since(inserted) < self.ttl {
+                break;
+            }
+            self.order.pop_front();
+            if self.is_current(&hash, inserted) {
+                debug!("Orphan block {:?} expired", hash);
+                self.remove(&hash);
+            }
+        }
+    }
+
+    fn evict_deepest(&mut self) -> bool {
+        // drop the stale front of the order queue, the live entries stay for the TTL
+        while let Some(&(inserted, hash)) = self.order.front() {
+            if self.is_current(&hash, inserted) {
+                break;
+            }
+            self.order.pop_front();
+        }
+        let mut depths = HashMap::new();
+        let hashes: Vec<H256> = self.blocks.keys().cloned().collect();
+        let victim = hashes
+            .into_iter()
+            .map(|hash| (self.depth(&hash, &mut depths), Reverse(self.blocks[&hash].inserted), hash))
+            .max();
+        match victim {
+            Some((depth, _, hash)) => {
+                debug!("Orphan pool full, evicting {:?} at depth {}", hash, depth);
+                self.remove(&hash);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // number of ancestors of the orphan `hash` held in the pool, memoized in `depths`
+    fn depth(&self, hash: &H256, depths: &mut HashMap<H256, usize>) -> usize {
+        let mut chain = vec![];
+        let mut current = *hash;
+        let mut depth = loop {
+            if let Some(&depth) = depths.get(&current) {
+                break depth + 1;
+            }
+            match self.blocks.get(&current) {
+                Some(entry) => {
+                    chain.push(current);
+                    current = entry.block.header.parent;
+                }
+                None => break 0,
+            }
+        };
+        for ancestor in chain.into_iter().rev() {
+            depths.insert(ancestor, depth);
+            depth += 1;
+        }
+        depths[hash]
+    }
+
+    // whether an entry of the order queue still refers to a live orphan
+    fn is_current(&self, hash: &H256, inserted: Instant) -> bool {
+        match self.blocks.get(hash) {
+            Some(entry) => entry.inserted == inserted,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+    use crate::crypto::hash::Hashable;
+    use crate::crypto::hash::tests::generate_random_hash;
+
+    fn sender() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 6000))
+    }
+
+    #[test]
+    fn capacity_evicts_oldest() {
+        let mut pool = OrphanPool::new(3, Duration::from_secs(60), usize::MAX);
+        let blocks: Vec<Block> = (0..5).map(|_| generate_random_block(&generate_random_hash())).collect();
+        for block in blocks.iter() {
+            assert!(pool.insert(block.hash(), block.clone(), sender()));
+        }
+        assert_eq!(pool.len(), 3);
+        assert!(!pool.contains_key(&blocks[0].hash()));
+        assert!(!pool.contains_key(&blocks[1].hash()));
+        assert!(pool.contains_key(&blocks[4].hash()));
+    }
+
+    #[test]
+    fn capacity_evicts_deepest() {
+        let mut pool = OrphanPool::new(3, Duration::from_secs(60), usize::MAX);
+        let start = Instant::now();
+        let root = generate_random_block(&generate_random_hash());
+        let child = generate_random_block(&root.hash());
+        let grandchild = generate_random_block(&child.hash());
+        let unrelated = generate_random_block(&generate_random_hash());
+        // the grandchild arrives first, its depth grows as its ancestors arrive
+        pool.insert_at(grandchild.hash(), grandchild.clone(), sender(), start);
+        pool.insert_at(root.hash(), root.clone(), sender(), start + Duration::from_secs(1));
+        pool.insert_at(child.hash(), child.clone(), sender(), start + Duration::from_secs(2));
+        pool.insert_at(unrelated.hash(), unrelated.clone(), sender(), start + Duration::from_secs(3));
+        assert!(!pool.contains_key(&grandchild.hash()));
+        assert!(pool.contains_key(&root.hash()));
+        assert!(pool.contains_key(&child.hash()));
+        let other = generate_random_block(&generate_random_hash());
+        pool.insert_at(other.hash(), other.clone(), sender(), start + Duration::from_secs(4));
+        assert!(!pool.contains_key(&child.hash()));
+        assert!(pool.contains_key(&root.hash()));
+        // among orphans of equal depth, the oldest goes first
+        pool.insert_at(child.hash(), child.clone(), sender(), start + Duration::from_secs(5));
+        assert!(!pool.contains_key(&root.hash()));
+        assert!(pool.contains_key(&unrelated.hash()));
+        assert!(pool.contains_key(&other.hash()));
+        assert!(pool.contains_key(&child.hash()));
+    }
+
+    #[test]
+    fn ttl_expires_orphans() {
+        let mut pool = OrphanPool::new(10, Duration::from_secs(60), usize::MAX);
+        let start = Instant::now();
+        let old = generate_random_block(&generate_random_hash());
+        let new = generate_random_block(&generate_random_hash());
+        pool.insert_at(old.hash(), old.clone(), sender(), start);
+        pool.insert_at(new.hash(), new.clone(), sender(), start + Duration::from_secs(30));
+        pool.evict_expired(start + Duration::from_secs(61));
+        assert!(!pool.contains_key(&old.hash()));
+        assert!(pool.contains_key(&new.hash()));
+    }
+
+    #[test]
+    fn memory_budget() {
+        let block = generate_random_block(&generate_random_hash());
+        let size = bincode::serialized_size(&block).unwrap() as usize;
+        let mut pool = OrphanPool::new(10, Duration::from_secs(60), 2 * size);
+        for _ in 0..4 {
+            let block = generate_random_block(&generate_random_hash());
+            pool.insert(block.hash(), block, sender());
+        }
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.memory_used(), 2 * size);
+        let mut tiny_pool = OrphanPool::new(10, Duration::from_secs(60), size - 1);
+        assert!(!tiny_pool.insert(block.hash(), block, sender()));
+        assert!(tiny_pool.is_empty());
+    }
+}
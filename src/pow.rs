@@ -0,0 +1,189 @@
+use crate::block::Header;
+use crate::crypto::hash::Hashable;
+use crate::clock;
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use log::{debug, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time;
+
+/// Number of nonces a mining thread tries between two checks for cancellation.
+static NONCE_BATCH: u32 = 1000;
+
+struct Job {
+    header: Header,
+    generation: u64,
+    /// The thread searches the extra nonces `header.extra_nonce + k * stride`, each with the
+    /// whole 32-bit nonce space.
+    stride: u64,
+    /// Pause between two batches of nonces, so the lambda of the miner keeps controlling the rate.
+    throttle: time::Duration,
+}
+
+/// A header whose hash is below its difficulty.
+pub struct Solution {
+    pub header: Header,
+    pub generation: u64,
+}
+
+/// A pool of mining threads. The search space of each submitted header template is split by
+/// extra nonce, thread `i` of `n` taking the extra nonces equal to `i` modulo `n`; the first
+/// thread that finds a solution reports it on the solution channel. Submitting a new template
+/// or calling `cancel` abandons the current one.
+pub struct Engine {
+    job_chans: Vec<Sender<Job>>,
+    solution_chan: Receiver<Solution>,
+    generation: Arc<AtomicU64>,
+}
+
+impl Engine {
+    pub fn new(num_threads: usize) -> Self {
+        let (solution_sender, solution_receiver) = unbounded();
+        let generation = Arc::new(AtomicU64::new(0));
+        let mut job_chans = Vec::new();
+        for i in 0..num_threads.max(1) {
+            let (job_sender, job_receiver) = unbounded();
+            let solution_sender = solution_sender.clone();
+            let generation = Arc::clone(&generation);
+            thread::Builder::new()
+                .name(format!("pow-{}", i))
+                .spawn(move || {
+                    mining_thread(job_receiver, solution_sender, generation);
+                })
+                .unwrap();
+            job_chans.push(job_sender);
+        }
+        info!("PoW engine started with {} threads", job_chans.len());
+        Engine {
+            job_chans,
+            solution_chan: solution_receiver,
+            generation,
+        }
+    }
+
+    /// Start mining a new header template, abandoning the current one. Returns the generation of
+    /// the template, which the matching solutions carry.
+    pub fn submit(&self, header: Header, throttle: time::Duration) -> u64 {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let stride = self.job_chans.len() as u64;
+        for (i, chan) in self.job_chans.iter().enumerate() {
+            let mut header = header;
+            header.nonce = 0;
+            header.extra_nonce = header.extra_nonce.wrapping_add(i as u64);
+            chan.send(Job {
+                header,
+                generation,
+                stride,
+                throttle,
+            }).unwrap();
+        }
+        generation
+    }
+
+    /// Abandon the current template.
+    pub fn cancel(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// The generation of the template being mined.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    pub fn solutions(&self) -> &Receiver<Solution> {
+        &self.solution_chan
+    }
+}
+
+/// Move to the next point of the search space: the next nonce, or the first nonce of the next
+/// extra nonce once the nonces are exhausted.
+fn advance(header: &mut Header, stride: u64) {
+    if header.nonce == u32::MAX {
+        header.nonce = 0;
+        header.extra_nonce = header.extra_nonce.wrapping_add(stride);
+    } else {
+        header.nonce += 1;
+    }
+}
+
+fn mining_thread(jobs: Receiver<Job>, solutions: Sender<Solution>, generation: Arc<AtomicU64>) {
+    // the engine is dropped once the job channel is disconnected
+    while let Ok(job) = jobs.recv() {
+        let mut header = job.header;
+        'batches: loop {
+            if generation.load(Ordering::SeqCst) != job.generation || !jobs.is_empty() {
+                break;
+            }
+            for _ in 0..NONCE_BATCH {
+                if header.hash() < header.difficulty {
+                    debug!("Found nonce {} extra nonce {} for generation {}", header.nonce, header.extra_nonce, job.generation);
+                    if solutions.send(Solution { header, generation: job.generation }).is_err() {
+                        return;
+                    }
+                    break 'batches;
+                }
+                advance(&mut header, job.stride);
+            }
+            if job.throttle > time::Duration::from_micros(0) {
+                clock::sleep(job.throttle);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+
+    fn easy_header() -> Header {
+        let mut difficulty = [0u8; 32];
+        difficulty[0] = 0x10;
+        Header {
+            difficulty: H256::from(difficulty),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn finds_solution() {
+        let engine = Engine::new(4);
+        let generation = engine.submit(easy_header(), time::Duration::from_micros(0));
+        let solution = engine.solutions().recv_timeout(time::Duration::from_secs(10)).unwrap();
+        assert_eq!(solution.generation, generation);
+        assert!(solution.header.hash() < solution.header.difficulty);
+    }
+
+    #[test]
+    fn nonce_rolls_into_extra_nonce() {
+        let mut header = easy_header();
+        header.nonce = u32::MAX - 1;
+        header.extra_nonce = 2;
+        advance(&mut header, 4);
+        assert_eq!((header.nonce, header.extra_nonce), (u32::MAX, 2));
+        advance(&mut header, 4);
+        assert_eq!((header.nonce, header.extra_nonce), (0, 6));
+    }
+
+    #[test]
+    fn threads_search_disjoint_extra_nonces() {
+        let engine = Engine::new(3);
+        engine.submit(easy_header(), time::Duration::from_micros(0));
+        let solution = engine.solutions().recv_timeout(time::Duration::from_secs(10)).unwrap();
+        assert!(solution.header.extra_nonce < 3);
+    }
+
+    #[test]
+    fn cancel_stops_mining() {
+        let engine = Engine::new(2);
+        let mut header = easy_header();
+        // an impossible target
+        header.difficulty = Default::default();
+        engine.submit(header, time::Duration::from_micros(0));
+        engine.cancel();
+        let generation = engine.submit(easy_header(), time::Duration::from_micros(0));
+        let solution = engine.solutions().recv_timeout(time::Duration::from_secs(10)).unwrap();
+        assert_eq!(solution.generation, generation);
+    }
+}
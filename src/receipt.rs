@@ -0,0 +1,106 @@
+//! Receipts of the transactions of a block: the outcome of every transaction, computed when the
+//! block is inserted by replaying it on the state of its parent, so that clients can confirm
+//! what a transaction did instead of inferring it from balances.
+
+use crate::block::{Block, State};
+use crate::crypto::address::H160;
+use crate::crypto::hash::{H256, Hashable};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceiptStatus {
+    Success,
+    /// The transaction was not valid on top of the transactions before it, and left the state
+    /// unchanged.
+    Failure,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub txid: H256,
+    pub block_hash: H256,
+    pub position: usize,
+    pub status: ReceiptStatus,
+    pub gas_used: u64,
+    /// Gas used by the transactions of the block up to this one, included.
+    pub cumulative_gas_used: u64,
+    /// Balances after the transaction of the sender, then of every recipient.
+    pub balances: Vec<(H160, u64)>,
+}
+
+/// Execute the transactions of `block` on `parent_state`, the state after its parent, and
+/// return their receipts in block order.
+pub fn execute(block: &Block, parent_state: &State) -> Vec<Receipt> {
+    let block_hash = block.hash();
+    let mut state = parent_state.clone();
+    let mut cumulative_gas_used = 0u64;
+    block.content.transactions.iter().enumerate().map(|(position, tx)| {
+        let status = if tx.update_state(&mut state) {
+            ReceiptStatus::Success
+        } else {
+            ReceiptStatus::Failure
+        };
+        let gas_used = tx.gas();
+        cumulative_gas_used = cumulative_gas_used.saturating_add(gas_used);
+        let mut addresses = vec![tx.sender()];
+        for (recipient, _) in tx.transaction.outputs.iter() {
+            if !addresses.contains(recipient) {
+                addresses.push(*recipient);
+            }
+        }
+        Receipt {
+            txid: tx.hash(),
+            block_hash,
+            position,
+            status,
+            gas_used,
+            cumulative_gas_used,
+            balances: addresses.into_iter().map(|address| (address, state.balance(&address))).collect(),
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Content;
+    use crate::crypto::key_pair;
+    use crate::genesis::GenesisConfig;
+    use crate::transaction::{sign, SignatureScheme, SignedTransaction, Transaction};
+    use ring::signature::KeyPair;
+
+    fn transfer(key: &ring::signature::Ed25519KeyPair, recipient: H160, value: u64, nonce: u64) -> SignedTransaction {
+        let transaction = Transaction {
+            outputs: vec![(recipient, value)],
+            account_nonce: nonce,
+            ..Default::default()
+        };
+        SignedTransaction {
+            signature: sign(&transaction, key).as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            transaction,
+            scheme: SignatureScheme::Ed25519,
+            multisig: None,
+            sender_cache: Default::default(),
+        }
+    }
+
+    #[test]
+    fn receipts_follow_block_order() {
+        let (genesis, state) = GenesisConfig::default().build().unwrap();
+        let key = key_pair::frombyte(0);
+        let recipient = H160::default();
+        let txs = vec![transfer(&key, recipient, 10, 1), transfer(&key, recipient, 20, 2)];
+        let block = Block { header: genesis.header, content: Content::new(txs.clone()) };
+
+        let receipts = execute(&block, &state);
+        assert_eq!(receipts.len(), 2);
+        assert_eq!(receipts[0].txid, txs[0].hash());
+        assert_eq!(receipts[0].status, ReceiptStatus::Success);
+        assert_eq!(receipts[0].balances, vec![(txs[0].sender(), 15), (recipient, 10)]);
+        // the second transfer exceeds what is left
+        assert_eq!(receipts[1].position, 1);
+        assert_eq!(receipts[1].status, ReceiptStatus::Failure);
+        assert_eq!(receipts[1].balances, vec![(txs[0].sender(), 15), (recipient, 10)]);
+        assert_eq!(receipts[1].cumulative_gas_used, txs[0].gas() + txs[1].gas());
+    }
+}
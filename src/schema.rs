@@ -0,0 +1,118 @@
+//! Versions of the files a node keeps across restarts. Every file starts with `SCHEMA_MAGIC` and
+//! the schema version of its store, and is migrated to the current version as it is read, so a
+//! change to the serialization of `Block`, `State` or `SignedTransaction` comes with a
+//! migration instead of silently misreading the files of an older node. A file newer than the
+//! node is refused.
+
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Marks a versioned file. The files saved before versioning do not start with it, and are read
+/// as version 0.
+pub static SCHEMA_MAGIC: &[u8; 4] = b"PRSM";
+
+/// Upgrades the contents of a file by one version.
+pub type Migration = fn(Vec<u8>) -> Result<Vec<u8>>;
+
+/// A kind of file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Store {
+    /// The transactions of the mempool, see `mempool::save`.
+    Mempool,
+    /// The latest checkpoint, see `snapshot::save`.
+    Snapshot,
+    /// The blocks of a block file, see `archive::export_chain`.
+    Chain,
+}
+
+impl Store {
+    /// The migrations from every version to the next one, the `i`th upgrading from version `i`.
+    /// The current version is the number of migrations.
+    pub fn migrations(&self) -> &'static [Migration] {
+        match self {
+            // version 1 only added the header
+            Store::Mempool => &[unchanged],
+            Store::Snapshot => &[unchanged],
+            Store::Chain => &[],
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.migrations().len() as u32
+    }
+}
+
+fn unchanged(bytes: Vec<u8>) -> Result<Vec<u8>> {
+    Ok(bytes)
+}
+
+/// Prefix `bytes` with the header of the current version of `store`.
+pub fn encode(store: Store, bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(SCHEMA_MAGIC.len() + 4 + bytes.len());
+    encoded.extend_from_slice(SCHEMA_MAGIC);
+    encoded.extend_from_slice(&store.version().to_be_bytes());
+    encoded.extend_from_slice(bytes);
+    encoded
+}
+
+/// The contents of a file of `store`, migrated to the current version.
+pub fn decode(store: Store, bytes: Vec<u8>) -> Result<Vec<u8>> {
+    let (version, mut bytes) = match bytes.strip_prefix(SCHEMA_MAGIC) {
+        Some(rest) if rest.len() >= 4 => {
+            let version = u32::from_be_bytes(rest[..4].try_into().unwrap());
+            (version, rest[4..].to_vec())
+        }
+        Some(_) => return Err(Error::new(ErrorKind::InvalidData, "truncated schema header")),
+        None => (0, bytes),
+    };
+    let migrations = store.migrations();
+    if version as usize > migrations.len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} schema version {} is newer than the supported version {}", store, version, store.version()),
+        ));
+    }
+    for migration in migrations[version as usize..].iter() {
+        bytes = migration(bytes)?;
+    }
+    Ok(bytes)
+}
+
+/// Write `bytes` to `path` with the header of `store`. The file is replaced atomically, so a
+/// crash while saving leaves the previous file intact.
+pub fn save(path: &Path, store: Store, bytes: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, encode(store, bytes))?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// Read the file of `store` at `path`, migrated to the current version, `None` if there is no
+/// file.
+pub fn load(path: &Path, store: Store) -> Result<Option<Vec<u8>>> {
+    match std::fs::read(path) {
+        Ok(bytes) => decode(store, bytes).map(Some),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_older_versions() {
+        let payload = b"payload".to_vec();
+        let encoded = encode(Store::Snapshot, &payload);
+        assert!(encoded.starts_with(SCHEMA_MAGIC));
+        assert_eq!(decode(Store::Snapshot, encoded).unwrap(), payload);
+        // saved before versioning
+        assert_eq!(decode(Store::Snapshot, payload.clone()).unwrap(), payload);
+
+        let mut newer = SCHEMA_MAGIC.to_vec();
+        newer.extend_from_slice(&(Store::Snapshot.version() + 1).to_be_bytes());
+        assert_eq!(decode(Store::Snapshot, newer).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert!(decode(Store::Mempool, SCHEMA_MAGIC.to_vec()).is_err());
+    }
+}
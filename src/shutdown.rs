@@ -0,0 +1,78 @@
+use log::{error, info};
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+type Step = Box<dyn FnOnce() + Send>;
+
+/// Coordinates the shutdown of the node. The modules register a step as they start, and the
+/// steps run once, in the order of their registration, when SIGINT or SIGTERM is caught. A step
+/// that panics is logged and the following steps still run.
+#[derive(Default)]
+pub struct Shutdown {
+    requested: AtomicBool,
+    steps: Mutex<Vec<(&'static str, Step)>>,
+}
+
+impl Shutdown {
+    pub fn on_shutdown<F: FnOnce() + Send + 'static>(&self, name: &'static str, step: F) {
+        self.steps.lock().unwrap().push((name, Box::new(step)));
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Run the registered steps. Only the first call does anything.
+    pub fn run(&self) {
+        if self.requested.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let steps = std::mem::take(&mut *self.steps.lock().unwrap());
+        for (name, step) in steps {
+            info!("Shutting down {}", name);
+            if panic::catch_unwind(AssertUnwindSafe(step)).is_err() {
+                error!("Error shutting down {}", name);
+            }
+        }
+        info!("Shutdown complete");
+    }
+}
+
+/// Run the shutdown steps and exit on SIGINT or SIGTERM.
+pub fn install(shutdown: &Arc<Shutdown>) {
+    let shutdown = Arc::clone(shutdown);
+    ctrlc::set_handler(move || {
+        shutdown.run();
+        process::exit(0);
+    }).unwrap_or_else(|e| {
+        error!("Error setting the shutdown handler: {}", e);
+        process::exit(1);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steps_run_once_in_order() {
+        let shutdown = Shutdown::default();
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in ["miner", "network", "mempool"].iter() {
+            let log = Arc::clone(&log);
+            shutdown.on_shutdown(name, move || {
+                if *name == "network" {
+                    panic!("already gone");
+                }
+                log.lock().unwrap().push(*name);
+            });
+        }
+        assert!(!shutdown.is_requested());
+        shutdown.run();
+        shutdown.run();
+        assert!(shutdown.is_requested());
+        assert_eq!(*log.lock().unwrap(), vec!["miner", "mempool"]);
+    }
+}
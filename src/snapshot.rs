@@ -0,0 +1,123 @@
+//! Checkpoints of the longest chain: the state after a block, every `CHECKPOINT_INTERVAL` blocks,
+//! along with the headers from the genesis to that block. A node imports a checkpoint, saved by
+//! `save` or served by a peer, instead of replaying all the blocks before it.
+
+use crate::block::{Block, Header, State};
+use crate::crypto::hash::{H256, Hashable};
+use crate::schema::{self, Store};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+/// Height interval of the checkpoints.
+pub static CHECKPOINT_INTERVAL: u32 = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Snapshot {
+    /// Headers of the longest chain from the genesis to the checkpoint block, both included.
+    pub headers: Vec<Header>,
+    /// The checkpoint block.
+    pub block: Block,
+    /// The state after the checkpoint block.
+    pub state: State,
+}
+
+impl Snapshot {
+    /// Height of the checkpoint block, the genesis being at height 0.
+    pub fn height(&self) -> u32 {
+        self.headers.len().saturating_sub(1) as u32
+    }
+
+    /// Check that the headers lead from the genesis `genesis` to the checkpoint block with a
+    /// valid proof of work, and that the state is the one committed to by the block. The
+    /// transactions of the blocks before the checkpoint are not checked.
+    pub fn verify(&self, genesis: &H256) -> bool {
+        match self.headers.first() {
+            Some(first) if first.hash() == *genesis => {}
+            _ => {
+                debug!("Snapshot does not start at the genesis {:?}", genesis);
+                return false;
+            }
+        }
+        for pair in self.headers.windows(2) {
+            if pair[1].parent != pair[0].hash() || !pair[1].meets_difficulty(Some(&pair[0])) {
+                debug!("Snapshot header {:?} does not extend its parent", pair[1].hash());
+                return false;
+            }
+        }
+        if self.headers.last().map(|header| header.hash()) != Some(self.block.hash()) || !self.block.is_well_formed() {
+            debug!("Snapshot block {:?} does not match the headers", self.block.hash());
+            return false;
+        }
+        if self.state.root() != self.block.header.state_root {
+            debug!("Snapshot state does not match block {:?}", self.block.hash());
+            return false;
+        }
+        true
+    }
+}
+
+/// Write `snapshot` to `path`. The file is replaced atomically, so a crash while saving leaves
+/// the previous file intact.
+pub fn save(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let bytes = bincode::serialize(snapshot).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    schema::save(path, Store::Snapshot, &bytes)?;
+    info!("Saved the checkpoint at height {} to {}", snapshot.height(), path.display());
+    Ok(())
+}
+
+/// Read the snapshot saved at `path`, `None` if there is no file.
+pub fn load(path: &Path) -> Result<Option<Snapshot>> {
+    let bytes = match schema::load(path, Store::Snapshot)? {
+        Some(bytes) => bytes,
+        None => return Ok(None),
+    };
+    bincode::deserialize(&bytes).map(Some).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::Content;
+    use crate::crypto::merkle::MerkleTree;
+    use crate::genesis::GenesisConfig;
+    use crate::transaction::SignedTransaction;
+
+    /// A checkpoint at height 2 on top of the default genesis, without transactions.
+    fn snapshot() -> Snapshot {
+        let (genesis, state) = GenesisConfig::default().build().unwrap();
+        let mut headers = vec![genesis.header];
+        for _ in 0..2 {
+            let mut header = Header {
+                parent: headers.last().unwrap().hash(),
+                merkle_root: MerkleTree::new(&Vec::<SignedTransaction>::new()).root(),
+                state_root: state.root(),
+                ..genesis.header
+            };
+            while header.hash() > header.difficulty {
+                header.nonce += 1;
+            }
+            headers.push(header);
+        }
+        let block = Block { header: headers[2], content: Content::new(vec![]) };
+        Snapshot { headers, block, state }
+    }
+
+    #[test]
+    fn verifies_headers_and_state() {
+        let snapshot = snapshot();
+        let genesis = snapshot.headers[0].hash();
+        assert_eq!(snapshot.height(), 2);
+        assert!(snapshot.verify(&genesis));
+        assert!(!snapshot.verify(&H256::default()));
+
+        let mut unsolved = snapshot.clone();
+        unsolved.headers[1].nonce += 1;
+        assert!(!unsolved.verify(&genesis));
+
+        let mut wrong_state = snapshot;
+        wrong_state.state.account_state.insert(Default::default(), Default::default());
+        assert!(!wrong_state.verify(&genesis));
+    }
+}
@@ -0,0 +1,354 @@
+use serde::{Serialize, Deserialize};
+use crate::block::Header;
+use crate::crypto::hash::{H256, Hashable};
+use crate::miner::Handle as MinerHandle;
+use crate::template::BlockTemplate;
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// How often the coordinator asks the miner for a fresh template.
+static REFRESH_INTERVAL: time::Duration = time::Duration::from_millis(200);
+/// Number of jobs that can still be submitted after newer work was announced.
+static RECENT_JOBS: usize = 16;
+
+/// A request line from a client. `subscribe` takes no parameters, `submit` takes a `Submit`.
+#[derive(Deserialize, Debug)]
+struct Request {
+    id: u64,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+#[derive(Serialize, Debug)]
+struct Response {
+    id: u64,
+    result: serde_json::Value,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct Notification {
+    method: &'static str,
+    params: Job,
+}
+
+/// Work pushed to the clients. The header is the hex encoded bincode of a `Header` whose extra
+/// nonce carries the prefix of the client in its upper 32 bits; the client searches the nonce and
+/// the lower 32 bits of the extra nonce for a hash below the share target.
+#[derive(Serialize, Debug, Clone)]
+pub struct Job {
+    pub job_id: u64,
+    pub header: String,
+    pub target: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Submit {
+    pub job_id: u64,
+    pub nonce: u32,
+    pub extra_nonce: u64,
+}
+
+/// The outcome of an accepted submission.
+#[derive(Debug)]
+pub enum Share {
+    /// Meets the share target only, counted as proof of work done by the client.
+    Share,
+    /// Meets the block difficulty, the header is handed to the miner.
+    Block(Header),
+}
+
+/// The jobs derived from the block templates, and the checks of the submitted solutions.
+pub struct Jobs {
+    /// Target of a share, `None` meaning the block difficulty.
+    share_target: Option<H256>,
+    next_job_id: u64,
+    current: Option<BlockTemplate>,
+    // template headers of the recent jobs, most recent last
+    recent: VecDeque<(u64, Header)>,
+}
+
+impl Jobs {
+    pub fn new(share_target: Option<H256>) -> Self {
+        Jobs {
+            share_target,
+            next_job_id: 0,
+            current: None,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Take a fresh template. Returns the id of the new job when the work changed.
+    pub fn refresh(&mut self, template: Option<BlockTemplate>) -> Option<u64> {
+        let template = template?;
+        if let Some(current) = &self.current {
+            if current.same_work(&template) {
+                return None;
+            }
+        }
+        self.next_job_id += 1;
+        if self.recent.len() == RECENT_JOBS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((self.next_job_id, template.header()));
+        self.current = Some(template);
+        Some(self.next_job_id)
+    }
+
+    fn share_target(&self, header: &Header) -> H256 {
+        // a share target harder than the block difficulty would hide blocks
+        match self.share_target {
+            Some(target) if target > header.difficulty => target,
+            _ => header.difficulty,
+        }
+    }
+
+    /// The latest job, as seen by the client with the given extra nonce prefix.
+    pub fn current_job(&self, prefix: u32) -> Option<Job> {
+        let (job_id, header) = self.recent.back()?;
+        let mut header = *header;
+        header.extra_nonce = (prefix as u64) << 32;
+        Some(Job {
+            job_id: *job_id,
+            header: hex::encode(bincode::serialize(&header).unwrap()),
+            target: self.share_target(&header).to_string(),
+        })
+    }
+
+    /// Check a solution submitted by the client with the given extra nonce prefix.
+    pub fn check(&self, prefix: u32, submit: &Submit) -> Result<Share, &'static str> {
+        let header = match self.recent.iter().find(|(job_id, _)| *job_id == submit.job_id) {
+            Some((_, header)) => header,
+            None => return Err("unknown or stale job"),
+        };
+        if submit.extra_nonce >> 32 != prefix as u64 {
+            return Err("extra nonce outside of the client range");
+        }
+        let header = Header {
+            nonce: submit.nonce,
+            extra_nonce: submit.extra_nonce,
+            ..*header
+        };
+        let hash = header.hash();
+        if hash < header.difficulty {
+            Ok(Share::Block(header))
+        } else if hash < self.share_target(&header) {
+            Ok(Share::Share)
+        } else {
+            Err("hash above the share target")
+        }
+    }
+}
+
+struct Coordinator {
+    miner: MinerHandle,
+    jobs: Mutex<Jobs>,
+    // writers of the subscribed clients, with their extra nonce prefix
+    clients: Mutex<Vec<(u32, TcpStream)>>,
+}
+
+fn send_line<T: Serialize>(stream: &mut TcpStream, message: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(message).unwrap();
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+/// Start the TCP endpoint where external hashing clients subscribe for work derived from the
+/// block templates of the miner and submit their solutions.
+pub fn start(addr: SocketAddr, miner: &MinerHandle, share_target: Option<H256>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let coordinator = Arc::new(Coordinator {
+        miner: miner.clone(),
+        jobs: Mutex::new(Jobs::new(share_target)),
+        clients: Mutex::new(Vec::new()),
+    });
+
+    let notifier = Arc::clone(&coordinator);
+    thread::Builder::new()
+        .name("stratum-notify".to_string())
+        .spawn(move || loop {
+            notifier.refresh();
+            thread::sleep(REFRESH_INTERVAL);
+        })
+        .unwrap();
+
+    thread::Builder::new()
+        .name("stratum".to_string())
+        .spawn(move || {
+            let mut next_prefix: u32 = 1;
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("Error accepting stratum client: {}", e);
+                        continue;
+                    }
+                };
+                // prefix 0 is left to the built-in miner
+                let prefix = next_prefix;
+                next_prefix = next_prefix.wrapping_add(1).max(1);
+                let coordinator = Arc::clone(&coordinator);
+                thread::spawn(move || {
+                    coordinator.serve(prefix, stream);
+                });
+            }
+        })
+        .unwrap();
+    info!("Stratum server listening at {}", addr);
+    Ok(())
+}
+
+impl Coordinator {
+    /// Ask the miner for a template and notify the clients if the work changed.
+    fn refresh(&self) {
+        let template = self.miner.get_template();
+        let mut jobs = self.jobs.lock().unwrap();
+        if jobs.refresh(template).is_none() {
+            return;
+        }
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain_mut(|(prefix, stream)| {
+            let job = jobs.current_job(*prefix).unwrap();
+            send_line(stream, &Notification { method: "notify", params: job }).is_ok()
+        });
+    }
+
+    fn serve(&self, prefix: u32, stream: TcpStream) {
+        let peer = stream.peer_addr().ok();
+        let mut writer = match stream.try_clone() {
+            Ok(s) => s,
+            Err(_) => return,
+        };
+        let mut shares: u64 = 0;
+        let mut blocks: u64 = 0;
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            let request: Request = match serde_json::from_str(&line) {
+                Ok(r) => r,
+                Err(e) => {
+                    debug!("Bad stratum request from {:?}: {}", peer, e);
+                    break;
+                }
+            };
+            let (result, error) = match request.method.as_str() {
+                "subscribe" => {
+                    let mut clients = self.clients.lock().unwrap();
+                    match writer.try_clone() {
+                        Ok(s) => clients.push((prefix, s)),
+                        Err(_) => break,
+                    }
+                    (serde_json::json!({ "extra_nonce_prefix": prefix }), None)
+                }
+                "submit" => match serde_json::from_value::<Submit>(request.params) {
+                    Ok(submit) => {
+                        let checked = self.jobs.lock().unwrap().check(prefix, &submit);
+                        match checked {
+                            Ok(Share::Share) => {
+                                shares += 1;
+                                (serde_json::json!("share"), None)
+                            }
+                            Ok(Share::Block(header)) => {
+                                shares += 1;
+                                if self.miner.submit_header(header) {
+                                    blocks += 1;
+                                    info!("Stratum client {:?} found block {}", peer, header.hash());
+                                    (serde_json::json!("block"), None)
+                                } else {
+                                    (serde_json::Value::Null, Some("block rejected by the node".to_string()))
+                                }
+                            }
+                            Err(e) => (serde_json::Value::Null, Some(e.to_string())),
+                        }
+                    }
+                    Err(e) => (serde_json::Value::Null, Some(format!("bad submit parameters: {}", e))),
+                },
+                _ => (serde_json::Value::Null, Some("unknown method".to_string())),
+            };
+            let subscribed = request.method == "subscribe" && error.is_none();
+            if send_line(&mut writer, &Response { id: request.id, result, error }).is_err() {
+                break;
+            }
+            // a new subscriber gets the current work right away
+            if subscribed {
+                let job = self.jobs.lock().unwrap().current_job(prefix);
+                if let Some(job) = job {
+                    if send_line(&mut writer, &Notification { method: "notify", params: job }).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+        self.clients.lock().unwrap().retain(|(p, _)| *p != prefix);
+        info!("Stratum client {:?} disconnected after {} shares and {} blocks", peer, shares, blocks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::test::generate_random_block;
+
+    fn template(parent: &H256) -> BlockTemplate {
+        let mut block = generate_random_block(parent);
+        let mut difficulty = [0u8; 32];
+        difficulty[0] = 0x10;
+        block.header.difficulty = H256::from(difficulty);
+        BlockTemplate {
+            block,
+            state: Default::default(),
+        }
+    }
+
+    fn job_header(job: &Job) -> Header {
+        bincode::deserialize(&hex::decode(&job.header).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn jobs_follow_the_work() {
+        let mut jobs = Jobs::new(None);
+        assert!(jobs.current_job(1).is_none());
+        let first = template(&H256::default());
+        assert_eq!(jobs.refresh(Some(first.clone())), Some(1));
+        // the same work keeps the job
+        assert_eq!(jobs.refresh(Some(first.clone())), None);
+        assert_eq!(jobs.refresh(None), None);
+        assert_eq!(jobs.refresh(Some(template(&first.block.hash()))), Some(2));
+
+        let job = jobs.current_job(7).unwrap();
+        assert_eq!(job.job_id, 2);
+        assert_eq!(job_header(&job).extra_nonce, 7 << 32);
+        assert_eq!(job_header(&job).parent, first.block.hash());
+    }
+
+    #[test]
+    fn check_submissions() {
+        let mut jobs = Jobs::new(None);
+        jobs.refresh(Some(template(&H256::default())));
+        let job = jobs.current_job(3).unwrap();
+        let mut header = job_header(&job);
+        while header.hash() >= header.difficulty {
+            header.nonce += 1;
+        }
+        let submit = Submit {
+            job_id: job.job_id,
+            nonce: header.nonce,
+            extra_nonce: header.extra_nonce,
+        };
+        match jobs.check(3, &submit) {
+            Ok(Share::Block(solved)) => assert_eq!(solved.hash(), header.hash()),
+            other => panic!("expected a block, got {:?}", other),
+        }
+        // another client cannot claim the work
+        assert!(jobs.check(4, &submit).is_err());
+        assert!(jobs.check(3, &Submit { job_id: 9, ..submit }).is_err());
+    }
+}
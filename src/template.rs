@@ -0,0 +1,263 @@
+use crate::blockchain::Blockchain;
+use crate::clock;
+use crate::block::{Block, Header, Content, State};
+use crate::crypto::merkle::MerkleTree;
+use crate::crypto::hash::{H256, Hashable};
+use crate::mempool::{DropReason, Mempool};
+use crate::transaction::{SignedTransaction, GAS_PER_TRANSACTION};
+use std::sync::{Arc, Mutex};
+
+/// A block ready to be mined on top of the current tip: the transactions are selected and
+/// committed to by the merkle root, only the nonces of the header are left to search.
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub block: Block,
+    /// The state after the block.
+    pub state: State,
+}
+
+impl BlockTemplate {
+    pub fn header(&self) -> Header {
+        self.block.header
+    }
+
+    /// Whether two templates commit to the same parent and transactions, in which case a miner
+    /// can keep working on the older one.
+    pub fn same_work(&self, other: &BlockTemplate) -> bool {
+        self.block.header.parent == other.block.header.parent
+            && self.block.header.merkle_root == other.block.header.merkle_root
+    }
+
+    /// Whether `header` is a solved header of this template. Only the nonces and the timestamp
+    /// may differ from the template header, the timestamp moving forward but not too far ahead.
+    pub fn is_solved_by(&self, header: &Header) -> bool {
+        header.parent == self.block.header.parent
+            && header.merkle_root == self.block.header.merkle_root
+            && header.state_root == self.block.header.state_root
+            && header.difficulty == self.block.header.difficulty
+            && header.timestamp >= self.block.header.timestamp
+            && !header.is_from_future(clock::now_micros())
+            && header.hash() < header.difficulty
+    }
+
+    /// The mined block for a solved header of this template.
+    pub fn solve(&self, header: Header) -> Block {
+        Block {
+            header,
+            content: self.block.content.clone(),
+        }
+    }
+}
+
+/// Builds block templates from the longest chain and the transaction mempool.
+#[derive(Clone)]
+pub struct TemplateBuilder {
+    blockchain: Arc<Blockchain>,
+    tx_mempool: Arc<Mutex<Mempool>>,
+}
+
+impl TemplateBuilder {
+    pub fn new(
+        blockchain: &Arc<Blockchain>,
+        tx_mempool: &Arc<Mutex<Mempool>>,
+    ) -> Self {
+        TemplateBuilder {
+            blockchain: Arc::clone(blockchain),
+            tx_mempool: Arc::clone(tx_mempool),
+        }
+    }
+
+    /// Build a template on the current tip. Returns `None` when the mempool does not hold enough
+    /// valid transactions to fill a block up to its gas limit.
+    pub fn build(&self) -> Option<BlockTemplate> {
+        // Read the tip and its state, then build the template without holding any blockchain lock.
+        let (parent, state) = self.blockchain.tip_with_state();
+        let parent_header = self.blockchain.get_header(&parent).unwrap();
+        let difficulty: H256 = parent_header.difficulty;
+        let gas_limit = parent_header.gas_limit;
+
+        let height = self.blockchain.get_block_height(&parent).unwrap() + 1;
+        let (content, merkle_tree, new_state, full) = self.collect_txs(&state, height, gas_limit);
+        if !full {
+            return None;
+        }
+        let merkle_root = merkle_tree.root();
+        // the timestamp must be later than the median time past, whatever the local clock says
+        let timestamp = clock::now_micros().max(self.blockchain.median_time_past(&parent).unwrap_or(0) + 1);
+        let header = Header{
+            parent,
+            nonce: 0,
+            extra_nonce: 0,
+            difficulty,
+            timestamp,
+            merkle_root,
+            state_root: new_state.root(),
+            gas_limit,
+        };
+        Some(BlockTemplate {
+            block: Block {
+                header,
+                content,
+            },
+            state: new_state,
+        })
+    }
+
+    /// Select transactions valid on top of `_state` in a block at `height`, using up to
+    /// `gas_limit` gas, erasing from the mempool those that can never become valid or have
+    /// expired. Also returns whether the block is full: a valid transaction was left out for lack
+    /// of gas, or no transaction fits in the gas left. The merkle tree of the transactions is
+    /// extended as they are selected.
+    fn collect_txs(&self, _state: &State, height: u32, gas_limit: u64) -> (Content, MerkleTree, State, bool) {
+        let mut valid_transactions = vec![];
+        let mut merkle_tree = MerkleTree::default();
+        let mut erase_transactions = vec![];
+        let mut state = _state.clone();
+        let mut gas_used = 0;
+        let mut full = false;
+
+        if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
+            loop{
+                let mut finished = true;
+                erase_transactions.clear();
+
+                for tx_signed in _tx_mempool.pending() {
+                    if gas_limit - gas_used < GAS_PER_TRANSACTION {
+                        full = true;
+                        break;
+                    }
+                    // already selected on a previous pass
+                    if valid_transactions.iter().any(|tx: &SignedTransaction| tx.hash() == tx_signed.hash()) {
+                        continue;
+                    }
+                    if tx_signed.transaction.is_expired(height) {
+                        erase_transactions.push((tx_signed.hash(), DropReason::Expired));
+                        continue;
+                    }
+                    if tx_signed.is_erasable(&state) {
+                        erase_transactions.push((tx_signed.hash(), DropReason::Conflicted));
+                        continue;
+                    }
+                    // the nonce is not the next one yet
+                    if !tx_signed.is_valid(&state) {
+                        continue;
+                    }
+                    // the valid transaction, if it fits
+                    let gas = tx_signed.gas();
+                    if gas > gas_limit - gas_used {
+                        full = true;
+                        continue;
+                    }
+                    gas_used += gas;
+                    tx_signed.update_state(&mut state);
+                    merkle_tree.append(tx_signed);
+                    valid_transactions.push(tx_signed.clone());
+                    finished = false;
+                }
+
+                // remove invalid txs
+                for (tx, reason) in erase_transactions.iter() {
+                    _tx_mempool.discard(tx, *reason);
+                }
+
+                // if no more transactions can be added, return
+                if finished || gas_limit - gas_used < GAS_PER_TRANSACTION {
+                    break;
+                }
+            }
+        }
+        full |= gas_limit - gas_used < GAS_PER_TRANSACTION;
+
+        let content = Content {
+            transactions: valid_transactions,
+        };
+        (content, merkle_tree, state, full)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::address::H160;
+    use crate::crypto::key_pair;
+    use crate::transaction::{sign, SignatureScheme, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn signed_transaction(key: &Ed25519KeyPair, value: u64, account_nonce: u64) -> SignedTransaction {
+        let transaction = Transaction {
+            inputs: Vec::new(),
+            outputs: vec![(H160::default(), value)],
+            fee: 0,
+            account_nonce,
+            data: Vec::new(),
+            expires_at_block: None,
+            token: None,
+        };
+        let signature = sign(&transaction, key);
+        SignedTransaction {
+            transaction,
+            signature: signature.as_ref().to_vec(),
+            public_key: key.public_key().as_ref().to_vec(),
+            scheme: SignatureScheme::Ed25519,
+            multisig: None,
+            sender_cache: Default::default(),
+        }
+    }
+
+    fn mempool_of(blockchain: &Blockchain, txs: Vec<SignedTransaction>) -> Arc<Mutex<Mempool>> {
+        let (_, state) = blockchain.tip_with_state();
+        let mut tx_mempool = Mempool::default();
+        for tx in txs {
+            assert!(tx_mempool.insert(tx, &state).is_ok());
+        }
+        Arc::new(Mutex::new(tx_mempool))
+    }
+
+    #[test]
+    fn build_and_solve() {
+        let blockchain = Arc::new(Blockchain::new());
+        // the genesis funds the well-known keys
+        let key = key_pair::frombyte(0);
+        let tx_mempool = mempool_of(&blockchain, (1..=4).map(|nonce| signed_transaction(&key, 1, nonce)).collect());
+        let builder = TemplateBuilder::new(&blockchain, &tx_mempool);
+        let template = builder.build().unwrap();
+        assert_eq!(template.block.header.parent, blockchain.tip());
+        // the fourth transaction does not fit in the gas limit
+        assert_eq!(template.block.content.len(), 3);
+        assert_eq!(template.block.header.gas_limit, blockchain.get_header(&blockchain.tip()).unwrap().gas_limit);
+        assert!(template.block.gas_used() <= template.block.header.gas_limit);
+        assert_eq!(template.block.header.merkle_root, MerkleTree::new(&template.block.content.transactions).root());
+        assert_eq!(template.block.header.state_root, template.state.root());
+        assert!(template.same_work(&builder.build().unwrap()));
+
+        let mut header = template.header();
+        while !template.is_solved_by(&header) {
+            header.nonce += 1;
+        }
+        let block = template.solve(header);
+        assert!(block.is_well_formed());
+        blockchain.insert(&block, &template.state);
+        assert_eq!(blockchain.tip(), block.hash());
+
+        // the chain moved, so the next template commits to another parent
+        assert!(!template.is_solved_by(&Header { parent: block.hash(), ..header }));
+    }
+
+    #[test]
+    fn not_enough_transactions() {
+        let blockchain = Arc::new(Blockchain::new());
+        let key = key_pair::frombyte(0);
+        let other = key_pair::frombyte(1);
+        // a nonce gap, and a second transfer the balance left by the first cannot pay
+        let tx_mempool = mempool_of(&blockchain, vec![
+            signed_transaction(&key, 1, 1),
+            signed_transaction(&key, 1, 3),
+            signed_transaction(&other, 20, 1),
+            signed_transaction(&other, 20, 2),
+        ]);
+        let builder = TemplateBuilder::new(&blockchain, &tx_mempool);
+        assert!(builder.build().is_none());
+        // the unaffordable transaction is erased, the gapped one is kept
+        assert_eq!(tx_mempool.lock().unwrap().len(), 3);
+    }
+}
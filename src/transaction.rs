@@ -1,33 +1,293 @@
 use serde::{Serialize,Deserialize};
 use ring::signature::{Ed25519KeyPair, Signature, KeyPair, UnparsedPublicKey, ED25519};
-use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::hash::{self, H256, Hashable};
 use crate::crypto::address::{H160};
-use crate::block::State;
+use crate::crypto::secp256k1;
+use crate::block::{AccountState, Model, State, Utxo};
+use std::sync::OnceLock;
+
+/// Most inputs of a transaction.
+pub static MAX_INPUTS: usize = 256;
+/// Most outputs of a transaction.
+pub static MAX_OUTPUTS: usize = 256;
+/// Largest data payload of a transaction, in bytes.
+pub static MAX_DATA_SIZE: usize = 256;
+/// Fee a transaction pays at least for each byte of its data payload.
+pub static FEE_PER_DATA_BYTE: u64 = 1;
+/// Most keys of a multisig account.
+pub static MAX_COSIGNERS: usize = 16;
+/// Gas every transaction uses, on top of `GAS_PER_BYTE`.
+pub static GAS_PER_TRANSACTION: u64 = 1000;
+/// Gas a transaction uses for each byte of its serialized form, signatures included.
+pub static GAS_PER_BYTE: u64 = 10;
 
 // Account based model transaction (Ethereum).
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
-    pub recipient_address: H160,
-    pub value: u64,
-    pub account_nonce: i32,
+    /// The outputs of earlier transactions spent, at most `MAX_INPUTS`, in the UTXO model. Empty in
+    /// the account model.
+    pub inputs: Vec<OutPoint>,
+    /// (recipient, value) of every transfer, at least one and at most `MAX_OUTPUTS`, made at
+    /// once under the nonce of the sender.
+    pub outputs: Vec<(H160, u64)>,
+    /// Paid by the sender on top of the values. Blocks carry no beneficiary yet, so fees are burned;
+    /// they order competing transactions of a sender in the mempool.
+    pub fee: u64,
+    /// Unused in the UTXO model, where the inputs make every transaction unique.
+    pub account_nonce: u64,
+    /// Bytes left to the applications, e.g. a hash they anchor on chain, at most `MAX_DATA_SIZE`.
+    /// The fee covers `FEE_PER_DATA_BYTE` for each of them.
+    #[serde(with = "crate::crypto::hex_bytes")]
+    pub data: Vec<u8>,
+    /// Height of the last block that may include the transaction, if any. Past it, the
+    /// transaction is dropped from the mempools.
+    pub expires_at_block: Option<u32>,
+    /// What the outputs pay in when not in coins, in the account model only.
+    pub token: Option<TokenOp>,
 }
 
-// UTXO based transaction
-/*
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
-pub struct Transaction {
-prev_tx_hash: H256,
-index: i8,
-recipient_address: H160,
-value:  u32,
+/// A transaction of the native tokens. The fee is paid in coins either way.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenOp {
+    /// Issue a new token, whose id is `token_id` of the sender and the nonce, to the outputs. Its
+    /// supply is their total.
+    Create,
+    /// Pay the outputs in the token of this id.
+    Transfer(H160),
+}
+
+/// Id of the token issued by `issuer` in its transaction of nonce `account_nonce`.
+pub fn token_id(issuer: &H160, account_nonce: u64) -> H160 {
+    let bytes = bincode::serialize(&(b"token", issuer, account_nonce)).unwrap();
+    ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+}
+
+/// An output of a transaction, which a later transaction spends as an input in the UTXO model.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    /// Hash of the signed transaction, the default hash for the genesis outputs.
+    pub txid: H256,
+    /// Position in the outputs of the transaction.
+    pub index: u32,
+}
+
+impl OutPoint {
+    /// The key of the output in the UTXO trie of the state.
+    pub fn key(&self) -> H160 {
+        let bytes = bincode::serialize(self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+    }
+}
+
+impl Transaction {
+    /// The canonical encoding of the transaction, handed to an external signer, which signs
+    /// its digest under the hash function of the chain, `hash()`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        bincode::serialize(self).unwrap()
+    }
+
+    /// Decode the canonical encoding of a transaction, `None` if `bytes` is not one.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Transaction> {
+        let transaction: Transaction = bincode::deserialize(bytes).ok()?;
+        // a canonical encoding has no trailing bytes
+        if transaction.to_bytes() != bytes {
+            return None;
+        }
+        Some(transaction)
+    }
+
+    /// Sum of the values of the outputs, `None` if it overflows.
+    pub fn value(&self) -> Option<u64> {
+        self.outputs.iter().try_fold(0u64, |sum, (_, value)| sum.checked_add(*value))
+    }
+
+    /// Sum of the coins paid to the outputs, zero when they are paid in a token.
+    pub fn coin_value(&self) -> Option<u64> {
+        match self.token {
+            Some(_) => Some(0),
+            None => self.value(),
+        }
+    }
+
+    /// What the transaction takes from the sender coin balance, `None` if it overflows.
+    pub fn cost(&self) -> Option<u64> {
+        self.coin_value()?.checked_add(self.fee)
+    }
+
+    /// Least fee paying for the data payload.
+    pub fn data_fee(&self) -> u64 {
+        self.data.len() as u64 * FEE_PER_DATA_BYTE
+    }
+
+    /// Whether the transaction is within bounds whatever the state: the number of inputs and of
+    /// outputs and the data size are within limits, and the fee pays for the data.
+    pub fn is_well_formed(&self) -> bool {
+        self.inputs.len() <= MAX_INPUTS
+            && !self.outputs.is_empty()
+            && self.outputs.len() <= MAX_OUTPUTS
+            && self.data.len() <= MAX_DATA_SIZE
+            && self.fee >= self.data_fee()
+    }
+
+    /// Whether a block at `height` can no longer include the transaction.
+    pub fn is_expired(&self, height: u32) -> bool {
+        self.expires_at_block.is_some_and(|last| height > last)
+    }
 }
-*/
 
 impl Hashable for Transaction{
     fn hash(&self) -> H256 {
-        let t_bytes = bincode::serialize(&self).unwrap();
-        let t_digest = ring::digest::digest(&ring::digest::SHA256, &t_bytes);
-        t_digest.into()
+        let t_bytes = self.to_bytes();
+        hash::digest(&t_bytes)
+    }
+}
+
+/// The keys of an M-of-N account, `threshold` of which sign its transactions. The keys are
+/// sorted, so that a set of keys has one address whatever the order it is given in.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct MultisigPolicy {
+    pub threshold: u8,
+    #[serde(with = "crate::crypto::hex_bytes::list")]
+    pub public_keys: Vec<Vec<u8>>,
+}
+
+impl MultisigPolicy {
+    pub fn new(threshold: u8, mut public_keys: Vec<Vec<u8>>) -> Self {
+        public_keys.sort();
+        public_keys.dedup();
+        MultisigPolicy { threshold, public_keys }
+    }
+
+    /// Whether at least one and at most all of the keys must sign, there are at most
+    /// `MAX_COSIGNERS` keys, and they are sorted without duplicates.
+    pub fn is_well_formed(&self) -> bool {
+        self.threshold >= 1
+            && self.threshold as usize <= self.public_keys.len()
+            && self.public_keys.len() <= MAX_COSIGNERS
+            && self.public_keys.windows(2).all(|pair| pair[0] < pair[1])
+    }
+
+    /// The address of the account, derived from the threshold and the sorted keys. The digest
+    /// is over more bytes than a single key, so it is never the address of a single key.
+    pub fn address(&self) -> H160 {
+        let mut bytes = b"multisig".to_vec();
+        bytes.push(self.threshold);
+        for public_key in self.public_keys.iter() {
+            bytes.extend_from_slice(public_key);
+        }
+        ring::digest::digest(&ring::digest::SHA256, &bytes).into()
+    }
+}
+
+/// The signatures of a transaction by the keys of a multisig account, collected one cosigner at a
+/// time.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Multisig {
+    pub policy: MultisigPolicy,
+    /// (position of the key in the policy, signature), sorted by position.
+    #[serde(with = "crate::crypto::hex_bytes::indexed")]
+    pub signatures: Vec<(u8, Vec<u8>)>,
+}
+
+impl Multisig {
+    pub fn new(policy: MultisigPolicy) -> Self {
+        Multisig { policy, signatures: Vec::new() }
+    }
+
+    /// Add the signature of `key` over `transaction`. Returns false if `key` is not one of the
+    /// policy.
+    pub fn sign(&mut self, transaction: &Transaction, key: &Ed25519KeyPair) -> bool {
+        let position = match self.policy.public_keys.iter().position(|public_key| public_key.as_slice() == key.public_key().as_ref()) {
+            Some(position) => position as u8,
+            None => return false,
+        };
+        let signature = sign(transaction, key).as_ref().to_vec();
+        match self.signatures.binary_search_by_key(&position, |(position, _)| *position) {
+            Ok(i) => self.signatures[i].1 = signature,
+            Err(i) => self.signatures.insert(i, (position, signature)),
+        }
+        true
+    }
+
+    /// Whether the policy is well formed and `threshold` distinct keys of it signed `transaction`.
+    pub fn verify(&self, transaction: &Transaction) -> bool {
+        if !self.policy.is_well_formed()
+            || self.signatures.len() < self.policy.threshold as usize
+            || !self.signatures.windows(2).all(|pair| pair[0].0 < pair[1].0) {
+            return false;
+        }
+        let hash = transaction.hash();
+        self.signatures.iter().all(|(position, signature)| match self.policy.public_keys.get(*position as usize) {
+            Some(public_key) => UnparsedPublicKey::new(&ED25519, public_key).verify(hash.as_ref(), signature).is_ok(),
+            None => false,
+        })
+    }
+}
+
+/// The algorithm of the signature of a transaction, which also determines how the address of
+/// the sender is derived from the public key.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureScheme {
+    /// The address is the first 20 bytes of the SHA256 hash of the public key.
+    #[default]
+    Ed25519,
+    /// ECDSA over secp256k1 with Ethereum addresses, see `crypto::secp256k1`.
+    Secp256k1,
+}
+
+/// Why a transaction is refused by the mempool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxValidationError {
+    /// The mempool holds it already.
+    AlreadyKnown,
+    BadSignature,
+    /// Over the limits of inputs, outputs or data, whatever the state.
+    Malformed,
+    /// The nonce is confirmed already.
+    NonceTooLow,
+    /// The balance of the sender does not cover the value and the fee.
+    InsufficientBalance,
+    /// In the UTXO model, the inputs are not unspent outputs of the sender worth the cost.
+    InvalidInputs,
+    /// Past the last height it could be included at.
+    Expired,
+    /// Below the minimum fee of the mempool, or not enough over the transaction it would replace.
+    FeeTooLow,
+    /// The mempool is full of transactions that cannot be evicted.
+    OverCapacity,
+}
+
+impl TxValidationError {
+    /// A short name, as accounted in the peer statistics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TxValidationError::AlreadyKnown => "already-known",
+            TxValidationError::BadSignature => "bad-signature",
+            TxValidationError::Malformed => "malformed",
+            TxValidationError::NonceTooLow => "nonce-too-low",
+            TxValidationError::InsufficientBalance => "insufficient-balance",
+            TxValidationError::InvalidInputs => "invalid-inputs",
+            TxValidationError::Expired => "expired",
+            TxValidationError::FeeTooLow => "fee-too-low",
+            TxValidationError::OverCapacity => "over-capacity",
+        }
+    }
+}
+
+impl std::fmt::Display for TxValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let msg = match self {
+            TxValidationError::AlreadyKnown => "transaction already known",
+            TxValidationError::BadSignature => "invalid signature",
+            TxValidationError::Malformed => "transaction over the limits of inputs, outputs or data",
+            TxValidationError::NonceTooLow => "nonce already used",
+            TxValidationError::InsufficientBalance => "balance too low for the value and the fee",
+            TxValidationError::InvalidInputs => "inputs not unspent outputs of the sender worth the cost",
+            TxValidationError::Expired => "transaction expired",
+            TxValidationError::FeeTooLow => "fee too low",
+            TxValidationError::OverCapacity => "mempool full",
+        };
+        write!(f, "{}", msg)
     }
 }
 
@@ -35,63 +295,230 @@ impl Hashable for Transaction{
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct SignedTransaction {
     pub transaction: Transaction,
+    /// Empty for a multisig sender.
+    #[serde(with = "crate::crypto::hex_bytes")]
     pub signature: Vec<u8>,
+    /// Empty for a multisig sender.
+    #[serde(with = "crate::crypto::hex_bytes")]
     pub public_key: Vec<u8>,
+    /// Ed25519 for a multisig sender.
+    pub scheme: SignatureScheme,
+    /// The signatures of the cosigners when the sender is a multisig account.
+    pub multisig: Option<Multisig>,
+    /// The sender, computed on the first call to `sender` and never serialized. The fields above
+    /// must not change once it is set.
+    #[serde(skip)]
+    pub(crate) sender_cache: OnceLock<H160>,
 }
 
 impl Hashable for SignedTransaction{
     fn hash(&self) -> H256 {
         let t_bytes = bincode::serialize(&self).unwrap();
-        let t_digest = ring::digest::digest(&ring::digest::SHA256, &t_bytes);
-        t_digest.into()
+        hash::digest(&t_bytes)
     }
 }
 
 impl SignedTransaction {
+    /// Attach the detached `signature` of `transaction` made by an external signer holding the
+    /// key of `public_key`. `None` if the signature does not verify.
+    pub fn assemble(transaction: Transaction, scheme: SignatureScheme, signature: Vec<u8>, public_key: Vec<u8>) -> Option<SignedTransaction> {
+        let tx = SignedTransaction {
+            transaction,
+            signature,
+            public_key,
+            scheme,
+            multisig: None,
+            sender_cache: Default::default(),
+        };
+        if !tx.has_valid_signature() {
+            return None;
+        }
+        Some(tx)
+    }
+
+    /// The address of the sender, derived from the public key as the signature scheme says, or
+    /// from the policy of a multisig sender. The default address if the public key is invalid.
+    pub fn sender(&self) -> H160 {
+        *self.sender_cache.get_or_init(|| match (&self.multisig, self.scheme) {
+            (Some(multisig), _) => multisig.policy.address(),
+            (None, SignatureScheme::Ed25519) => ring::digest::digest(&ring::digest::SHA256, self.public_key.as_ref()).into(),
+            (None, SignatureScheme::Secp256k1) => secp256k1::address(&self.public_key).unwrap_or_default(),
+        })
+    }
+
+    /// Whether the signature is the one of the sender over the transaction, or enough cosigners
+    /// of a multisig sender signed it.
+    pub fn has_valid_signature(&self) -> bool {
+        match (&self.multisig, self.scheme) {
+            (Some(multisig), scheme) => {
+                scheme == SignatureScheme::Ed25519 && self.signature.is_empty() && self.public_key.is_empty()
+                    && multisig.verify(&self.transaction)
+            }
+            (None, SignatureScheme::Ed25519) => {
+                let public_key = UnparsedPublicKey::new(&ED25519, self.public_key.clone());
+                public_key.verify(self.transaction.hash().as_ref(), self.signature.as_ref()).is_ok()
+            }
+            (None, SignatureScheme::Secp256k1) => {
+                secp256k1::verify(self.transaction.hash().as_ref(), &self.public_key, &self.signature)
+            }
+        }
+    }
+
+    /// Share of the block gas limit the transaction takes up.
+    pub fn gas(&self) -> u64 {
+        let size = bincode::serialized_size(self).unwrap();
+        GAS_PER_TRANSACTION.saturating_add(GAS_PER_BYTE.saturating_mul(size))
+    }
+
     pub fn is_valid(&self, state: &State) -> bool {
-        let address: H160 = ring::digest::digest(&ring::digest::SHA256, self.public_key.as_ref()).into();
-        if self.is_erasable(state) {
-            return false;
+        self.has_valid_signature() && self.is_valid_in_state(state)
+    }
+
+    /// `is_valid` without the signature check, for a transaction whose signature was checked
+    /// already: the transaction is well formed, the nonce is the next one of the sender, whose
+    /// balance covers the cost, and whose token balance covers the outputs of a token transfer,
+    /// and no recipient balance overflows. In the UTXO model, see `spend`.
+    pub fn is_valid_in_state(&self, state: &State) -> bool {
+        match state.model {
+            Model::Account => self.transfer(state).is_some(),
+            Model::Utxo => self.spend(state).is_some(),
         }
-        if let Some(peer_state) = state.account_state.get(&address) {
-            if self.transaction.account_nonce != (peer_state.nonce + 1) {
-                return false
+    }
+
+    /// The accounts of the sender and of the recipients after the transfer, the sender first,
+    /// `None` if it is not valid on top of `state`. An address that is not in the state yet is an
+    /// empty account.
+    fn transfer(&self, state: &State) -> Option<Vec<(H160, AccountState)>> {
+        if !self.transaction.is_well_formed() || !self.transaction.inputs.is_empty() {
+            return None;
+        }
+        let sender = self.sender();
+        let mut sender_state = state.account_state.get(&sender).cloned().unwrap_or_default();
+        if sender_state.nonce.checked_add(1)? != self.transaction.account_nonce {
+            return None;
+        }
+        sender_state.nonce = self.transaction.account_nonce;
+        sender_state.balance = sender_state.balance.checked_sub(self.transaction.cost()?)?;
+        let token = match self.transaction.token {
+            None => None,
+            Some(TokenOp::Create) => Some(token_id(&sender, self.transaction.account_nonce)),
+            Some(TokenOp::Transfer(token)) => {
+                sender_state.debit_token(&token, self.transaction.value()?)?;
+                Some(token)
+            }
+        };
+        let mut accounts = vec![(sender, sender_state)];
+        for (recipient, value) in self.transaction.outputs.iter() {
+            let position = match accounts.iter().position(|(address, _)| address == recipient) {
+                Some(position) => position,
+                None => {
+                    accounts.push((*recipient, state.account_state.get(recipient).cloned().unwrap_or_default()));
+                    accounts.len() - 1
+                }
+            };
+            let account = &mut accounts[position].1;
+            match token {
+                Some(token) => account.credit_token(&token, *value)?,
+                None => account.balance = account.balance.checked_add(*value)?,
+            }
+        }
+        Some(accounts)
+    }
+
+    /// The keys of the outputs spent and the outputs created, `None` if the transaction is not
+    /// valid on top of the UTXO `state`: it is well formed, pays in coins, and spends at least one
+    /// output, each once, all confirmed, owned by the sender and together worth exactly the cost.
+    fn spend(&self, state: &State) -> Option<(Vec<H160>, Vec<Utxo>)> {
+        if !self.transaction.is_well_formed() || self.transaction.inputs.is_empty() || self.transaction.token.is_some() {
+            return None;
+        }
+        let sender = self.sender();
+        let mut spent = Vec::with_capacity(self.transaction.inputs.len());
+        let mut total = 0u64;
+        for input in self.transaction.inputs.iter() {
+            let key = input.key();
+            let utxo = state.utxos.get(&key)?;
+            if utxo.owner != sender || spent.contains(&key) {
+                return None;
             }
+            total = total.checked_add(utxo.value)?;
+            spent.push(key);
         }
-        return true;
+        if Some(total) != self.transaction.cost() {
+            return None;
+        }
+        let txid = self.hash();
+        let created = self.transaction.outputs.iter().enumerate().map(|(index, (owner, value))| Utxo {
+            outpoint: OutPoint { txid, index: index as u32 },
+            owner: *owner,
+            value: *value,
+        }).collect();
+        Some((spent, created))
     }
 
+    /// Whether the transaction can never become valid on top of `state`. An address that is not
+    /// in the state yet is an empty account.
     pub fn is_erasable(&self, state: &State) -> bool {
-        let address: H160 = ring::digest::digest(&ring::digest::SHA256, self.public_key.as_ref()).into();
-        let public_key = UnparsedPublicKey::new(&ED25519, self.public_key.clone());
+        self.erasable_reason(state).is_some()
+    }
+
+    /// Why the transaction can never become valid on top of `state`, if it cannot.
+    pub fn erasable_reason(&self, state: &State) -> Option<TxValidationError> {
         // verification fails
-        if public_key.verify(self.transaction.hash().as_ref(), self.signature.as_ref()).is_err() {
-            return true;
+        if !self.has_valid_signature() {
+            return Some(TxValidationError::BadSignature);
+        }
+        if !self.transaction.is_well_formed() {
+            return Some(TxValidationError::Malformed);
+        }
+        // the outputs spent are confirmed already, or never
+        if state.model == Model::Utxo {
+            return self.spend(state).is_none().then_some(TxValidationError::InvalidInputs);
         }
         // get the peer state
-        if let Some(peer_state) = state.account_state.get(&address) {
-            // the nonce is smaller
-            if self.transaction.account_nonce <= peer_state.nonce {
-                return true;
-            }
-            // the balance is not enough
-            if self.transaction.value > peer_state.balance {
-                return true;
-            }
+        let peer_state = state.account_state.get(&self.sender()).cloned().unwrap_or_default();
+        // the nonce is smaller
+        if self.transaction.account_nonce <= peer_state.nonce {
+            return Some(TxValidationError::NonceTooLow);
+        }
+        // the balance is not enough, or no balance could be
+        match self.transaction.cost() {
+            Some(cost) if cost <= peer_state.balance => None,
+            _ => Some(TxValidationError::InsufficientBalance),
         }
-        return false;
     }
 
-    pub fn update_state(&self, state: &mut State){
-        let address: H160 = ring::digest::digest(&ring::digest::SHA256, self.public_key.as_ref()).into();
-        if let Some(sender_state) = state.account_state.get_mut(&address) {   
-            assert_eq!(sender_state.nonce + 1, self.transaction.account_nonce);
-            sender_state.balance -= self.transaction.value;
-            sender_state.nonce += 1;
+    /// Apply the transfer to `state`. The accounts are created on their first transfer; in the
+    /// UTXO model, the outputs spent are replaced by the new ones. Returns false, leaving the
+    /// state unchanged, if the transaction is not valid in it.
+    pub fn update_state(&self, state: &mut State) -> bool {
+        if state.model == Model::Utxo {
+            let (spent, created) = match self.spend(state) {
+                Some(utxos) => utxos,
+                None => return false,
+            };
+            for key in spent.iter() {
+                state.utxos.remove(key);
+            }
+            for utxo in created {
+                if !state.address_list.contains(&utxo.owner) {
+                    state.address_list.push(utxo.owner);
+                }
+                state.utxos.insert(utxo.outpoint.key(), utxo);
+            }
+            return true;
         }
-        if let Some(receiver_state) = state.account_state.get_mut(&self.transaction.recipient_address) {
-            receiver_state.balance += self.transaction.value;
+        let accounts = match self.transfer(state) {
+            Some(accounts) => accounts,
+            None => return false,
+        };
+        for (address, account) in accounts {
+            if !state.account_state.contains_key(&address) {
+                state.address_list.push(address);
+            }
+            state.account_state.insert(address, account);
         }
+        true
     }
 }
 
@@ -111,12 +538,358 @@ impl SignedTransaction {
 #[cfg(any(test, test_utilities))]
     mod tests {
         use super::*;
+        use crate::block::{AccountState, INIT_COINS};
         use crate::crypto::key_pair;
+        use crate::genesis::GenesisConfig;
 
         pub fn generate_random_transaction() -> Transaction {
             Default::default()
         }
 
+        fn signed_transaction(key: &Ed25519KeyPair, recipient: H160, value: u64, account_nonce: u64) -> SignedTransaction {
+            let transaction = Transaction {
+                outputs: vec![(recipient, value)],
+                inputs: Vec::new(),
+                fee: 0,
+                account_nonce,
+                data: Vec::new(),
+                expires_at_block: None,
+                token: None,
+            };
+            let signature = sign(&transaction, key);
+            SignedTransaction {
+                transaction,
+                signature: signature.as_ref().to_vec(),
+                public_key: key.public_key().as_ref().to_vec(),
+                scheme: SignatureScheme::Ed25519,
+                multisig: None,
+                sender_cache: Default::default(),
+            }
+        }
+
+        #[test]
+        fn first_credit_creates_account() {
+            let alice = key_pair::random();
+            let bob = key_pair::random();
+            let tx = signed_transaction(&alice, H160::default(), 0, 1);
+            let mut state = State::default();
+            state.address_list.push(tx.sender());
+            state.account_state.insert(tx.sender(), AccountState { nonce: 0, balance: 10, ..Default::default() });
+
+            let bob_address = signed_transaction(&bob, H160::default(), 0, 1).sender();
+            let pay_bob = signed_transaction(&alice, bob_address, 4, 1);
+            assert!(pay_bob.is_valid(&state));
+            pay_bob.update_state(&mut state);
+            assert_eq!(state.address_list, vec![tx.sender(), bob_address]);
+            assert_eq!(state.account_state[&bob_address].balance, 4);
+            assert_eq!(state.account_state[&tx.sender()].balance, 6);
+
+            // the new account can spend what it received, but not more
+            assert!(signed_transaction(&bob, tx.sender(), 4, 1).is_valid(&state));
+            assert!(!signed_transaction(&bob, tx.sender(), 5, 1).is_valid(&state));
+        }
+
+        #[test]
+        fn fee_is_charged_to_sender() {
+            let alice = key_pair::random();
+            let mut tx = signed_transaction(&alice, H160::default(), 6, 1);
+            tx.transaction.fee = 4;
+            tx.signature = sign(&tx.transaction, &alice).as_ref().to_vec();
+            let mut state = State::default();
+            state.address_list.push(tx.sender());
+            state.account_state.insert(tx.sender(), AccountState { nonce: 0, balance: 10, ..Default::default() });
+            assert!(tx.is_valid(&state));
+            tx.update_state(&mut state);
+            assert_eq!(state.account_state[&tx.sender()].balance, 0);
+            assert_eq!(state.account_state[&H160::default()].balance, 6);
+
+            // value and fee together must be covered
+            tx.transaction.fee = 5;
+            tx.signature = sign(&tx.transaction, &alice).as_ref().to_vec();
+            assert!(tx.is_erasable(&State {
+                address_list: vec![tx.sender()],
+                account_state: vec![(tx.sender(), AccountState { nonce: 0, balance: 10, ..Default::default() })].into_iter().collect(),
+                ..Default::default()
+            }));
+        }
+
+        #[test]
+        fn batch_pays_every_output() {
+            let alice = key_pair::random();
+            let bob = key_pair::random();
+            let bob_address = signed_transaction(&bob, H160::default(), 0, 1).sender();
+            let mut tx = signed_transaction(&alice, bob_address, 3, 1);
+            tx.transaction.outputs.push((H160::default(), 2));
+            tx.transaction.outputs.push((bob_address, 1));
+            tx.transaction.outputs.push((tx.sender(), 4));
+            tx.signature = sign(&tx.transaction, &alice).as_ref().to_vec();
+            assert_eq!(tx.transaction.cost(), Some(10));
+            let mut state = State::default();
+            state.address_list.push(tx.sender());
+            state.account_state.insert(tx.sender(), AccountState { nonce: 0, balance: 10, ..Default::default() });
+            assert!(tx.is_valid(&state));
+            assert!(tx.update_state(&mut state));
+            assert_eq!(state.address_list, vec![tx.sender(), bob_address, H160::default()]);
+            assert_eq!(state.account_state[&tx.sender()], AccountState { nonce: 1, balance: 4, ..Default::default() });
+            assert_eq!(state.account_state[&bob_address].balance, 4);
+            assert_eq!(state.account_state[&H160::default()].balance, 2);
+
+            // the outputs must be covered together, and there must be some but not too many
+            tx.transaction.account_nonce = 2;
+            tx.transaction.outputs[0].1 = 5;
+            assert!(!tx.is_valid_in_state(&state));
+            tx.transaction.outputs = vec![];
+            assert!(!tx.is_valid_in_state(&state) && tx.is_erasable(&state));
+            tx.transaction.outputs = vec![(bob_address, 0); MAX_OUTPUTS + 1];
+            assert!(!tx.is_valid_in_state(&state) && tx.is_erasable(&state));
+            tx.transaction.outputs.pop();
+            assert!(tx.is_valid_in_state(&state));
+        }
+
+        #[test]
+        fn data_is_bounded_and_paid() {
+            let alice = key_pair::random();
+            let mut tx = signed_transaction(&alice, H160::default(), 1, 1);
+            tx.transaction.data = vec![7; MAX_DATA_SIZE];
+            let mut state = State::default();
+            state.account_state.insert(tx.sender(), AccountState { nonce: 0, balance: 1000, ..Default::default() });
+            // the fee must pay for every byte
+            tx.transaction.fee = tx.transaction.data_fee() - 1;
+            assert!(!tx.transaction.is_well_formed() && !tx.is_valid_in_state(&state));
+            tx.transaction.fee += 1;
+            assert!(tx.is_valid_in_state(&state));
+            tx.transaction.data.push(7);
+            tx.transaction.fee = tx.transaction.data_fee();
+            tx.signature = sign(&tx.transaction, &alice).as_ref().to_vec();
+            assert!(!tx.transaction.is_well_formed() && tx.is_erasable(&state));
+        }
+
+        #[test]
+        fn utxo_spends_whole_outputs() {
+            let config = GenesisConfig { model: Model::Utxo, ..Default::default() };
+            let (_, mut state) = config.build().unwrap();
+            let alice = key_pair::frombyte(0);
+            let alice_address = state.address_list[0];
+            let bob_address = state.address_list[1];
+            let genesis_output = OutPoint { txid: Default::default(), index: 0 };
+            assert_eq!(state.balance(&alice_address), INIT_COINS);
+            assert!(state.account_state.is_empty());
+            let spend = |inputs: Vec<OutPoint>, outputs: Vec<(H160, u64)>| {
+                let mut tx = signed_transaction(&alice, H160::default(), 0, 0);
+                tx.transaction.inputs = inputs;
+                tx.transaction.outputs = outputs;
+                tx.transaction.fee = 1;
+                tx.signature = sign(&tx.transaction, &alice).as_ref().to_vec();
+                tx
+            };
+
+            // the inputs pay exactly the outputs and the fee
+            assert!(!spend(vec![genesis_output], vec![(bob_address, 10)]).is_valid(&state));
+            let tx = spend(vec![genesis_output], vec![(bob_address, 10), (alice_address, 14)]);
+            assert!(tx.is_valid(&state));
+            // an output spent twice, one of someone else, or none at all
+            assert!(!spend(vec![genesis_output, genesis_output], vec![(bob_address, 49)]).is_valid(&state));
+            assert!(!spend(vec![OutPoint { index: 1, ..genesis_output }], vec![(bob_address, 24)]).is_valid(&state));
+            assert!(!spend(vec![], vec![(bob_address, 0)]).is_valid(&state));
+
+            assert!(tx.update_state(&mut state));
+            assert!(tx.is_erasable(&state));
+            assert_eq!(state.balance(&alice_address), 14);
+            assert_eq!(state.balance(&bob_address), INIT_COINS + 10);
+            let change = OutPoint { txid: tx.hash(), index: 1 };
+            assert!(spend(vec![change], vec![(bob_address, 13)]).is_valid(&state));
+
+            // the transactions of one model are invalid in the other
+            let (_, account_state) = GenesisConfig::default().build().unwrap();
+            assert!(!spend(vec![genesis_output], vec![(bob_address, 24)]).is_valid(&account_state));
+            assert!(!signed_transaction(&alice, bob_address, 1, 1).is_valid(&state));
+        }
+
+        #[test]
+        fn multisig_needs_threshold() {
+            let keys: Vec<Ed25519KeyPair> = (0..3).map(|_| key_pair::random()).collect();
+            let public_keys: Vec<Vec<u8>> = keys.iter().map(|key| key.public_key().as_ref().to_vec()).collect();
+            let policy = MultisigPolicy::new(2, public_keys.iter().rev().cloned().collect());
+            // one address per threshold and set of keys, whatever their order
+            assert!(policy.is_well_formed());
+            assert_eq!(policy.address(), MultisigPolicy::new(2, public_keys.clone()).address());
+            assert_ne!(policy.address(), MultisigPolicy::new(1, public_keys.clone()).address());
+            assert!(!MultisigPolicy::new(4, public_keys).is_well_formed());
+
+            let mut tx = SignedTransaction {
+                transaction: Transaction { outputs: vec![(H160::default(), 4)], account_nonce: 1, ..Default::default() },
+                multisig: Some(Multisig::new(policy.clone())),
+                sender_cache: Default::default(),
+                ..Default::default()
+            };
+            let mut state = State::default();
+            state.account_state.insert(policy.address(), AccountState { nonce: 0, balance: 10, ..Default::default() });
+            assert_eq!(tx.sender(), policy.address());
+            let multisig = tx.multisig.as_mut().unwrap();
+            assert!(multisig.sign(&tx.transaction, &keys[0]));
+            // a key counts once, and only the keys of the policy count
+            assert!(multisig.sign(&tx.transaction, &keys[0]));
+            assert!(!multisig.sign(&tx.transaction, &key_pair::random()));
+            assert!(!tx.is_valid(&state));
+            tx.multisig.as_mut().unwrap().sign(&tx.transaction, &keys[2]);
+            assert!(tx.is_valid(&state));
+            // the cosigners sign with Ed25519
+            tx.scheme = SignatureScheme::Secp256k1;
+            assert!(!tx.has_valid_signature());
+            tx.scheme = SignatureScheme::Ed25519;
+
+            tx.transaction.outputs[0].1 = 5;
+            assert!(!tx.has_valid_signature());
+        }
+
+        #[test]
+        fn tokens_are_issued_and_transferred() {
+            let alice = key_pair::random();
+            let bob = key_pair::random();
+            let bob_address = signed_transaction(&bob, H160::default(), 0, 1).sender();
+            let mut issue = signed_transaction(&alice, bob_address, 30, 1);
+            issue.transaction.outputs.push((issue.sender(), 70));
+            issue.transaction.fee = 1;
+            issue.transaction.token = Some(TokenOp::Create);
+            let alice_address = issue.sender();
+            let token = token_id(&alice_address, 1);
+            let mut state = State::default();
+            state.account_state.insert(alice_address, AccountState { nonce: 0, balance: 1, ..Default::default() });
+            // only the fee is paid in coins
+            assert_eq!(issue.transaction.cost(), Some(1));
+            assert!(issue.update_state(&mut state));
+            assert_eq!(state.account_state[&alice_address].balance, 0);
+            assert_eq!(state.account_state[&alice_address].tokens[&token], 70);
+            assert_eq!(state.account_state[&bob_address].tokens[&token], 30);
+            assert_eq!(state.account_state[&bob_address].balance, 0);
+
+            // a transfer is covered by the token balance
+            let mut transfer = signed_transaction(&bob, alice_address, 31, 1);
+            transfer.transaction.token = Some(TokenOp::Transfer(token));
+            assert!(!transfer.is_valid_in_state(&state));
+            transfer.transaction.outputs[0].1 = 30;
+            assert!(transfer.update_state(&mut state));
+            assert!(state.account_state[&bob_address].tokens.is_empty());
+            assert_eq!(state.account_state[&alice_address].tokens[&token], 100);
+            // a token nobody holds cannot be paid
+            let mut unknown = signed_transaction(&alice, bob_address, 1, 2);
+            unknown.transaction.token = Some(TokenOp::Transfer(H160::default()));
+            assert!(!unknown.is_valid_in_state(&state));
+        }
+
+        #[test]
+        fn sender_is_cached_but_not_serialized() {
+            let alice = key_pair::random();
+            let tx = signed_transaction(&alice, H160::default(), 1, 1);
+            let hash = tx.hash();
+            let size = tx.gas();
+            let sender = tx.sender();
+            assert_eq!(tx.sender_cache.get(), Some(&sender));
+            assert_eq!(tx.hash(), hash);
+            assert_eq!(tx.gas(), size);
+            let received: SignedTransaction = bincode::deserialize(&bincode::serialize(&tx).unwrap()).unwrap();
+            assert!(received.sender_cache.get().is_none());
+            assert_eq!(received.sender(), sender);
+        }
+
+        #[test]
+        fn json_is_hex_and_bincode_unchanged() {
+            let alice = key_pair::random();
+            let mut tx = signed_transaction(&alice, [0xab; 20].into(), 1, 1);
+            tx.transaction.data = vec![0xca, 0xfe];
+            tx.signature = sign(&tx.transaction, &alice).as_ref().to_vec();
+            let bytes = bincode::serialize(&tx).unwrap();
+            let json = serde_json::to_value(&tx).unwrap();
+            assert_eq!(json["transaction"]["outputs"][0][0], "abababababababababababababababababababab");
+            assert_eq!(json["transaction"]["data"], "cafe");
+            assert_eq!(json["signature"], hex::encode(&tx.signature));
+            let parsed: SignedTransaction = serde_json::from_value(json).unwrap();
+            assert_eq!(bincode::serialize(&parsed).unwrap(), bytes);
+            assert!(parsed.has_valid_signature());
+            // the hashes of the binary form go on 32 bytes, without a length prefix
+            assert_eq!(bincode::serialize(&tx.hash()).unwrap().len(), 32);
+            assert_eq!(bincode::serialize(&tx.sender()).unwrap().len(), 20);
+        }
+
+        #[test]
+        fn unknown_sender_is_empty_account() {
+            let carol = key_pair::random();
+            let state = State::default();
+            assert!(signed_transaction(&carol, H160::default(), 1, 1).is_erasable(&state));
+            assert!(signed_transaction(&carol, H160::default(), 0, 1).is_valid(&state));
+        }
+
+        #[test]
+        fn signature_checked_apart_from_state() {
+            let alice = key_pair::random();
+            let mut tx = signed_transaction(&alice, H160::default(), 1, 1);
+            let mut state = State::default();
+            state.account_state.insert(tx.sender(), AccountState { nonce: 0, balance: 10, ..Default::default() });
+            assert!(tx.has_valid_signature() && tx.is_valid(&state));
+            tx.transaction.outputs[0].1 = 2;
+            assert!(!tx.has_valid_signature());
+            assert!(!tx.is_valid(&state));
+            assert!(tx.is_valid_in_state(&state));
+        }
+
+        #[test]
+        fn signed_offline() {
+            let key = key_pair::random();
+            let transaction = signed_transaction(&key, H160::default(), 3, 1).transaction;
+            let bytes = transaction.to_bytes();
+            let exported = Transaction::from_bytes(&bytes).unwrap();
+            assert!(Transaction::from_bytes(&[bytes.clone(), vec![0]].concat()).is_none());
+            assert!(Transaction::from_bytes(&bytes[1..]).is_none());
+
+            // the external signer signs the digest of the bytes
+            let digest = ring::digest::digest(&ring::digest::SHA256, &bytes);
+            let signature = key.sign(digest.as_ref()).as_ref().to_vec();
+            let public_key = key.public_key().as_ref().to_vec();
+            let tx = SignedTransaction::assemble(exported.clone(), SignatureScheme::Ed25519, signature.clone(), public_key.clone()).unwrap();
+            assert_eq!(tx.hash(), signed_transaction(&key, H160::default(), 3, 1).hash());
+            assert!(SignedTransaction::assemble(exported, SignatureScheme::Ed25519, signature, key_pair::random().public_key().as_ref().to_vec()).is_none());
+        }
+
+        #[test]
+        fn secp256k1_sender() {
+            let key = secp256k1::random();
+            let transaction = Transaction { outputs: vec![(H160::default(), 3)], account_nonce: 1, ..Default::default() };
+            let signature = secp256k1::sign(transaction.hash().as_ref(), &key);
+            let public_key = secp256k1::public_key(&key);
+            let tx = SignedTransaction::assemble(transaction.clone(), SignatureScheme::Secp256k1, signature.clone(), public_key.clone()).unwrap();
+            assert_eq!(tx.sender(), secp256k1::address(&public_key).unwrap());
+            let mut state = State::default();
+            state.account_state.insert(tx.sender(), AccountState { nonce: 0, balance: 10, ..Default::default() });
+            assert!(tx.is_valid(&state));
+            // the scheme is part of what the signature is checked against
+            assert!(SignedTransaction::assemble(transaction, SignatureScheme::Ed25519, signature, public_key).is_none());
+        }
+
+        #[test]
+        fn overflow_is_invalid() {
+            let alice = key_pair::random();
+            let bob = key_pair::random();
+            let bob_address = signed_transaction(&bob, H160::default(), 0, 1).sender();
+            let mut tx = signed_transaction(&alice, bob_address, u64::MAX, 1);
+            tx.transaction.fee = 1;
+            let mut state = State::default();
+            state.account_state.insert(tx.sender(), AccountState { nonce: 0, balance: u64::MAX, ..Default::default() });
+            state.account_state.insert(bob_address, AccountState { nonce: 0, balance: 1, ..Default::default() });
+            // the cost overflows
+            assert!(!tx.is_valid_in_state(&state));
+            assert!(tx.is_erasable(&state));
+            // the recipient balance overflows
+            tx.transaction.fee = 0;
+            assert!(!tx.is_valid_in_state(&state));
+            assert!(!tx.update_state(&mut state));
+            assert_eq!(state.account_state[&tx.sender()].balance, u64::MAX);
+            // the sender nonce cannot move past the last one
+            state.account_state.insert(tx.sender(), AccountState { nonce: u64::MAX, balance: 1, ..Default::default() });
+            tx.transaction.outputs[0].1 = 0;
+            tx.transaction.account_nonce = 0;
+            assert!(!tx.is_valid_in_state(&state));
+        }
+
         #[test]
         fn sign_verify() {
             for _ in 0..20 {
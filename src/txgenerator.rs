@@ -1,47 +1,121 @@
 use std::thread;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use std::time;
 use rand::Rng;
 use log::{info, debug};
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
-use crate::transaction::{SignedTransaction, Transaction, sign};
+use crate::transaction::{SignedTransaction, SignatureScheme, Transaction, sign, MAX_INPUTS, MAX_OUTPUTS};
 use crate::network::server::Handle as ServerHandle;
 use crate::network::message::Message;
-use crate::crypto::hash::{Hashable, H256};
+use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::address::H160;
-use crate::miner::{Identity, OperatingState, ControlSignal, Handle};
+use crate::miner::{Identity, OperatingState};
 use crate::blockchain::{Blockchain};
-use rand::seq::IteratorRandom;
-use rand::thread_rng;
+use crate::block::{Model, Utxo};
+use crate::mempool::Mempool;
+use crate::fee::FeeEstimator;
+use crate::clock;
+use crate::events::{EventBus, NodeEvent};
 
 static GEN_INTERVAL: u64 = 10000;
-pub static TX_MEMPOOL_CAPACITY: usize = 1000;
+/// Number of blocks the generated transactions aim to be confirmed within.
+static FEE_TARGET_BLOCKS: u32 = 3;
+/// Generated transactions not confirmed yet past which the generator slows down.
+pub static MAX_IN_FLIGHT: usize = 256;
+/// Most times the interval between two transactions is stretched by the flow control.
+static MAX_SLOWDOWN: u64 = 64;
+
+pub enum ControlSignal {
+    /// Generate a transaction every lambda microseconds, every `GEN_INTERVAL` for 0
+    Start(u64),
+    Exit,
+    /// Change the lambda, taking effect at once when running, on `Resume` when paused
+    SetRate(u64),
+    /// Stop until `Resume`, keeping the lambda
+    Pause,
+    /// Run again at the last lambda after `Pause`
+    Resume,
+    /// Pay these addresses only, every other address of the state when empty
+    SetTargets(Vec<H160>),
+    SetValues(ValueDistribution),
+    /// Generate this many transactions back to back, then go on at the lambda
+    Burst(usize),
+}
+
+/// The value paid to each recipient of a generated transaction.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueDistribution {
+    /// Half the balance of the sender, split between the recipients.
+    HalfBalance,
+    Fixed(u64),
+    /// Drawn uniformly from `min` to `max`, both included.
+    Uniform(u64, u64),
+}
+
+#[derive(Clone)]
+pub struct Handle {
+    /// Channel for sending signal to the generator thread
+    pub control_chan: Sender<ControlSignal>,
+}
 
 pub struct Context {
     server: ServerHandle,
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
-    blockchain: Arc<Mutex<Blockchain>>,
-    tx_mempool: Arc<Mutex<HashMap<H256,SignedTransaction>>>,
-    id: Arc<Identity>,
+    /// The lambda of the last `Start` or `SetRate`, to resume with.
+    lambda: Option<u64>,
+    blockchain: Arc<Blockchain>,
+    tx_mempool: Arc<Mutex<Mempool>>,
+    events: Arc<EventBus>,
+    /// The accounts the transactions are sent from, in turn.
+    accounts: Vec<Arc<Identity>>,
+    next_account: usize,
+    batch_size: usize,
+    /// Sets the fee of the generated transactions, the minimum fee of the mempool without it.
+    fee_estimator: Option<Arc<FeeEstimator>>,
+    /// The addresses paid, every other address of the state when empty.
+    targets: Vec<H160>,
+    values: ValueDistribution,
+    /// Transactions left to generate without waiting.
+    burst: usize,
+    /// Nonce -> (hash, cost) of the transactions of each account not confirmed yet, in the
+    /// account model.
+    in_flight: HashMap<H160, BTreeMap<u64, (H256, u64)>>,
+    /// Generated transactions the mempool dropped since the last `adjust_slowdown`.
+    dropped: usize,
+    /// How many times the lambda the generator waits between two transactions, see
+    /// `adjust_slowdown`.
+    slowdown: u64,
 }
 
 pub fn new (
     server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>,
-    tx_mempool: &Arc<Mutex<HashMap<H256,SignedTransaction>>>,
+    blockchain: &Arc<Blockchain>,
+    tx_mempool: &Arc<Mutex<Mempool>>,
+    events: &Arc<EventBus>,
     id: &Arc<Identity>,
     ) -> (Context, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let ctx = Context {
         control_chan: signal_chan_receiver,
         operating_state: OperatingState::Paused,
+        lambda: None,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
         tx_mempool: Arc::clone(tx_mempool),
-        id: Arc::clone(id),
+        events: Arc::clone(events),
+        accounts: vec![Arc::clone(id)],
+        next_account: 0,
+        batch_size: 1,
+        fee_estimator: None,
+        targets: Vec::new(),
+        values: ValueDistribution::HalfBalance,
+        burst: 0,
+        in_flight: HashMap::new(),
+        dropped: 0,
+        slowdown: 1,
     };
 
     let handle = Handle {
@@ -51,7 +125,118 @@ pub fn new (
     (ctx, handle)
 }
 
+impl Handle {
+    /// Stop the generator. Does nothing if it has stopped already.
+    pub fn exit(&self) {
+        let _ = self.control_chan.send(ControlSignal::Exit);
+    }
+
+    pub fn start(&self, lambda: u64) {
+        self.control_chan
+            .send(ControlSignal::Start(lambda))
+            .unwrap();
+    }
+
+    /// Change the lambda without stopping. Does nothing if it has stopped already.
+    pub fn set_rate(&self, lambda: u64) {
+        let _ = self.control_chan.send(ControlSignal::SetRate(lambda));
+    }
+
+    /// Stop until `resume`, keeping the lambda. Does nothing if it has stopped already.
+    pub fn pause(&self) {
+        let _ = self.control_chan.send(ControlSignal::Pause);
+    }
+
+    /// Run again at the last lambda after `pause`. Does nothing if it was never started.
+    pub fn resume(&self) {
+        let _ = self.control_chan.send(ControlSignal::Resume);
+    }
+
+    /// Pay `targets` only, every other address of the state when empty.
+    pub fn set_targets(&self, targets: Vec<H160>) {
+        let _ = self.control_chan.send(ControlSignal::SetTargets(targets));
+    }
+
+    pub fn set_values(&self, values: ValueDistribution) {
+        let _ = self.control_chan.send(ControlSignal::SetValues(values));
+    }
+
+    /// Generate `count` transactions back to back once running.
+    pub fn burst(&self, count: usize) {
+        let _ = self.control_chan.send(ControlSignal::Burst(count));
+    }
+}
+
 impl Context {
+    /// Pay `batch_size` recipients in each generated transaction, the way an exchange batches
+    /// its withdrawals. At most `MAX_OUTPUTS - 1`, leaving an output for the change in the UTXO
+    /// model.
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size.max(1).min(MAX_OUTPUTS - 1);
+    }
+
+    /// Pay the fee `estimator` estimates for a confirmation within `FEE_TARGET_BLOCKS` blocks,
+    /// at least the minimum fee of the mempool.
+    pub fn set_fee_estimator(&mut self, estimator: &Arc<FeeEstimator>) {
+        self.fee_estimator = Some(Arc::clone(estimator));
+    }
+
+    /// The fee of the next transaction.
+    fn fee(&self) -> u64 {
+        let min_fee = self.tx_mempool.lock().unwrap().min_fee();
+        let estimate = self.fee_estimator.as_ref().and_then(|estimator| estimator.estimate_fee(FEE_TARGET_BLOCKS));
+        estimate.unwrap_or(0).max(min_fee)
+    }
+
+    /// The next nonce of `address`, whose last confirmed nonce is `confirmed`, and what its
+    /// transactions not confirmed yet leave of `balance`. Forgets the transactions confirmed
+    /// since, and rolls back to the nonce of the ones the mempool dropped.
+    fn next_nonce(&mut self, address: &H160, confirmed: u64, balance: u64) -> (u64, u64) {
+        let tx_mempool = self.tx_mempool.lock().unwrap();
+        let in_flight = self.in_flight.entry(*address).or_default();
+        let mut dropped = 0;
+        in_flight.retain(|nonce, (hash, _)| {
+            let is_dropped = *nonce > confirmed && !tx_mempool.contains_key(hash);
+            if is_dropped {
+                debug!("Generated transaction {} with nonce {} dropped, issuing the nonce again", hash, nonce);
+                dropped += 1;
+            }
+            *nonce > confirmed && !is_dropped
+        });
+        self.dropped += dropped;
+        let spent = in_flight.values().fold(0u64, |sum, (_, cost)| sum.saturating_add(*cost));
+        // the first free nonce, skipping the transactions a reorg returned to the mempool
+        let nonce = (confirmed + 1..).find(|nonce| !tx_mempool.has_nonce(address, *nonce)).unwrap();
+        (nonce, balance.saturating_sub(spent))
+    }
+
+    /// Double the slowdown when too many generated transactions wait for a block or the mempool
+    /// dropped some, and go back to the lambda once the mempool is empty.
+    fn adjust_slowdown(&mut self) {
+        let in_flight: usize = self.in_flight.values().map(|in_flight| in_flight.len()).sum();
+        if in_flight > MAX_IN_FLIGHT || self.dropped > 0 {
+            self.slowdown = (self.slowdown * 2).min(MAX_SLOWDOWN);
+            debug!("TXgenerator slowing down {} times: {} transactions in flight, {} dropped", self.slowdown, in_flight, self.dropped);
+        } else if self.tx_mempool.lock().unwrap().is_empty() {
+            self.slowdown = 1;
+        }
+        self.dropped = 0;
+    }
+
+    /// Send transactions from `account` too, taking turns with the accounts added before.
+    pub fn add_account(&mut self, account: Arc<Identity>) {
+        if self.accounts.iter().all(|id| id.address != account.address) {
+            self.accounts.push(account);
+        }
+    }
+
+    /// The account to send the next transaction from.
+    fn select_account(&mut self) -> Arc<Identity> {
+        let account = Arc::clone(&self.accounts[self.next_account % self.accounts.len()]);
+        self.next_account = (self.next_account + 1) % self.accounts.len();
+        account
+    }
+
     pub fn start(mut self) {
         thread::Builder::new()
             .name("txgenerator".to_string())
@@ -70,16 +255,45 @@ impl Context {
             }
             ControlSignal::Start(i) => {
                 info!("TXgenerator starting in continuous mode with lambda {}", i);
+                self.lambda = Some(i);
                 self.operating_state = OperatingState::Run(i);
             }
+            ControlSignal::SetRate(i) => {
+                info!("TXgenerator lambda set to {}", i);
+                self.lambda = Some(i);
+                if let OperatingState::Run(_) = self.operating_state {
+                    self.operating_state = OperatingState::Run(i);
+                }
+            }
+            ControlSignal::Pause => {
+                if let OperatingState::Run(_) = self.operating_state {
+                    info!("TXgenerator paused");
+                    self.operating_state = OperatingState::Paused;
+                }
+            }
+            ControlSignal::Resume => {
+                if let (OperatingState::Paused, Some(i)) = (&self.operating_state, self.lambda) {
+                    info!("TXgenerator resuming with lambda {}", i);
+                    self.operating_state = OperatingState::Run(i);
+                }
+            }
+            ControlSignal::SetTargets(targets) => {
+                info!("TXgenerator paying {} target addresses", targets.len());
+                self.targets = targets;
+            }
+            ControlSignal::SetValues(values) => {
+                info!("TXgenerator values set to {:?}", values);
+                self.values = values;
+            }
+            ControlSignal::Burst(count) => {
+                info!("TXgenerator bursting {} transactions", count);
+                self.burst = count;
+            }
         }
     }
 
     pub fn gen_loop(&mut self) {
         let mut txs_hash_buffer: Vec<H256> = Vec::new();
-        let _id = self.id.clone();
-        let public_key = (*_id).key_pair.public_key();
-        let self_address = (*_id).address;
         loop {
             // check and react to control signals
             match self.operating_state {
@@ -105,63 +319,106 @@ impl Context {
                 txs_hash_buffer.clear();
             }
             */
-            if let Ok(chain) = self.blockchain.lock(){
-                let tip_hash = chain.tip();
-                if let Some(state) = chain.get_state(&tip_hash) {
-                    // get the latest state of my account
-                    if let Some(self_state) = state.account_state.get(&self_address) {
-                        let balance = self_state.balance;
-                        let nonce = self_state.nonce;
-                        // already generate transactions for this block, skip
-                        // if last_nonce == nonce {
-                        //     let interval = time::Duration::from_micros(GEN_INTERVAL);
-                        //     thread::sleep(interval);
-                        //     continue;
-                        // }
-                        // last_nonce = nonce;
-                        // generate transactions for this block
-                        // simply send 1/(2*num_peer) * balance to all other peers
-                        let mut peer_address: Vec<H160> = Vec::new();
-                        for address in state.address_list.iter() {
-                            if address == &self_address {
-                                continue;
-                            }
-                            peer_address.push(address.clone());
-                        }
-                        let mut rng = rand::thread_rng();
-                        let receiver = peer_address[rng.gen_range(0, peer_address.len())];
-                        let tx = Transaction {
-                            recipient_address: receiver,
-                            value: balance as u64 / 2,
-                            account_nonce: nonce+1
-                        };
-                        let signature = sign(&tx, &(*self.id).key_pair);
-                        let signed_tx = SignedTransaction {
-                            transaction: tx,
-                            signature: signature.as_ref().iter().cloned().collect(),
-                            public_key: public_key.as_ref().iter().cloned().collect()
-                        };
-                        //txs_hash_buffer.push(signed_tx.hash());
-
-                        //info!("Generate Tx: {:#?}", signed_tx.transaction);
-                        if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
-                            if _tx_mempool.len() >= TX_MEMPOOL_CAPACITY{
-                                let random_key = {
-                                    let mut rng = thread_rng();
-                                    _tx_mempool.keys().choose(&mut rng).unwrap().clone()
-                                };
-                                _tx_mempool.remove(&random_key);
-                            }
-                            _tx_mempool.insert(signed_tx.hash(), signed_tx.clone());
-                            self.server.broadcast(Message::Transactions(vec![signed_tx]));
-                            //debug!("tx_pool size: {:?}", _tx_mempool.len());
-                            //self.server.broadcast(Message::NewTransactionHashes(vec![signed_tx.hash()]));
+            let (_, state) = self.blockchain.tip_with_state();
+            let id = self.select_account();
+            let self_address = id.address;
+            // get the latest state of my account, or my unspent outputs
+            let spendable = match state.model {
+                Model::Account => state.account_state.get(&self_address).cloned().map(|account| {
+                    let (nonce, balance) = self.next_nonce(&self_address, account.nonce, account.balance);
+                    (Vec::new(), balance, nonce)
+                }),
+                Model::Utxo => {
+                    let utxos: Vec<&Utxo> = state.unspent_of(&self_address).take(MAX_INPUTS).collect();
+                    if utxos.is_empty() {
+                        None
+                    } else {
+                        let balance = utxos.iter().fold(0u64, |sum, utxo| sum.saturating_add(utxo.value));
+                        Some((utxos.iter().map(|utxo| utxo.outpoint).collect(), balance, 0))
+                    }
+                }
+            };
+            if let Some((inputs, balance, account_nonce)) = spendable {
+                // already generate transactions for this block, skip
+                // if last_nonce == nonce {
+                //     let interval = time::Duration::from_micros(GEN_INTERVAL);
+                //     thread::sleep(interval);
+                //     continue;
+                // }
+                // last_nonce = nonce;
+                // generate transactions for this block
+                // pay the targets, or all other peers
+                let mut peer_address: Vec<H160> = Vec::new();
+                for address in state.address_list.iter() {
+                    if address == &self_address {
+                        continue;
+                    }
+                    peer_address.push(*address);
+                }
+                if !self.targets.is_empty() {
+                    peer_address = self.targets.clone();
+                }
+                let mut rng = clock::rng();
+                let mut outputs: Vec<(H160, u64)> = (0..self.batch_size).map(|_| {
+                    let receiver = peer_address[rng.gen_range(0, peer_address.len())];
+                    let value = match self.values {
+                        ValueDistribution::HalfBalance => balance / 2 / self.batch_size as u64,
+                        ValueDistribution::Fixed(value) => value,
+                        ValueDistribution::Uniform(min, max) => rng.gen_range(min, max.max(min) + 1),
+                    };
+                    (receiver, value)
+                }).collect();
+                let fee = self.fee();
+                // the outputs are spent whole, the rest comes back as change
+                if state.model == Model::Utxo {
+                    let change = balance.saturating_sub(outputs.iter().map(|(_, value)| value).sum::<u64>()).saturating_sub(fee);
+                    outputs.push((self_address, change));
+                }
+                let tx = Transaction {
+                    inputs,
+                    outputs,
+                    fee,
+                    account_nonce,
+                    data: Vec::new(),
+                    expires_at_block: None,
+                    token: None,
+                };
+                let signature = sign(&tx, &id.key_pair);
+                let signed_tx = SignedTransaction {
+                    transaction: tx,
+                    signature: signature.as_ref().to_vec(),
+                    public_key: id.key_pair.public_key().as_ref().to_vec(),
+                    scheme: SignatureScheme::Ed25519,
+                    multisig: None,
+                    sender_cache: Default::default(),
+                };
+                //txs_hash_buffer.push(signed_tx.hash());
+
+                //info!("Generate Tx: {:#?}", signed_tx.transaction);
+                if let Ok(mut _tx_mempool) = self.tx_mempool.lock() {
+                    if _tx_mempool.insert(signed_tx.clone(), &state).is_ok() {
+                        if state.model == Model::Account {
+                            let cost = signed_tx.transaction.cost().unwrap_or(u64::MAX);
+                            self.in_flight.entry(self_address).or_default().insert(account_nonce, (signed_tx.hash(), cost));
                         }
+                        self.events.publish(NodeEvent::TxGenerated(signed_tx.hash()));
+                        self.server.broadcast(Message::Transactions(vec![signed_tx]));
                     }
+                    //debug!("tx_pool size: {:?}", _tx_mempool.len());
+                    //self.server.broadcast(Message::NewTransactionHashes(vec![signed_tx.hash()]));
                 }
             }
-            let interval = time::Duration::from_micros(GEN_INTERVAL);
-            thread::sleep(interval);
+            if self.burst > 0 {
+                self.burst -= 1;
+                continue;
+            }
+            // lambda is the interval between two transactions in microseconds, 0 for the default
+            let interval = match self.operating_state {
+                OperatingState::Run(i) if i > 0 => i,
+                _ => GEN_INTERVAL,
+            };
+            self.adjust_slowdown();
+            clock::sleep(time::Duration::from_micros(interval.saturating_mul(self.slowdown)));
         }
     }
 }
@@ -0,0 +1,101 @@
+//! Hierarchical deterministic keys: many Ed25519 keys derived from a single seed along BIP32
+//! style paths, following SLIP-0010. Ed25519 only allows hardened derivation, so every index of
+//! a path is hardened, `m/44'/0'/3'` and `m/44/0/3` naming the same key.
+
+use ring::hmac;
+use ring::signature::Ed25519KeyPair;
+use std::io::{Error, ErrorKind, Result};
+
+/// Offset of the hardened indices.
+pub static HARDENED: u32 = 1 << 31;
+/// Length of a random seed, in bytes.
+pub static SEED_LEN: usize = 32;
+
+/// A private key along with the chain code its children are derived with.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ExtendedKey {
+    pub key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+fn split(tag: hmac::Tag) -> ExtendedKey {
+    let mut key = [0u8; 32];
+    let mut chain_code = [0u8; 32];
+    key.copy_from_slice(&tag.as_ref()[..32]);
+    chain_code.copy_from_slice(&tag.as_ref()[32..]);
+    ExtendedKey { key, chain_code }
+}
+
+impl ExtendedKey {
+    /// The root key of `seed`.
+    pub fn master(seed: &[u8]) -> ExtendedKey {
+        let key = hmac::Key::new(hmac::HMAC_SHA512, b"ed25519 seed");
+        split(hmac::sign(&key, seed))
+    }
+
+    /// The hardened child `index`.
+    pub fn child(&self, index: u32) -> ExtendedKey {
+        let key = hmac::Key::new(hmac::HMAC_SHA512, &self.chain_code);
+        let mut data = Vec::with_capacity(37);
+        data.push(0);
+        data.extend_from_slice(&self.key);
+        data.extend_from_slice(&(index | HARDENED).to_be_bytes());
+        split(hmac::sign(&key, &data))
+    }
+
+    /// The key at `path` below the root key of `seed`.
+    pub fn derive(seed: &[u8], path: &str) -> Result<ExtendedKey> {
+        Ok(parse_path(path)?.into_iter().fold(ExtendedKey::master(seed), |key, index| key.child(index)))
+    }
+
+    pub fn key_pair(&self) -> Ed25519KeyPair {
+        Ed25519KeyPair::from_seed_unchecked(&self.key).unwrap()
+    }
+}
+
+/// The indices of a path such as `m/44'/0'/3'`, without the hardened offset.
+pub fn parse_path(path: &str) -> Result<Vec<u32>> {
+    let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid derivation path {}", path));
+    let mut parts = path.split('/');
+    if parts.next() != Some("m") {
+        return Err(invalid());
+    }
+    parts.map(|part| {
+        let index: u32 = part.trim_end_matches(['\'', 'h']).parse().map_err(|_| invalid())?;
+        if index >= HARDENED {
+            return Err(invalid());
+        }
+        Ok(index)
+    }).collect()
+}
+
+/// Path of the `index`th account of a wallet.
+pub fn account_path(index: u32) -> String {
+    format!("m/44'/0'/{}'", index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slip10_test_vector() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedKey::master(&seed);
+        assert_eq!(hex::encode(master.key), "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7");
+        assert_eq!(hex::encode(master.chain_code), "90046a93de5380a72b5e45010748567d5ea02bbf6522f979e05c0d8d8ca9fffb");
+        let child = ExtendedKey::derive(&seed, "m/0'").unwrap();
+        assert_eq!(hex::encode(child.key), "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3");
+        assert_eq!(hex::encode(child.chain_code), "8b59aa11380b624e81507a27fedda59fea6d0b779a778918a2fd3590e16e9c69");
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(parse_path("m").unwrap(), Vec::<u32>::new());
+        assert_eq!(parse_path("m/44'/0h/3").unwrap(), vec![44, 0, 3]);
+        assert!(parse_path("44'/0'").is_err());
+        assert!(parse_path("m/x").is_err());
+        assert!(parse_path("m/2147483648").is_err());
+        assert_eq!(ExtendedKey::derive(&[1; 32], &account_path(3)).unwrap(), ExtendedKey::master(&[1; 32]).child(44).child(0).child(3));
+    }
+}
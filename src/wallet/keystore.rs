@@ -0,0 +1,113 @@
+use serde::{Serialize, Deserialize};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroU32;
+use std::path::Path;
+
+pub static KEYFILE_VERSION: u32 = 1;
+pub static PBKDF2_ITERATIONS: u32 = 100_000;
+static SALT_LEN: usize = 16;
+static KEY_LEN: usize = 32;
+
+/// A key file on disk. The secret of the key, the PKCS#8 document of the key pair or the seed of
+/// a derived key, is encrypted with ChaCha20-Poly1305 under a key derived from the passphrase
+/// with PBKDF2-HMAC-SHA256.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyFile {
+    pub version: u32,
+    /// Hex encoded address of the key, so a key can be found without the passphrase.
+    pub address: String,
+    pub iterations: u32,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+fn invalid_data(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Result<LessSafeKey> {
+    let iterations = NonZeroU32::new(iterations).ok_or_else(|| invalid_data("zero pbkdf2 iterations"))?;
+    let mut key = vec![0u8; KEY_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, passphrase.as_bytes(), &mut key);
+    let unbound = UnboundKey::new(&CHACHA20_POLY1305, &key).map_err(|_| invalid_data("bad key length"))?;
+    Ok(LessSafeKey::new(unbound))
+}
+
+/// Encrypt a PKCS#8 document with the passphrase.
+pub fn encrypt(pkcs8: &[u8], address: &str, passphrase: &str) -> Result<KeyFile> {
+    encrypt_with_iterations(pkcs8, address, passphrase, PBKDF2_ITERATIONS)
+}
+
+pub fn encrypt_with_iterations(pkcs8: &[u8], address: &str, passphrase: &str, iterations: u32) -> Result<KeyFile> {
+    let rng = SystemRandom::new();
+    let mut salt = vec![0u8; SALT_LEN];
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill(&mut salt).map_err(|_| Error::other("system randomness unavailable"))?;
+    rng.fill(&mut nonce).map_err(|_| Error::other("system randomness unavailable"))?;
+
+    let key = derive_key(passphrase, &salt, iterations)?;
+    let mut in_out = pkcs8.to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut in_out)
+        .map_err(|_| Error::other("encryption failed"))?;
+
+    Ok(KeyFile {
+        version: KEYFILE_VERSION,
+        address: address.to_string(),
+        iterations,
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        ciphertext: hex::encode(in_out),
+    })
+}
+
+/// Decrypt the PKCS#8 document of a key file. Fails on a wrong passphrase or a corrupted file.
+pub fn decrypt(file: &KeyFile, passphrase: &str) -> Result<Vec<u8>> {
+    if file.version != KEYFILE_VERSION {
+        return Err(invalid_data("unsupported key file version"));
+    }
+    let salt = hex::decode(&file.salt).map_err(|_| invalid_data("bad salt encoding"))?;
+    let nonce = hex::decode(&file.nonce).map_err(|_| invalid_data("bad nonce encoding"))?;
+    let mut in_out = hex::decode(&file.ciphertext).map_err(|_| invalid_data("bad ciphertext encoding"))?;
+    let nonce = Nonce::try_assume_unique_for_key(&nonce).map_err(|_| invalid_data("bad nonce length"))?;
+
+    let key = derive_key(passphrase, &salt, file.iterations)?;
+    let plaintext = key.open_in_place(nonce, Aad::empty(), &mut in_out)
+        .map_err(|_| invalid_data("wrong passphrase or corrupted key file"))?;
+    Ok(plaintext.to_vec())
+}
+
+pub fn read(path: &Path) -> Result<KeyFile> {
+    let bytes = std::fs::read(path)?;
+    serde_json::from_slice(&bytes).map_err(|e| invalid_data(&e.to_string()))
+}
+
+pub fn write(path: &Path, file: &KeyFile) -> Result<()> {
+    let bytes = serde_json::to_vec_pretty(file).map_err(|e| invalid_data(&e.to_string()))?;
+    std::fs::write(path, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_decrypt() {
+        let secret = b"not really a pkcs8 document";
+        let file = encrypt_with_iterations(secret, "00", "passphrase", 10).unwrap();
+        assert_eq!(decrypt(&file, "passphrase").unwrap(), secret.to_vec());
+        assert!(decrypt(&file, "wrong passphrase").is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext() {
+        let mut file = encrypt_with_iterations(b"secret", "00", "passphrase", 10).unwrap();
+        let mut ciphertext = hex::decode(&file.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        file.ciphertext = hex::encode(&ciphertext);
+        assert!(decrypt(&file, "passphrase").is_err());
+    }
+}
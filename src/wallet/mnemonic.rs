@@ -0,0 +1,150 @@
+//! Mnemonic phrases backing up the HD seed of a wallet, in the style of BIP39: the phrase
+//! encodes random entropy and a checksum, 11 bits per word, and the seed is stretched from the
+//! phrase with PBKDF2-HMAC-SHA512. The words are generated syllables such as `bika` rather than
+//! the BIP39 English list, so the phrases are not interchangeable with other wallets.
+
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::io::{Error, ErrorKind, Result};
+use std::num::NonZeroU32;
+
+/// Entropy of a new phrase, in bytes: 12 words.
+pub static ENTROPY_LEN: usize = 16;
+/// Number of words of the list, 2^11.
+pub static WORD_COUNT: usize = 2048;
+static SEED_ITERATIONS: u32 = 2048;
+static SEED_LEN: usize = 64;
+
+const CONSONANTS: &[u8; 16] = b"bdfghjklmnprstvz";
+const VOWELS: &[u8; 4] = b"aeiu";
+const LAST_VOWELS: &[u8; 2] = b"ao";
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, msg)
+}
+
+/// The word of index `index`, below `WORD_COUNT`: consonant, vowel, consonant, vowel.
+pub fn word(index: usize) -> String {
+    let bytes = [
+        CONSONANTS[index >> 7 & 15],
+        VOWELS[index >> 5 & 3],
+        CONSONANTS[index >> 1 & 15],
+        LAST_VOWELS[index & 1],
+    ];
+    String::from_utf8(bytes.to_vec()).unwrap()
+}
+
+fn index_of(word: &str) -> Option<usize> {
+    let bytes = word.as_bytes();
+    if bytes.len() != 4 {
+        return None;
+    }
+    let position = |set: &[u8], byte: u8| set.iter().position(|b| *b == byte);
+    Some(position(CONSONANTS, bytes[0])? << 7
+        | position(VOWELS, bytes[1])? << 5
+        | position(CONSONANTS, bytes[2])? << 1
+        | position(LAST_VOWELS, bytes[3])?)
+}
+
+/// The phrase encoding `entropy`, of 16 to 32 bytes in steps of 4.
+pub fn from_entropy(entropy: &[u8]) -> Result<String> {
+    if entropy.len() < 16 || entropy.len() > 32 || !entropy.len().is_multiple_of(4) {
+        return Err(invalid("the entropy must be 16 to 32 bytes, in steps of 4"));
+    }
+    let checksum = ring::digest::digest(&ring::digest::SHA256, entropy);
+    let mut bits: Vec<bool> = Vec::new();
+    for byte in entropy.iter().chain(checksum.as_ref().iter()) {
+        bits.extend((0..8).rev().map(|i| byte >> i & 1 == 1));
+    }
+    // one checksum bit per 32 bits of entropy
+    bits.truncate(entropy.len() * 8 * 33 / 32);
+    let words: Vec<String> = bits.chunks(11)
+        .map(|chunk| word(chunk.iter().fold(0, |index, bit| index << 1 | *bit as usize)))
+        .collect();
+    Ok(words.join(" "))
+}
+
+/// The entropy encoded by `phrase`. Fails on an unknown word, a wrong number of words or a
+/// wrong checksum.
+pub fn to_entropy(phrase: &str) -> Result<Vec<u8>> {
+    let indices = phrase.split_whitespace()
+        .map(|word| index_of(&word.to_lowercase()))
+        .collect::<Option<Vec<usize>>>()
+        .ok_or_else(|| invalid("unknown word in the mnemonic"))?;
+    if indices.len() < 12 || indices.len() > 24 || !indices.len().is_multiple_of(3) {
+        return Err(invalid("a mnemonic has 12 to 24 words, in steps of 3"));
+    }
+    let mut bits: Vec<bool> = Vec::new();
+    for index in indices {
+        bits.extend((0..11).rev().map(|i| index >> i & 1 == 1));
+    }
+    let entropy_len = bits.len() * 32 / 33 / 8;
+    let entropy: Vec<u8> = bits[..entropy_len * 8].chunks(8)
+        .map(|chunk| chunk.iter().fold(0, |byte, bit| byte << 1 | *bit as u8))
+        .collect();
+    if from_entropy(&entropy)? != normalize(phrase) {
+        return Err(invalid("wrong mnemonic checksum"));
+    }
+    Ok(entropy)
+}
+
+fn normalize(phrase: &str) -> String {
+    phrase.split_whitespace().map(|word| word.to_lowercase()).collect::<Vec<_>>().join(" ")
+}
+
+/// A new phrase of `ENTROPY_LEN` random bytes.
+pub fn generate() -> Result<String> {
+    let mut entropy = vec![0u8; ENTROPY_LEN];
+    SystemRandom::new().fill(&mut entropy).map_err(|_| Error::other("system randomness unavailable"))?;
+    from_entropy(&entropy)
+}
+
+/// The HD seed of `phrase`, which must be valid, further protected by the optional `password`.
+pub fn to_seed(phrase: &str, password: &str) -> Result<Vec<u8>> {
+    to_entropy(phrase)?;
+    let salt = format!("mnemonic{}", password);
+    let mut seed = vec![0u8; SEED_LEN];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA512, NonZeroU32::new(SEED_ITERATIONS).unwrap(),
+        salt.as_bytes(), normalize(phrase).as_bytes(), &mut seed);
+    Ok(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_are_distinct() {
+        let words: std::collections::HashSet<String> = (0..WORD_COUNT).map(word).collect();
+        assert_eq!(words.len(), WORD_COUNT);
+        assert!((0..WORD_COUNT).all(|index| index_of(&word(index)) == Some(index)));
+    }
+
+    #[test]
+    fn round_trip() {
+        let entropy: Vec<u8> = (0..16).collect();
+        let phrase = from_entropy(&entropy).unwrap();
+        assert_eq!(phrase.split(' ').count(), 12);
+        assert_eq!(to_entropy(&phrase).unwrap(), entropy);
+        assert_eq!(to_entropy(&phrase.to_uppercase().replace(' ', "  ")).unwrap(), entropy);
+        assert_eq!(from_entropy(&[0xff; 32]).unwrap().split(' ').count(), 24);
+        assert!(from_entropy(&[0; 15]).is_err());
+
+        // flipping a checksum bit of the last word
+        let mut words: Vec<String> = phrase.split(' ').map(String::from).collect();
+        words[11] = word(index_of(&words[11]).unwrap() ^ 1);
+        assert!(to_entropy(&words.join(" ")).is_err());
+        assert!(to_entropy("bika bika").is_err());
+        assert!(to_entropy(&phrase.replace(&phrase[..4], "xxxx")).is_err());
+    }
+
+    #[test]
+    fn seed_depends_on_password() {
+        let phrase = generate().unwrap();
+        let seed = to_seed(&phrase, "").unwrap();
+        assert_eq!(seed.len(), 64);
+        assert_eq!(to_seed(&phrase, "").unwrap(), seed);
+        assert_ne!(to_seed(&phrase, "password").unwrap(), seed);
+        assert!(to_seed("bika", "").is_err());
+    }
+}
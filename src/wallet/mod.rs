@@ -0,0 +1,286 @@
+pub mod hd;
+pub mod keystore;
+pub mod mnemonic;
+
+use crate::crypto::address::H160;
+use crate::miner::Identity;
+use crate::transaction::{sign, SignatureScheme, SignedTransaction, Transaction};
+use hd::ExtendedKey;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use log::info;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Path, PathBuf};
+
+/// Name of the encrypted HD seed in the keystore directory.
+pub static HD_SEED_FILE: &str = "hd_seed.key";
+
+/// A directory of encrypted key files, one `<address>.json` per key, all sharing a passphrase.
+/// The keys are random, or derived from the HD seed of the wallet, see `hd_accounts`.
+pub struct Wallet {
+    dir: PathBuf,
+    passphrase: String,
+    iterations: u32,
+    // decrypted secrets, see `key_pair_of`, sorted by address
+    keys: Vec<(H160, Vec<u8>)>,
+    hd_seed: Option<Vec<u8>>,
+}
+
+pub fn address_of(key_pair: &Ed25519KeyPair) -> H160 {
+    ring::digest::digest(&ring::digest::SHA256, key_pair.public_key().as_ref()).into()
+}
+
+/// The key pair of a secret: the 32-byte seed of a derived key, or a PKCS#8 document.
+fn key_pair_of(secret: &[u8]) -> Option<Ed25519KeyPair> {
+    if secret.len() == 32 {
+        Ed25519KeyPair::from_seed_unchecked(secret).ok()
+    } else {
+        Ed25519KeyPair::from_pkcs8(secret).ok()
+    }
+}
+
+impl Wallet {
+    /// Open the keystore at `dir`, creating the directory if needed, and decrypt every key in it.
+    pub fn open(dir: &Path, passphrase: &str) -> Result<Self> {
+        Self::open_with_iterations(dir, passphrase, keystore::PBKDF2_ITERATIONS)
+    }
+
+    fn open_with_iterations(dir: &Path, passphrase: &str, iterations: u32) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let paths: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension() == Some(std::ffi::OsStr::new("json")))
+            .collect();
+
+        let mut keys = Vec::new();
+        for path in paths.iter() {
+            let file = keystore::read(path)?;
+            let secret = keystore::decrypt(&file, passphrase)?;
+            let key_pair = key_pair_of(&secret)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "key file does not hold an ed25519 key"))?;
+            keys.push((address_of(&key_pair), secret));
+        }
+        keys.sort_by_key(|(address, _)| *address);
+        let hd_seed_path = dir.join(HD_SEED_FILE);
+        let hd_seed = if hd_seed_path.exists() {
+            Some(keystore::decrypt(&keystore::read(&hd_seed_path)?, passphrase)?)
+        } else {
+            None
+        };
+        info!("Loaded {} keys from keystore {}", keys.len(), dir.display());
+        Ok(Wallet {
+            dir: dir.to_path_buf(),
+            passphrase: passphrase.to_string(),
+            iterations,
+            keys,
+            hd_seed,
+        })
+    }
+
+    fn add_key(&mut self, address: H160, secret: &[u8]) -> Result<()> {
+        let file = keystore::encrypt_with_iterations(secret, &address.to_string(), &self.passphrase, self.iterations)?;
+        keystore::write(&self.dir.join(format!("{}.json", address)), &file)?;
+        self.keys.push((address, secret.to_vec()));
+        self.keys.sort_by_key(|(address, _)| *address);
+        Ok(())
+    }
+
+    /// Generate a fresh random key and persist it encrypted in the keystore.
+    pub fn generate_key(&mut self) -> Result<H160> {
+        let rng = SystemRandom::new();
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+            .map_err(|_| Error::other("key generation failed"))?;
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let address = address_of(&key_pair);
+        self.add_key(address, pkcs8.as_ref())?;
+        info!("Generated new key with address {}", address);
+        Ok(address)
+    }
+
+    /// Set the seed the HD accounts are derived from, and persist it encrypted in the keystore.
+    /// Fails if the wallet has a seed already.
+    pub fn set_hd_seed(&mut self, seed: &[u8]) -> Result<()> {
+        if self.hd_seed.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "the wallet has an HD seed already"));
+        }
+        let file = keystore::encrypt_with_iterations(seed, "", &self.passphrase, self.iterations)?;
+        keystore::write(&self.dir.join(HD_SEED_FILE), &file)?;
+        self.hd_seed = Some(seed.to_vec());
+        Ok(())
+    }
+
+    /// Set a new HD seed backed up by a fresh mnemonic phrase, and return the phrase. The wallet
+    /// must be empty, so that the phrase restores its identity.
+    pub fn new_mnemonic(&mut self) -> Result<String> {
+        let phrase = mnemonic::generate()?;
+        self.restore_mnemonic(&phrase)?;
+        Ok(phrase)
+    }
+
+    /// Set the HD seed backed up by `phrase` in an empty wallet, which then derives the same
+    /// identity and HD accounts as the wallet the phrase was created for.
+    pub fn restore_mnemonic(&mut self, phrase: &str) -> Result<()> {
+        if !self.keys.is_empty() || self.hd_seed.is_some() {
+            return Err(Error::new(ErrorKind::AlreadyExists, "the wallet is not empty"));
+        }
+        self.set_hd_seed(&mnemonic::to_seed(phrase, "")?)
+    }
+
+    /// The addresses of the first `count` HD accounts, at `hd::account_path(0)` onwards. The
+    /// accounts not in the keystore yet are derived and persisted, along with a random seed if
+    /// the wallet has none.
+    pub fn hd_accounts(&mut self, count: u32) -> Result<Vec<H160>> {
+        if self.hd_seed.is_none() {
+            let mut seed = vec![0u8; hd::SEED_LEN];
+            SystemRandom::new().fill(&mut seed).map_err(|_| Error::other("system randomness unavailable"))?;
+            self.set_hd_seed(&seed)?;
+        }
+        let seed = self.hd_seed.clone().unwrap();
+        let mut addresses = Vec::new();
+        for index in 0..count {
+            let key = ExtendedKey::derive(&seed, &hd::account_path(index))?;
+            let address = address_of(&key.key_pair());
+            if self.key_pair(&address).is_none() {
+                self.add_key(address, &key.key)?;
+                info!("Derived HD account {} with address {}", index, address);
+            }
+            addresses.push(address);
+        }
+        Ok(addresses)
+    }
+
+    pub fn addresses(&self) -> Vec<H160> {
+        self.keys.iter().map(|(address, _)| *address).collect()
+    }
+
+    pub fn key_pair(&self, address: &H160) -> Option<Ed25519KeyPair> {
+        self.keys.iter()
+            .find(|(a, _)| a == address)
+            .map(|(_, secret)| key_pair_of(secret).unwrap())
+    }
+
+    /// Sign `transaction` with the key of `from`, `None` if the wallet does not hold it.
+    pub fn sign(&self, from: &H160, transaction: Transaction) -> Option<SignedTransaction> {
+        let key_pair = self.key_pair(from)?;
+        Some(SignedTransaction {
+            signature: sign(&transaction, &key_pair).as_ref().to_vec(),
+            public_key: key_pair.public_key().as_ref().to_vec(),
+            transaction,
+            scheme: SignatureScheme::Ed25519,
+            multisig: None,
+            sender_cache: Default::default(),
+        })
+    }
+
+    /// Sign the multisig transaction `tx` with every key of the wallet among its cosigners.
+    /// Returns the number of signatures added.
+    pub fn cosign(&self, tx: &mut SignedTransaction) -> usize {
+        let transaction = &tx.transaction;
+        let multisig = match tx.multisig.as_mut() {
+            Some(multisig) => multisig,
+            None => return 0,
+        };
+        self.keys.iter()
+            .map(|(_, secret)| key_pair_of(secret).unwrap())
+            .filter(|key_pair| multisig.sign(transaction, key_pair))
+            .count()
+    }
+
+    /// The node identity: the random key with the lowest address. Without random keys, the HD
+    /// account 0 if the wallet has an HD seed, or else a newly generated key.
+    pub fn identity(&mut self) -> Result<Identity> {
+        // derived keys are stored as their 32-byte seed, random ones as a PKCS#8 document
+        let address = match self.keys.iter().find(|(_, secret)| secret.len() != 32) {
+            Some((address, _)) => *address,
+            None if self.hd_seed.is_some() => self.hd_accounts(1)?[0],
+            None => self.generate_key()?,
+        };
+        Ok(Identity::from_key_pair(self.key_pair(&address).unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::{Multisig, MultisigPolicy};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("prism-wallet-{}", rand::random::<u64>()))
+    }
+
+    #[test]
+    fn keys_persist_across_open() {
+        let dir = temp_dir();
+        let mut wallet = Wallet::open_with_iterations(&dir, "passphrase", 10).unwrap();
+        let identity = wallet.identity().unwrap();
+        let second = wallet.generate_key().unwrap();
+
+        let mut reopened = Wallet::open_with_iterations(&dir, "passphrase", 10).unwrap();
+        let mut addresses = vec![identity.address, second];
+        addresses.sort();
+        assert_eq!(reopened.addresses(), addresses);
+        assert_eq!(reopened.identity().unwrap().address, addresses[0]);
+        assert!(Wallet::open_with_iterations(&dir, "wrong", 10).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hd_accounts_are_deterministic() {
+        let dir = temp_dir();
+        let mut wallet = Wallet::open_with_iterations(&dir, "passphrase", 10).unwrap();
+        wallet.set_hd_seed(&[7; 32]).unwrap();
+        assert!(wallet.set_hd_seed(&[8; 32]).is_err());
+        let accounts = wallet.hd_accounts(3).unwrap();
+        assert_eq!(wallet.hd_accounts(2).unwrap(), accounts[..2].to_vec());
+        assert_eq!(accounts[1], address_of(&ExtendedKey::derive(&[7; 32], "m/44'/0'/1'").unwrap().key_pair()));
+
+        // the seed and the derived keys persist
+        let mut reopened = Wallet::open_with_iterations(&dir, "passphrase", 10).unwrap();
+        assert_eq!(reopened.addresses().len(), 3);
+        assert_eq!(reopened.hd_accounts(4).unwrap()[..3], accounts[..]);
+
+        let tx = reopened.sign(&accounts[2], Transaction::default()).unwrap();
+        assert!(tx.has_valid_signature());
+        assert_eq!(tx.sender(), accounts[2]);
+        assert!(reopened.sign(&H160::default(), Transaction::default()).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restores_from_mnemonic() {
+        let (dir, other_dir) = (temp_dir(), temp_dir());
+        let mut wallet = Wallet::open_with_iterations(&dir, "passphrase", 10).unwrap();
+        let phrase = wallet.new_mnemonic().unwrap();
+        let identity = wallet.identity().unwrap();
+        let accounts = wallet.hd_accounts(2).unwrap();
+        assert_eq!(identity.address, accounts[0]);
+
+        let mut restored = Wallet::open_with_iterations(&other_dir, "other", 10).unwrap();
+        assert!(restored.restore_mnemonic("bika bika").is_err());
+        restored.restore_mnemonic(&phrase).unwrap();
+        assert_eq!(restored.identity().unwrap().address, identity.address);
+        assert_eq!(restored.hd_accounts(2).unwrap(), accounts);
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&other_dir).unwrap();
+    }
+
+    #[test]
+    fn cosigns_with_its_keys() {
+        let dir = temp_dir();
+        let mut wallet = Wallet::open_with_iterations(&dir, "passphrase", 10).unwrap();
+        let mut public_keys: Vec<Vec<u8>> = (0..2).map(|_| {
+            let address = wallet.generate_key().unwrap();
+            wallet.key_pair(&address).unwrap().public_key().as_ref().to_vec()
+        }).collect();
+        public_keys.push(crate::crypto::key_pair::random().public_key().as_ref().to_vec());
+        let mut tx = SignedTransaction {
+            multisig: Some(Multisig::new(MultisigPolicy::new(2, public_keys))),
+            sender_cache: Default::default(),
+            ..Default::default()
+        };
+        assert_eq!(wallet.cosign(&mut tx), 2);
+        assert!(tx.has_valid_signature());
+        assert_eq!(wallet.cosign(&mut SignedTransaction::default()), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}